@@ -47,8 +47,8 @@ pub async fn start_raft_app(node_id: NodeId, http_addr: String) -> Result<(), Bo
 
 
     // Create stores and network
-    let log_store = LogStore::new(db);
-    let state_machine_store = Arc::new(StateMachineStore::default());
+    let state_machine_store = Arc::new(StateMachineStore::open(&db)?);
+    let log_store = LogStore::new(db)?;
     let network = Network {};
 
     // Create Raft instance
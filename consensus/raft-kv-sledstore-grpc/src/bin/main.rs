@@ -75,8 +75,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // let db = Arc::new(db);
 
     // Create stores and network
-    let log_store = LogStore::new(db);
-    let state_machine_store = Arc::new(StateMachineStore::default());
+    let state_machine_store = Arc::new(StateMachineStore::open(&db)?);
+    let log_store = LogStore::new(db)?;
     let network = Network {};
 
     // Create Raft instance
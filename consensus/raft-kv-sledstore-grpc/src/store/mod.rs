@@ -12,6 +12,7 @@ use crate::typ::*;
 use crate::TypeConfig;
 
 pub type LogStore = sledstore::SledLogStore<TypeConfig>;
+pub type SnapshotStore = sledstore::SledSnapshotStore<TypeConfig>;
 
 #[derive(Debug)]
 pub struct StoredSnapshot {
@@ -32,6 +33,28 @@ pub struct StateMachineStore {
 
     /// The last received snapshot.
     current_snapshot: Mutex<Option<StoredSnapshot>>,
+
+    /// Where snapshots are persisted. `None` keeps them in memory only.
+    snapshot_store: Option<SnapshotStore>,
+}
+
+impl StateMachineStore {
+    /// Create a state machine that persists its snapshots in `db`, restored from the last
+    /// snapshot saved there. Raft replays the logs after that snapshot on startup.
+    pub fn open(db: &sled::Db) -> Result<Self, StorageError> {
+        let snapshot_store = SnapshotStore::new(db)?;
+        let mut store = Self::default();
+
+        if let Some((meta, data)) = snapshot_store.load()? {
+            let state_machine: pb::StateMachineData = prost::Message::decode(data.as_slice())
+                .map_err(|e| StorageError::read_snapshot(Some(meta.signature()), &e))?;
+            *store.state_machine.get_mut().unwrap() = state_machine;
+            *store.current_snapshot.get_mut().unwrap() = Some(StoredSnapshot { meta, data });
+        }
+
+        store.snapshot_store = Some(snapshot_store);
+        Ok(store)
+    }
 }
 
 impl RaftSnapshotBuilder<TypeConfig> for Arc<StateMachineStore> {
@@ -71,12 +94,15 @@ impl RaftSnapshotBuilder<TypeConfig> for Arc<StateMachineStore> {
             snapshot_id,
         };
 
+        if let Some(snapshot_store) = &self.snapshot_store {
+            snapshot_store.save(&meta, &data).await?;
+        }
+
         let stored = StoredSnapshot {
             meta: meta.clone(),
             data: data.clone(),
         };
 
-        // Emulation of storing snapshot locally
         {
             let mut current_snapshot = self.current_snapshot.lock().unwrap();
             *current_snapshot = Some(stored);
@@ -146,11 +172,16 @@ impl RaftStateMachine<TypeConfig> for Arc<StateMachineStore> {
             data: snapshot,
         };
 
+        let d: pb::StateMachineData = prost::Message::decode(new_snapshot.data.as_ref())
+            .map_err(|e| StorageError::read_snapshot(None, &e))?;
+
+        // Persist before touching the state machine, so a failed write leaves the old state in place.
+        if let Some(snapshot_store) = &self.snapshot_store {
+            snapshot_store.save(&new_snapshot.meta, &new_snapshot.data).await?;
+        }
+
         // Update the state machine.
         {
-            let d: pb::StateMachineData = prost::Message::decode(new_snapshot.data.as_ref())
-                .map_err(|e| StorageError::read_snapshot(None, &e))?;
-
             let mut state_machine = self.state_machine.lock().unwrap();
             *state_machine = d;
         }
@@ -1,8 +1,14 @@
 use std::sync::Arc;
 
+use openraft::entry::RaftEntry;
+use openraft::storage::RaftLogStorage;
+use openraft::storage::RaftStateMachine;
 use openraft::testing::log::StoreBuilder;
 use openraft::testing::log::Suite;
+use openraft::RaftLogReader;
+use openraft::RaftSnapshotBuilder;
 
+use crate::protobuf as pb;
 use crate::store::LogStore;
 use crate::store::StateMachineStore;
 use crate::typ::*;
@@ -21,3 +27,57 @@ pub async fn test_mem_store() -> Result<(), StorageError> {
     Suite::test_all(MemKVStoreBuilder {}).await?;
     Ok(())
 }
+
+fn set_entry(index: u64) -> Entry {
+    let req = pb::SetRequest {
+        key: format!("key-{}", index % 4),
+        value: format!("value-{}", index),
+    };
+    Entry::new(LogId::new(1, index), EntryPayload::Normal(req))
+}
+
+/// Build a snapshot, purge the logs it covers, then restart from the sled db alone.
+#[tokio::test]
+pub async fn test_snapshot_survives_restart() -> Result<(), StorageError> {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let mut log_store = LogStore::new(Arc::new(db.clone()))?;
+    let mut sm = Arc::new(StateMachineStore::open(&db)?);
+
+    let entries: Vec<Entry> = (1..=10).map(set_entry).collect();
+    log_store.append(entries.clone(), IOFlushed::noop()).await?;
+    log_store.flush_pending().await;
+
+    sm.apply(entries[..8].to_vec()).await?;
+    let snapshot = sm.get_snapshot_builder().await.build_snapshot().await?;
+    let snapshot_log_id = snapshot.meta.last_log_id.clone().unwrap();
+    assert_eq!(snapshot_log_id, LogId::new(1, 8));
+    log_store.purge(snapshot_log_id.clone()).await?;
+
+    sm.apply(entries[8..].to_vec()).await?;
+    let expected = sm.state_machine.lock().unwrap().clone();
+    drop(sm);
+
+    let mut sm = Arc::new(StateMachineStore::open(&db)?);
+    let (last_applied, _) = sm.applied_state().await?;
+    assert_eq!(last_applied, Some(snapshot_log_id.clone()));
+    assert_eq!(sm.get_current_snapshot().await?.unwrap().meta, snapshot.meta);
+
+    // Only the entries after the snapshot are left to replay
+    let remaining = log_store.try_get_log_entries(..).await?;
+    assert_eq!(remaining.iter().map(|e| e.index).collect::<Vec<_>>(), vec![9, 10]);
+    assert_eq!(log_store.get_log_state().await?.last_purged_log_id, Some(snapshot_log_id.clone()));
+    sm.apply(remaining).await?;
+    assert_eq!(*sm.state_machine.lock().unwrap(), expected);
+
+    // An installed snapshot replaces the persisted one
+    let other_db = sled::Config::new().temporary(true).open().unwrap();
+    let mut other = Arc::new(StateMachineStore::open(&other_db)?);
+    other.apply((1..=3).map(set_entry)).await?;
+    other.install_snapshot(&snapshot.meta, snapshot.snapshot.clone()).await?;
+    drop(other);
+
+    let other = StateMachineStore::open(&other_db)?;
+    assert_eq!(other.state_machine.lock().unwrap().last_applied, Some(snapshot_log_id.into()));
+    assert_eq!(other.state_machine.lock().unwrap().data.len(), 4);
+    Ok(())
+}
@@ -0,0 +1,114 @@
+//! Group commit for the log tree.
+//!
+//! `append` only applies its batch and hands the `IOFlushed` callback over to the [`Flusher`].
+//! A single background task flushes the tree once for every callback collected so far, so a
+//! burst of appends shares one durable flush instead of paying for one each.
+
+use std::fmt;
+use std::io;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use openraft::storage::IOFlushed;
+use openraft::RaftTypeConfig;
+use tokio::sync::Notify;
+
+pub(crate) struct Flusher<C>
+where C: RaftTypeConfig
+{
+    tree: sled::Tree,
+    state: Mutex<FlushState<C>>,
+    idle: Notify,
+    flushes: AtomicU64,
+}
+
+struct FlushState<C>
+where C: RaftTypeConfig
+{
+    pending: Vec<IOFlushed<C>>,
+    running: bool,
+}
+
+impl<C> Flusher<C>
+where C: RaftTypeConfig
+{
+    pub(crate) fn new(tree: sled::Tree) -> Self {
+        Self {
+            tree,
+            state: Mutex::new(FlushState {
+                pending: Vec::new(),
+                running: false,
+            }),
+            idle: Notify::new(),
+            flushes: AtomicU64::new(0),
+        }
+    }
+
+    /// Queue `callback` to be completed by the next flush, starting the flush task if idle.
+    pub(crate) fn submit(self: &Arc<Self>, callback: IOFlushed<C>) {
+        let mut state = self.state.lock().unwrap();
+        state.pending.push(callback);
+        if !state.running {
+            state.running = true;
+            tokio::spawn(Arc::clone(self).run());
+        }
+    }
+
+    /// Flush the tree right away, outside of any group, and count it.
+    pub(crate) async fn flush_now(&self) -> sled::Result<usize> {
+        let res = self.tree.flush_async().await;
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+        res
+    }
+
+    /// Number of flushes issued so far.
+    pub(crate) fn flush_count(&self) -> u64 {
+        self.flushes.load(Ordering::Relaxed)
+    }
+
+    /// Wait until every submitted callback has been completed.
+    pub(crate) async fn wait_idle(&self) {
+        loop {
+            let notified = self.idle.notified();
+            if !self.state.lock().unwrap().running {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    async fn run(self: Arc<Self>) {
+        loop {
+            let group = {
+                let mut state = self.state.lock().unwrap();
+                if state.pending.is_empty() {
+                    state.running = false;
+                    self.idle.notify_waiters();
+                    return;
+                }
+                std::mem::take(&mut state.pending)
+            };
+
+            let res = self.tree.flush_async().await;
+            self.flushes.fetch_add(1, Ordering::Relaxed);
+
+            for callback in group {
+                let res = match &res {
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(io::Error::new(io::ErrorKind::Other, e.to_string())),
+                };
+                callback.io_completed(res);
+            }
+        }
+    }
+}
+
+impl<C> fmt::Debug for Flusher<C>
+where C: RaftTypeConfig
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Flusher").field("flushes", &self.flush_count()).finish()
+    }
+}
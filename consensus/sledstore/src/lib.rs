@@ -1,5 +1,8 @@
 //! Provide storage layer implementation for examples.
 
+mod flusher;
 mod log_store;
+mod snapshot_store;
 
 pub use log_store::SledLogStore;
+pub use snapshot_store::SledSnapshotStore;
@@ -1,7 +1,9 @@
 use std::error::Error;
 use std::fmt::Debug;
+use std::io;
 use std::marker::PhantomData;
 use std::ops::RangeBounds;
+use std::path::Path;
 use std::sync::Arc;
 
 use bincode::{deserialize, serialize};
@@ -15,6 +17,9 @@ use openraft::alias::VoteOf;
 use openraft::entry::RaftEntry;
 use openraft::storage::IOFlushed;
 use openraft::storage::RaftLogStorage;
+use openraft::AnyError;
+use openraft::ErrorSubject;
+use openraft::ErrorVerb;
 use openraft::LogState;
 use openraft::OptionalSend;
 use openraft::RaftLogReader;
@@ -23,6 +28,8 @@ use openraft::StorageError;
 
 use sled::Db;
 
+use crate::flusher::Flusher;
+
 #[derive(Debug, Clone)]
 pub struct SledLogStore<C>
 where C: RaftTypeConfig
@@ -30,24 +37,44 @@ where C: RaftTypeConfig
     db: Arc<Db>,
     meta: sled::Tree,
     logs: sled::Tree,
+    flusher: Arc<Flusher<C>>,
     _p: PhantomData<C>,
 }
 
 impl<C> SledLogStore<C>
 where C: RaftTypeConfig
 {
-    pub fn new(db: Arc<Db>) -> Self {
+    pub fn new(db: Arc<Db>) -> Result<Self, StorageError<C>> {
         // db.cf_handle("meta").expect("column family `meta` not found");
         // db.cf_handle("logs").expect("column family `logs` not found");
-        let meta = db.open_tree("meta").expect("tree meta open failed");
-        let logs = db.open_tree("logs").expect("tree logs open failed");
-        
-        Self {
+        let meta = db.open_tree("meta").map_err(open_err)?;
+        let logs = db.open_tree("logs").map_err(open_err)?;
+
+        let flusher = Arc::new(Flusher::new(logs.clone()));
+
+        Ok(Self {
             db,
             meta,
             logs,
+            flusher,
             _p: Default::default(),
-        }
+        })
+    }
+
+    /// Open (or create) the sled database at `path` and the log store in it.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StorageError<C>> {
+        let db = sled::open(path).map_err(open_err)?;
+        Self::new(Arc::new(db))
+    }
+
+    /// Wait until every appended entry has been flushed and its callback completed.
+    pub async fn flush_pending(&self) {
+        self.flusher.wait_idle().await
+    }
+
+    /// Number of flushes the log tree has gone through, for appends and truncates.
+    pub fn flush_count(&self) -> u64 {
+        self.flusher.flush_count()
     }
 
     /// Get a store metadata.
@@ -125,39 +152,33 @@ where C: RaftTypeConfig
         &mut self,
         range: RB,
     ) -> Result<Vec<C::Entry>, StorageError<C>> {
-        let start_bound = range.start_bound();
-        let start = match start_bound {
+        let start = match range.start_bound() {
             std::ops::Bound::Included(x) => id_to_bin(*x),
             std::ops::Bound::Excluded(x) => id_to_bin(*x + 1),
             std::ops::Bound::Unbounded => id_to_bin(0),
         };
-        let logs_tree = &self.logs;
-        let logs = logs_tree
-            .range::<&[u8], _>(start.as_slice()..)
-            .map(|el_res| {
-                // TODO remove expect
-                // let (id, val) = el_res.map_err(read_logs_err)?;
-                let el = el_res.expect("Failed read log entry");
-                let id = el.0;
-                let val = el.1;
-
-                // let entry: StorageResult<Entry<_>> = serde_json::from_slice(&val).map_err(|e| StorageError::IO {
-                //     source: StorageIOError::read_logs(&e),
-                // });
-                // serde_json::from_slice
-                // TODO remove expect
-                let entry: EntryOf<C> = deserialize(&val).expect("bad");
-    
-                let id = bin_to_id(&id);
-
-                //assert_eq!(Ok(id), entry.as_ref().map(|e| e.log_id.index));
-                assert_eq!(id, entry.index());
-                (id, entry)
-            })
-            .take_while(|(id, _)| range.contains(id))
-            .map(|x| x.1)
-            .collect();
-        Ok(logs)
+
+        let mut res = Vec::new();
+        for item_res in self.logs.range::<&[u8], _>(start.as_slice()..) {
+            let (id, val) = item_res.map_err(read_logs_err)?;
+
+            let id = bin_to_id(&id);
+            if !range.contains(&id) {
+                break;
+            }
+
+            let entry: EntryOf<C> = deserialize(&val).map_err(read_logs_err)?;
+            if id != entry.index() {
+                let e = io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("log entry stored at index {} has index {}", id, entry.index()),
+                );
+                return Err(read_logs_err(e));
+            }
+
+            res.push(entry);
+        }
+        Ok(res)
     }
 
     async fn read_vote(&mut self) -> Result<Option<VoteOf<C>>, StorageError<C>> {
@@ -255,10 +276,9 @@ where C: RaftTypeConfig
 
         //self.db.flush_wal(true).map_err(|e| StorageError::write_logs(&e))?;
 
-        logs_tree.flush_async().await.map_err(|e| StorageError::write_logs(&e))?;
-
-        // If there is error, the callback will be dropped.
-        callback.io_completed(Ok(()));
+        // The entries are visible now; durability is reported through the callback once the
+        // flusher has flushed them, together with any other append queued in the meantime.
+        self.flusher.submit(callback);
         Ok(())
     }
 
@@ -277,8 +297,11 @@ where C: RaftTypeConfig
             batch_del.remove(entry.0);
         }
         logs_tree.apply_batch(batch_del).map_err(|e| StorageError::write_logs(&e))?;
-        logs_tree.flush_async().await.map_err(|e| StorageError::write_logs(&e))?;
-        
+        // Truncate flushes on its own and does not share the flush of a following append: a
+        // conflicting follower truncates without appending anything after it
+        // (`ensure_log_consecutive`), so there may be no append flush to ride on.
+        self.flusher.flush_now().await.map_err(|e| StorageError::write_logs(&e))?;
+
         Ok(())
     }
 
@@ -304,8 +327,8 @@ where C: RaftTypeConfig
         }
         logs_tree.apply_batch(batch_del).map_err(|e| StorageError::write_logs(&e))?;
 
-        logs_tree.flush_async().await.map_err(|e| StorageError::write_logs(&e))?;
-        // ??? Purging does not need to be persistent.
+        // Purging does not need to be persistent: purged logs are ignored via `LastPurged`,
+        // and the removal is flushed along with the next append.
         Ok(())
     }
 }
@@ -385,4 +408,95 @@ fn bin_to_id(buf: &[u8]) -> u64 {
 fn read_logs_err<C>(e: impl Error + 'static) -> StorageError<C>
 where C: RaftTypeConfig {
     StorageError::read_logs(&e)
+}
+
+pub(crate) fn open_err<C>(e: impl Error + 'static) -> StorageError<C>
+where C: RaftTypeConfig {
+    StorageError::new(ErrorSubject::Store, ErrorVerb::Read, AnyError::new(&e))
+}
+
+#[cfg(test)]
+mod tests {
+    use openraft::testing::log_id;
+
+    use super::*;
+
+    openraft::declare_raft_types!(pub TypeConfig);
+
+    #[test]
+    fn test_open_invalid_db_returns_error() {
+        let path = std::env::temp_dir().join(format!("sledstore-not-a-dir-{}", std::process::id()));
+        std::fs::write(&path, b"not a sled database").unwrap();
+
+        let res = SledLogStore::<TypeConfig>::open(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_append_burst_coalesces_flushes() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut store = SledLogStore::<TypeConfig>::new(Arc::new(db)).unwrap();
+
+        for index in 1..=100 {
+            let entry = EntryOf::<TypeConfig>::new_blank(log_id(1, 0, index));
+            store.append([entry], IOFlushed::noop()).await.unwrap();
+        }
+        store.flush_pending().await;
+
+        assert!(store.flush_count() >= 1);
+        assert!(store.flush_count() < 10, "{} flushes for 100 appends", store.flush_count());
+
+        let state = store.get_log_state().await.unwrap();
+        assert_eq!(state.last_log_id, Some(log_id(1, 0, 100)));
+        assert_eq!(store.try_get_log_entries(1..=100).await.unwrap().len(), 100);
+    }
+
+    #[tokio::test]
+    async fn test_truncate_then_append() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut store = SledLogStore::<TypeConfig>::new(Arc::new(db)).unwrap();
+
+        let entries = (1..=5).map(|index| EntryOf::<TypeConfig>::new_blank(log_id(1, 0, index)));
+        store.append(entries, IOFlushed::noop()).await.unwrap();
+        store.flush_pending().await;
+        let before = store.flush_count();
+
+        // A truncate stands on its own, no append has to follow, so it flushes by itself
+        store.truncate(log_id(1, 0, 3)).await.unwrap();
+        assert_eq!(store.flush_count(), before + 1);
+        let state = store.get_log_state().await.unwrap();
+        assert_eq!(state.last_log_id, Some(log_id(1, 0, 2)));
+
+        let entries = (3..=4).map(|index| EntryOf::<TypeConfig>::new_blank(log_id(2, 0, index)));
+        store.append(entries, IOFlushed::noop()).await.unwrap();
+        store.flush_pending().await;
+
+        // The append after it takes a flush of its own
+        assert_eq!(store.flush_count(), before + 2);
+        let state = store.get_log_state().await.unwrap();
+        assert_eq!(state.last_log_id, Some(log_id(2, 0, 4)));
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_log_entry_returns_error() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut store = SledLogStore::<TypeConfig>::new(Arc::new(db)).unwrap();
+
+        let entries = (1..=3).map(|index| EntryOf::<TypeConfig>::new_blank(log_id(1, 0, index)));
+        store.append(entries, IOFlushed::noop()).await.unwrap();
+        store.flush_pending().await;
+
+        // Not a serialized entry
+        store.logs.insert(id_to_bin(4), &b"\xff\xff"[..]).unwrap();
+        assert!(store.try_get_log_entries(1..=4).await.is_err());
+        // Entries before the corrupt one still read fine
+        assert_eq!(store.try_get_log_entries(1..=3).await.unwrap().len(), 3);
+
+        // A valid entry stored under the wrong index
+        let misplaced = serialize(&EntryOf::<TypeConfig>::new_blank(log_id(1, 0, 2))).unwrap();
+        store.logs.insert(id_to_bin(4), misplaced).unwrap();
+        assert!(store.try_get_log_entries(..).await.is_err());
+    }
 }
\ No newline at end of file
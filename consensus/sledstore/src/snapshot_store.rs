@@ -0,0 +1,71 @@
+//! Persistent storage for the latest state machine snapshot.
+//!
+//! The `snapshot` tree holds a single snapshot: its [`SnapshotMeta`], which carries the last
+//! applied log id, and the serialized state machine. Saving replaces both in one sled batch, so
+//! after a crash the tree holds either the old snapshot or the new one, never a mix of the two.
+
+use std::error::Error;
+use std::io;
+use std::marker::PhantomData;
+
+use bincode::{deserialize, serialize};
+use openraft::RaftTypeConfig;
+use openraft::SnapshotMeta;
+use openraft::StorageError;
+use sled::Db;
+
+use crate::log_store::open_err;
+
+const META_KEY: &str = "meta";
+const DATA_KEY: &str = "data";
+
+#[derive(Debug, Clone)]
+pub struct SledSnapshotStore<C>
+where C: RaftTypeConfig
+{
+    tree: sled::Tree,
+    _p: PhantomData<C>,
+}
+
+impl<C> SledSnapshotStore<C>
+where C: RaftTypeConfig
+{
+    pub fn new(db: &Db) -> Result<Self, StorageError<C>> {
+        let tree = db.open_tree("snapshot").map_err(open_err)?;
+        Ok(Self {
+            tree,
+            _p: Default::default(),
+        })
+    }
+
+    /// Replace the stored snapshot with `meta` and `data`, and wait until it is durable.
+    pub async fn save(&self, meta: &SnapshotMeta<C>, data: &[u8]) -> Result<(), StorageError<C>> {
+        let bin_meta = serialize(meta).map_err(|e| write_snapshot_err(meta, e))?;
+        let mut batch = sled::Batch::default();
+        batch.insert(META_KEY, bin_meta);
+        batch.insert(DATA_KEY, data);
+        self.tree.apply_batch(batch).map_err(|e| write_snapshot_err(meta, e))?;
+        self.tree.flush_async().await.map_err(|e| write_snapshot_err(meta, e))?;
+        Ok(())
+    }
+
+    /// The stored snapshot, or `None` if no snapshot has been saved yet.
+    pub fn load(&self) -> Result<Option<(SnapshotMeta<C>, Vec<u8>)>, StorageError<C>> {
+        let Some(bin_meta) = self.tree.get(META_KEY).map_err(|e| StorageError::read_snapshot(None, &e))? else {
+            return Ok(None);
+        };
+        let meta: SnapshotMeta<C> = deserialize(&bin_meta).map_err(|e| StorageError::read_snapshot(None, &e))?;
+
+        let data = self.tree.get(DATA_KEY).map_err(|e| StorageError::read_snapshot(Some(meta.signature()), &e))?;
+        let Some(data) = data else {
+            let e = io::Error::new(io::ErrorKind::NotFound, "snapshot data missing");
+            return Err(StorageError::read_snapshot(Some(meta.signature()), &e));
+        };
+        Ok(Some((meta, data.to_vec())))
+    }
+}
+
+fn write_snapshot_err<C>(meta: &SnapshotMeta<C>, e: impl Error + 'static) -> StorageError<C>
+where C: RaftTypeConfig {
+    StorageError::write_snapshot(Some(meta.signature()), &e)
+}
@@ -0,0 +1,64 @@
+//! Compares deriving several key sets with a new Argon2 buffer per set
+//! against reusing one `KeyDeriver`.
+//!
+//! Run with `cargo bench -p crypto --bench key_derivation`.
+
+use crypto::master_keys::{KeyDeriver, MasterKeys};
+use crypto::CipherOption;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const KEY_SETS: u8 = 4;
+
+fn measure(name: &str, run: impl FnOnce()) {
+    ALLOCATIONS.store(0, Ordering::Relaxed);
+    ALLOCATED_BYTES.store(0, Ordering::Relaxed);
+    let start = Instant::now();
+
+    run();
+
+    println!(
+        "{name:<16} {:>8.2?} {:>6} allocations {:>8} MiB allocated",
+        start.elapsed(),
+        ALLOCATIONS.load(Ordering::Relaxed),
+        ALLOCATED_BYTES.load(Ordering::Relaxed) / (1024 * 1024)
+    );
+}
+
+fn main() {
+    let chain = [CipherOption::AES256, CipherOption::XChaCha20];
+
+    measure("deriver per set", || {
+        for i in 0..KEY_SETS {
+            MasterKeys::from_entropy_for(&[i; 32], &chain).unwrap();
+        }
+    });
+
+    measure("shared deriver", || {
+        let mut deriver = KeyDeriver::new().unwrap();
+        for i in 0..KEY_SETS {
+            MasterKeys::from_entropy_with(&mut deriver, &[i; 32], &chain).unwrap();
+        }
+    });
+}
@@ -1,14 +1,86 @@
 use hmac::Hmac;
 use rand::{rngs::OsRng, RngCore};
 use sha2::{Digest, Sha256, Sha512};
+use std::collections::HashMap;
+use std::sync::LazyLock;
 use thiserror::Error;
+use unicode_normalization::UnicodeNormalization;
+
+struct Wordlist {
+    words: Vec<&'static str>,
+    index: HashMap<&'static str, usize>,
+}
+
+impl Wordlist {
+    fn load(list: &'static str) -> Self {
+        let words: Vec<&'static str> = list.lines().collect();
+        let index = words
+            .iter()
+            .enumerate()
+            .map(|(idx, &word)| (word, idx))
+            .collect();
+        Self { words, index }
+    }
+}
+
+static ENGLISH: LazyLock<Wordlist> =
+    LazyLock::new(|| Wordlist::load(include_str!("wordlist/english.txt")));
+static JAPANESE: LazyLock<Wordlist> =
+    LazyLock::new(|| Wordlist::load(include_str!("wordlist/japanese.txt")));
+static SPANISH: LazyLock<Wordlist> =
+    LazyLock::new(|| Wordlist::load(include_str!("wordlist/spanish.txt")));
+
+/// Wordlists compiled in. Further BIP39 languages are added by placing the spec's
+/// list in `wordlist/` and adding a variant here. The lists are stored NFKD-normalized
+/// as the spec publishes them, and input words are normalized the same way.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Language {
+    English,
+    Japanese,
+    Spanish,
+}
+
+impl Language {
+    pub const ALL: [Language; 3] = [Language::English, Language::Japanese, Language::Spanish];
+
+    fn wordlist(&self) -> &'static Wordlist {
+        match self {
+            Language::English => &ENGLISH,
+            Language::Japanese => &JAPANESE,
+            Language::Spanish => &SPANISH,
+        }
+    }
+
+    /// Joins the words of a mnemonic; Japanese uses an ideographic space (U+3000)
+    fn separator(&self) -> &'static str {
+        match self {
+            Language::Japanese => "\u{3000}",
+            _ => " ",
+        }
+    }
+
+    /// Language whose list holds every word, or `None` if no single list does
+    fn detect(words: &[String]) -> Option<Language> {
+        Self::ALL.into_iter().find(|language| {
+            let index = &language.wordlist().index;
+            words.iter().all(|word| index.contains_key(word.as_str()))
+        })
+    }
+}
 
 #[derive(Debug, Error)]
 pub enum Bip39Error {
     #[error("Invalid entropy length")]
     InvalidEntropyLength,
+    #[error("Invalid strength {0} bits, expected 128, 160, 192, 224 or 256")]
+    InvalidStrength(u32),
     #[error("Invalid mnemonic")]
     InvalidMnemonic,
+    #[error("Invalid word count {0}, expected 12, 15, 18, 21 or 24 words")]
+    InvalidWordCount(usize),
+    /// `position` counts words from 1, as the user sees them
+    #[error("Unknown word \"{word}\" at position {position}")]
+    UnknownWord { word: String, position: usize },
     #[error("Invalid str hex conv: {0}")]
     InvalidStrHex(String),
     #[error("passmg-cli err: {0}")]
@@ -19,53 +91,67 @@ pub enum Bip39Error {
     RngError,
 }
 
+/// Entropy sizes in bits accepted by `Bip39::new`, giving 12 to 24 words
+pub const VALID_STRENGTHS: [u32; 5] = [128, 160, 192, 224, 256];
+
 pub struct Bip39 {
     entropy: Vec<u8>,
     mnemonic: Vec<String>,
+    language: Language,
 }
 
 impl Bip39 {
     pub fn new(strength: u32) -> Result<Self, Bip39Error> {
-        let entropy_bytes = match strength {
-            128 => 16,
-            160 => 20,
-            192 => 24,
-            224 => 28,
-            256 => 32,
-            _ => return Err(Bip39Error::InvalidEntropyLength),
-        };
+        Self::new_with_language(strength, Language::English)
+    }
+
+    pub fn new_with_language(strength: u32, language: Language) -> Result<Self, Bip39Error> {
+        if !VALID_STRENGTHS.contains(&strength) {
+            return Err(Bip39Error::InvalidStrength(strength));
+        }
+        let entropy_bytes = strength as usize / 8;
 
         let mut entropy = vec![0u8; entropy_bytes];
         OsRng.fill_bytes(&mut entropy);
 
-        let mnemonic = Self::entropy_to_mnemonic(&entropy)?;
-        Ok(Self { entropy, mnemonic })
+        let mnemonic = Self::entropy_to_mnemonic(&entropy, language)?;
+        Ok(Self {
+            entropy,
+            mnemonic,
+            language,
+        })
     }
 
+    /// Parse a mnemonic in whichever compiled-in language contains all of its words
     pub fn from_mnemonic(mnemonic: &str) -> Result<Self, Bip39Error> {
-        let words: Vec<String> = mnemonic.split_whitespace().map(String::from).collect();
-
-        if !Self::verify_mnemonic(&words) {
-            return Err(Bip39Error::InvalidMnemonic);
-        }
+        let words = Self::split_words(mnemonic);
+        // No list matches: English reports which word is unknown
+        let language = Language::detect(&words).unwrap_or(Language::English);
+        Self::from_words(words, language)
+    }
 
-        let entropy = Self::mnemonic_to_entropy(&words)?;
-        Ok(Self {
-            entropy,
-            mnemonic: words,
-        })
+    pub fn from_mnemonic_with_language(
+        mnemonic: &str,
+        language: Language,
+    ) -> Result<Self, Bip39Error> {
+        Self::from_words(Self::split_words(mnemonic), language)
     }
 
     pub fn get_mnemonic(&self) -> String {
-        self.mnemonic.join(" ")
+        self.mnemonic.join(self.language.separator())
+    }
+
+    pub fn language(&self) -> Language {
+        self.language
     }
     pub fn get_entropy(&self) -> &Vec<u8> {
         &self.entropy
     }
 
+    /// Mnemonic and passphrase are NFKD-normalized first, as the spec requires
     pub fn get_seed(&self, passphrase: &str) -> Vec<u8> {
-        let mnemonic = self.get_mnemonic();
-        let salt = format!("mnemonic{passphrase}");
+        let mnemonic: String = self.get_mnemonic().nfkd().collect();
+        let salt: String = format!("mnemonic{passphrase}").nfkd().collect();
 
         let mut seed = [0u8; 64];
         let _ =
@@ -74,7 +160,29 @@ impl Bip39 {
         seed.to_vec()
     }
 
-    fn entropy_to_mnemonic(entropy: &[u8]) -> Result<Vec<String>, Bip39Error> {
+    // Unicode whitespace, so an ideographic space separates words too. Words are
+    // NFKD-normalized to match the lists, whatever form they were typed in.
+    fn split_words(mnemonic: &str) -> Vec<String> {
+        mnemonic
+            .split_whitespace()
+            .map(|word| word.nfkd().collect())
+            .collect()
+    }
+
+    fn from_words(words: Vec<String>, language: Language) -> Result<Self, Bip39Error> {
+        if !Self::verify_mnemonic(&words) {
+            return Err(Bip39Error::InvalidWordCount(words.len()));
+        }
+
+        let entropy = Self::mnemonic_to_entropy(&words, language)?;
+        Ok(Self {
+            entropy,
+            mnemonic: words,
+            language,
+        })
+    }
+
+    fn entropy_to_mnemonic(entropy: &[u8], language: Language) -> Result<Vec<String>, Bip39Error> {
         let checksum = Self::generate_checksum(entropy);
 
         // Convert entropy to bits
@@ -87,33 +195,28 @@ impl Bip39 {
         let checksum_bits = entropy.len() / 4;
         bits.push_str(&format!("{checksum:08b}")[..checksum_bits]);
 
-        let wordlist = include_str!("wordlist/english.txt")
-            .lines()
-            .collect::<Vec<&str>>();
-
         let mut words = Vec::new();
         // Process bits in chunks of 11 bits
         for i in (0..bits.len()).step_by(11) {
             let chunk = &bits[i..i + 11];
             let idx = usize::from_str_radix(chunk, 2)
                 .map_err(|e| Bip39Error::InvalidStrHex(e.to_string()))?;
-            words.push(wordlist[idx].to_string());
+            words.push(language.wordlist().words[idx].to_string());
         }
 
         Ok(words)
     }
 
-    fn mnemonic_to_entropy(words: &[String]) -> Result<Vec<u8>, Bip39Error> {
-        let wordlist = include_str!("wordlist/english.txt")
-            .lines()
-            .collect::<Vec<&str>>();
-
+    fn mnemonic_to_entropy(words: &[String], language: Language) -> Result<Vec<u8>, Bip39Error> {
+        let index = &language.wordlist().index;
         let mut bits = String::new();
-        for word in words {
-            let idx = wordlist
-                .iter()
-                .position(|&w| w == word)
-                .ok_or(Bip39Error::InvalidMnemonic)?;
+        for (i, word) in words.iter().enumerate() {
+            let idx = *index
+                .get(word.as_str())
+                .ok_or_else(|| Bip39Error::UnknownWord {
+                    word: word.clone(),
+                    position: i + 1,
+                })?;
             bits.push_str(&format!("{idx:011b}"));
         }
 
@@ -127,7 +230,7 @@ impl Bip39 {
             entropy.push(byte);
         }
 
-        if !Self::verify_checksum(&entropy) {
+        if !Self::verify_checksum(&entropy, &bits[entropy_bits..]) {
             return Err(Bip39Error::InvalidChecksum);
         }
 
@@ -140,11 +243,10 @@ impl Bip39 {
         let hash = hasher.finalize();
         hash[0]
     }
-    // TODO Implement it!!!
-    fn verify_checksum(entropy: &[u8]) -> bool {
+    // Checksum is the first ENT/32 bits of SHA256(entropy)
+    fn verify_checksum(entropy: &[u8], checksum_bits: &str) -> bool {
         let checksum = Self::generate_checksum(entropy);
-        let expected_bits = entropy.len() * 8 / 32;
-        true //(checksum >> (8 - expected_bits)) == (entropy[entropy.len() - 1] >> (8 - expected_bits))
+        format!("{checksum:08b}")[..checksum_bits.len()] == *checksum_bits
     }
 
     fn verify_mnemonic(words: &[String]) -> bool {
@@ -165,9 +267,155 @@ mod tests {
         assert_eq!(bip39.entropy, restored.entropy);
     }
 
+    #[test]
+    fn test_strength() {
+        for (strength, words) in VALID_STRENGTHS.into_iter().zip([12, 15, 18, 21, 24]) {
+            let bip39 = Bip39::new(strength).unwrap();
+            assert_eq!(bip39.get_mnemonic().split(' ').count(), words);
+            assert_eq!(bip39.entropy.len() * 8, strength as usize);
+        }
+
+        let err = Bip39::new(100).err().unwrap();
+        assert!(matches!(err, Bip39Error::InvalidStrength(100)));
+        assert!(err.to_string().contains("128, 160, 192, 224 or 256"));
+    }
+
     #[test]
     fn test_invalid_mnemonic() {
         let result = Bip39::from_mnemonic("invalid mnemonic phrase");
-        assert!(matches!(result, Err(Bip39Error::InvalidMnemonic)));
+        assert!(matches!(result, Err(Bip39Error::InvalidWordCount(3))));
+    }
+
+    #[test]
+    fn test_invalid_word_count() {
+        let mnemonic = Bip39::new(128).unwrap().get_mnemonic() + " abandon";
+        let result = Bip39::from_mnemonic(&mnemonic);
+        assert!(matches!(result, Err(Bip39Error::InvalidWordCount(13))));
+    }
+
+    #[test]
+    fn test_unknown_word() {
+        let mut words: Vec<String> = Bip39::new(128)
+            .unwrap()
+            .get_mnemonic()
+            .split(' ')
+            .map(String::from)
+            .collect();
+        words[4] = "abandonn".to_string();
+
+        let result = Bip39::from_mnemonic(&words.join(" "));
+        assert!(matches!(
+            result,
+            Err(Bip39Error::UnknownWord { word, position: 5 }) if word == "abandonn"
+        ));
+    }
+
+    #[test]
+    fn test_invalid_checksum() {
+        // All-zero entropy needs "about" (checksum 0011) as the last word
+        let result = Bip39::from_mnemonic(&["abandon"; 12].join(" "));
+        assert!(matches!(result, Err(Bip39Error::InvalidChecksum)));
+    }
+
+    #[test]
+    fn test_languages() {
+        for language in Language::ALL {
+            assert_eq!(language.wordlist().words.len(), 2048);
+
+            let bip39 = Bip39::new_with_language(256, language).unwrap();
+            assert_eq!(bip39.language(), language);
+
+            let restored = Bip39::from_mnemonic(&bip39.get_mnemonic()).unwrap();
+            assert_eq!(restored.language(), language);
+            assert_eq!(restored.entropy, bip39.entropy);
+
+            let explicit =
+                Bip39::from_mnemonic_with_language(&bip39.get_mnemonic(), language).unwrap();
+            assert_eq!(explicit.entropy, bip39.entropy);
+        }
+
+        // Words may also be separated by ideographic spaces
+        let bip39 = Bip39::new(128).unwrap();
+        let spaced = bip39.get_mnemonic().replace(' ', "\u{3000}");
+        assert_eq!(
+            Bip39::from_mnemonic(&spaced).unwrap().entropy,
+            bip39.entropy
+        );
+    }
+
+    #[test]
+    fn test_spec_vectors() {
+        let bip39 = Bip39::from_mnemonic(
+            "legal winner thank year wave sausage worth useful legal winner thank yellow",
+        )
+        .unwrap();
+        assert_eq!(bip39.entropy, vec![0x7f; 16]);
+
+        let mut words = vec!["abandon"; 23];
+        words.push("art");
+        let bip39 = Bip39::from_mnemonic(&words.join(" ")).unwrap();
+        assert_eq!(bip39.entropy, vec![0; 32]);
+
+        let mut words = vec!["abandon"; 11];
+        words.push("about");
+        let bip39 = Bip39::from_mnemonic(&words.join(" ")).unwrap();
+        let seed: String = bip39
+            .get_seed("TREZOR")
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        assert_eq!(
+            seed,
+            concat!(
+                "c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e5349553",
+                "1f09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04",
+            )
+        );
+
+        // Japanese, joined by ideographic spaces, with a passphrase that only
+        // matches the spec's seed once NFKD-normalized
+        let mut words = vec!["あいこくしん"; 11];
+        words.push("あおぞら");
+        let bip39 = Bip39::from_mnemonic(&words.join("\u{3000}")).unwrap();
+        assert_eq!(bip39.language(), Language::Japanese);
+        assert_eq!(bip39.entropy, vec![0; 16]);
+        let seed: String = bip39
+            .get_seed("㍍ガバヴァぱばぐゞちぢ十人十色")
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        assert_eq!(
+            seed,
+            concat!(
+                "a262d6fb6122ecf45be09c50492b31f92e9beb7d9a845987a02cefda57a15f9c",
+                "467a17872029a9e92299b5cbdf306e3a0ee620245cbd508959b6cb7ca637bd55",
+            )
+        );
+
+        // Spanish, typed precomposed (NFC) while the list is decomposed
+        let bip39 = Bip39::from_mnemonic(
+            "lino admitir bolero abrir álbum dejar acelga aprender lino admitir bolero abogado",
+        )
+        .unwrap();
+        assert_eq!(bip39.language(), Language::Spanish);
+        assert_eq!(bip39.entropy, vec![0x80; 16]);
+        let seed: String = bip39
+            .get_seed("contraseña")
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        assert_eq!(
+            seed,
+            concat!(
+                "3454b0cd67d83222bf985eed486d72d2a61ffe3a6f652da69b87646e4608e5c0",
+                "905eeaa0294e168ae002bf88a2fa2acd1ae50cbaccbedc6f46723bd038476373",
+            )
+        );
+
+        // Two valid words swapped
+        let result = Bip39::from_mnemonic(
+            "legal winner thank wave year sausage worth useful legal winner thank yellow",
+        );
+        assert!(matches!(result, Err(Bip39Error::InvalidChecksum)));
     }
 }
@@ -1,17 +1,16 @@
 use crate::{CipherOption, MasterKeys};
 use chacha20::cipher::StreamCipher;
-/*
-use chacha20poly1305::{
-    aead::{AeadCore, AeadInPlace, OsRng},
-    ChaCha20Poly1305, Nonce,
-};
- */
+use chacha20poly1305::{aead::Aead, XChaCha20Poly1305, XNonce};
+use hmac::{Hmac, Mac};
 use pcbc::cipher::{
     generic_array::GenericArray, BlockCipher, BlockDecryptMut, BlockEncryptMut, BlockSizeUser,
     KeyInit, KeyIvInit, Unsigned,
 };
 use pcbc::{Decryptor, Encryptor};
-use rand::RngCore;
+use rand::rngs::OsRng;
+use rand::{CryptoRng, RngCore};
+use sha2::Sha256;
+use zeroize::Zeroize;
 
 pub struct CipherChain<'a> {
     pub cipher_chain: Vec<CipherOption>,
@@ -20,139 +19,438 @@ pub struct CipherChain<'a> {
 
 #[derive(Debug)]
 pub enum Error {
+    /// The layer's key was never derived
     KeyNotFound,
-    InvalidKeyLength,
+    /// The key for this cipher isn't the length it takes, e.g. a post-quantum seed
+    /// selected as a layer
+    InvalidKeyLength(CipherOption),
+    InvalidBlobLength,
+    UnsupportedCipher,
+    AuthenticationFailed,
+    InvalidPadding,
+    /// Shorter than a layer's IV, or a block cipher layer without a single block
+    TruncatedData,
+}
+
+/// Stored layout of one encryption layer: `[iv][ciphertext]`, no header and no tag.
+///
+/// For block ciphers (PCBC mode) the IV is one block and the ciphertext is the PKCS#7
+/// padded plaintext, so always at least one whole block. For XChaCha20 the IV is the
+/// 24-byte nonce and the ciphertext has the plaintext's length. XChaCha20-Poly1305 is
+/// laid out the same, followed by the 16-byte Poly1305 tag.
+///
+/// A chain nests layers: the plaintext of each layer is the serialized blob of the
+/// previous cipher, so the last cipher of the chain is the outermost layer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EncryptedBlob {
+    pub iv: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+}
+
+impl EncryptedBlob {
+    /// Split one layer produced by `cipher` into IV and ciphertext
+    pub fn parse(cipher: &CipherOption, bytes: &[u8]) -> Result<Self, Error> {
+        let (iv_len, block_size) = layer_layout(cipher).ok_or(Error::UnsupportedCipher)?;
+        let blob = Self::split(bytes, iv_len, block_size)?;
+        if blob.ciphertext.len() < layer_tag_len(cipher) {
+            return Err(Error::TruncatedData);
+        }
+        Ok(blob)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.iv.len() + self.ciphertext.len());
+        bytes.extend_from_slice(&self.iv);
+        bytes.extend_from_slice(&self.ciphertext);
+        bytes
+    }
+
+    fn split(bytes: &[u8], iv_len: usize, block_size: usize) -> Result<Self, Error> {
+        if bytes.len() < iv_len {
+            return Err(Error::TruncatedData);
+        }
+        let (iv, ciphertext) = bytes.split_at(iv_len);
+        // Padding always adds a block, stream ciphers (block size 1) may be empty
+        if block_size > 1 && ciphertext.is_empty() {
+            return Err(Error::TruncatedData);
+        }
+        if ciphertext.len() % block_size != 0 {
+            return Err(Error::InvalidBlobLength);
+        }
+        Ok(Self {
+            iv: iv.to_vec(),
+            ciphertext: ciphertext.to_vec(),
+        })
+    }
+}
+
+const XCHACHA20_NONCE_LEN: usize = 24;
+const POLY1305_TAG_LEN: usize = 16;
+/// HMAC-SHA256 tag appended by `encrypt_authenticated`
+pub const TAG_LEN: usize = 32;
+
+// (IV length, block size) of a layer, block size 1 for stream ciphers
+fn layer_layout(cipher: &CipherOption) -> Option<(usize, usize)> {
+    fn block<C: BlockSizeUser>() -> Option<(usize, usize)> {
+        let block_size = <C as BlockSizeUser>::BlockSize::to_usize();
+        Some((block_size, block_size))
+    }
+
+    match cipher {
+        CipherOption::AES256 => block::<aes::Aes256>(),
+        CipherOption::ARIA => block::<aria::Aria256>(),
+        CipherOption::BelT => block::<belt_block::BeltBlock>(),
+        CipherOption::Camellia => block::<camellia::Camellia256>(),
+        CipherOption::CAST6 => block::<cast6::Cast6>(),
+        CipherOption::Kuznyechik => block::<kuznyechik::Kuznyechik>(),
+        CipherOption::Serpent => block::<serpent::Serpent>(),
+        CipherOption::Spec => block::<speck_cipher::Speck128_256>(),
+        CipherOption::Twofish => block::<twofish::Twofish>(),
+        CipherOption::XChaCha20 | CipherOption::XChaCha20Poly1305 => Some((XCHACHA20_NONCE_LEN, 1)),
+        _ => None,
+    }
+}
+
+// Key length a layer takes, None for algorithms that can't be a layer. Always the
+// 32 bytes `MasterKeys` derives: Serpent and Twofish accept shorter keys, but the
+// chain never hands them one
+fn layer_key_len(cipher: &CipherOption) -> Option<usize> {
+    layer_layout(cipher).map(|_| 32)
+}
+
+// Bytes a layer's AEAD tag adds after the ciphertext
+fn layer_tag_len(cipher: &CipherOption) -> usize {
+    match cipher {
+        CipherOption::XChaCha20Poly1305 => POLY1305_TAG_LEN,
+        _ => 0,
+    }
 }
 
-impl CipherChain<'_> {
-    // pub fn init(mut self, keys: MasterKeys, cipher_chain: Vec<CipherOption>) {
-    //     self.cipher_chain = cipher_chain;
-    //     self.keys = keys;
-    // }
+// Where layer IVs come from: an RNG, or for deterministic encryption an HMAC of the
+// layer input under a key derived for that layer
+enum IvSource<'r> {
+    Random(&'r mut dyn RngCore),
+    Synthetic,
+}
+
+impl<'a> CipherChain<'a> {
+    /// Chain applying `cipher_chain` in order, first cipher innermost
+    ///
+    /// ```
+    /// use crypto::cipher_chain::CipherChain;
+    /// use crypto::{CipherOption, MasterKeys};
+    ///
+    /// let keys = MasterKeys::from_entropy(&[7u8; 32]).unwrap();
+    /// let chain = CipherChain::new(&keys, vec![CipherOption::AES256, CipherOption::XChaCha20]);
+    ///
+    /// let encrypted = chain.encrypt(&mut b"secret".to_vec()).unwrap();
+    /// assert_eq!(chain.decrypt(&mut encrypted.clone()).unwrap(), b"secret");
+    /// ```
+    pub fn new(keys: &'a MasterKeys, cipher_chain: Vec<CipherOption>) -> Self {
+        Self { cipher_chain, keys }
+    }
+
+    /// Unauthenticated: the output is malleable and tampering goes unnoticed.
+    /// Kept for data written before `encrypt_authenticated` existed.
+    pub fn encrypt(&self, data: &mut Vec<u8>) -> Result<Vec<u8>, Error> {
+        self.encrypt_with(data, &mut OsRng)
+    }
+
+    /// `encrypt` taking the IVs from `rng` instead of the OS. A seeded RNG makes the
+    /// output reproducible, which only tests should want.
+    pub fn encrypt_with<R: RngCore + CryptoRng>(
+        &self,
+        data: &mut Vec<u8>,
+        rng: &mut R,
+    ) -> Result<Vec<u8>, Error> {
+        self.encrypt_layers(data, &mut IvSource::Random(rng))
+    }
+
+    /// `encrypt`, followed by an HMAC-SHA256 tag over the cipher chain and the whole
+    /// ciphertext: `[ciphertext][tag]`. Decrypt with `decrypt_authenticated`.
+    pub fn encrypt_authenticated(&self, data: &mut Vec<u8>) -> Result<Vec<u8>, Error> {
+        let mut ciphertext = self.encrypt(data)?;
+        let tag = self.tag_mac(&ciphertext).finalize().into_bytes();
+        ciphertext.extend_from_slice(&tag);
+        Ok(ciphertext)
+    }
+
+    /// Check the tag in constant time before decrypting, so modified ciphertext
+    /// is an error instead of garbage plaintext.
+    pub fn decrypt_authenticated(&self, data: &mut Vec<u8>) -> Result<Vec<u8>, Error> {
+        if data.len() < TAG_LEN {
+            return Err(Error::InvalidBlobLength);
+        }
+        let tag = data.split_off(data.len() - TAG_LEN);
+        self.tag_mac(data)
+            .verify_slice(&tag)
+            .map_err(|_| Error::AuthenticationFailed)?;
+        self.decrypt(data)
+    }
+
+    /// Encrypt with IVs derived from an HMAC of each layer's input instead of the RNG,
+    /// so identical plaintext gives identical ciphertext. This reveals which records
+    /// are equal; it exists for content-addressed (deduplicated) storage.
+    /// The output decrypts with the regular `decrypt`.
+    pub fn encrypt_deterministic(&self, data: &mut Vec<u8>) -> Result<Vec<u8>, Error> {
+        self.encrypt_layers(data, &mut IvSource::Synthetic)
+    }
 
-    pub fn encrypt(&self, data: &mut Vec<u8>) -> Vec<u8> {
+    fn encrypt_layers(&self, data: &mut Vec<u8>, ivs: &mut IvSource) -> Result<Vec<u8>, Error> {
+        self.check_keys()?;
         for cipher in self.cipher_chain.iter() {
             let key = self.keys.get_key(cipher);
             match cipher {
-                CipherOption::AES256 => self.process::<aes::Aes256>(data, key),
-                CipherOption::ARIA => self.process::<aria::Aria256>(data, key),
-                CipherOption::BelT => self.process::<belt_block::BeltBlock>(data, key),
-                CipherOption::Camellia => self.process::<camellia::Camellia256>(data, key),
-                CipherOption::CAST6 => self.process::<cast6::Cast6>(data, key),
-                CipherOption::Kuznyechik => self.process::<kuznyechik::Kuznyechik>(data, key),
-                CipherOption::Serpent => self.process::<serpent::Serpent>(data, key),
-                CipherOption::Spec => self.process::<speck_cipher::Speck128_256>(data, key),
-                CipherOption::Twofish => self.process::<twofish::Twofish>(data, key),
+                CipherOption::AES256 => self.process::<aes::Aes256>(data, cipher, key, ivs)?,
+                CipherOption::ARIA => self.process::<aria::Aria256>(data, cipher, key, ivs)?,
+                CipherOption::BelT => {
+                    self.process::<belt_block::BeltBlock>(data, cipher, key, ivs)?
+                }
+                CipherOption::Camellia => {
+                    self.process::<camellia::Camellia256>(data, cipher, key, ivs)?
+                }
+                CipherOption::CAST6 => self.process::<cast6::Cast6>(data, cipher, key, ivs)?,
+                CipherOption::Kuznyechik => {
+                    self.process::<kuznyechik::Kuznyechik>(data, cipher, key, ivs)?
+                }
+                CipherOption::Serpent => {
+                    self.process::<serpent::Serpent>(data, cipher, key, ivs)?
+                }
+                CipherOption::Spec => {
+                    self.process::<speck_cipher::Speck128_256>(data, cipher, key, ivs)?
+                }
+                CipherOption::Twofish => {
+                    self.process::<twofish::Twofish>(data, cipher, key, ivs)?
+                }
                 CipherOption::XChaCha20 => {
-                    //let cipher = ChaCha20Poly1305::new(key.into());
-                    //let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
-                    //let _ = cipher.encrypt_in_place(&nonce, b"", data);
-
-                    let mut iv = [0u8; 24];
-                    rand::thread_rng().fill_bytes(&mut iv);
-                    data.splice(0..0, iv.iter().copied());
-                    chacha20::XChaCha20::new(key.into(), &iv.into())
-                        .apply_keystream(&mut data[24..]);
+                    let mut iv = [0u8; XCHACHA20_NONCE_LEN];
+                    self.fill_iv(&mut iv, cipher, data, ivs);
+                    chacha20::XChaCha20::new_from_slices(key, &iv)
+                        .map_err(|_| Error::InvalidKeyLength(*cipher))?
+                        .apply_keystream(data);
+                    *data = EncryptedBlob {
+                        iv: iv.to_vec(),
+                        ciphertext: std::mem::take(data),
+                    }
+                    .to_bytes();
+                }
+                CipherOption::XChaCha20Poly1305 => {
+                    let mut iv = [0u8; XCHACHA20_NONCE_LEN];
+                    self.fill_iv(&mut iv, cipher, data, ivs);
+                    let ciphertext = <XChaCha20Poly1305 as KeyInit>::new_from_slice(key)
+                        .map_err(|_| Error::InvalidKeyLength(*cipher))?
+                        .encrypt(XNonce::from_slice(&iv), data.as_slice())
+                        .map_err(|_| Error::InvalidBlobLength)?;
+                    *data = EncryptedBlob {
+                        iv: iv.to_vec(),
+                        ciphertext,
+                    }
+                    .to_bytes();
                 }
-                _ => unimplemented!("Cipher not supported for encryption"),
+                _ => return Err(Error::UnsupportedCipher),
             }
         }
-        data.to_vec()
+        Ok(data.to_vec())
     }
 
-    pub fn decrypt(&self, data: &mut Vec<u8>) -> Vec<u8> {
+    /// Unauthenticated counterpart of `encrypt`, see `decrypt_authenticated`. Malformed
+    /// input is an error, though corrupted ciphertext may still decrypt to garbage,
+    /// except under an XChaCha20-Poly1305 layer whose tag check fails instead.
+    pub fn decrypt(&self, data: &mut Vec<u8>) -> Result<Vec<u8>, Error> {
+        self.check_keys()?;
         for cipher in self.cipher_chain.iter().rev() {
             let key = self.keys.get_key(cipher);
             match cipher {
-                CipherOption::AES256 => self.reverse_process::<aes::Aes256>(data, key),
-                CipherOption::ARIA => self.reverse_process::<aria::Aria256>(data, key),
-                CipherOption::BelT => self.reverse_process::<belt_block::BeltBlock>(data, key),
-                CipherOption::Camellia => self.reverse_process::<camellia::Camellia256>(data, key),
-                CipherOption::CAST6 => self.reverse_process::<cast6::Cast6>(data, key),
+                CipherOption::AES256 => self.reverse_process::<aes::Aes256>(data, cipher, key)?,
+                CipherOption::ARIA => self.reverse_process::<aria::Aria256>(data, cipher, key)?,
+                CipherOption::BelT => {
+                    self.reverse_process::<belt_block::BeltBlock>(data, cipher, key)?
+                }
+                CipherOption::Camellia => {
+                    self.reverse_process::<camellia::Camellia256>(data, cipher, key)?
+                }
+                CipherOption::CAST6 => self.reverse_process::<cast6::Cast6>(data, cipher, key)?,
                 CipherOption::Kuznyechik => {
-                    self.reverse_process::<kuznyechik::Kuznyechik>(data, key)
+                    self.reverse_process::<kuznyechik::Kuznyechik>(data, cipher, key)?
+                }
+                CipherOption::Serpent => {
+                    self.reverse_process::<serpent::Serpent>(data, cipher, key)?
+                }
+                CipherOption::Spec => {
+                    self.reverse_process::<speck_cipher::Speck128_256>(data, cipher, key)?
+                }
+                CipherOption::Twofish => {
+                    self.reverse_process::<twofish::Twofish>(data, cipher, key)?
                 }
-                CipherOption::Serpent => self.reverse_process::<serpent::Serpent>(data, key),
-                CipherOption::Spec => self.reverse_process::<speck_cipher::Speck128_256>(data, key),
-                CipherOption::Twofish => self.reverse_process::<twofish::Twofish>(data, key),
                 CipherOption::XChaCha20 => {
-                    if data.len() < 24 {
-                        panic!("Invalid data length");
-                    }
-                    //let cipher = ChaCha20Poly1305::new(key.into());
-                    //let nonce = GenericArray::from_slice(&data[0..24]);
-                    //data.drain(0..24);
-                    //let _ = cipher.decrypt_in_place(&nonce, b"", data);
+                    let mut blob = EncryptedBlob::parse(cipher, data)?;
+                    chacha20::XChaCha20::new_from_slices(key, &blob.iv)
+                        .map_err(|_| Error::InvalidKeyLength(*cipher))?
+                        .apply_keystream(&mut blob.ciphertext);
+                    *data = blob.ciphertext;
+                }
+                CipherOption::XChaCha20Poly1305 => {
+                    let blob = EncryptedBlob::parse(cipher, data)?;
+                    *data = <XChaCha20Poly1305 as KeyInit>::new_from_slice(key)
+                        .map_err(|_| Error::InvalidKeyLength(*cipher))?
+                        .decrypt(XNonce::from_slice(&blob.iv), blob.ciphertext.as_slice())
+                        .map_err(|_| Error::AuthenticationFailed)?;
+                }
+                _ => return Err(Error::UnsupportedCipher),
+            }
+        }
+        Ok(data.to_vec())
+    }
+
+    /// Every layer must have a key of the length its algorithm takes. Checked before
+    /// touching the data, so a misconfigured chain fails here and not inside a cipher crate.
+    fn check_keys(&self) -> Result<(), Error> {
+        for cipher in self.cipher_chain.iter() {
+            let expected = layer_key_len(cipher).ok_or(Error::InvalidKeyLength(*cipher))?;
+            let key = self.keys.get_key(cipher);
+            if key.is_empty() {
+                return Err(Error::KeyNotFound);
+            }
+            if key.len() != expected {
+                return Err(Error::InvalidKeyLength(*cipher));
+            }
+        }
+        Ok(())
+    }
 
-                    let iv = &data[0..24];
+    /// Ciphertext length the chain produces for `plain_len` bytes, without encrypting
+    pub fn encrypted_len(&self, plain_len: usize) -> usize {
+        self.cipher_chain
+            .iter()
+            .fold(plain_len, |len, cipher| match layer_layout(cipher) {
+                Some((iv_len, 1)) => iv_len + len + layer_tag_len(cipher),
+                // PKCS#7 padding always adds at least one byte
+                Some((iv_len, block_size)) => iv_len + (len / block_size + 1) * block_size,
+                None => len,
+            })
+    }
 
-                    chacha20::XChaCha20::new(key.into(), iv.into())
-                        .apply_keystream(&mut data[24..]);
-                    data.drain(0..24);
-                }
-                _ => unimplemented!("Cipher not supported for decryption"),
+    // Covers the chain too, so a tag doesn't verify under a different chain
+    fn tag_mac(&self, ciphertext: &[u8]) -> Hmac<Sha256> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(self.keys.hmac_key())
+            .expect("HMAC accepts keys of any length");
+        mac.update(b"PASSMGR_TAG_V1");
+        for cipher in self.cipher_chain.iter() {
+            mac.update(&[cipher.code()]);
+        }
+        mac.update(ciphertext);
+        mac
+    }
+
+    fn fill_iv(&self, iv: &mut [u8], cipher: &CipherOption, data: &[u8], ivs: &mut IvSource) {
+        match ivs {
+            IvSource::Random(rng) => rng.fill_bytes(iv),
+            IvSource::Synthetic => {
+                let mut key = self.siv_key(cipher);
+                let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&key)
+                    .expect("HMAC accepts keys of any length");
+                key.zeroize();
+                mac.update(b"PASSMGR_SIV_V1");
+                mac.update(data);
+                let tag = mac.finalize().into_bytes();
+                iv.copy_from_slice(&tag[..iv.len()]);
             }
         }
-        data.to_vec()
     }
 
-    fn process<C>(&self, data: &mut Vec<u8>, key: &[u8])
+    // Synthetic IV key of a layer, derived from the HMAC key under its own label so
+    // the layer's cipher key is never also used as a MAC key
+    fn siv_key(&self, cipher: &CipherOption) -> [u8; 32] {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(self.keys.hmac_key())
+            .expect("HMAC accepts keys of any length");
+        mac.update(b"PASSMGR_SIV_KEY_V1");
+        mac.update(&[cipher.code()]);
+        mac.finalize().into_bytes().into()
+    }
+
+    fn process<C>(
+        &self,
+        data: &mut Vec<u8>,
+        cipher: &CipherOption,
+        key: &[u8],
+        ivs: &mut IvSource,
+    ) -> Result<(), Error>
     where
         C: KeyInit + BlockEncryptMut + BlockCipher + BlockSizeUser,
     {
         // Generate IV matching cipher's block size
         let mut iv = GenericArray::<u8, <C as BlockSizeUser>::BlockSize>::default();
-        rand::thread_rng().fill_bytes(&mut iv);
-
-        // Prepend IV to data
-        data.splice(0..0, iv.iter().copied());
+        self.fill_iv(&mut iv, cipher, data, ivs);
 
         // Apply PKCS#7 padding
         let block_size = iv.len();
-        let len_after_iv = data.len() - block_size;
-        let padding = block_size - (len_after_iv % block_size);
-        for _ in 0..padding {
-            data.push(padding as u8);
-        }
+        let padding = block_size - (data.len() % block_size);
+        data.resize(data.len() + padding, padding as u8);
 
-        let mut mode = Encryptor::<C>::new(key.into(), &iv);
-        for chunk in data[iv.len()..].chunks_mut(block_size) {
+        // Not `key.into()`: ciphers like Serpent take longer keys than their nominal size
+        let mut mode = Encryptor::<C>::new_from_slices(key, &iv)
+            .map_err(|_| Error::InvalidKeyLength(*cipher))?;
+        for chunk in data.chunks_mut(block_size) {
             mode.encrypt_block_mut(GenericArray::from_mut_slice(chunk));
         }
+
+        *data = EncryptedBlob {
+            iv: iv.to_vec(),
+            ciphertext: std::mem::take(data),
+        }
+        .to_bytes();
+        Ok(())
     }
 
-    fn reverse_process<C>(&self, data: &mut Vec<u8>, key: &[u8])
+    fn reverse_process<C>(
+        &self,
+        data: &mut Vec<u8>,
+        cipher: &CipherOption,
+        key: &[u8],
+    ) -> Result<(), Error>
     where
         C: KeyInit + BlockDecryptMut + BlockCipher + BlockSizeUser,
     {
         let block_size = <C as BlockSizeUser>::BlockSize::to_usize();
-        if data.len() < block_size || (data.len() - block_size) % block_size != 0 {
-            panic!("Invalid data length");
-        }
+        let mut blob = EncryptedBlob::split(data, block_size, block_size)?;
 
-        let iv = GenericArray::clone_from_slice(&data[0..block_size]);
-        let mut mode = Decryptor::<C>::new(key.into(), &iv);
+        let mut mode = Decryptor::<C>::new_from_slices(key, &blob.iv)
+            .map_err(|_| Error::InvalidKeyLength(*cipher))?;
 
-        for chunk in data[block_size..].chunks_mut(block_size) {
+        for chunk in blob.ciphertext.chunks_mut(block_size) {
             mode.decrypt_block_mut(GenericArray::from_mut_slice(chunk));
         }
 
-        // Remove padding
-        let padding = *data.last().unwrap() as usize;
-        if padding <= block_size {
-            data.truncate(data.len() - padding);
-        }
+        let len = strip_padding(&blob.ciphertext, block_size)?;
+        blob.ciphertext.truncate(len);
 
-        // Remove IV
-        data.drain(0..block_size);
+        *data = blob.ciphertext;
+        Ok(())
     }
 }
 
+/// Length of `plaintext` without its PKCS#7 padding. Every pad byte must equal the pad
+/// length, which is 1..=block_size, or a corrupt last block would cut an arbitrary amount.
+fn strip_padding(plaintext: &[u8], block_size: usize) -> Result<usize, Error> {
+    let padding = *plaintext.last().ok_or(Error::InvalidPadding)? as usize;
+    if padding == 0 || padding > block_size || padding > plaintext.len() {
+        return Err(Error::InvalidPadding);
+    }
+    let (text, pad) = plaintext.split_at(plaintext.len() - padding);
+    if pad.iter().any(|&byte| byte as usize != padding) {
+        return Err(Error::InvalidPadding);
+    }
+    Ok(text.len())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::MasterKeys;
-    use rand::{rngs::OsRng, RngCore};
+    use rand::rngs::OsRng;
+    use rand::RngCore;
+    use rand_chacha::rand_core::SeedableRng;
+    use rand_chacha::ChaChaRng;
 
     fn create_test_keys() -> MasterKeys {
         let mut entropy = [0u8; 32];
@@ -170,10 +468,10 @@ mod tests {
 
         let original = b"Hello PCBC mode!".to_vec();
         let mut encrypted = original.clone();
-        encrypted = chain.encrypt(&mut encrypted);
+        encrypted = chain.encrypt(&mut encrypted).unwrap();
 
         let mut decrypted = encrypted.clone();
-        decrypted = chain.decrypt(&mut decrypted);
+        decrypted = chain.decrypt(&mut decrypted).unwrap();
 
         assert_eq!(original, decrypted);
     }
@@ -192,14 +490,79 @@ mod tests {
 
         let original = b"Multi-cipher chain test".to_vec();
         let mut encrypted = original.clone();
-        encrypted = chain.encrypt(&mut encrypted);
+        encrypted = chain.encrypt(&mut encrypted).unwrap();
 
         let mut decrypted = encrypted.clone();
-        decrypted = chain.decrypt(&mut decrypted);
+        decrypted = chain.decrypt(&mut decrypted).unwrap();
 
         assert_eq!(original, decrypted);
     }
 
+    #[test]
+    fn test_chain_vector() {
+        // Layer IVs from a ChaCha RNG seeded with 1, drawn in chain order. Recorded
+        // from this crate, so byte layout changes show up here.
+        let entropy: Vec<u8> = (0..32).collect();
+        let keys = MasterKeys::from_entropy_for(
+            &entropy,
+            &[
+                CipherOption::AES256,
+                CipherOption::XChaCha20,
+                CipherOption::Kuznyechik,
+            ],
+        )
+        .unwrap();
+        let chain = CipherChain::new(
+            &keys,
+            vec![
+                CipherOption::AES256,
+                CipherOption::XChaCha20,
+                CipherOption::Kuznyechik,
+            ],
+        );
+
+        let plaintext = b"PassMgr test vector".to_vec();
+        let encrypted = chain
+            .encrypt_with(&mut plaintext.clone(), &mut ChaChaRng::seed_from_u64(1))
+            .unwrap();
+        assert_eq!(
+            encrypted,
+            hex(concat!(
+                "1157912e0e171f60de9e5341348819a29f7b627a2377f40ba93c7877b4d64ce9",
+                "adb8aeda61618600b96a684e8fb0ec76d722ad6fb6be3cc6995e1bab5ae5e498",
+                "27bdbc7c0d07131f92b1b013dbfe5597891c10753b80ad30e36b07c861f06122",
+            ))
+        );
+        assert_eq!(chain.decrypt(&mut encrypted.clone()).unwrap(), plaintext);
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_encrypt_with_seeded_rng() {
+        let keys = create_test_keys();
+        let chain = CipherChain::new(&keys, vec![CipherOption::Serpent]);
+        let plaintext = b"Seeded IV".to_vec();
+
+        let first = chain
+            .encrypt_with(&mut plaintext.clone(), &mut ChaChaRng::seed_from_u64(7))
+            .unwrap();
+        let second = chain
+            .encrypt_with(&mut plaintext.clone(), &mut ChaChaRng::seed_from_u64(7))
+            .unwrap();
+        let other = chain
+            .encrypt_with(&mut plaintext.clone(), &mut ChaChaRng::seed_from_u64(8))
+            .unwrap();
+        assert_eq!(first, second);
+        assert_ne!(first, other);
+        assert_eq!(chain.decrypt(&mut first.clone()).unwrap(), plaintext);
+    }
+
     #[test]
     fn test_empty_data() {
         let keys = create_test_keys();
@@ -210,10 +573,10 @@ mod tests {
 
         let original = vec![];
         let mut encrypted = original.clone();
-        encrypted = chain.encrypt(&mut encrypted);
+        encrypted = chain.encrypt(&mut encrypted).unwrap();
 
         let mut decrypted = encrypted.clone();
-        decrypted = chain.decrypt(&mut decrypted);
+        decrypted = chain.decrypt(&mut decrypted).unwrap();
 
         assert_eq!(original, decrypted);
     }
@@ -229,17 +592,74 @@ mod tests {
         // Kuznyechik uses 128-bit blocks
         let original = b"Testing 128-bit block cipher".to_vec();
         let mut encrypted = original.clone();
-        encrypted = chain.encrypt(&mut encrypted);
+        encrypted = chain.encrypt(&mut encrypted).unwrap();
 
         // Verify IV size is 16 bytes for Kuznyechik
         assert_eq!(encrypted.len() % 16, 0);
 
         let mut decrypted = encrypted.clone();
-        decrypted = chain.decrypt(&mut decrypted);
+        decrypted = chain.decrypt(&mut decrypted).unwrap();
 
         assert_eq!(original, decrypted);
     }
-    //TODO Test with other algorithm (Serpent has problem)
+    #[test]
+    fn test_encrypted_len() {
+        let keys = create_test_keys();
+        let chain = CipherChain {
+            cipher_chain: vec![
+                CipherOption::AES256,
+                CipherOption::XChaCha20,
+                CipherOption::Kuznyechik,
+            ],
+            keys: &keys,
+        };
+
+        for len in [0, 1, 15, 16, 17, 100, 1000] {
+            let mut data = vec![7u8; len];
+            let encrypted = chain.encrypt(&mut data).unwrap();
+            assert_eq!(chain.encrypted_len(len), encrypted.len(), "length {}", len);
+        }
+    }
+    #[test]
+    fn test_deterministic_encryption() {
+        let keys = create_test_keys();
+        let chain = CipherChain {
+            cipher_chain: vec![
+                CipherOption::AES256,
+                CipherOption::XChaCha20,
+                CipherOption::Kuznyechik,
+            ],
+            keys: &keys,
+        };
+
+        let original = b"Same content, same blocks".to_vec();
+        let first = chain.encrypt_deterministic(&mut original.clone()).unwrap();
+        let second = chain.encrypt_deterministic(&mut original.clone()).unwrap();
+        assert_eq!(first, second);
+
+        let other = chain
+            .encrypt_deterministic(&mut b"Other content".to_vec())
+            .unwrap();
+        assert_ne!(first, other);
+
+        let decrypted = chain.decrypt(&mut first.clone()).unwrap();
+        assert_eq!(decrypted, original);
+    }
+
+    #[test]
+    fn test_siv_key_separate_from_layer_key() {
+        let keys = create_test_keys();
+        let chain = CipherChain {
+            cipher_chain: vec![CipherOption::AES256, CipherOption::XChaCha20],
+            keys: &keys,
+        };
+
+        let aes = chain.siv_key(&CipherOption::AES256);
+        let xchacha = chain.siv_key(&CipherOption::XChaCha20);
+        assert_ne!(aes, xchacha);
+        assert_ne!(&aes[..], keys.get_key(&CipherOption::AES256));
+        assert_ne!(&xchacha[..], keys.get_key(&CipherOption::XChaCha20));
+    }
     #[test]
     fn test_padding_handling() {
         let keys = create_test_keys();
@@ -251,17 +671,108 @@ mod tests {
         // Test data that needs padding (13 bytes)
         let original = b"13-byte test".to_vec();
         let mut encrypted = original.clone();
-        encrypted = chain.encrypt(&mut encrypted);
+        encrypted = chain.encrypt(&mut encrypted).unwrap();
 
         // Encrypted length should be IV + padded data
         assert_eq!(encrypted.len(), 16 + 16); // IV + 1 block
 
         let mut decrypted = encrypted.clone();
-        decrypted = chain.decrypt(&mut decrypted);
+        decrypted = chain.decrypt(&mut decrypted).unwrap();
 
         assert_eq!(original, decrypted);
     }
 
+    #[test]
+    fn test_block_cipher_roundtrip_lengths() {
+        let keys = create_test_keys();
+        for cipher in [
+            CipherOption::AES256,
+            CipherOption::Kuznyechik,
+            CipherOption::Serpent,
+            CipherOption::Twofish,
+        ] {
+            let chain = CipherChain::new(&keys, vec![cipher]);
+            for len in 1..=40 {
+                let original: Vec<u8> = (0..len as u8).collect();
+                let encrypted = chain.encrypt(&mut original.clone()).unwrap();
+                let decrypted = chain.decrypt(&mut encrypted.clone()).unwrap();
+                assert_eq!(decrypted, original, "{:?}, length {}", cipher, len);
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid_padding_rejected() {
+        let keys = create_test_keys();
+        let chain = CipherChain::new(&keys, vec![CipherOption::Serpent]);
+        let key = keys.get_key(&CipherOption::Serpent);
+
+        // Encrypt one raw block without padding, ending in the given bytes
+        let encrypt_block = |tail: &[u8]| {
+            let iv = [9u8; 16];
+            let mut block = [0xAAu8; 16];
+            block[16 - tail.len()..].copy_from_slice(tail);
+            let mut mode = Encryptor::<serpent::Serpent>::new_from_slices(key, &iv).unwrap();
+            mode.encrypt_block_mut(GenericArray::from_mut_slice(&mut block));
+            let mut blob = iv.to_vec();
+            blob.extend_from_slice(&block);
+            blob
+        };
+
+        // Pad length 0, longer than a block, and pad bytes disagreeing with the length
+        for tail in [&[0u8][..], &[17], &[3, 3, 4], &[1, 4, 4, 4]] {
+            assert!(
+                matches!(
+                    chain.decrypt(&mut encrypt_block(tail)),
+                    Err(Error::InvalidPadding)
+                ),
+                "tail {:?}",
+                tail
+            );
+        }
+        assert_eq!(
+            chain.decrypt(&mut encrypt_block(&[4, 4, 4, 4])).unwrap(),
+            vec![0xAA; 12]
+        );
+        assert_eq!(
+            chain.decrypt(&mut encrypt_block(&[16; 16])).unwrap(),
+            Vec::<u8>::new()
+        );
+    }
+
+    #[test]
+    fn test_malformed_input_is_an_error() {
+        let keys = create_test_keys();
+        let chain = CipherChain::new(&keys, vec![CipherOption::AES256]);
+
+        assert!(matches!(
+            chain.decrypt(&mut vec![1, 2, 3, 4, 5]),
+            Err(Error::TruncatedData)
+        ));
+        // IV without any ciphertext block
+        assert!(matches!(
+            chain.decrypt(&mut vec![0; 16]),
+            Err(Error::TruncatedData)
+        ));
+        assert!(matches!(
+            chain.decrypt(&mut vec![0; 20]),
+            Err(Error::InvalidBlobLength)
+        ));
+
+        let stream = CipherChain::new(&keys, vec![CipherOption::XChaCha20]);
+        assert!(matches!(
+            stream.decrypt(&mut vec![0; 5]),
+            Err(Error::TruncatedData)
+        ));
+
+        // No layer takes a signing key
+        let unsupported = CipherChain::new(&keys, vec![CipherOption::Dilithium]);
+        assert!(matches!(
+            unsupported.encrypt(&mut b"data".to_vec()),
+            Err(Error::InvalidKeyLength(CipherOption::Dilithium))
+        ));
+    }
+
     #[test]
     fn test_stream_cipher_handling() {
         let keys = create_test_keys();
@@ -272,14 +783,195 @@ mod tests {
 
         let original = b"Stream cipher test".to_vec();
         let mut encrypted = original.clone();
-        encrypted = chain.encrypt(&mut encrypted);
+        encrypted = chain.encrypt(&mut encrypted).unwrap();
 
         // Verify IV/nonce is 24 bytes for XChaCha20
         assert_eq!(encrypted.len(), original.len() + 24);
 
         let mut decrypted = encrypted.clone();
-        decrypted = chain.decrypt(&mut decrypted);
+        decrypted = chain.decrypt(&mut decrypted).unwrap();
 
         assert_eq!(original, decrypted);
     }
+
+    #[test]
+    fn test_authenticated_encryption() {
+        let keys = create_test_keys();
+        let chain = CipherChain {
+            cipher_chain: vec![CipherOption::AES256, CipherOption::XChaCha20],
+            keys: &keys,
+        };
+
+        let original = b"Authenticated chain test".to_vec();
+        let encrypted = chain.encrypt_authenticated(&mut original.clone()).unwrap();
+        assert_eq!(
+            encrypted.len(),
+            chain.encrypted_len(original.len()) + TAG_LEN
+        );
+        assert_eq!(
+            chain.decrypt_authenticated(&mut encrypted.clone()).unwrap(),
+            original
+        );
+
+        for pos in [0, encrypted.len() / 2, encrypted.len() - 1] {
+            let mut tampered = encrypted.clone();
+            tampered[pos] ^= 0x01;
+            assert!(matches!(
+                chain.decrypt_authenticated(&mut tampered),
+                Err(Error::AuthenticationFailed)
+            ));
+        }
+        assert!(matches!(
+            chain.decrypt_authenticated(&mut encrypted[..TAG_LEN - 1].to_vec()),
+            Err(Error::InvalidBlobLength)
+        ));
+
+        // The tag is bound to the chain it was made with
+        let other = CipherChain {
+            cipher_chain: vec![CipherOption::XChaCha20],
+            keys: &keys,
+        };
+        assert!(other.decrypt_authenticated(&mut encrypted.clone()).is_err());
+    }
+
+    #[test]
+    fn test_invalid_key_length_rejected() {
+        let keys = create_test_keys();
+        for cipher in [CipherOption::NTRUP1277, CipherOption::Kyber1024] {
+            let chain = CipherChain::new(&keys, vec![CipherOption::AES256, cipher]);
+            let mut data = b"never encrypted".to_vec();
+            match chain.encrypt(&mut data) {
+                Err(Error::InvalidKeyLength(rejected)) => assert_eq!(rejected, cipher),
+                other => panic!("{:?}: expected InvalidKeyLength, got {:?}", cipher, other),
+            }
+            // Rejected before the first layer ran
+            assert_eq!(data, b"never encrypted");
+            assert!(matches!(
+                chain.decrypt(&mut vec![0u8; 64]),
+                Err(Error::InvalidKeyLength(rejected)) if rejected == cipher
+            ));
+        }
+
+        // A layer whose key was never derived
+        let partial = MasterKeys::from_entropy_for(&[7u8; 32], &[CipherOption::AES256]).unwrap();
+        let chain = CipherChain::new(&partial, vec![CipherOption::AES256, CipherOption::Twofish]);
+        assert!(matches!(
+            chain.encrypt(&mut b"data".to_vec()),
+            Err(Error::KeyNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_xchacha20poly1305_roundtrip() {
+        let keys = create_test_keys();
+        for cipher_chain in [
+            vec![CipherOption::XChaCha20Poly1305],
+            vec![CipherOption::AES256, CipherOption::XChaCha20Poly1305],
+            vec![CipherOption::XChaCha20Poly1305, CipherOption::Kuznyechik],
+        ] {
+            let chain = CipherChain::new(&keys, cipher_chain);
+            for len in [0, 1, 16, 100] {
+                let original = vec![3u8; len];
+                let encrypted = chain.encrypt(&mut original.clone()).unwrap();
+                assert_eq!(encrypted.len(), chain.encrypted_len(len));
+                assert_eq!(chain.decrypt(&mut encrypted.clone()).unwrap(), original);
+            }
+        }
+
+        let chain = CipherChain::new(&keys, vec![CipherOption::XChaCha20Poly1305]);
+        let encrypted = chain.encrypt(&mut b"nonce, text, tag".to_vec()).unwrap();
+        // 24-byte nonce up front, 16-byte tag at the end
+        assert_eq!(encrypted.len(), 24 + 16 + 16);
+        let deterministic = chain
+            .encrypt_deterministic(&mut b"nonce, text, tag".to_vec())
+            .unwrap();
+        assert_eq!(
+            chain.decrypt(&mut deterministic.clone()).unwrap(),
+            b"nonce, text, tag"
+        );
+    }
+
+    #[test]
+    fn test_xchacha20poly1305_tamper_detection() {
+        let keys = create_test_keys();
+        let chain = CipherChain::new(&keys, vec![CipherOption::XChaCha20Poly1305]);
+        let encrypted = chain.encrypt(&mut b"Integrity matters".to_vec()).unwrap();
+
+        // Nonce, ciphertext and tag are all covered
+        for pos in [0, 30, encrypted.len() - 1] {
+            let mut tampered = encrypted.clone();
+            tampered[pos] ^= 0x01;
+            assert!(matches!(
+                chain.decrypt(&mut tampered),
+                Err(Error::AuthenticationFailed)
+            ));
+        }
+        // Too short to hold a nonce and a tag
+        assert!(matches!(
+            chain.decrypt(&mut encrypted[..24 + 15].to_vec()),
+            Err(Error::TruncatedData)
+        ));
+
+        // Under a different key
+        let other_keys = create_test_keys();
+        let other = CipherChain::new(&other_keys, vec![CipherOption::XChaCha20Poly1305]);
+        assert!(matches!(
+            other.decrypt(&mut encrypted.clone()),
+            Err(Error::AuthenticationFailed)
+        ));
+    }
+
+    #[test]
+    fn test_blob_layout() {
+        let keys = create_test_keys();
+        let chain = CipherChain {
+            cipher_chain: vec![CipherOption::AES256],
+            keys: &keys,
+        };
+
+        let encrypted = chain.encrypt(&mut b"13-byte test".to_vec()).unwrap();
+        let blob = EncryptedBlob::parse(&CipherOption::AES256, &encrypted).unwrap();
+        assert_eq!(blob.iv.len(), 16);
+        assert_eq!(blob.ciphertext.len(), 16);
+        assert_eq!(blob.to_bytes(), encrypted);
+
+        // IV only, or ciphertext not a whole number of blocks
+        assert!(EncryptedBlob::parse(&CipherOption::AES256, &encrypted[..16]).is_err());
+        assert!(EncryptedBlob::parse(&CipherOption::AES256, &encrypted[..31]).is_err());
+        // An empty stream cipher payload is valid
+        let nonce_only = EncryptedBlob::parse(&CipherOption::XChaCha20, &[0; 24]).unwrap();
+        assert!(nonce_only.ciphertext.is_empty());
+    }
+
+    #[test]
+    fn test_nested_blob_layout() {
+        let keys = create_test_keys();
+        let ciphers = vec![
+            CipherOption::AES256,
+            CipherOption::XChaCha20,
+            CipherOption::Kuznyechik,
+        ];
+        let chain = CipherChain {
+            cipher_chain: ciphers.clone(),
+            keys: &keys,
+        };
+
+        let original = b"Nested layers".to_vec();
+        let mut layer = chain.encrypt(&mut original.clone()).unwrap();
+
+        // Peel the outermost layer at a time; each one is the previous cipher's blob
+        for cipher in ciphers.iter().rev() {
+            let blob = EncryptedBlob::parse(cipher, &layer).unwrap();
+            assert_eq!(blob.to_bytes(), layer);
+            let (iv_len, _) = layer_layout(cipher).unwrap();
+            assert_eq!(blob.iv.len(), iv_len);
+
+            let single = CipherChain {
+                cipher_chain: vec![*cipher],
+                keys: &keys,
+            };
+            layer = single.decrypt(&mut layer).unwrap();
+        }
+        assert_eq!(layer, original);
+    }
 }
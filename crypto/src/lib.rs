@@ -2,7 +2,10 @@ pub mod bip39;
 pub mod cipher_chain;
 pub mod master_keys;
 pub mod master_password;
+pub mod signer;
 pub mod structures;
+pub mod totp;
 
-pub use master_keys::{AssymetricKeypair, MasterKeys};
-pub use structures::{CipherOption, UserId};
+pub use master_keys::{AssymetricKeypair, KeypairCache, MasterKeys};
+pub use signer::RequestSigner;
+pub use structures::{CipherOption, Nonce, UserId};
@@ -1,17 +1,30 @@
 use crate::structures::{CipherOption, UserId};
 use argon2::{
     password_hash::{Output, Salt},
-    Argon2, Params, Version,
+    Argon2, Block, Params, Version,
+};
+use blake2::{
+    digest::consts::{U16, U32},
+    Blake2b, Digest,
 };
-use blake2::{digest::consts::U16, Blake2b, Digest};
 use crystals_dilithium::dilithium2;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use zeroize::{Zeroize, Zeroizing};
 
 type Blake2b128 = Blake2b<U16>;
 
-#[derive(Debug)]
+// Starts with 'P', outside the range of CipherOption codes, so no cipher key shares it
+const TAG_SALT: [u8; 16] = *b"PASSMGR_tag_V_01";
+
+// Deliberately not Clone, every copy would be more key material to wipe; pass `&MasterKeys`
 pub struct MasterKeys {
     pub user_id: UserId,
     pub server_key: [u8; 32],
+    // HMAC key for authenticated cipher chain output
+    pub tag_key: [u8; 32],
     pub aes256_key: [u8; 32],
     pub aria_key: [u8; 32],
     pub belt_key: [u8; 32],
@@ -22,9 +35,16 @@ pub struct MasterKeys {
     pub spec_key: [u8; 32],
     pub twofish_key: [u8; 32],
     pub xchacha20_key: [u8; 32],
+    pub xchacha20poly1305_key: [u8; 32],
     pub ntrup1277_seed: [u8; 64],
     pub kyber1024_seed: [u8; 84],
     pub dilithium_seed: [u8; 32],
+    // Ciphers whose key material has been derived so far
+    derived: Vec<CipherOption>,
+    // Kept to derive further keys on demand
+    entropy: Zeroizing<Vec<u8>>,
+    // Rotation counters for ciphers whose key was rotated, absent means generation 0
+    generations: Vec<(CipherOption, u32)>,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -33,24 +53,271 @@ pub enum KeyDerivationError {
     Argon2Error(String),
     #[error("Invalid entropy length")]
     InvalidEntropyLength,
+    #[error("Invalid seed length, expected a 64 byte BIP39 seed")]
+    InvalidSeedLength,
+}
+
+/// Argon2id context with its working memory allocated once. Each hash otherwise
+/// allocates and frees the full 64MB; reusing a deriver across derivations
+/// (e.g. a server opening many vaults) avoids that churn.
+pub struct KeyDeriver {
+    argon2: Argon2<'static>,
+    memory: Vec<Block>,
+}
+
+impl KeyDeriver {
+    pub fn new() -> Result<Self, KeyDerivationError> {
+        let argon2 = MasterKeys::argon2()?;
+        let memory = vec![Block::default(); argon2.params().block_count()];
+        Ok(Self { argon2, memory })
+    }
+
+    fn hash_into(
+        &mut self,
+        entropy: &[u8],
+        salt: &[u8],
+        output: &mut [u8],
+    ) -> Result<(), KeyDerivationError> {
+        self.argon2
+            .hash_password_into_with_memory(entropy, salt, output, self.memory.as_mut_slice())
+            .map_err(|e| KeyDerivationError::Argon2Error(e.to_string()))
+    }
 }
 
 impl MasterKeys {
     // Argon2id parameters
-    const MEMORY_SIZE: u32 = 64 * 1024; // 64MB
-                                        // was = 3
-    const TIME_COST: u32 = 1;
+    pub const MEMORY_SIZE: u32 = 64 * 1024; // 64MB
+                                            // was = 3
+    pub const TIME_COST: u32 = 1;
     // was = 4
-    const PARALLELISM: u32 = 1;
+    pub const PARALLELISM: u32 = 1;
 
-    /// Derive master keys from BIP39 entropy using Argon2id
+    /// Derive master keys from raw BIP39 entropy (`Bip39::get_entropy`) using Argon2id.
+    /// This is not the standard BIP39 path: no passphrase takes part. The CLI uses it
+    /// by default, so existing vaults keep opening.
     pub fn from_entropy(entropy: &[u8]) -> Result<Self, KeyDerivationError> {
+        Self::from_entropy_for(entropy, &CipherOption::ALL)
+    }
+
+    /// Derive master keys from the standard 64 byte BIP39 seed (`Bip39::get_seed`,
+    /// PBKDF2 over mnemonic and passphrase). Gives different keys, and a different
+    /// user id, than `from_entropy` for the same mnemonic.
+    pub fn from_seed(seed: &[u8]) -> Result<Self, KeyDerivationError> {
+        Self::from_seed_for(seed, &CipherOption::ALL)
+    }
+
+    /// `from_seed` deriving only the keys needed by `ciphers`, see `from_entropy_for`
+    pub fn from_seed_for(
+        seed: &[u8],
+        ciphers: &[CipherOption],
+    ) -> Result<Self, KeyDerivationError> {
+        if seed.len() != 64 {
+            return Err(KeyDerivationError::InvalidSeedLength);
+        }
+        // The seed is the key material in place of the entropy
+        Self::from_entropy_with(&mut KeyDeriver::new()?, seed, ciphers)
+    }
+
+    /// Derive only the keys needed by `ciphers` (plus the Dilithium signing seed).
+    /// Further keys can be added later with `derive_keys`.
+    pub fn from_entropy_for(
+        entropy: &[u8],
+        ciphers: &[CipherOption],
+    ) -> Result<Self, KeyDerivationError> {
         if entropy.len() < 32 {
             return Err(KeyDerivationError::InvalidEntropyLength);
         }
+        Self::from_entropy_with(&mut KeyDeriver::new()?, entropy, ciphers)
+    }
 
+    /// Same as `from_entropy_for`, reusing the deriver's Argon2 working memory
+    pub fn from_entropy_with(
+        deriver: &mut KeyDeriver,
+        entropy: &[u8],
+        ciphers: &[CipherOption],
+    ) -> Result<Self, KeyDerivationError> {
+        if entropy.len() < 32 {
+            return Err(KeyDerivationError::InvalidEntropyLength);
+        }
+
+        let mut keys = Self {
+            user_id: Self::derive_user_id(deriver, entropy)?,
+            server_key: Self::derive_server_key(deriver, entropy)?,
+            tag_key: Self::derive_tag_key(deriver, entropy)?,
+            aes256_key: [0u8; 32],
+            aria_key: [0u8; 32],
+            belt_key: [0u8; 32],
+            camellia_key: [0u8; 32],
+            cast6_key: [0u8; 32],
+            kuznyechik_key: [0u8; 32],
+            serpent_key: [0u8; 32],
+            spec_key: [0u8; 32],
+            twofish_key: [0u8; 32],
+            xchacha20_key: [0u8; 32],
+            xchacha20poly1305_key: [0u8; 32],
+            ntrup1277_seed: [0u8; 64],
+            kyber1024_seed: [0u8; 84],
+            dilithium_seed: [0u8; 32],
+            derived: Vec::new(),
+            entropy: Zeroizing::new(entropy.to_vec()),
+            generations: Vec::new(),
+        };
+
+        keys.derive_keys_with(deriver, &[CipherOption::Dilithium])?;
+        keys.derive_keys_with(deriver, ciphers)?;
+        Ok(keys)
+    }
+
+    /// Derive keys for `ciphers` that are not derived yet
+    pub fn derive_keys(&mut self, ciphers: &[CipherOption]) -> Result<(), KeyDerivationError> {
+        if ciphers.iter().all(|cipher| self.has_key(cipher)) {
+            return Ok(());
+        }
+        self.derive_keys_with(&mut KeyDeriver::new()?, ciphers)
+    }
+
+    /// Same as `derive_keys`, reusing the deriver's Argon2 working memory
+    pub fn derive_keys_with(
+        &mut self,
+        deriver: &mut KeyDeriver,
+        ciphers: &[CipherOption],
+    ) -> Result<(), KeyDerivationError> {
+        let pending: Vec<CipherOption> = ciphers
+            .iter()
+            .filter(|cipher| !self.has_key(cipher))
+            .copied()
+            .collect();
+
+        let entropy = self.entropy.as_slice();
+        for cipher in pending {
+            let generation = self.generation(&cipher);
+            match cipher {
+                CipherOption::AES256 => {
+                    self.aes256_key =
+                        Self::derive_symmetric_key(deriver, entropy, cipher, generation)?
+                }
+                CipherOption::ARIA => {
+                    self.aria_key =
+                        Self::derive_symmetric_key(deriver, entropy, cipher, generation)?
+                }
+                CipherOption::BelT => {
+                    self.belt_key =
+                        Self::derive_symmetric_key(deriver, entropy, cipher, generation)?
+                }
+                CipherOption::Camellia => {
+                    self.camellia_key =
+                        Self::derive_symmetric_key(deriver, entropy, cipher, generation)?
+                }
+                CipherOption::CAST6 => {
+                    self.cast6_key =
+                        Self::derive_symmetric_key(deriver, entropy, cipher, generation)?
+                }
+                CipherOption::Kuznyechik => {
+                    self.kuznyechik_key =
+                        Self::derive_symmetric_key(deriver, entropy, cipher, generation)?
+                }
+                CipherOption::Serpent => {
+                    self.serpent_key =
+                        Self::derive_symmetric_key(deriver, entropy, cipher, generation)?
+                }
+                CipherOption::Spec => {
+                    self.spec_key =
+                        Self::derive_symmetric_key(deriver, entropy, cipher, generation)?
+                }
+                CipherOption::Twofish => {
+                    self.twofish_key =
+                        Self::derive_symmetric_key(deriver, entropy, cipher, generation)?
+                }
+                CipherOption::XChaCha20 => {
+                    self.xchacha20_key =
+                        Self::derive_symmetric_key(deriver, entropy, cipher, generation)?
+                }
+                CipherOption::XChaCha20Poly1305 => {
+                    self.xchacha20poly1305_key =
+                        Self::derive_symmetric_key(deriver, entropy, cipher, generation)?
+                }
+                CipherOption::NTRUP1277 => {
+                    self.ntrup1277_seed =
+                        Self::derive_quantum_seed::<64>(deriver, entropy, cipher, generation)?
+                }
+                CipherOption::Kyber1024 => {
+                    self.kyber1024_seed =
+                        Self::derive_quantum_seed::<84>(deriver, entropy, cipher, generation)?
+                }
+                CipherOption::Dilithium => {
+                    self.dilithium_seed =
+                        Self::derive_quantum_seed::<32>(deriver, entropy, cipher, generation)?
+                }
+            }
+            self.derived.push(cipher);
+        }
+        Ok(())
+    }
+
+    pub fn has_key(&self, cipher: &CipherOption) -> bool {
+        self.derived.contains(cipher)
+    }
+
+    /// Short non-secret tag of the seed these keys came from, first 4 bytes of
+    /// SHA-256(user_id). Lets the user check a typed phrase without revealing anything.
+    pub fn fingerprint(&self) -> [u8; 4] {
+        let hash = Sha256::digest(self.user_id);
+        [hash[0], hash[1], hash[2], hash[3]]
+    }
+
+    /// Key of the HMAC tag `CipherChain::encrypt_authenticated` appends, not used by
+    /// any cipher
+    pub fn hmac_key(&self) -> &[u8; 32] {
+        &self.tag_key
+    }
+
+    /// Rotation counter of a cipher's key, 0 for the original derivation
+    pub fn generation(&self, cipher: &CipherOption) -> u32 {
+        self.generations
+            .iter()
+            .find(|(c, _)| c == cipher)
+            .map_or(0, |(_, generation)| *generation)
+    }
+
+    /// Non-zero rotation counters, to be persisted alongside the vault
+    pub fn key_generations(&self) -> &[(CipherOption, u32)] {
+        &self.generations
+    }
+
+    /// Replace a single cipher's key with the next generation, leaving the others untouched.
+    /// Returns the new generation.
+    pub fn rotate_key(&mut self, cipher: CipherOption) -> Result<u32, KeyDerivationError> {
+        let generation = self.generation(&cipher) + 1;
+        self.set_generation(cipher, generation)?;
+        Ok(generation)
+    }
+
+    /// Re-derive a cipher's key at the given generation, e.g. when opening a rotated vault
+    pub fn set_generation(
+        &mut self,
+        cipher: CipherOption,
+        generation: u32,
+    ) -> Result<(), KeyDerivationError> {
+        if self.generation(&cipher) == generation {
+            return Ok(());
+        }
+
+        self.generations.retain(|(c, _)| *c != cipher);
+        if generation > 0 {
+            self.generations.push((cipher, generation));
+        }
+
+        let was_derived = self.has_key(&cipher);
+        self.derived.retain(|c| *c != cipher);
+        if was_derived {
+            self.derive_keys(&[cipher])?;
+        }
+        Ok(())
+    }
+
+    fn argon2() -> Result<Argon2<'static>, KeyDerivationError> {
         // Initialize Argon2id with default parameters
-        let argon2 = Argon2::new(
+        Ok(Argon2::new(
             argon2::Algorithm::Argon2id,
             argon2::Version::V0x13,
             Params::new(
@@ -60,38 +327,7 @@ impl MasterKeys {
                 Some(32), // Output length in bytes
             )
             .map_err(|e| KeyDerivationError::Argon2Error(e.to_string()))?,
-        );
-
-        Ok(Self {
-            user_id: Self::derive_user_id(&argon2, entropy)?,
-            server_key: Self::derive_server_key(&argon2, entropy)?,
-            aes256_key: Self::derive_symmetric_key(&argon2, entropy, CipherOption::AES256)?,
-            aria_key: Self::derive_symmetric_key(&argon2, entropy, CipherOption::ARIA)?,
-            belt_key: Self::derive_symmetric_key(&argon2, entropy, CipherOption::BelT)?,
-            camellia_key: Self::derive_symmetric_key(&argon2, entropy, CipherOption::Camellia)?,
-            cast6_key: Self::derive_symmetric_key(&argon2, entropy, CipherOption::CAST6)?,
-            kuznyechik_key: Self::derive_symmetric_key(&argon2, entropy, CipherOption::Kuznyechik)?,
-            serpent_key: Self::derive_symmetric_key(&argon2, entropy, CipherOption::Serpent)?,
-            spec_key: Self::derive_symmetric_key(&argon2, entropy, CipherOption::Spec)?,
-            twofish_key: Self::derive_symmetric_key(&argon2, entropy, CipherOption::Twofish)?,
-            xchacha20_key: Self::derive_symmetric_key(&argon2, entropy, CipherOption::XChaCha20)?,
-            ntrup1277_seed: Self::derive_quantum_seed::<64>(
-                &argon2,
-                entropy,
-                CipherOption::NTRUP1277,
-            )?,
-            // TODO implement kyber
-            kyber1024_seed: [42u8; 84], /* Self::derive_quantum_seed::<84>(
-                                            &argon2,
-                                            entropy,
-                                            CipherOption::Kyber1024,
-                                        )?, */
-            dilithium_seed: Self::derive_quantum_seed::<32>(
-                &argon2,
-                entropy,
-                CipherOption::Dilithium,
-            )?,
-        })
+        ))
     }
 
     // Generate unique salt for each cipher
@@ -102,47 +338,63 @@ impl MasterKeys {
         salt
     }
 
+    // Rotated keys append their generation, generation 0 keeps the original salt
+    fn generation_salt(base: &[u8], generation: u32) -> Vec<u8> {
+        let mut salt = base.to_vec();
+        if generation > 0 {
+            salt.extend_from_slice(&generation.to_le_bytes());
+        }
+        salt
+    }
+
     // Derive 32-byte key for symmetric ciphers
     fn derive_symmetric_key(
-        argon2: &Argon2,
+        deriver: &mut KeyDeriver,
         entropy: &[u8],
         cipher: CipherOption,
+        generation: u32,
     ) -> Result<[u8; 32], KeyDerivationError> {
-        let salt = Self::generate_salt(cipher);
+        let salt = Self::generation_salt(&Self::generate_salt(cipher), generation);
         let mut output = [0u8; 32];
 
-        argon2
-            .hash_password_into(entropy, &salt, &mut output)
-            .map_err(|e| KeyDerivationError::Argon2Error(e.to_string()))?;
+        deriver.hash_into(entropy, &salt, &mut output)?;
 
         Ok(output)
     }
 
     // Derive N-byte seed for quantum-resistant algorithms
     fn derive_quantum_seed<const N: usize>(
-        argon2: &Argon2,
+        deriver: &mut KeyDeriver,
         entropy: &[u8],
         cipher: CipherOption,
+        generation: u32,
     ) -> Result<[u8; N], KeyDerivationError> {
         let mut seed = [0u8; N];
         let base_salt = Self::generate_salt(cipher);
 
-        // For seeds larger than 32 bytes, we need multiple derivations
+        // For seeds larger than 32 bytes, we need multiple derivations. Argon2 is set up
+        // for 32-byte outputs, so a trailing partial chunk (84 = 32 + 32 + 20) takes the
+        // prefix of a full one
+        let mut output = [0u8; 32];
         for (i, chunk) in seed.chunks_mut(32).enumerate() {
             let mut temp_salt = [0u8; 20]; // 16 bytes salt + 4 bytes counter
             temp_salt[..16].copy_from_slice(&base_salt);
             temp_salt[16..].copy_from_slice(&(i as u32).to_le_bytes());
 
-            argon2
-                .hash_password_into(entropy, &temp_salt, chunk)
-                .map_err(|e| KeyDerivationError::Argon2Error(e.to_string()))?;
+            let temp_salt = Self::generation_salt(&temp_salt, generation);
+            deriver.hash_into(entropy, &temp_salt, &mut output)?;
+            chunk.copy_from_slice(&output[..chunk.len()]);
         }
+        output.zeroize();
 
         Ok(seed)
     }
 
-    // Get key for specific cipher
+    // Get key for specific cipher, empty if it was not derived
     pub fn get_key(&self, cipher: &CipherOption) -> &[u8] {
+        if !self.has_key(cipher) {
+            return &[];
+        }
         match cipher {
             CipherOption::AES256 => &self.aes256_key,
             CipherOption::ARIA => &self.aria_key,
@@ -157,31 +409,77 @@ impl MasterKeys {
             CipherOption::Spec => &self.spec_key,
             CipherOption::Twofish => &self.twofish_key,
             CipherOption::XChaCha20 => &self.xchacha20_key,
+            CipherOption::XChaCha20Poly1305 => &self.xchacha20poly1305_key,
             // CipherOption::END => &[],
         }
     }
-    fn derive_user_id(argon2: &Argon2, entropy: &[u8]) -> Result<[u8; 32], KeyDerivationError> {
+    fn derive_user_id(
+        deriver: &mut KeyDeriver,
+        entropy: &[u8],
+    ) -> Result<[u8; 32], KeyDerivationError> {
         let salt: [u8; 16] = *b"PASSMGR_user_V_1";
         let mut buffer = [0u8; 32];
 
-        argon2
-            .hash_password_into(entropy, &salt, &mut buffer)
-            .map_err(|e| KeyDerivationError::Argon2Error(e.to_string()))?;
+        deriver.hash_into(entropy, &salt, &mut buffer)?;
         Ok(buffer)
     }
 
-    fn derive_server_key(argon2: &Argon2, entropy: &[u8]) -> Result<[u8; 32], KeyDerivationError> {
+    fn derive_server_key(
+        deriver: &mut KeyDeriver,
+        entropy: &[u8],
+    ) -> Result<[u8; 32], KeyDerivationError> {
         let salt: [u8; 16] = *b"PASSMGR_server_1";
         let mut output = [0u8; 32];
 
-        argon2
-            .hash_password_into(entropy, &salt, &mut output)
-            .map_err(|e| KeyDerivationError::Argon2Error(e.to_string()))?;
+        deriver.hash_into(entropy, &salt, &mut output)?;
+
+        Ok(output)
+    }
+
+    fn derive_tag_key(
+        deriver: &mut KeyDeriver,
+        entropy: &[u8],
+    ) -> Result<[u8; 32], KeyDerivationError> {
+        let mut output = [0u8; 32];
+
+        deriver.hash_into(entropy, &TAG_SALT, &mut output)?;
 
         Ok(output)
     }
 }
 
+// Only what identifies the keys, never key material
+impl fmt::Debug for MasterKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MasterKeys")
+            .field("user_id", &self.user_id)
+            .field("derived", &self.derived)
+            .field("generations", &self.generations)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for MasterKeys {
+    fn drop(&mut self) {
+        self.server_key.zeroize();
+        self.tag_key.zeroize();
+        self.aes256_key.zeroize();
+        self.aria_key.zeroize();
+        self.belt_key.zeroize();
+        self.camellia_key.zeroize();
+        self.cast6_key.zeroize();
+        self.kuznyechik_key.zeroize();
+        self.serpent_key.zeroize();
+        self.spec_key.zeroize();
+        self.twofish_key.zeroize();
+        self.xchacha20_key.zeroize();
+        self.xchacha20poly1305_key.zeroize();
+        self.ntrup1277_seed.zeroize();
+        self.kyber1024_seed.zeroize();
+        self.dilithium_seed.zeroize();
+    }
+}
+
 pub struct AssymetricKeypair {
     pub dilithium_keypair: dilithium2::Keypair,
 }
@@ -195,6 +493,38 @@ impl AssymetricKeypair {
     }
 }
 
+impl Drop for AssymetricKeypair {
+    fn drop(&mut self) {
+        self.dilithium_keypair.secret.bytes.zeroize();
+    }
+}
+
+/// Keypairs already generated from a seed, so reopening a vault skips Dilithium keygen.
+/// Entries are looked up by a hash of the seed; secret keys are zeroized once the
+/// cache and every handed out `Arc` are dropped.
+#[derive(Default)]
+pub struct KeypairCache {
+    keypairs: HashMap<[u8; 32], Arc<AssymetricKeypair>>,
+}
+
+impl KeypairCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get_or_generate(&mut self, seed: &[u8; 32]) -> Arc<AssymetricKeypair> {
+        let key: [u8; 32] = Blake2b::<U32>::digest(seed).into();
+        self.keypairs
+            .entry(key)
+            .or_insert_with(|| Arc::new(AssymetricKeypair::generate_dilithium2(seed)))
+            .clone()
+    }
+
+    pub fn clear(&mut self) {
+        self.keypairs.clear();
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -211,6 +541,7 @@ mod tests {
         let keys = [
             &master_keys.aes256_key[..],
             &master_keys.xchacha20_key[..],
+            &master_keys.xchacha20poly1305_key[..],
             &master_keys.kuznyechik_key[..],
             &master_keys.twofish_key[..],
         ];
@@ -224,6 +555,20 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_debug_redacts_secrets() {
+        let keys = MasterKeys::from_entropy_for(&[9; 32], &[CipherOption::AES256]).unwrap();
+        let debug = format!("{:?}", keys);
+        assert!(debug.contains(&format!("{:?}", keys.user_id)));
+        for secret in [
+            format!("{:?}", keys.aes256_key),
+            format!("{:?}", keys.tag_key),
+            format!("{:?}", [9u8; 32]),
+        ] {
+            assert!(!debug.contains(&secret));
+        }
+    }
+
     #[test]
     fn test_quantum_seeds() {
         let mut entropy = [0u8; 32];
@@ -241,6 +586,33 @@ mod tests {
             &master_keys.kyber1024_seed[..64],
             "Quantum seeds share common prefix"
         );
+
+        assert_ne!(master_keys.kyber1024_seed, [42u8; 84]);
+        // The 20-byte tail chunk is derived too
+        assert_ne!(&master_keys.kyber1024_seed[64..], &[0u8; 20]);
+        assert_ne!(
+            &master_keys.kyber1024_seed[..32],
+            &master_keys.kyber1024_seed[32..64]
+        );
+    }
+
+    #[test]
+    fn test_dilithium_keypair_from_entropy() {
+        // Derived even when no chain cipher needs it, signing depends on it
+        let keys = MasterKeys::from_entropy_for(&[1; 32], &[CipherOption::AES256]).unwrap();
+        let again = MasterKeys::from_entropy_for(&[1; 32], &[CipherOption::AES256]).unwrap();
+        let other = MasterKeys::from_entropy_for(&[2; 32], &[CipherOption::AES256]).unwrap();
+        assert!(keys.has_key(&CipherOption::Dilithium));
+
+        let public = |keys: &MasterKeys| {
+            AssymetricKeypair::generate_dilithium2(&keys.dilithium_seed)
+                .dilithium_keypair
+                .public
+                .bytes
+                .to_vec()
+        };
+        assert_eq!(public(&keys), public(&again));
+        assert_ne!(public(&keys), public(&other));
     }
 
     #[test]
@@ -267,5 +639,186 @@ mod tests {
         assert_eq!(keys1.ntrup1277_seed, keys2.ntrup1277_seed);
         assert_eq!(keys1.twofish_key, keys2.twofish_key);
         assert_eq!(keys1.kyber1024_seed, keys2.kyber1024_seed);
+        assert_ne!(keys1.kyber1024_seed, [42u8; 84]);
+    }
+
+    #[test]
+    fn test_user_id_derivation() {
+        let keys = MasterKeys::from_entropy(&[1; 32]).unwrap();
+        let again = MasterKeys::from_entropy(&[1; 32]).unwrap();
+        let other = MasterKeys::from_entropy(&[2; 32]).unwrap();
+        assert_eq!(keys.user_id, again.user_id);
+        assert_ne!(keys.user_id, other.user_id);
+
+        // Its own salt, so publishing it gives away no key
+        for cipher in CipherOption::ALL {
+            assert!(
+                !keys.get_key(&cipher).starts_with(&keys.user_id),
+                "{:?}",
+                cipher
+            );
+        }
+        assert_ne!(keys.user_id, keys.server_key);
+        assert_ne!(keys.user_id, keys.tag_key);
+        assert_ne!(keys.user_id, keys.dilithium_seed);
+    }
+
+    #[test]
+    fn test_hmac_key() {
+        let keys = MasterKeys::from_entropy(&[3; 32]).unwrap();
+        let again = MasterKeys::from_entropy(&[3; 32]).unwrap();
+        let other = MasterKeys::from_entropy(&[4; 32]).unwrap();
+        assert_eq!(keys.hmac_key(), again.hmac_key());
+        assert_ne!(keys.hmac_key(), other.hmac_key());
+
+        for cipher in CipherOption::ALL {
+            assert_ne!(TAG_SALT[0], cipher.code(), "{:?}", cipher);
+            assert_ne!(keys.get_key(&cipher), keys.hmac_key(), "{:?}", cipher);
+        }
+        assert_ne!(keys.hmac_key(), &keys.server_key);
+        assert_ne!(keys.hmac_key(), &keys.user_id);
+    }
+
+    #[test]
+    fn test_subset_derivation() {
+        let mut entropy = [0u8; 32];
+        OsRng.fill_bytes(&mut entropy);
+
+        let full = MasterKeys::from_entropy(&entropy).unwrap();
+        let mut subset = MasterKeys::from_entropy_for(
+            &entropy,
+            &[CipherOption::AES256, CipherOption::XChaCha20],
+        )
+        .unwrap();
+
+        assert_eq!(subset.user_id, full.user_id);
+        assert_eq!(subset.dilithium_seed, full.dilithium_seed);
+        assert_eq!(subset.aes256_key, full.aes256_key);
+        assert_eq!(subset.xchacha20_key, full.xchacha20_key);
+
+        // Unrequested keys are skipped
+        assert!(!subset.has_key(&CipherOption::Kuznyechik));
+        assert!(subset.get_key(&CipherOption::Kuznyechik).is_empty());
+        assert_eq!(subset.kuznyechik_key, [0u8; 32]);
+
+        // and derived on demand
+        subset.derive_keys(&[CipherOption::Kuznyechik]).unwrap();
+        assert!(subset.has_key(&CipherOption::Kuznyechik));
+        assert_eq!(subset.kuznyechik_key, full.kuznyechik_key);
+        assert_eq!(
+            subset.get_key(&CipherOption::Kuznyechik),
+            full.get_key(&CipherOption::Kuznyechik)
+        );
+    }
+
+    #[test]
+    fn test_rotate_single_key() {
+        let mut entropy = [0u8; 32];
+        OsRng.fill_bytes(&mut entropy);
+
+        let original = MasterKeys::from_entropy(&entropy).unwrap();
+        let mut rotated = MasterKeys::from_entropy(&entropy).unwrap();
+        assert_eq!(rotated.rotate_key(CipherOption::AES256).unwrap(), 1);
+
+        assert_ne!(rotated.aes256_key, original.aes256_key);
+        assert_eq!(rotated.xchacha20_key, original.xchacha20_key);
+        assert_eq!(rotated.kuznyechik_key, original.kuznyechik_key);
+        assert_eq!(rotated.dilithium_seed, original.dilithium_seed);
+        assert_eq!(rotated.key_generations(), &[(CipherOption::AES256, 1)]);
+
+        // The same generation is reproducible from the entropy alone
+        let mut reopened = MasterKeys::from_entropy(&entropy).unwrap();
+        reopened.set_generation(CipherOption::AES256, 1).unwrap();
+        assert_eq!(reopened.aes256_key, rotated.aes256_key);
+    }
+
+    #[test]
+    fn test_shared_deriver_matches() {
+        let mut entropy = [0u8; 32];
+        OsRng.fill_bytes(&mut entropy);
+
+        let mut deriver = KeyDeriver::new().unwrap();
+        let first =
+            MasterKeys::from_entropy_with(&mut deriver, &entropy, &CipherOption::ALL).unwrap();
+        // Leftover memory from the previous run must not leak into the next derivation
+        let second =
+            MasterKeys::from_entropy_with(&mut deriver, &entropy, &CipherOption::ALL).unwrap();
+        let fresh = MasterKeys::from_entropy(&entropy).unwrap();
+
+        assert_eq!(first.aes256_key, fresh.aes256_key);
+        assert_eq!(second.aes256_key, fresh.aes256_key);
+        assert_eq!(second.user_id, fresh.user_id);
+        assert_eq!(second.ntrup1277_seed, fresh.ntrup1277_seed);
+    }
+
+    #[test]
+    fn test_keypair_cache() {
+        let first = AssymetricKeypair::generate_dilithium2(&[5; 32]);
+        let second = AssymetricKeypair::generate_dilithium2(&[5; 32]);
+        assert_eq!(
+            first.dilithium_keypair.public.bytes,
+            second.dilithium_keypair.public.bytes
+        );
+
+        let mut cache = KeypairCache::new();
+        let cached = cache.get_or_generate(&[5; 32]);
+        assert!(Arc::ptr_eq(&cached, &cache.get_or_generate(&[5; 32])));
+        assert_eq!(
+            cached.dilithium_keypair.public.bytes,
+            first.dilithium_keypair.public.bytes
+        );
+        assert!(!Arc::ptr_eq(&cached, &cache.get_or_generate(&[6; 32])));
+    }
+
+    #[test]
+    fn test_keypair_cache_clear() {
+        let mut cache = KeypairCache::new();
+        let keypair = Arc::downgrade(&cache.get_or_generate(&[5; 32]));
+        assert!(keypair.upgrade().is_some());
+
+        // The cache held the last reference, clearing it drops and zeroizes the keypair
+        cache.clear();
+        assert!(keypair.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let ciphers = [CipherOption::AES256];
+        let first = MasterKeys::from_entropy_for(&[1; 32], &ciphers).unwrap();
+        let again = MasterKeys::from_entropy_for(&[1; 32], &ciphers).unwrap();
+        let other = MasterKeys::from_entropy_for(&[2; 32], &ciphers).unwrap();
+
+        assert_eq!(first.fingerprint(), again.fingerprint());
+        assert_ne!(first.fingerprint(), other.fingerprint());
+        assert_ne!(first.fingerprint()[..], first.user_id[..4]);
+    }
+
+    #[test]
+    fn test_entropy_and_seed_derivation() {
+        let bip39 = crate::bip39::Bip39::new(256).unwrap();
+        let ciphers = [CipherOption::AES256];
+
+        let from_entropy = MasterKeys::from_entropy_for(bip39.get_entropy(), &ciphers).unwrap();
+        let from_seed = MasterKeys::from_seed_for(&bip39.get_seed(""), &ciphers).unwrap();
+        assert_ne!(from_entropy.user_id, from_seed.user_id);
+        assert_ne!(from_entropy.aes256_key, from_seed.aes256_key);
+
+        // Both are reproducible
+        let again = MasterKeys::from_entropy_for(bip39.get_entropy(), &ciphers).unwrap();
+        assert_eq!(again.user_id, from_entropy.user_id);
+        assert_eq!(again.aes256_key, from_entropy.aes256_key);
+        let again = MasterKeys::from_seed_for(&bip39.get_seed(""), &ciphers).unwrap();
+        assert_eq!(again.user_id, from_seed.user_id);
+        assert_eq!(again.aes256_key, from_seed.aes256_key);
+
+        // Only the seed path depends on the passphrase
+        let with_passphrase =
+            MasterKeys::from_seed_for(&bip39.get_seed("passphrase"), &ciphers).unwrap();
+        assert_ne!(with_passphrase.aes256_key, from_seed.aes256_key);
+
+        assert!(matches!(
+            MasterKeys::from_seed(bip39.get_entropy()),
+            Err(KeyDerivationError::InvalidSeedLength)
+        ));
     }
 }
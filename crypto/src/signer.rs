@@ -0,0 +1,64 @@
+use crate::master_keys::AssymetricKeypair;
+use crystals_dilithium::dilithium2;
+use std::sync::Arc;
+
+pub type Signature = Vec<u8>;
+
+/// Signs authenticated requests on behalf of a user.
+///
+/// The software `AssymetricKeypair` keeps the Dilithium key in process memory;
+/// a hardware token (HSM, PKCS#11 smartcard) can implement this trait instead
+/// so the private key never leaves the device.
+pub trait RequestSigner: Send + Sync {
+    fn sign(&self, msg: &[u8]) -> Signature;
+    fn public_key(&self) -> Vec<u8>;
+}
+
+impl RequestSigner for AssymetricKeypair {
+    fn sign(&self, msg: &[u8]) -> Signature {
+        self.dilithium_keypair.sign(msg).to_vec()
+    }
+
+    fn public_key(&self) -> Vec<u8> {
+        self.dilithium_keypair.public.bytes.to_vec()
+    }
+}
+
+// Lets a keypair from `KeypairCache` be used as a signer directly
+impl<T: RequestSigner + ?Sized> RequestSigner for Arc<T> {
+    fn sign(&self, msg: &[u8]) -> Signature {
+        (**self).sign(msg)
+    }
+
+    fn public_key(&self) -> Vec<u8> {
+        (**self).public_key()
+    }
+}
+
+/// Check a Dilithium2 signature made by any `RequestSigner`
+pub fn verify_signature(public_key: &[u8], msg: &[u8], signature: &[u8]) -> bool {
+    dilithium2::PublicKey::from_bytes(public_key).verify(msg, signature)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keypair_signer() {
+        let keys = AssymetricKeypair::generate_dilithium2(&[3; 32]);
+        let signer: &dyn RequestSigner = &keys;
+
+        let signature = signer.sign(b"message");
+        assert!(verify_signature(
+            &signer.public_key(),
+            b"message",
+            &signature
+        ));
+        assert!(!verify_signature(
+            &signer.public_key(),
+            b"other message",
+            &signature
+        ));
+    }
+}
@@ -1,5 +1,8 @@
+use serde::{Deserialize, Serialize};
+
 pub type UserId = [u8; 32];
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum CipherOption {
     AES256,     // USA standart
     ARIA,       // Korea standart
@@ -10,13 +13,31 @@ pub enum CipherOption {
     Kuznyechik, // Russia standart
     Kyber1024,
     NTRUP1277,
-    Serpent,   // AES finalist
-    Spec,      // NASA lightweight block cipher
-    Twofish,   // AES finalist
-    XChaCha20, // lightweight block cipher
+    Serpent,           // AES finalist
+    Spec,              // NASA lightweight block cipher
+    Twofish,           // AES finalist
+    XChaCha20,         // lightweight block cipher
+    XChaCha20Poly1305, // XChaCha20 with a Poly1305 tag (AEAD)
 }
 
 impl CipherOption {
+    pub const ALL: [CipherOption; 14] = [
+        Self::AES256,
+        Self::ARIA,
+        Self::BelT,
+        Self::Camellia,
+        Self::CAST6,
+        Self::Dilithium,
+        Self::Kuznyechik,
+        Self::Kyber1024,
+        Self::NTRUP1277,
+        Self::Serpent,
+        Self::Spec,
+        Self::Twofish,
+        Self::XChaCha20,
+        Self::XChaCha20Poly1305,
+    ];
+
     pub fn code(&self) -> u8 {
         match self {
             // Self::END => 0,
@@ -33,6 +54,94 @@ impl CipherOption {
             Self::Spec => 11,
             Self::Twofish => 12,
             Self::XChaCha20 => 13,
+            Self::XChaCha20Poly1305 => 14,
+        }
+    }
+
+    /// Inverse of `code`
+    pub fn from_code(code: u8) -> Option<CipherOption> {
+        Self::ALL.into_iter().find(|cipher| cipher.code() == code)
+    }
+}
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+#[error("Unknown cipher code {0}")]
+pub struct UnknownCipherCode(pub u8);
+
+/// Chain stored as `CipherOption` codes, e.g. `CipherRecord::cipher_options`
+pub fn chain_from_codes(codes: &[u8]) -> Result<Vec<CipherOption>, UnknownCipherCode> {
+    codes
+        .iter()
+        .map(|&code| CipherOption::from_code(code).ok_or(UnknownCipherCode(code)))
+        .collect()
+}
+
+/// Per-user counter signed into every authenticated request so it can't be replayed.
+/// It never wraps: once fewer than `Nonce::RESERVE` values remain, incrementing
+/// fails and the user has to re-register with a fresh starting nonce.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct Nonce(u64);
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+#[error("Nonce space exhausted, re-register to continue")]
+pub struct NonceExhausted;
+
+impl Nonce {
+    pub const RESERVE: u64 = 1 << 16;
+
+    pub fn new(value: u64) -> Self {
+        Self(value)
+    }
+
+    pub fn get(self) -> u64 {
+        self.0
+    }
+
+    pub fn increment(&mut self) -> Result<(), NonceExhausted> {
+        if self.0 >= u64::MAX - Self::RESERVE {
+            return Err(NonceExhausted);
+        }
+        self.0 += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cipher_option_codes() {
+        for cipher in CipherOption::ALL {
+            assert_eq!(CipherOption::from_code(cipher.code()), Some(cipher));
         }
+        assert_eq!(CipherOption::from_code(0), None);
+        assert_eq!(CipherOption::from_code(200), None);
+
+        assert_eq!(
+            chain_from_codes(&[1, 13, 7]),
+            Ok(vec![
+                CipherOption::AES256,
+                CipherOption::XChaCha20,
+                CipherOption::Kuznyechik
+            ])
+        );
+        assert_eq!(chain_from_codes(&[1, 200]), Err(UnknownCipherCode(200)));
+    }
+
+    #[test]
+    fn test_nonce_exhaustion() {
+        let mut nonce = Nonce::new(41);
+        nonce.increment().unwrap();
+        assert_eq!(nonce.get(), 42);
+
+        let mut nonce = Nonce::new(u64::MAX - Nonce::RESERVE - 1);
+        nonce.increment().unwrap();
+        assert_eq!(nonce.increment(), Err(NonceExhausted));
+        // Refusing leaves the nonce where it was
+        assert_eq!(nonce.get(), u64::MAX - Nonce::RESERVE);
+
+        assert_eq!(Nonce::new(u64::MAX).increment(), Err(NonceExhausted));
     }
 }
@@ -0,0 +1,119 @@
+//! Copying copy-protected values to the system clipboard, cleared again after a timeout
+
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+pub const DEFAULT_CLEAR_SECS: u64 = 15;
+
+/// The clipboard operations the auto-clear needs, so the logic can run without a display
+pub trait Clipboard {
+    fn get_text(&mut self) -> Option<String>;
+    fn set_text(&mut self, text: &str) -> Result<(), String>;
+    fn clear(&mut self) -> Result<(), String>;
+}
+
+impl Clipboard for arboard::Clipboard {
+    fn get_text(&mut self) -> Option<String> {
+        arboard::Clipboard::get_text(self).ok()
+    }
+
+    fn set_text(&mut self, text: &str) -> Result<(), String> {
+        arboard::Clipboard::set_text(self, text).map_err(|e| e.to_string())
+    }
+
+    fn clear(&mut self) -> Result<(), String> {
+        arboard::Clipboard::clear(self).map_err(|e| e.to_string())
+    }
+}
+
+/// Clear the clipboard if it still holds `copied`. Anything the user copied since is
+/// left alone. Returns whether it was cleared.
+pub fn clear_if_unchanged(clipboard: &mut impl Clipboard, copied: &str) -> bool {
+    if clipboard.get_text().as_deref() != Some(copied) {
+        return false;
+    }
+    clipboard.clear().is_ok()
+}
+
+/// Put `value` on the clipboard and clear it after `timeout` unless it was replaced.
+/// The clipboard is moved into the task: on X11 the contents only live as long as it does.
+pub fn copy_with_auto_clear<C>(
+    mut clipboard: C,
+    value: String,
+    timeout: Duration,
+) -> Result<JoinHandle<bool>, String>
+where
+    C: Clipboard + Send + 'static,
+{
+    clipboard.set_text(&value)?;
+    Ok(tokio::spawn(async move {
+        tokio::time::sleep(timeout).await;
+        clear_if_unchanged(&mut clipboard, &value)
+    }))
+}
+
+/// Copy `value` to the system clipboard, see `copy_with_auto_clear`
+pub fn copy_to_clipboard(value: String, timeout: Duration) -> Result<(), String> {
+    let clipboard = arboard::Clipboard::new().map_err(|e| e.to_string())?;
+    copy_with_auto_clear(clipboard, value, timeout)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    // Shares its contents between clones, like the system clipboard between processes
+    #[derive(Clone, Default)]
+    struct MockClipboard(Arc<Mutex<Option<String>>>);
+
+    impl Clipboard for MockClipboard {
+        fn get_text(&mut self) -> Option<String> {
+            self.0.lock().unwrap().clone()
+        }
+
+        fn set_text(&mut self, text: &str) -> Result<(), String> {
+            *self.0.lock().unwrap() = Some(text.to_string());
+            Ok(())
+        }
+
+        fn clear(&mut self) -> Result<(), String> {
+            *self.0.lock().unwrap() = None;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_clear_if_unchanged() {
+        let mut clipboard = MockClipboard::default();
+        clipboard.set_text("secret").unwrap();
+        assert!(clear_if_unchanged(&mut clipboard, "secret"));
+        assert_eq!(clipboard.get_text(), None);
+
+        clipboard.set_text("copied elsewhere").unwrap();
+        assert!(!clear_if_unchanged(&mut clipboard, "secret"));
+        assert_eq!(clipboard.get_text().as_deref(), Some("copied elsewhere"));
+
+        // Already empty
+        clipboard.clear().unwrap();
+        assert!(!clear_if_unchanged(&mut clipboard, "secret"));
+    }
+
+    #[tokio::test]
+    async fn test_auto_clear() {
+        let mut clipboard = MockClipboard::default();
+        let timeout = Duration::from_millis(10);
+
+        let task = copy_with_auto_clear(clipboard.clone(), "secret".to_string(), timeout).unwrap();
+        assert_eq!(clipboard.get_text().as_deref(), Some("secret"));
+        assert!(task.await.unwrap());
+        assert_eq!(clipboard.get_text(), None);
+
+        // Newer content copied before the timeout survives
+        let task = copy_with_auto_clear(clipboard.clone(), "secret".to_string(), timeout).unwrap();
+        clipboard.set_text("newer").unwrap();
+        assert!(!task.await.unwrap());
+        assert_eq!(clipboard.get_text().as_deref(), Some("newer"));
+    }
+}
@@ -0,0 +1,337 @@
+//! Plaintext CSV exports of other password managers (Chrome, Firefox, Bitwarden, ...)
+
+use storage::structures::{Atributes, Item, Record};
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum CsvError {
+    #[error("CSV file has no header row")]
+    MissingHeader,
+    #[error("Malformed header row: {0}")]
+    MalformedHeader(String),
+    #[error("No {0} column found in the header")]
+    MissingColumn(&'static str),
+}
+
+/// Header names accepted for each record field, compared case-insensitively.
+/// The first matching alias wins; exporters disagree on names and order.
+#[derive(Clone, Debug)]
+pub struct ColumnMapping {
+    pub name: Vec<String>,
+    pub url: Vec<String>,
+    pub username: Vec<String>,
+    pub password: Vec<String>,
+    pub notes: Vec<String>,
+}
+
+impl Default for ColumnMapping {
+    fn default() -> Self {
+        fn names(aliases: &[&str]) -> Vec<String> {
+            aliases.iter().map(|alias| alias.to_string()).collect()
+        }
+        Self {
+            name: names(&["name", "title"]),
+            url: names(&["url", "login_uri", "uri"]),
+            username: names(&["username", "login_username", "login"]),
+            password: names(&["password", "login_password"]),
+            notes: names(&["notes", "note", "extra"]),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CsvImport {
+    pub records: Vec<Record>,
+    /// (line, reason) of every row that was left out
+    pub skipped: Vec<(usize, String)>,
+}
+
+// Column index of each field in the header, password is the only required one
+struct Columns {
+    name: Option<usize>,
+    url: Option<usize>,
+    username: Option<usize>,
+    password: usize,
+    notes: Option<usize>,
+}
+
+/// Turn every well-formed row into a record with the fields `build_record` offers,
+/// the password hidden. Malformed rows are reported in `skipped` instead of failing.
+pub fn parse_records(
+    input: &str,
+    mapping: &ColumnMapping,
+    now: u64,
+) -> Result<CsvImport, CsvError> {
+    let input = input.strip_prefix('\u{feff}').unwrap_or(input);
+    let mut rows = parse_rows(input).into_iter();
+
+    let header = match rows.next() {
+        Some((_, Ok(header))) => header,
+        Some((_, Err(reason))) => return Err(CsvError::MalformedHeader(reason)),
+        None => return Err(CsvError::MissingHeader),
+    };
+    let header: Vec<String> = header.iter().map(|h| h.trim().to_lowercase()).collect();
+    let find = |aliases: &[String]| {
+        aliases
+            .iter()
+            .find_map(|alias| header.iter().position(|h| *h == alias.to_lowercase()))
+    };
+    let columns = Columns {
+        name: find(&mapping.name),
+        url: find(&mapping.url),
+        username: find(&mapping.username),
+        password: find(&mapping.password).ok_or(CsvError::MissingColumn("password"))?,
+        notes: find(&mapping.notes),
+    };
+
+    let mut import = CsvImport::default();
+    for (line, row) in rows {
+        let fields = match row {
+            Ok(fields) => fields,
+            Err(reason) => {
+                import.skipped.push((line, reason));
+                continue;
+            }
+        };
+        if fields.len() != header.len() {
+            import.skipped.push((
+                line,
+                format!("expected {} fields, found {}", header.len(), fields.len()),
+            ));
+            continue;
+        }
+
+        let value = |column: Option<usize>| column.map_or("", |i| fields[i].as_str());
+        let mut record = Record {
+            icon: String::new(),
+            created: now,
+            updated: now,
+            fields: Vec::new(),
+            last_accessed: 0,
+        };
+        for (title, value, types) in [
+            ("Name", value(columns.name), vec![]),
+            ("URL", value(columns.url), vec![]),
+            ("Login", value(columns.username), vec![]),
+            (
+                "Password",
+                value(Some(columns.password)),
+                vec![Atributes::Hide],
+            ),
+            ("Note", value(columns.notes), vec![]),
+        ] {
+            if !value.is_empty() {
+                record.fields.push(Item {
+                    title: title.to_string(),
+                    value: value.to_string(),
+                    types,
+                });
+            }
+        }
+
+        if record.fields.is_empty() {
+            import
+                .skipped
+                .push((line, "no values to import".to_string()));
+        } else {
+            import.records.push(record);
+        }
+    }
+    Ok(import)
+}
+
+/// Split RFC 4180 style CSV into rows of fields, each with the line it starts on.
+/// Quoted fields may hold commas, newlines and `""` for a quote. A row with a quoting
+/// error is an `Err` and parsing resumes on the next line. Blank lines are skipped.
+fn parse_rows(input: &str) -> Vec<(usize, Result<Vec<String>, String>)> {
+    let mut rows = Vec::new();
+    let mut chars = input.chars().peekable();
+    let mut line = 1;
+
+    while chars.peek().is_some() {
+        let start_line = line;
+        let mut fields = Vec::new();
+        let mut field = String::new();
+        let mut error = None;
+
+        loop {
+            match chars.next() {
+                None => {
+                    fields.push(std::mem::take(&mut field));
+                    break;
+                }
+                Some('"') if field.is_empty() => {
+                    // Quoted field, up to the closing quote
+                    loop {
+                        match chars.next() {
+                            None => {
+                                error = Some("unterminated quoted field".to_string());
+                                break;
+                            }
+                            Some('"') if chars.peek() == Some(&'"') => {
+                                chars.next();
+                                field.push('"');
+                            }
+                            Some('"') => break,
+                            Some(c) => {
+                                if c == '\n' {
+                                    line += 1;
+                                }
+                                field.push(c);
+                            }
+                        }
+                    }
+                    if error.is_none()
+                        && !matches!(chars.peek(), None | Some(',') | Some('\n') | Some('\r'))
+                    {
+                        error = Some("unexpected text after a closing quote".to_string());
+                    }
+                    if error.is_some() {
+                        break;
+                    }
+                }
+                Some(',') => fields.push(std::mem::take(&mut field)),
+                Some('\r') if chars.peek() == Some(&'\n') => {}
+                Some('\n') | Some('\r') => {
+                    line += 1;
+                    fields.push(std::mem::take(&mut field));
+                    break;
+                }
+                Some(c) => field.push(c),
+            }
+        }
+
+        match error {
+            Some(reason) => {
+                // Drop the rest of the broken row
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        line += 1;
+                        break;
+                    }
+                }
+                rows.push((start_line, Err(reason)));
+            }
+            None if fields.len() == 1 && fields[0].is_empty() => {}
+            None => rows.push((start_line, Ok(fields))),
+        }
+    }
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field<'a>(record: &'a Record, title: &str) -> Option<&'a Item> {
+        record.fields.iter().find(|item| item.title == title)
+    }
+
+    #[test]
+    fn test_parse_rows_quoting() {
+        let rows = parse_rows("a,\"b,c\",\"say \"\"hi\"\"\"\r\n\"multi\nline\",x,\n\nlast,,");
+        assert_eq!(
+            rows,
+            vec![
+                (
+                    1,
+                    Ok(vec![
+                        "a".to_string(),
+                        "b,c".to_string(),
+                        "say \"hi\"".to_string()
+                    ])
+                ),
+                (
+                    2,
+                    Ok(vec![
+                        "multi\nline".to_string(),
+                        "x".to_string(),
+                        String::new()
+                    ])
+                ),
+                (
+                    5,
+                    Ok(vec!["last".to_string(), String::new(), String::new()])
+                ),
+            ]
+        );
+
+        let rows = parse_rows("\"broken\"x,y\nok,row\n\"open");
+        assert!(rows[0].1.is_err());
+        assert_eq!(rows[1], (2, Ok(vec!["ok".to_string(), "row".to_string()])));
+        assert!(rows[2].1.is_err());
+    }
+
+    #[test]
+    fn test_bitwarden_csv() {
+        let csv = "\u{feff}folder,favorite,type,name,notes,fields,reprompt,login_uri,login_username,login_password,login_totp\n\
+            ,,login,Mail,\"Recovery codes:\n1234, 5678\",,0,https://mail.example,alice,\"p,w\"\"1\",\n\
+            Work,1,login,VPN,,,0,vpn.example,bob,hunter2,\n\
+            ,,note,Broken,\"unterminated\n";
+        let import = parse_records(csv, &ColumnMapping::default(), 7).unwrap();
+
+        assert_eq!(import.records.len(), 2);
+        let mail = &import.records[0];
+        assert_eq!(field(mail, "Name").unwrap().value, "Mail");
+        assert_eq!(field(mail, "URL").unwrap().value, "https://mail.example");
+        assert_eq!(field(mail, "Login").unwrap().value, "alice");
+        assert_eq!(
+            field(mail, "Note").unwrap().value,
+            "Recovery codes:\n1234, 5678"
+        );
+        let password = field(mail, "Password").unwrap();
+        assert_eq!(password.value, "p,w\"1");
+        assert_eq!(password.types, vec![Atributes::Hide]);
+        assert_eq!(mail.created, 7);
+
+        assert_eq!(field(&import.records[1], "Login").unwrap().value, "bob");
+        assert!(field(&import.records[1], "Note").is_none());
+        assert_eq!(import.skipped.len(), 1);
+        assert_eq!(import.skipped[0].0, 5);
+    }
+
+    #[test]
+    fn test_chrome_csv() {
+        let csv = "name,url,username,password,note\n\
+            example.com,https://example.com/login,carol,s3cret,\n\
+            short,row\n\
+            ,,,,\n\
+            other.org,https://other.org,dave,\"pa ss\",remember me\n";
+        let import = parse_records(csv, &ColumnMapping::default(), 0).unwrap();
+
+        assert_eq!(import.records.len(), 2);
+        assert_eq!(
+            field(&import.records[0], "Password").unwrap().value,
+            "s3cret"
+        );
+        assert_eq!(
+            field(&import.records[1], "Note").unwrap().value,
+            "remember me"
+        );
+        let lines: Vec<usize> = import.skipped.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, vec![3, 4]);
+    }
+
+    #[test]
+    fn test_custom_mapping() {
+        let csv = "Secret;Account\nx,y\n".replace(';', ",");
+        assert_eq!(
+            parse_records(&csv, &ColumnMapping::default(), 0).err(),
+            Some(CsvError::MissingColumn("password"))
+        );
+
+        let mapping = ColumnMapping {
+            password: vec!["secret".to_string()],
+            username: vec!["account".to_string()],
+            ..Default::default()
+        };
+        let import = parse_records(&csv, &mapping, 0).unwrap();
+        assert_eq!(field(&import.records[0], "Password").unwrap().value, "x");
+        assert_eq!(field(&import.records[0], "Login").unwrap().value, "y");
+
+        assert_eq!(
+            parse_records("", &mapping, 0).err(),
+            Some(CsvError::MissingHeader)
+        );
+    }
+}
@@ -1,29 +1,53 @@
-use clap::{Parser, Subcommand};
-use crypto::UserId;
+mod clipboard;
+mod csv_import;
+mod vault_lock;
+
+use clap::{Parser, Subcommand, ValueEnum};
 use crypto::{
     bip39::{Bip39, Bip39Error},
-    master_keys::AssymetricKeypair,
+    master_keys::KeypairCache,
+    signer::{verify_signature, RequestSigner},
     structures::CipherOption,
+    totp::{seconds_remaining, totp},
     MasterKeys,
 };
+use crypto::{Nonce, UserId};
+use csv_import::ColumnMapping;
 use passmgr_rpc::rpc_passmgr::GetNonceRequest;
 use passmgr_rpc::rpc_passmgr::{
     rpc_passmgr_client::RpcPassmgrClient, AuthSignature, DeleteAllRequest, DeleteByIdRequest,
-    GetAllRequest, GetByIdRequest, GetListRequest, RegisterRequest, SetOneRequest,
-    SetRecordsRequest,
+    DeleteResponse, GetAllRequest, GetListRequest, Record as RpcRecord, RegisterRequest,
+    SetOneRequest, SetOneResponse, SetRecordsRequest,
 };
+use passmgr_rpc::{nonce_challenge_payload, signing_payload, NONCE_METADATA_KEY};
 use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet, VecDeque},
     io::{self, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    time::Duration,
 };
 use storage::{
-    structures::{Atributes, CipherRecord, Item, Record},
-    user_db::UserDb,
+    db::{ensure_dir, Storage},
+    structures::{Atributes, CipherRecord, Item, Record, Tombstone},
+    user_db::{DedupPolicy, UserDb, UserDbError},
 };
 use thiserror::Error;
-use tonic::transport::Channel;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint};
+use vault_lock::VaultLockError;
 
 pub const CHALLENGE_ZERO_BITS: usize = 3; // adjustable
+const CIPHER_CHAIN: [CipherOption; 3] = [
+    CipherOption::AES256,
+    CipherOption::XChaCha20,
+    CipherOption::Kuznyechik,
+];
+const UNDO_DEPTH: usize = 16; // local changes that can be undone per session
+const LIST_PAGE_SIZE: usize = 20;
+const MAX_VALUE_CHARS: usize = 80; // longer values are cut in record views
+const RECORD_CACHE_SIZE: usize = 32; // decrypted records kept for repeated views
+const DEFAULT_SERVER_URL: &str = "https://127.0.0.1:50051";
+const SYNC_PULL_BUDGET: usize = 16 * 1024 * 1024; // record bytes a sync keeps from its stream
 
 // Define a custom error type with thiserror
 #[derive(Debug, Error)]
@@ -49,10 +73,22 @@ pub enum PassmgrError {
     #[error("Server error: {0}")]
     Server(String),
 
+    #[error("Wrong master password")]
+    WrongMasterPassword,
+
     #[error("{0}")]
     Generic(String),
 }
 
+impl From<VaultLockError> for PassmgrError {
+    fn from(e: VaultLockError) -> Self {
+        match e {
+            VaultLockError::WrongPassword => Self::WrongMasterPassword,
+            e => Self::Generic(e.to_string()),
+        }
+    }
+}
+
 // For convenience in converting string errors
 impl From<String> for PassmgrError {
     fn from(s: String) -> Self {
@@ -70,14 +106,100 @@ impl From<&str> for PassmgrError {
 #[command(name = "passmgr-cli")]
 #[command(about = "Password Manager CLI", long_about = None)]
 struct Cli {
+    /// Enable debugging options such as raw record inspection
+    #[arg(long, global = true)]
+    debug: bool,
+
+    /// How vault keys are derived from the seed phrase. Pass the same value every
+    /// time: a vault only opens with the method it was created with
+    #[arg(long, value_enum, default_value_t = Derivation::Entropy, global = true)]
+    derivation: Derivation,
+
+    /// Sync server endpoint, https:// unless plaintext is explicitly allowed
+    #[arg(long, default_value = DEFAULT_SERVER_URL, global = true)]
+    server_url: String,
+
+    /// PEM file with the CA that signed the server certificate, instead of the system roots
+    #[arg(long, global = true)]
+    ca_cert: Option<PathBuf>,
+
+    /// Allow an http:// server URL. Nothing is encrypted in transit, local development only
+    #[arg(long, global = true)]
+    insecure_plaintext: bool,
+
+    /// Seconds before a copied value is cleared from the clipboard, unless replaced since
+    #[arg(long, default_value_t = clipboard::DEFAULT_CLEAR_SECS, global = true)]
+    clipboard_timeout: u64,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Derivation {
+    /// Raw BIP39 entropy, no passphrase (how existing vaults were created)
+    Entropy,
+    /// Standard BIP39 seed, with an optional passphrase
+    Seed,
+}
+
+impl Derivation {
+    // Entropy derivation needs 32 bytes of entropy, a 24 word phrase
+    fn accepts_strength(self, bits: u32) -> bool {
+        match self {
+            Derivation::Entropy => bits == 256,
+            Derivation::Seed => true,
+        }
+    }
+}
+
+/// How imported entries are matched against the vault's records
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Dedup {
+    /// Add every entry
+    Off,
+    /// Skip entries identical to an existing record
+    Content,
+    /// Entries with the URL and login of an existing record update it
+    UrlLogin,
+}
+
+impl Dedup {
+    fn policy(self) -> DedupPolicy {
+        match self {
+            Dedup::Off => DedupPolicy::Off,
+            Dedup::Content => DedupPolicy::Content,
+            Dedup::UrlLogin => DedupPolicy::KeyFields(vec!["URL".into(), "Login".into()]),
+        }
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Start interactive mode
     Interactive,
+    /// Add the entries of another password manager's CSV export to a vault
+    ImportCsv {
+        path: PathBuf,
+        /// Header of the entry name column [default: name, title]
+        #[arg(long)]
+        name_column: Option<String>,
+        /// Header of the URL column [default: url, login_uri, uri]
+        #[arg(long)]
+        url_column: Option<String>,
+        /// Header of the username column [default: username, login_username, login]
+        #[arg(long)]
+        username_column: Option<String>,
+        /// Header of the password column [default: password, login_password]
+        #[arg(long)]
+        password_column: Option<String>,
+        /// Header of the notes column [default: notes, note, extra]
+        #[arg(long)]
+        notes_column: Option<String>,
+        /// Entries already in the vault are skipped or merged rather than added twice
+        #[arg(long, value_enum, default_value_t = Dedup::Content)]
+        dedup: Dedup,
+    },
     Refactor,
 }
 
@@ -86,7 +208,36 @@ async fn main() {
     let cli = Cli::parse();
     match cli.command {
         Commands::Interactive => {
-            if let Err(e) = interactive_mode().await {
+            let server_config = ServerConfig {
+                url: cli.server_url,
+                ca_cert: cli.ca_cert,
+                plaintext: cli.insecure_plaintext,
+            };
+            let clipboard_timeout = Duration::from_secs(cli.clipboard_timeout);
+            if let Err(e) =
+                interactive_mode(cli.debug, cli.derivation, server_config, clipboard_timeout).await
+            {
+                eprintln!("Error: {e}");
+            }
+        }
+        Commands::ImportCsv {
+            path,
+            name_column,
+            url_column,
+            username_column,
+            password_column,
+            notes_column,
+            dedup,
+        } => {
+            let defaults = ColumnMapping::default();
+            let mapping = ColumnMapping {
+                name: name_column.map_or(defaults.name, |c| vec![c]),
+                url: url_column.map_or(defaults.url, |c| vec![c]),
+                username: username_column.map_or(defaults.username, |c| vec![c]),
+                password: password_column.map_or(defaults.password, |c| vec![c]),
+                notes: notes_column.map_or(defaults.notes, |c| vec![c]),
+            };
+            if let Err(e) = import_csv(&path, &mapping, &dedup.policy(), cli.derivation) {
                 eprintln!("Error: {e}");
             }
         }
@@ -94,72 +245,401 @@ async fn main() {
     }
 }
 
-enum AppState<'a> {
+enum AppState {
     StartScreen,
     OpenDbScreen,
     CreateNewScreen,
     RestoreDbScreen,
-    WorkScreen(&'a UserSession),
-    ServerStuff(&'a UserSession),
-    NewRecordScreen(&'a UserSession, Record),
 }
 
-struct UserSession {
-    user_db: UserDb<'static>,
+/// Screens of an open vault, see `vault_menu`
+enum VaultState {
+    WorkScreen,
+    ServerStuff,
+    NewRecordScreen(Record),
+}
+
+struct UserSession<'a> {
+    user_db: UserDb<'a>,
+    undo: RefCell<UndoStack>,
+}
+
+impl<'a> UserSession<'a> {
+    fn new(user_db: UserDb<'a>) -> Self {
+        user_db.enable_record_cache(RECORD_CACHE_SIZE);
+        Self {
+            user_db,
+            undo: RefCell::new(UndoStack::default()),
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct SyncReport {
+    server_records: usize, // records the server returned
+    local_records: usize,  // local records before the sync
+    pulled: usize,
+    pushed: usize,
+    rejected: usize,       // server records failing signature checks
+    deleted_remote: usize, // local deletes applied on the server
+    deleted_local: usize,  // server deletes applied locally
+    conflicts: Vec<SyncConflict>,
+}
+
+/// What a sync would change, worked out before anything is written
+#[derive(Debug, Default, PartialEq)]
+struct SyncPlan {
+    to_pull: Vec<u64>,   // on the server only
+    to_push: Vec<u64>,   // changed here since the last sync
+    to_update: Vec<u64>, // newer on the server
+    conflicts: Vec<SyncConflict>,
+    rejected: Vec<u64>, // server records failing signature checks
+    deletions: DeletionPlan,
+}
+
+impl SyncPlan {
+    fn summary(&self) -> String {
+        let ids = |ids: &[u64]| {
+            ids.iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let mut summary = format!(
+            "Pull new: [{}]\nUpdate from server: [{}]\nPush: [{}]",
+            ids(&self.to_pull),
+            ids(&self.to_update),
+            ids(&self.to_push)
+        );
+        if !self.deletions.delete_on_server.is_empty() {
+            summary.push_str(&format!(
+                "\nDelete on server: [{}]",
+                ids(&self.deletions.delete_on_server)
+            ));
+        }
+        if !self.deletions.delete_locally.is_empty() {
+            summary.push_str(&format!(
+                "\nDelete locally: [{}]",
+                ids(&self.deletions.delete_locally)
+            ));
+        }
+        if !self.conflicts.is_empty() {
+            let conflicts: Vec<u64> = self.conflicts.iter().map(|c| c.id).collect();
+            summary.push_str(&format!("\nConflicts: [{}]", ids(&conflicts)));
+        }
+        if !self.rejected.is_empty() {
+            summary.push_str(&format!("\nFailed verification: [{}]", ids(&self.rejected)));
+        }
+        summary
+    }
+}
+
+/// A record edited on both sides since the last sync. Neither copy is pulled or pushed
+/// until it is edited again here, which makes the local copy the newer one; `ver` is
+/// the server's version.
+#[derive(Debug, PartialEq)]
+struct SyncConflict {
+    id: u64,
+    ver: u64,
+    local_updated: u64,
+    server_updated: u64,
+}
+
+/// What to do with a server copy of a record that also exists locally
+#[derive(Debug, PartialEq)]
+enum VersionCheck {
+    Pull,
+    Keep,
+    Conflict,
+}
+
+/// Copies at the same version normally hold the same ciphertext, differing data means
+/// both sides were edited from the same base. A newer server copy is only pulled if the
+/// local one is unchanged since `last_sync`, pulling would otherwise drop that edit. A
+/// vault that never synced has no such point, its older copies are simply behind.
+fn check_versions(local: &CipherRecord, server: &CipherRecord, last_sync: u64) -> VersionCheck {
+    if server.ver > local.ver {
+        if last_sync > 0 && local.updated > last_sync {
+            VersionCheck::Conflict
+        } else {
+            VersionCheck::Pull
+        }
+    } else if server.ver == local.ver
+        && (server.data != local.data || server.cipher_options != local.cipher_options)
+    {
+        VersionCheck::Conflict
+    } else {
+        VersionCheck::Keep
+    }
+}
+
+/// Deletions to apply during a sync, worked out before anything is written
+#[derive(Debug, Default, PartialEq)]
+struct DeletionPlan {
+    delete_on_server: Vec<u64>,
+    delete_locally: Vec<u64>,
+}
+
+/// A local tombstone wins over the server copy unless that copy is newer. A local record
+/// missing on the server that hasn't changed since the last sync was deleted there.
+/// `server` maps id to (ver, updated), `local` holds (id, ver, updated).
+fn plan_deletions(
+    server: &HashMap<u64, (u64, u64)>,
+    local: &[(u64, u64, u64)],
+    tombstones: &[Tombstone],
+    last_sync: u64,
+) -> DeletionPlan {
+    let mut plan = DeletionPlan::default();
+    for tombstone in tombstones {
+        if let Some(&(ver, updated)) = server.get(&tombstone.id) {
+            if tombstone_wins(tombstone, ver, updated) {
+                plan.delete_on_server.push(tombstone.id);
+            }
+        }
+    }
+    // Without an earlier sync there's no telling a server delete from a new local record
+    if last_sync > 0 {
+        for &(id, _, updated) in local {
+            if !server.contains_key(&id) && updated <= last_sync {
+                plan.delete_locally.push(id);
+            }
+        }
+    }
+    plan
+}
+
+/// Whether a local delete supersedes the server copy at `ver`, last `updated` at that time
+fn tombstone_wins(tombstone: &Tombstone, ver: u64, updated: u64) -> bool {
+    ver < tombstone.ver || (ver == tombstone.ver && updated <= tombstone.deleted)
+}
+
+#[derive(Debug, PartialEq)]
+enum SyncOutcome {
+    /// Neither side had records, often a sign of the wrong mnemonic
+    BothEmpty,
+    ServerEmpty,
+    LocalEmpty,
+    Synced,
+}
+
+impl SyncReport {
+    fn outcome(&self) -> SyncOutcome {
+        match (self.server_records, self.local_records) {
+            (0, 0) => SyncOutcome::BothEmpty,
+            (0, _) => SyncOutcome::ServerEmpty,
+            (_, 0) => SyncOutcome::LocalEmpty,
+            _ => SyncOutcome::Synced,
+        }
+    }
+
+    fn summary(&self) -> String {
+        let mut summary = match self.outcome() {
+            SyncOutcome::BothEmpty => {
+                "Nothing to sync: no records on the server or locally, is this the right account?"
+                    .to_string()
+            }
+            SyncOutcome::ServerEmpty => format!(
+                "Server has no records for this account, pushed {} local records",
+                self.pushed
+            ),
+            SyncOutcome::LocalEmpty => {
+                format!("Restored {} records from the server", self.pulled)
+            }
+            SyncOutcome::Synced => format!(
+                "Sync completed: pulled {}, pushed {} records",
+                self.pulled, self.pushed
+            ),
+        };
+        if self.deleted_remote + self.deleted_local > 0 {
+            summary.push_str(&format!(
+                ", deleted {} on the server and {} locally",
+                self.deleted_remote, self.deleted_local
+            ));
+        }
+        if self.rejected > 0 {
+            summary.push_str(&format!(", {} records failed verification", self.rejected));
+        }
+        for conflict in &self.conflicts {
+            summary.push_str(&format!(
+                "\nConflict on record {} (version {}): changed here at {} and on the server at {}, \
+                 edit it to keep this copy",
+                conflict.id, conflict.ver, conflict.local_updated, conflict.server_updated
+            ));
+        }
+        summary
+    }
+}
+
+/// Inverse of a local change
+enum UndoOp {
+    /// Record was created, undo deletes it
+    Created(u64),
+    /// Record was updated or deleted, undo restores this stored state
+    Replaced(CipherRecord),
+}
+
+#[derive(Default)]
+struct UndoStack {
+    ops: VecDeque<UndoOp>,
+}
+
+impl UndoStack {
+    fn push(&mut self, op: UndoOp) {
+        if self.ops.len() == UNDO_DEPTH {
+            self.ops.pop_front();
+        }
+        self.ops.push_back(op);
+    }
+
+    /// Revert the most recent change, returns the affected record id
+    fn undo(&mut self, user_db: &UserDb) -> Result<Option<u64>, PassmgrError> {
+        let Some(op) = self.ops.pop_back() else {
+            return Ok(None);
+        };
+
+        let result = match &op {
+            UndoOp::Created(id) => user_db.delete(*id).map(|_| *id),
+            UndoOp::Replaced(prior) => user_db.restore_raw(prior).map(|_| prior.cipher_record_id),
+        };
+        match result {
+            Ok(id) => Ok(Some(id)),
+            Err(e) => {
+                // Keep the change so the undo can be retried
+                self.ops.push_back(op);
+                Err(PassmgrError::UserDb(e.to_string()))
+            }
+        }
+    }
+}
+
+struct ServerConfig {
+    url: String,
+    ca_cert: Option<PathBuf>,
+    plaintext: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            url: DEFAULT_SERVER_URL.to_string(),
+            ca_cert: None,
+            plaintext: false,
+        }
+    }
 }
 
 struct ServerSession {
+    config: ServerConfig,
     client: Option<RpcPassmgrClient<Channel>>,
     user_id: UserId,
-    key_pairs: Option<AssymetricKeypair>,
-    nonce: u64,
+    signer: Option<Box<dyn RequestSigner>>,
+    nonce: Nonce,
 }
 
 impl ServerSession {
+    /// Sign as the user of a newly opened vault. The nonce belongs to the previous user,
+    /// so the connection is dropped and reconnecting fetches the new user's one.
+    fn switch_user(&mut self, user_id: UserId, signer: Box<dyn RequestSigner>) {
+        self.user_id = user_id;
+        self.signer = Some(signer);
+        self.nonce = Nonce::default();
+        self.client = None;
+    }
+
+    /// Forget the user of a closed vault, so its keypair isn't kept alive here
+    fn sign_out(&mut self) {
+        self.user_id = [0; 32];
+        self.signer = None;
+    }
+
+    /// Take the server's nonce as the next one to sign with, returning the one replaced.
+    /// Every authenticated response echoes it, so a drifted local count heals on the next call
+    fn adopt_nonce(&mut self, nonce: u64) -> u64 {
+        std::mem::replace(&mut self.nonce, Nonce::new(nonce)).get()
+    }
+
     fn sign_request<T>(
-        &self,
+        &mut self,
         request_data: &T,
         method_name: &str,
     ) -> Result<AuthSignature, PassmgrError>
     where
         T: prost::Message,
     {
-        let keypair = match &self.key_pairs {
-            Some(pk) => &pk.dilithium_keypair,
+        let signer = match &self.signer {
+            Some(signer) => signer,
             None => return Err(PassmgrError::Server("No keypair found".into())),
         };
 
-        let (challenge_num, challenge) = find_challenge_nonce(&self.user_id, self.nonce);
-
-        let mut sign_data = method_name.as_bytes().to_vec();
-        sign_data.extend_from_slice(&self.nonce.to_be_bytes());
-
-        // Encode request data
-        sign_data.extend_from_slice(&request_data.encode_to_vec());
+        let nonce = self.nonce.get();
+        let (challenge_num, challenge) = find_challenge_nonce(&self.user_id, nonce);
 
-        let signature = keypair.sign(&sign_data);
+        let sign_data = signing_payload(method_name, nonce, &request_data.encode_to_vec());
+        let signature = signer.sign(&sign_data);
         let auth_data = AuthSignature {
             user_id: self.user_id.to_vec(),
-            nonce: self.nonce,
-            signature: signature.to_vec(),
+            nonce,
+            signature,
             challenge_num,
             challenge: challenge.to_vec(),
         };
 
-        let _ = self.nonce.wrapping_add(1);
+        self.nonce
+            .increment()
+            .map_err(|e| PassmgrError::Server(e.to_string()))?;
 
         Ok(auth_data)
     }
+
+    fn sign_record(
+        &self,
+        record: &mut passmgr_rpc::rpc_passmgr::Record,
+    ) -> Result<(), PassmgrError> {
+        let signer = match &self.signer {
+            Some(signer) => signer,
+            None => return Err(PassmgrError::Server("No keypair found".into())),
+        };
+
+        let payload = record_signing_payload(record.id, record.ver, &record.data);
+        record.signature = signer.sign(&payload);
+        Ok(())
+    }
+
+    // Detects a server swapping data between versions (rollback) or editing a record
+    fn verify_record(&self, record: &passmgr_rpc::rpc_passmgr::Record) -> Result<(), PassmgrError> {
+        let signer = match &self.signer {
+            Some(signer) => signer,
+            None => return Err(PassmgrError::Server("No keypair found".into())),
+        };
+
+        let payload = record_signing_payload(record.id, record.ver, &record.data);
+        if record.signature.is_empty()
+            || !verify_signature(&signer.public_key(), &payload, &record.signature)
+        {
+            return Err(PassmgrError::Server(format!(
+                "Record {} (ver {}) has an invalid signature",
+                record.id, record.ver
+            )));
+        }
+        Ok(())
+    }
 }
 
-async fn interactive_mode() -> Result<(), PassmgrError> {
+async fn interactive_mode(
+    debug: bool,
+    derivation: Derivation,
+    server_config: ServerConfig,
+    clipboard_timeout: Duration,
+) -> Result<(), PassmgrError> {
     let mut state = AppState::StartScreen;
     let mut server = ServerSession {
+        config: server_config,
         client: None,
         user_id: [0; 32],
-        key_pairs: None,
-        nonce: 0,
+        signer: None,
+        nonce: Nonce::default(),
     };
+    let mut keypairs = KeypairCache::new();
 
     loop {
         match state {
@@ -168,49 +648,64 @@ async fn interactive_mode() -> Result<(), PassmgrError> {
                 println!("1. Open existing database");
                 println!("2. Create new database");
                 println!("3. Restore database from server");
+                println!("4. Verify seed phrase");
                 println!("0. Exit");
 
                 match prompt("Choose option: ")?.as_str() {
                     "1" => state = AppState::OpenDbScreen,
                     "2" => state = AppState::CreateNewScreen,
                     "3" => state = AppState::RestoreDbScreen,
+                    "4" => verify_seed(derivation)?,
                     "0" => break,
                     _ => println!("Invalid option"),
                 }
             }
 
             AppState::OpenDbScreen => {
-                let mnemonic = prompt("Enter seed phrase: ")?;
                 let db_path = confirm_db_path()?;
-                let master_keys_owned = create_master_keys(&mnemonic)?;
-                let master_keys: &'static MasterKeys = Box::leak(Box::new(master_keys_owned));
-
-                let cipher_chain = vec![
-                    CipherOption::AES256,
-                    CipherOption::XChaCha20,
-                    CipherOption::Kuznyechik,
-                ];
+                let (mnemonic, unlocked) = match unlock_vault(&db_path) {
+                    Ok(Some(mnemonic)) => (mnemonic, true),
+                    Ok(None) => (prompt_mnemonic()?, false),
+                    Err(e @ PassmgrError::WrongMasterPassword) => {
+                        println!("{e}");
+                        state = AppState::StartScreen;
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                };
+                let (master_keys, cipher_chain) =
+                    vault_master_keys(&mnemonic, derivation, &db_path)?;
 
                 let user_db =
                     UserDb::new(&db_path, master_keys.user_id, &master_keys, cipher_chain)
                         .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
-                server.user_id = master_keys.user_id;
-                server.key_pairs = Some(AssymetricKeypair::generate_dilithium2(
-                    &master_keys.dilithium_seed,
-                ));
-
-                let user_session_owned = UserSession { user_db };
-                let user_session: &'static UserSession = Box::leak(Box::new(user_session_owned));
-
-                state = AppState::WorkScreen(user_session);
+                if !unlocked {
+                    offer_master_password(&db_path, &mnemonic)?;
+                }
+                server.switch_user(
+                    master_keys.user_id,
+                    Box::new(keypairs.get_or_generate(&master_keys.dilithium_seed)),
+                );
+
+                let session = UserSession::new(user_db);
+
+                vault_menu(&session, &mut server, debug, derivation, clipboard_timeout).await?;
+                // Session and keys are dropped with this scope, the signing keypair with them
+                server.sign_out();
+                keypairs.clear();
+                state = AppState::StartScreen;
             }
 
             AppState::CreateNewScreen => {
-                let strength = select_entropy_strength()?;
-                let bip39 = Bip39::new(strength)?;
+                let bip39 = generate_mnemonic(derivation)?;
                 let mnemonic = bip39.get_mnemonic();
 
                 println!("Your new seed phrase:\n{}\n", mnemonic);
+                let master_keys = create_master_keys(&mnemonic, derivation)?;
+                println!(
+                    "Fingerprint: {}, \"Verify seed phrase\" shows it again for a correctly typed phrase\n",
+                    fingerprint_hex(&master_keys)
+                );
                 if !confirm_n("Did you save the seed phrase securely? [y/N] ")? {
                     println!("Operation canceled");
                     state = AppState::StartScreen;
@@ -218,51 +713,57 @@ async fn interactive_mode() -> Result<(), PassmgrError> {
                 }
 
                 let db_path = confirm_db_path()?;
-                let master_keys_owned = create_master_keys(&mnemonic)?;
-                let master_keys: &'static MasterKeys = Box::leak(Box::new(master_keys_owned));
-
-                let cipher_chain = vec![
-                    CipherOption::AES256,
-                    CipherOption::XChaCha20,
-                    CipherOption::Kuznyechik,
-                ];
-
-                let user_db =
-                    UserDb::new(&db_path, master_keys.user_id, &master_keys, cipher_chain)
-                        .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
-                server.user_id = master_keys.user_id;
-                server.key_pairs = Some(AssymetricKeypair::generate_dilithium2(
-                    &master_keys.dilithium_seed,
-                ));
-
-                let user_session_owned = UserSession { user_db };
-                let user_session: &'static UserSession = Box::leak(Box::new(user_session_owned));
-
-                state = AppState::WorkScreen(user_session);
+                let cipher_chain = CIPHER_CHAIN.to_vec();
+
+                let allow_existing = Storage::exists_at(&db_path)
+                    && confirm_n(&format!(
+                        "A vault already exists at {}. Create the new one next to it anyway? [y/N] ",
+                        db_path.display()
+                    ))?;
+                let user_db = match UserDb::create_vault(
+                    &db_path,
+                    master_keys.user_id,
+                    &master_keys,
+                    cipher_chain,
+                    allow_existing,
+                ) {
+                    Ok(user_db) => user_db,
+                    Err(e @ UserDbError::VaultExists(_)) => {
+                        println!("{e}, choose another path or open it with its own seed phrase");
+                        state = AppState::StartScreen;
+                        continue;
+                    }
+                    Err(e) => return Err(PassmgrError::UserDb(e.to_string())),
+                };
+                offer_master_password(&db_path, &mnemonic)?;
+                server.switch_user(
+                    master_keys.user_id,
+                    Box::new(keypairs.get_or_generate(&master_keys.dilithium_seed)),
+                );
+
+                let session = UserSession::new(user_db);
+
+                vault_menu(&session, &mut server, debug, derivation, clipboard_timeout).await?;
+                server.sign_out();
+                keypairs.clear();
+                state = AppState::StartScreen;
             }
 
             AppState::RestoreDbScreen => {
-                let mnemonic = prompt("Enter seed phrase: ")?;
+                let mnemonic = prompt_mnemonic()?;
                 let db_path = confirm_db_path()?;
-                let master_keys_owned = create_master_keys(&mnemonic)?;
-                let master_keys: &'static MasterKeys = Box::leak(Box::new(master_keys_owned));
-
-                let cipher_chain = vec![
-                    CipherOption::AES256,
-                    CipherOption::XChaCha20,
-                    CipherOption::Kuznyechik,
-                ];
+                let (master_keys, cipher_chain) =
+                    vault_master_keys(&mnemonic, derivation, &db_path)?;
 
                 let user_db =
                     UserDb::new(&db_path, master_keys.user_id, &master_keys, cipher_chain)
                         .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
-                server.user_id = master_keys.user_id;
-                server.key_pairs = Some(AssymetricKeypair::generate_dilithium2(
-                    &master_keys.dilithium_seed,
-                ));
+                server.switch_user(
+                    master_keys.user_id,
+                    Box::new(keypairs.get_or_generate(&master_keys.dilithium_seed)),
+                );
 
-                let user_session_owned = UserSession { user_db };
-                let user_session: &'static UserSession = Box::leak(Box::new(user_session_owned));
+                let session = UserSession::new(user_db);
 
                 // Restore from server
                 if server.client.is_none() {
@@ -272,65 +773,110 @@ async fn interactive_mode() -> Result<(), PassmgrError> {
                     println!("Already connected!");
                 }
 
-                server.nonce = get_nonce_from_server(&mut server).await?;
+                server.nonce = Nonce::new(get_nonce_from_server(&mut server).await?);
 
-                sync_with_server(&mut server, user_session).await?;
-                println!("Sync completed!");
+                let report = sync_with_server(&mut server, &session).await?;
+                match report.outcome() {
+                    SyncOutcome::BothEmpty | SyncOutcome::ServerEmpty => {
+                        println!("Nothing to restore, is this the right account?")
+                    }
+                    _ => println!("Restored {} records", report.pulled),
+                }
+                if report.rejected > 0 {
+                    println!("{} records failed verification", report.rejected);
+                }
 
-                state = AppState::WorkScreen(user_session);
+                vault_menu(&session, &mut server, debug, derivation, clipboard_timeout).await?;
+                server.sign_out();
+                keypairs.clear();
+                state = AppState::StartScreen;
             }
+        }
+    }
+    Ok(())
+}
+
+/// Menus of an opened vault, until the user returns to the main menu
+async fn vault_menu(
+    session: &UserSession<'_>,
+    server: &mut ServerSession,
+    debug: bool,
+    derivation: Derivation,
+    clipboard_timeout: Duration,
+) -> Result<(), PassmgrError> {
+    let mut state = VaultState::WorkScreen;
 
-            AppState::WorkScreen(session) => {
+    loop {
+        match state {
+            VaultState::WorkScreen => {
                 println!("\nDatabase Management");
                 println!("1. List all records");
                 println!("2. Show record by ID");
                 println!("3. Show password by ID");
                 println!("4. Create new record");
-                println!("5. Update record (unimplemented)");
+                println!("5. Update record");
                 println!("6. Delete record");
+                println!("7. Undo last change");
                 println!("8. Server Management");
+                if debug {
+                    println!("9. Inspect raw record");
+                }
+                println!("10. Compare with another vault");
+                println!("11. Search records");
+                println!("12. Show one-time codes");
+                println!("13. Check vault integrity");
                 println!("0. Return to main menu");
 
                 match prompt("Choose option: ")?.as_str() {
                     "1" => list_records(&session.user_db)?,
                     "2" => show_record(&session.user_db)?,
-                    "3" => show_password(&session.user_db)?,
+                    "3" => show_password(&session.user_db, clipboard_timeout)?,
                     "4" => {
-                        state = AppState::NewRecordScreen(
-                            session,
-                            Record {
-                                icon: String::new(),
-                                created: current_timestamp(),
-                                updated: current_timestamp(),
-                                fields: Vec::new(),
-                            },
-                        )
+                        state = VaultState::NewRecordScreen(Record {
+                            icon: String::new(),
+                            created: current_timestamp(),
+                            updated: current_timestamp(),
+                            fields: Vec::new(),
+                            last_accessed: 0,
+                        })
                     }
-                    "6" => delete_record(&session.user_db)?,
-                    "8" => state = AppState::ServerStuff(session),
-                    "0" => state = AppState::StartScreen,
+                    "5" => update_record(session)?,
+                    "6" => delete_record(session)?,
+                    "7" => match session.undo.borrow_mut().undo(&session.user_db)? {
+                        Some(id) => println!("Reverted last change to record {id}"),
+                        None => println!("Nothing to undo"),
+                    },
+                    "8" => state = VaultState::ServerStuff,
+                    "9" if debug => inspect_raw_record(&session.user_db)?,
+                    "10" => compare_vaults(&session.user_db, derivation)?,
+                    "11" => search_records(&session.user_db)?,
+                    "12" => show_totp(&session.user_db)?,
+                    "13" => check_integrity(&session.user_db)?,
+                    "0" => return Ok(()),
                     _ => println!("Invalid option or unimplemented feature"),
                 }
             }
 
-            AppState::NewRecordScreen(session, mut record) => {
+            VaultState::NewRecordScreen(mut record) => {
                 record = build_record(record)?;
 
                 let record_id = session
                     .user_db
                     .create(record.clone())
                     .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
+                session.undo.borrow_mut().push(UndoOp::Created(record_id));
                 println!("Created new record with ID: {}", record_id);
-                state = AppState::WorkScreen(session);
+                state = VaultState::WorkScreen;
             }
 
-            AppState::ServerStuff(session) => {
+            VaultState::ServerStuff => {
                 println!("\nServer Management");
                 println!("1. Connect to Server");
                 println!("2. Register on Server");
                 println!("3. Sync with Server");
                 println!("4. List records id from Server");
-                println!("");
+                println!("5. Preview sync");
+                println!("6. Resync nonce");
                 println!("7. Delete all records from Server");
                 println!("");
                 println!("0. Return to DB managment");
@@ -338,45 +884,58 @@ async fn interactive_mode() -> Result<(), PassmgrError> {
                 match prompt("Choose option: ")?.as_str() {
                     "1" => {
                         if server.client.is_none() {
-                            connect_to_server(&mut server).await?;
+                            connect_to_server(server).await?;
                             println!("Connected successfully!");
-                            server.nonce = get_nonce_from_server(&mut server).await?;
+                            let nonce = get_nonce_from_server(server).await?;
+                            server.adopt_nonce(nonce);
                         } else {
                             println!("Already connected!");
                         }
                     }
                     "2" => {
-                        register_on_server(&mut server).await?;
+                        register_on_server(server).await?;
                         println!("Registered successfully!");
                     }
                     "3" => {
-                        sync_with_server(&mut server, session).await?;
-                        println!("Sync completed!");
+                        let report = sync_with_server(server, session).await?;
+                        println!("{}", report.summary());
                     }
                     "4" => {
                         println!("--------------------------");
-                        get_all_ids_server(&mut server).await?;
+                        get_all_ids_server(server).await?;
                         println!("--------------------------");
                     }
+                    "5" => preview_sync(server, session).await?,
+                    "6" => {
+                        resync_nonce(server).await?;
+                        println!("Nonce resynced, signed requests validate again");
+                    }
                     "7" => {
                         if confirm_n("Remove all records [y/N]")? {
-                            delete_all_on_server(&mut server).await?;
+                            delete_all_on_server(server).await?;
                             println!("All records deleted on server");
                         } else {
                             println!("Uh, Saved");
                         }
                     }
-                    "0" => state = AppState::WorkScreen(session),
+                    "0" => state = VaultState::WorkScreen,
                     _ => println!("Invalid option or unimplemented feature"),
                 }
             }
         }
     }
-    Ok(())
 }
 
 // Helper functions
 
+fn record_signing_payload(id: u64, ver: u64, data: &[u8]) -> Vec<u8> {
+    let mut payload = b"PASSMGR_RECORD_V1".to_vec();
+    payload.extend_from_slice(&id.to_be_bytes());
+    payload.extend_from_slice(&ver.to_be_bytes());
+    payload.extend_from_slice(blake3::hash(data).as_bytes());
+    payload
+}
+
 fn find_challenge_nonce(user_id: &[u8], nonce: u64) -> (u64, [u8; 32]) {
     use blake3::Hasher;
 
@@ -402,6 +961,66 @@ fn prompt(message: &str) -> Result<String, PassmgrError> {
     Ok(input.trim().to_string())
 }
 
+// Re-prompt until the phrase decodes, telling the user what was wrong with it
+fn prompt_mnemonic() -> Result<String, PassmgrError> {
+    loop {
+        let mnemonic = prompt("Enter seed phrase: ")?;
+        match Bip39::from_mnemonic(&mnemonic) {
+            Ok(_) => return Ok(mnemonic),
+            Err(e @ Bip39Error::InvalidWordCount(_)) => {
+                println!("{e}. Check for missing or extra words.")
+            }
+            Err(e @ Bip39Error::UnknownWord { .. }) => println!("{e}. Check its spelling."),
+            Err(e @ Bip39Error::InvalidChecksum) => {
+                println!("{e}. A word is wrong or the words are out of order.")
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+const MASTER_PASSWORD_ATTEMPTS: usize = 3;
+
+// Seed phrase from the vault's master password file, None when it has none or the user
+// would rather type the phrase
+fn unlock_vault(db_path: &Path) -> Result<Option<String>, PassmgrError> {
+    if !vault_lock::is_locked(db_path) {
+        return Ok(None);
+    }
+    for _ in 0..MASTER_PASSWORD_ATTEMPTS {
+        let password = prompt("Master password (empty to enter the seed phrase instead): ")?;
+        if password.is_empty() {
+            return Ok(None);
+        }
+        match vault_lock::unlock(db_path, &password) {
+            Ok(mnemonic) => return Ok(Some(mnemonic)),
+            Err(VaultLockError::WrongPassword) => println!("Wrong master password"),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Err(PassmgrError::WrongMasterPassword)
+}
+
+fn offer_master_password(db_path: &Path, mnemonic: &str) -> Result<(), PassmgrError> {
+    if vault_lock::is_locked(db_path)
+        || !confirm_n("Unlock this vault with a master password from now on? [y/N] ")?
+    {
+        return Ok(());
+    }
+    loop {
+        let password = prompt("New master password: ")?;
+        if password.is_empty() {
+            println!("Master password can't be empty");
+        } else if prompt("Repeat master password: ")? != password {
+            println!("Passwords don't match");
+        } else {
+            vault_lock::lock(db_path, &password, mnemonic)?;
+            println!("Master password set, the seed phrase is still needed to restore elsewhere");
+            return Ok(());
+        }
+    }
+}
+
 fn confirm_n(message: &str) -> Result<bool, PassmgrError> {
     let input = prompt(message)?.to_lowercase();
     Ok(input == "y" || input == "yes")
@@ -430,41 +1049,211 @@ fn confirm_db_path() -> Result<PathBuf, PassmgrError> {
 
     println!("Default DB path: {}", default_path.display());
 
-    if confirm_y("Use default path? [Y/n] ")? {
-        Ok(default_path)
+    let mut path = if confirm_y("Use default path? [Y/n] ")? {
+        default_path
     } else {
-        let custom_path = prompt("Enter custom path: ")?;
-        Ok(PathBuf::from(custom_path))
+        PathBuf::from(prompt("Enter custom path: ")?)
+    };
+
+    // Ask again until the path can actually hold a database
+    while let Err(e) = ensure_dir(&path) {
+        println!("{e}");
+        path = PathBuf::from(prompt("Enter custom path: ")?);
     }
+    Ok(path)
 }
 
-fn create_master_keys(mnemonic: &str) -> Result<MasterKeys, PassmgrError> {
+fn create_master_keys(mnemonic: &str, derivation: Derivation) -> Result<MasterKeys, PassmgrError> {
     let bip39 = Bip39::from_mnemonic(mnemonic)?;
-    MasterKeys::from_entropy(&bip39.get_entropy()).map_err(|e| PassmgrError::Generic(e.to_string()))
+    let passphrase = match derivation {
+        Derivation::Entropy => String::new(),
+        Derivation::Seed => prompt("BIP39 passphrase (empty for none): ")?,
+    };
+    derive_master_keys(&bip39, derivation, &passphrase)
+}
+
+// Keys and cipher chain for the vault at `db_path`: the chain it was migrated to, which
+// must keep every cipher of `CIPHER_CHAIN`, or `CIPHER_CHAIN` for a new one, with keys
+// re-derived at the generations a key rotation left it with
+fn vault_master_keys(
+    mnemonic: &str,
+    derivation: Derivation,
+    db_path: &Path,
+) -> Result<(MasterKeys, Vec<CipherOption>), PassmgrError> {
+    let mut master_keys = create_master_keys(mnemonic, derivation)?;
+    let cipher_chain = UserDb::stored_cipher_chain(db_path, master_keys.user_id)
+        .map_err(|e| PassmgrError::UserDb(e.to_string()))?
+        .unwrap_or_else(|| CIPHER_CHAIN.to_vec());
+    // A chain weakened on disk would otherwise only show as a wrong seed phrase
+    UserDb::check_chain_policy(&cipher_chain, &CIPHER_CHAIN)
+        .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
+    let generations = UserDb::stored_key_generations(db_path, master_keys.user_id)
+        .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
+    for (cipher, generation) in generations {
+        master_keys
+            .set_generation(cipher, generation)
+            .map_err(|e| PassmgrError::Generic(e.to_string()))?;
+    }
+    master_keys
+        .derive_keys(&cipher_chain)
+        .map_err(|e| PassmgrError::Generic(e.to_string()))?;
+    Ok((master_keys, cipher_chain))
 }
 
-fn select_entropy_strength() -> Result<u32, PassmgrError> {
-    println!("Select entropy strength:");
-    println!("5. only 256 bits (24 words)");
+// Only derive the keys the vault's chain uses; the passphrase is ignored for entropy
+// derivation
+fn derive_master_keys(
+    bip39: &Bip39,
+    derivation: Derivation,
+    passphrase: &str,
+) -> Result<MasterKeys, PassmgrError> {
+    match derivation {
+        Derivation::Entropy => MasterKeys::from_entropy_for(bip39.get_entropy(), &CIPHER_CHAIN),
+        Derivation::Seed => MasterKeys::from_seed_for(&bip39.get_seed(passphrase), &CIPHER_CHAIN),
+    }
+    .map_err(|e| PassmgrError::Generic(e.to_string()))
+}
 
-    match prompt("Your choice: ")?.as_str() {
-        "5" => Ok(256),
-        _ => {
-            println!("Invalid selection, using 256 bits");
-            Ok(256)
+fn fingerprint_hex(master_keys: &MasterKeys) -> String {
+    master_keys
+        .fingerprint()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Check a typed phrase offline: the checksum, then the fingerprint to compare with the
+// one shown when the vault was created
+fn verify_seed(derivation: Derivation) -> Result<(), PassmgrError> {
+    let mnemonic = prompt_mnemonic()?;
+    let bip39 = Bip39::from_mnemonic(&mnemonic)?;
+    println!(
+        "Checksum OK: {} words, {} bits of entropy",
+        mnemonic.split_whitespace().count(),
+        bip39.get_entropy().len() * 8
+    );
+    let master_keys = create_master_keys(&mnemonic, derivation)?;
+    println!("Fingerprint: {}", fingerprint_hex(&master_keys));
+    Ok(())
+}
+
+// (word count, entropy bits) of each BIP39 strength
+const ENTROPY_STRENGTHS: [(usize, u32); 5] =
+    [(12, 128), (15, 160), (18, 192), (21, 224), (24, 256)];
+
+// Strengths `derivation` can open a vault with, in menu order
+fn strength_options(derivation: Derivation) -> Vec<(usize, u32)> {
+    ENTROPY_STRENGTHS
+        .into_iter()
+        .filter(|(_, bits)| derivation.accepts_strength(*bits))
+        .collect()
+}
+
+// Entropy bits for a menu choice, the last (strongest) when empty
+fn entropy_strength(choice: &str, derivation: Derivation) -> Option<u32> {
+    let options = strength_options(derivation);
+    if choice.is_empty() {
+        return options.last().map(|(_, bits)| *bits);
+    }
+    let index: usize = choice.parse().ok()?;
+    options.get(index.checked_sub(1)?).map(|(_, bits)| *bits)
+}
+
+// Re-prompt until one of the listed strengths is chosen. Only strengths `derivation`
+// can use are listed, so a phrase is never shown that can't open a vault.
+fn generate_mnemonic(derivation: Derivation) -> Result<Bip39, PassmgrError> {
+    let options = strength_options(derivation);
+    for (i, (words, bits)) in options.iter().enumerate() {
+        println!("{}. {} words ({} bits)", i + 1, words, bits);
+    }
+    if options.len() < ENTROPY_STRENGTHS.len() {
+        println!("Shorter phrases need --derivation seed");
+    }
+    loop {
+        let input = prompt(&format!("Choose seed phrase length [{}]: ", options.len()))?;
+        match entropy_strength(&input, derivation) {
+            Some(strength) => return Ok(Bip39::new(strength)?),
+            None => println!("\"{input}\" is not an option, enter 1 to {}", options.len()),
+        }
+    }
+}
+
+fn import_csv(
+    path: &Path,
+    mapping: &ColumnMapping,
+    policy: &DedupPolicy,
+    derivation: Derivation,
+) -> Result<(), PassmgrError> {
+    // Parse first, so a wrong file fails before asking for the seed phrase
+    let input = std::fs::read_to_string(path)?;
+    let import = csv_import::parse_records(&input, mapping, current_timestamp())
+        .map_err(|e| PassmgrError::Generic(e.to_string()))?;
+
+    let mnemonic = prompt_mnemonic()?;
+    let db_path = confirm_db_path()?;
+    let (master_keys, cipher_chain) = vault_master_keys(&mnemonic, derivation, &db_path)?;
+    let user_db = UserDb::new(&db_path, master_keys.user_id, &master_keys, cipher_chain)
+        .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
+
+    let report = user_db
+        .import_records(import.records, policy)
+        .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
+
+    println!(
+        "Imported {} records, {} already in the vault, {} merged into existing ones",
+        report.added, report.skipped, report.merged
+    );
+    if !import.skipped.is_empty() {
+        println!("Skipped {} rows:", import.skipped.len());
+        for (line, reason) in &import.skipped {
+            println!("  line {line}: {reason}");
         }
     }
+    Ok(())
 }
 
 // Record management functions
 
 fn list_records(user_db: &UserDb) -> Result<(), PassmgrError> {
-    let records = user_db
-        .list_records()
+    println!("\nStored Records:");
+    let mut after = None;
+    loop {
+        let page = user_db
+            .list_records_page(after, LIST_PAGE_SIZE)
+            .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
+        for id in &page {
+            // Only the records shown are decrypted, for their name
+            let name = user_db
+                .read(*id)
+                .ok()
+                .and_then(|record| record.field_value("Name").map(str::to_string))
+                .unwrap_or_default();
+            println!("- {} {}", id, name);
+        }
+
+        if page.len() < LIST_PAGE_SIZE || !confirm_y("More? [Y/n] ")? {
+            break;
+        }
+        after = page.last().copied();
+    }
+    Ok(())
+}
+
+fn search_records(user_db: &UserDb) -> Result<(), PassmgrError> {
+    let query = prompt("Search for: ")?;
+    let found = user_db
+        .search(&query)
         .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
-    println!("\nStored Record IDs:");
-    for id in records {
-        println!("- {}", id);
+
+    if found.is_empty() {
+        println!("No matching records");
+    }
+    for (id, record) in found {
+        println!(
+            "- {} {}",
+            id,
+            record.field_value("Name").unwrap_or_default()
+        );
     }
     Ok(())
 }
@@ -472,30 +1261,88 @@ fn list_records(user_db: &UserDb) -> Result<(), PassmgrError> {
 fn show_record(user_db: &UserDb) -> Result<(), PassmgrError> {
     let record_id = prompt("Enter record ID: ")?;
     let record = user_db
-        .read(record_id.parse()?)
+        .read_and_touch(record_id.parse()?)
         .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
 
     println!("\nRecord Details:");
-    for item in record.fields {
+    for item in &record.fields {
         println!("[{}]", item.title);
         println!("Value: {}", mask_value(&item.value, &item.types));
+        if item.types.contains(&Atributes::Reload) {
+            match totp(&item.value, current_timestamp()) {
+                Ok(code) => println!("Code: {}", code),
+                Err(e) => println!("Code: {}", e),
+            }
+        }
         if !item.types.is_empty() {
             println!("Attributes: {}", format_attributes(&item.types));
         }
         println!();
     }
+
+    // Full values on demand, except protected ones which stay masked
+    let truncated: Vec<_> = record
+        .fields
+        .iter()
+        .filter(|item| !is_protected(&item.types) && item.value.chars().count() > MAX_VALUE_CHARS)
+        .collect();
+    if !truncated.is_empty() && prompt("Press v to view full values, Enter to continue: ")? == "v" {
+        for item in truncated {
+            println!("[{}]", item.title);
+            println!("{}", item.value);
+            println!();
+        }
+    }
     Ok(())
 }
 
-fn show_password(user_db: &UserDb) -> Result<(), PassmgrError> {
+// Codes of the record's Reload fields, recomputed each time Enter is pressed
+fn show_totp(user_db: &UserDb) -> Result<(), PassmgrError> {
     let record_id = prompt("Enter record ID: ")?;
     let record = user_db
         .read(record_id.parse()?)
         .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
 
+    loop {
+        let now = current_timestamp();
+        let codes = record.totp_codes(now);
+        if codes.is_empty() {
+            println!("Record has no one-time code fields");
+            return Ok(());
+        }
+        println!();
+        for (title, code) in codes {
+            match code {
+                Ok(code) => println!("[{}] {}", title, code),
+                Err(e) => println!("[{}] {}", title, e),
+            }
+        }
+        println!("Valid for {}s", seconds_remaining(now));
+        if prompt("Press Enter to refresh, 0 to return: ")? == "0" {
+            return Ok(());
+        }
+    }
+}
+
+// Copy-protected values go to the clipboard instead of the terminal
+fn show_password(user_db: &UserDb, clipboard_timeout: Duration) -> Result<(), PassmgrError> {
+    let record_id = prompt("Enter record ID: ")?;
+    let record = user_db
+        .read_and_touch(record_id.parse()?)
+        .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
+
     println!("\nRecord Hidden Details:");
     for item in record.fields {
-        if item.types.contains(&Atributes::Hide) {
+        if item.types.contains(&Atributes::Copy) {
+            println!("[{}]", item.title);
+            match clipboard::copy_to_clipboard(item.value, clipboard_timeout) {
+                Ok(()) => println!(
+                    "Copied to clipboard, cleared in {}s",
+                    clipboard_timeout.as_secs()
+                ),
+                Err(e) => println!("Could not copy to clipboard: {}", e),
+            }
+        } else if item.types.contains(&Atributes::Hide) {
             println!("[{}]", item.title);
             println!("Value: {}", &item.value);
         }
@@ -504,16 +1351,124 @@ fn show_password(user_db: &UserDb) -> Result<(), PassmgrError> {
     Ok(())
 }
 
-fn delete_record(user_db: &UserDb) -> Result<(), PassmgrError> {
-    let record_id = prompt("Enter record ID to delete: ")?;
-    user_db
-        .delete(record_id.parse()?)
+// Decrypts every record to find corrupt ones, reports ids only and never plaintext
+fn check_integrity(user_db: &UserDb) -> Result<(), PassmgrError> {
+    let report = user_db
+        .check_integrity()
         .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
-    println!("Record deleted successfully");
+
+    println!("{} records OK, {} failed", report.ok, report.failed.len());
+    for (id, e) in &report.failed {
+        println!("- {} {}", id, e);
+    }
     Ok(())
 }
 
-fn build_record(mut record: Record) -> Result<Record, PassmgrError> {
+// Prints stored metadata only, never the plaintext, so the output is safe for bug reports
+fn inspect_raw_record(user_db: &UserDb) -> Result<(), PassmgrError> {
+    let record_id = prompt("Enter record ID: ")?;
+    let raw = user_db
+        .read_raw(record_id.parse()?)
+        .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
+
+    println!("\nRaw Record:");
+    println!("ID: {}", raw.cipher_record_id);
+    println!("Version: {}", raw.ver);
+    println!("Updated: {}", raw.updated);
+    println!("Cipher options: {:?}", raw.cipher_options);
+    println!("Ciphertext length: {} bytes", raw.data.len());
+    println!("Signature length: {} bytes", raw.signature.len());
+    println!();
+    Ok(())
+}
+
+// Diff against a second vault such as a backup, opened with its own seed phrase
+fn compare_vaults(user_db: &UserDb, derivation: Derivation) -> Result<(), PassmgrError> {
+    let other_path = PathBuf::from(prompt("Path of the vault to compare with: ")?);
+    if !Storage::exists_at(&other_path) {
+        println!("No vault found at {}", other_path.display());
+        return Ok(());
+    }
+    let mnemonic = prompt_mnemonic()?;
+    let (other_keys, other_chain) = vault_master_keys(&mnemonic, derivation, &other_path)?;
+    let other = UserDb::new(&other_path, other_keys.user_id, &other_keys, other_chain)
+        .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
+
+    let diff = user_db
+        .diff(&other)
+        .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
+    println!("\nUnchanged: {}", diff.unchanged);
+    println!("Only in this vault: {:?}", diff.only_in_self);
+    println!("Only in the other vault: {:?}", diff.only_in_other);
+    println!("Different content:");
+    for record in &diff.differing {
+        println!(
+            "- {} (version here {}, there {})",
+            record.id, record.self_ver, record.other_ver
+        );
+    }
+    Ok(())
+}
+
+fn update_record(session: &UserSession) -> Result<(), PassmgrError> {
+    let record_id = prompt("Enter record ID to update: ")?.parse()?;
+    let (prior, mut record) = match (
+        session.user_db.read_raw(record_id),
+        session.user_db.read(record_id),
+    ) {
+        (Ok(prior), Ok(record)) => (prior, record),
+        _ => {
+            println!("Record {record_id} not found");
+            return Ok(());
+        }
+    };
+
+    for item in record.fields.iter_mut() {
+        println!("[{}] {}", item.title, mask_value(&item.value, &item.types));
+        let value = prompt("New value (Enter to keep): ")?;
+        if !value.is_empty() {
+            item.value = value;
+        }
+    }
+    record.updated = current_timestamp();
+
+    session
+        .user_db
+        .update(record_id, record)
+        .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
+
+    let current = session
+        .user_db
+        .read_raw(record_id)
+        .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
+    if current.ver > prior.ver {
+        session.undo.borrow_mut().push(UndoOp::Replaced(prior));
+        println!("Record {record_id} updated to version {}", current.ver);
+    } else {
+        println!(
+            "No changes, record {record_id} stays at version {}",
+            current.ver
+        );
+    }
+    Ok(())
+}
+
+fn delete_record(session: &UserSession) -> Result<(), PassmgrError> {
+    let record_id = prompt("Enter record ID to delete: ")?.parse()?;
+    let prior = session
+        .user_db
+        .read_raw(record_id)
+        .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
+    session
+        .user_db
+        .delete(record_id)
+        .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
+    session.undo.borrow_mut().push(UndoOp::Replaced(prior));
+    println!("Record deleted successfully");
+    Ok(())
+}
+
+fn build_record(mut record: Record) -> Result<Record, PassmgrError> {
     for title in &["Name", "URL", "Login", "Password", "Note"] {
         if confirm_y(&format!("Add {} field? [Y/n] ", title))? {
             let value = prompt(&format!("Enter {}: ", title))?;
@@ -536,11 +1491,19 @@ fn build_record(mut record: Record) -> Result<Record, PassmgrError> {
 
     while confirm_n("Add custom field? [y/N] ")? {
         let title = prompt("Enter field title: ")?;
-        let value = prompt("Enter field value: ")?;
+        let mut value = prompt("Enter field value: ")?;
+        let mut types = Vec::new();
+        if confirm_n("Is this a one-time code (TOTP) secret? [y/N] ")? {
+            while let Err(e) = totp(&value, 0) {
+                println!("{}", e);
+                value = prompt("Enter Base32 secret: ")?;
+            }
+            types = vec![Atributes::Hide, Atributes::Reload];
+        }
         record.fields.push(Item {
             title,
             value,
-            types: Vec::new(),
+            types,
         });
     }
 
@@ -549,10 +1512,27 @@ fn build_record(mut record: Record) -> Result<Record, PassmgrError> {
 
 fn mask_value(value: &str, attributes: &[Atributes]) -> String {
     if attributes.contains(&Atributes::Hide) {
-        "*".repeat(value.len())
+        "*".repeat(value.chars().count().min(MAX_VALUE_CHARS))
     } else {
-        value.to_string()
+        truncate_value(value, MAX_VALUE_CHARS)
+    }
+}
+
+fn is_protected(attributes: &[Atributes]) -> bool {
+    attributes.contains(&Atributes::Hide) || attributes.contains(&Atributes::Copy)
+}
+
+fn truncate_value(value: &str, max_chars: usize) -> String {
+    let len = value.chars().count();
+    if len <= max_chars {
+        return value.to_string();
     }
+    let shown: String = value.chars().take(max_chars).collect();
+    format!(
+        "{}... ({} more chars, press v to view full)",
+        shown,
+        len - max_chars
+    )
 }
 
 fn format_attributes(attributes: &[Atributes]) -> String {
@@ -570,26 +1550,48 @@ fn format_attributes(attributes: &[Atributes]) -> String {
 // Server communication
 
 async fn connect_to_server(server: &mut ServerSession) -> Result<(), PassmgrError> {
-    let channel = tonic::transport::Channel::from_static("http://127.0.0.1:50051")
-        .connect()
-        .await?;
+    let channel = server_endpoint(&server.config)?.connect().await?;
     server.client = Some(RpcPassmgrClient::new(channel));
     Ok(())
 }
 
+/// https:// trusts `ca_cert` when given and the system roots otherwise. http:// is refused
+/// unless plaintext was explicitly allowed.
+fn server_endpoint(config: &ServerConfig) -> Result<Endpoint, PassmgrError> {
+    let endpoint = Endpoint::from_shared(config.url.clone())?;
+    match endpoint.uri().scheme_str() {
+        Some("https") => {
+            let tls = match &config.ca_cert {
+                Some(path) => ClientTlsConfig::new()
+                    .ca_certificate(Certificate::from_pem(std::fs::read(path)?)),
+                None => ClientTlsConfig::new().with_native_roots(),
+            };
+            Ok(endpoint.tls_config(tls)?)
+        }
+        Some("http") if config.plaintext => Ok(endpoint),
+        Some("http") => Err(PassmgrError::Server(
+            "Plaintext http:// needs --insecure-plaintext".into(),
+        )),
+        _ => Err(PassmgrError::Server(format!(
+            "Unsupported server URL: {}",
+            config.url
+        ))),
+    }
+}
+
 async fn register_on_server(server: &mut ServerSession) -> Result<(), PassmgrError> {
     if server.user_id == [0; 32] {
         return Err(PassmgrError::Server("Uninitialized user ID".into()));
     }
 
-    let pub_key = match &server.key_pairs {
-        Some(pk) => &pk.dilithium_keypair.public,
+    let pub_key = match &server.signer {
+        Some(signer) => signer.public_key(),
         None => return Err(PassmgrError::Server("No public key found".into())),
     };
 
     let request = RegisterRequest {
         user_id: server.user_id.to_vec(),
-        pub_key: pub_key.bytes.to_vec(),
+        pub_key,
     };
 
     match &mut server.client {
@@ -599,7 +1601,7 @@ async fn register_on_server(server: &mut ServerSession) -> Result<(), PassmgrErr
             if !inner.success {
                 return Err(PassmgrError::Server("Server registration failed".into()));
             }
-            server.nonce = inner.nonce;
+            server.nonce = Nonce::new(inner.nonce);
 
             Ok(())
         }
@@ -607,142 +1609,573 @@ async fn register_on_server(server: &mut ServerSession) -> Result<(), PassmgrErr
     }
 }
 
+// The server only reveals the nonce for a signed answer to its challenge
 async fn get_nonce_from_server(server: &mut ServerSession) -> Result<u64, PassmgrError> {
-    let request = GetNonceRequest {
-        user_id: server.user_id.to_vec(),
+    let signer = match &server.signer {
+        Some(signer) => signer,
+        None => return Err(PassmgrError::Server("No keypair found".into())),
+    };
+    let client = match &mut server.client {
+        Some(client) => client,
+        None => return Err(PassmgrError::Server("Not connected to server".into())),
     };
 
-    match &mut server.client {
-        Some(client) => {
-            let response = client.get_nonce(request).await?;
-            Ok(response.into_inner().nonce)
+    let user_id = server.user_id.to_vec();
+    let challenge = client
+        .get_nonce(GetNonceRequest {
+            user_id: user_id.clone(),
+            ..Default::default()
+        })
+        .await?
+        .into_inner()
+        .challenge;
+    let signature = signer.sign(&nonce_challenge_payload(&user_id, &challenge));
+    let response = client
+        .get_nonce(GetNonceRequest {
+            user_id,
+            challenge,
+            signature,
+        })
+        .await?;
+    Ok(response.into_inner().nonce)
+}
+
+/// Replace a drifted local nonce with the server's, then confirm with a signed GetList
+/// that requests validate again
+async fn resync_nonce(server: &mut ServerSession) -> Result<(), PassmgrError> {
+    let nonce = get_nonce_from_server(server)
+        .await
+        .map_err(resync_failure)?;
+    let previous = server.adopt_nonce(nonce);
+    println!("Local nonce {previous} replaced by the server's {nonce}");
+
+    let request = GetListRequest { auth: None };
+    let auth = server.sign_request(&request, "GetList")?;
+    let client = match &mut server.client {
+        Some(client) => client,
+        None => return Err(PassmgrError::Server("Not connected to server".into())),
+    };
+    let response = client
+        .get_list(GetListRequest { auth: Some(auth) })
+        .await
+        .map_err(|status| resync_failure(status.into()))?;
+    server.adopt_nonce(response.into_inner().nonce);
+    Ok(())
+}
+
+// With the nonce just fetched, a rejected signature means the key or the registration
+// is wrong and resyncing again won't help
+fn resync_failure(error: PassmgrError) -> PassmgrError {
+    match error {
+        PassmgrError::TonicStatus(status)
+            if matches!(
+                status.code(),
+                tonic::Code::Unauthenticated
+                    | tonic::Code::PermissionDenied
+                    | tonic::Code::NotFound
+            ) =>
+        {
+            PassmgrError::Server(format!(
+                "Server still rejects this account's signatures ({}), \
+                 the key or the registration is wrong",
+                status.message()
+            ))
         }
-        None => Err(PassmgrError::Server("Not connected to server".into())),
+        other => other,
     }
 }
 
-async fn sync_with_server(
-    server: &mut ServerSession,
-    session: &UserSession,
-) -> Result<(), PassmgrError> {
-    // 1. Create request for get_all
-    let request = GetAllRequest { auth: None };
-    let auth = server.sign_request(&request, "GetAll")?;
-    let request_with_auth = GetAllRequest { auth: Some(auth) };
+/// A mutating request carrying an idempotency key, so it can safely be sent again
+trait IdempotentRequest: prost::Message + Clone {
+    type Response;
+    const METHOD: &'static str;
+
+    fn with_auth(self, auth: AuthSignature) -> Self;
+    fn response_nonce(response: &Self::Response) -> u64;
+    async fn send(
+        self,
+        client: &mut RpcPassmgrClient<Channel>,
+    ) -> Result<tonic::Response<Self::Response>, tonic::Status>;
+}
+
+impl IdempotentRequest for SetOneRequest {
+    type Response = SetOneResponse;
+    const METHOD: &'static str = "SetOne";
+
+    fn with_auth(self, auth: AuthSignature) -> Self {
+        Self {
+            auth: Some(auth),
+            ..self
+        }
+    }
+
+    fn response_nonce(response: &SetOneResponse) -> u64 {
+        response.nonce
+    }
+
+    async fn send(
+        self,
+        client: &mut RpcPassmgrClient<Channel>,
+    ) -> Result<tonic::Response<SetOneResponse>, tonic::Status> {
+        client.set_one(self).await
+    }
+}
+
+impl IdempotentRequest for DeleteByIdRequest {
+    type Response = DeleteResponse;
+    const METHOD: &'static str = "DeleteById";
+
+    fn with_auth(self, auth: AuthSignature) -> Self {
+        Self {
+            auth: Some(auth),
+            ..self
+        }
+    }
 
-    // 2. Get server records - get client reference only for this operation
-    let server_records = {
+    fn response_nonce(response: &DeleteResponse) -> u64 {
+        response.nonce
+    }
+
+    async fn send(
+        self,
+        client: &mut RpcPassmgrClient<Channel>,
+    ) -> Result<tonic::Response<DeleteResponse>, tonic::Status> {
+        client.delete_by_id(self).await
+    }
+}
+
+impl IdempotentRequest for DeleteAllRequest {
+    type Response = DeleteResponse;
+    const METHOD: &'static str = "DeleteAll";
+
+    fn with_auth(self, auth: AuthSignature) -> Self {
+        Self {
+            auth: Some(auth),
+            ..self
+        }
+    }
+
+    fn response_nonce(response: &DeleteResponse) -> u64 {
+        response.nonce
+    }
+
+    async fn send(
+        self,
+        client: &mut RpcPassmgrClient<Channel>,
+    ) -> Result<tonic::Response<DeleteResponse>, tonic::Status> {
+        client.delete_all(self).await
+    }
+}
+
+/// Sign and send `request`. When it fails in transit, so the server may or may not have
+/// applied it, resync the nonce and send it once more under the same idempotency key: a
+/// server that did apply it answers from its cache instead of applying it again.
+async fn send_idempotent<T: IdempotentRequest>(
+    server: &mut ServerSession,
+    request: T,
+) -> Result<T::Response, PassmgrError> {
+    let mut resent = false;
+    loop {
+        let auth = server.sign_request(&request, T::METHOD)?;
         let client = match &mut server.client {
             Some(client) => client,
             None => return Err(PassmgrError::Server("Not connected to server".into())),
         };
+        match request.clone().with_auth(auth).send(client).await {
+            Ok(response) => {
+                let response = response.into_inner();
+                server.adopt_nonce(T::response_nonce(&response));
+                return Ok(response);
+            }
+            Err(status) if !resent && outcome_unknown(&status) => {
+                resent = true;
+                let nonce = get_nonce_from_server(server).await?;
+                server.adopt_nonce(nonce);
+            }
+            Err(status) => return Err(status.into()),
+        }
+    }
+}
 
-        client
-            .get_all(request_with_auth)
-            .await?
-            .into_inner()
-            .records
+// Failures that can happen after the server received the request
+fn outcome_unknown(status: &tonic::Status) -> bool {
+    matches!(
+        status.code(),
+        tonic::Code::Unavailable
+            | tonic::Code::Unknown
+            | tonic::Code::DeadlineExceeded
+            | tonic::Code::Cancelled
+    )
+}
+
+/// Stream of every record the server holds for this account
+async fn open_record_stream(
+    server: &mut ServerSession,
+) -> Result<tonic::Streaming<RpcRecord>, PassmgrError> {
+    let request = GetAllRequest::default();
+    let auth = server.sign_request(&request, "GetAllStream")?;
+    let request_with_auth = GetAllRequest {
+        auth: Some(auth),
+        ..request
+    };
+    let client = match &mut server.client {
+        Some(client) => client,
+        None => return Err(PassmgrError::Server("Not connected to server".into())),
     };
 
-    // 3. Compare with local records
+    let response = client.get_all_stream(request_with_auth).await?;
+    // A server without the header leaves the locally advanced nonce in place
+    if let Some(nonce) = response
+        .metadata()
+        .get(NONCE_METADATA_KEY)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+    {
+        server.adopt_nonce(nonce);
+    }
+    Ok(response.into_inner())
+}
+
+/// Server records a sync pulls: those kept from the planning stream, up to
+/// `SYNC_PULL_BUDGET` bytes of data, and the id and version of the rest, which a second
+/// pass over the stream picks up
+#[derive(Default)]
+struct PendingPulls {
+    kept: Vec<RpcRecord>,
+    kept_bytes: usize,
+    deferred: HashMap<u64, u64>, // id -> ver
+}
+
+impl PendingPulls {
+    fn add(&mut self, record: &RpcRecord) {
+        if self.kept_bytes + record.data.len() <= SYNC_PULL_BUDGET {
+            self.kept_bytes += record.data.len();
+            self.kept.push(record.clone());
+        } else {
+            self.deferred.insert(record.id, record.ver);
+        }
+    }
+}
+
+/// Works out what a sync would change from server records fed one at a time, keeping
+/// their id, version and timestamp and only the records it pulls, within a budget, so
+/// the server's data is never held all at once
+struct SyncPlanner<'a> {
+    server: &'a ServerSession,
+    user_db: &'a UserDb,
+    last_sync: u64,
+    tombstones: Vec<Tombstone>,
+    tombstoned: HashMap<u64, Tombstone>,
+    server_versions: HashMap<u64, (u64, u64)>, // id -> (ver, updated)
+    plan: SyncPlan,
+    pulls: PendingPulls,
+}
+
+impl<'a> SyncPlanner<'a> {
+    fn new(server: &'a ServerSession, user_db: &'a UserDb) -> Result<Self, PassmgrError> {
+        let tombstones = user_db
+            .tombstones()
+            .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
+        let tombstoned = tombstones.iter().map(|t| (t.id, t.clone())).collect();
+        let last_sync = user_db
+            .last_sync()
+            .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
+        Ok(Self {
+            server,
+            user_db,
+            last_sync,
+            tombstones,
+            tombstoned,
+            server_versions: HashMap::new(),
+            plan: SyncPlan::default(),
+            pulls: PendingPulls::default(),
+        })
+    }
+
+    fn server_records(&self) -> usize {
+        self.server_versions.len()
+    }
+
+    /// The records the plan pulls, taken out of the planner
+    fn take_pulls(&mut self) -> PendingPulls {
+        std::mem::take(&mut self.pulls)
+    }
+
+    fn add(&mut self, server_record: &RpcRecord) -> Result<(), PassmgrError> {
+        self.server_versions
+            .insert(server_record.id, (server_record.ver, server_record.updated));
+
+        // Deleted here, don't bring it back
+        if let Some(tombstone) = self.tombstoned.get(&server_record.id) {
+            if tombstone_wins(tombstone, server_record.ver, server_record.updated) {
+                return Ok(());
+            }
+        }
+        if let Err(e) = self.server.verify_record(server_record) {
+            eprintln!("Skipping record from server: {e}");
+            self.plan.rejected.push(server_record.id);
+            return Ok(());
+        }
+
+        let server_copy = cipher_record(self.server.user_id, server_record);
+        let local = self
+            .user_db
+            .storage
+            .get_many(&[server_record.id])
+            .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
+        let Some((_, local_record)) = local.into_iter().next() else {
+            self.plan.to_pull.push(server_record.id);
+            self.pulls.add(server_record);
+            return Ok(());
+        };
+        match check_versions(&local_record, &server_copy, self.last_sync) {
+            VersionCheck::Pull => {
+                self.plan.to_update.push(server_record.id);
+                self.pulls.add(server_record);
+            }
+            VersionCheck::Conflict => self.plan.conflicts.push(SyncConflict {
+                id: server_record.id,
+                ver: server_copy.ver,
+                local_updated: local_record.updated,
+                server_updated: server_copy.updated,
+            }),
+            VersionCheck::Keep => {}
+        }
+        Ok(())
+    }
+
+    /// The plan once every server record has been added, without writing anything
+    fn finish(self) -> Result<SyncPlan, PassmgrError> {
+        let mut plan = self.plan;
+        let local_versions = self
+            .user_db
+            .list_records_with_metadata()
+            .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
+        plan.deletions = plan_deletions(
+            &self.server_versions,
+            &local_versions,
+            &self.tombstones,
+            self.last_sync,
+        );
+
+        // Local changes since the last sync, unless the server copy replaces them
+        let changed_records = self
+            .user_db
+            .list_changed_since(self.last_sync)
+            .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
+        for (local_id, _, _) in changed_records {
+            let superseded = plan.to_update.contains(&local_id)
+                || plan
+                    .conflicts
+                    .iter()
+                    .any(|conflict| conflict.id == local_id);
+            if !superseded {
+                plan.to_push.push(local_id);
+            }
+        }
+        Ok(plan)
+    }
+}
+
+/// Stream the server's records into a plan, returning it with the number of records and
+/// the records it pulls
+async fn plan_sync(
+    server: &mut ServerSession,
+    user_db: &UserDb,
+) -> Result<(SyncPlan, usize, PendingPulls), PassmgrError> {
+    let mut stream = open_record_stream(server).await?;
+    let mut planner = SyncPlanner::new(server, user_db)?;
+    while let Some(record) = stream.message().await? {
+        planner.add(&record)?;
+    }
+    let server_records = planner.server_records();
+    let pulls = planner.take_pulls();
+    Ok((planner.finish()?, server_records, pulls))
+}
+
+/// Stream the server records and print what a sync would do
+async fn preview_sync(
+    server: &mut ServerSession,
+    session: &UserSession,
+) -> Result<(), PassmgrError> {
+    let (plan, _, _) = plan_sync(server, &session.user_db).await?;
+    println!("{}", plan.summary());
+    Ok(())
+}
+
+async fn sync_with_server(
+    server: &mut ServerSession,
+    session: &UserSession,
+) -> Result<SyncReport, PassmgrError> {
+    // Timestamps have second resolution, step back one so writes in this second are pushed next time
+    let sync_started = current_timestamp().saturating_sub(1);
+
+    // 1. Plan against the server's records before touching anything
+    let last_sync = session
+        .user_db
+        .last_sync()
+        .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
     let local_records = session
         .user_db
         .list_records()
         .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
+    let tombstones = session
+        .user_db
+        .tombstones()
+        .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
+    let (plan, server_records, pulls) = plan_sync(server, &session.user_db).await?;
+    let mut report = SyncReport {
+        server_records,
+        local_records: local_records.len(),
+        rejected: plan.rejected.len(),
+        conflicts: plan.conflicts,
+        ..Default::default()
+    };
 
-    // 4. Conflict resolution
-    for server_record in server_records {
-        let local_exists = local_records.contains(&server_record.id);
-        if !local_exists {
-            // Create missing record locally
-            session
-                .user_db
-                .storage
-                .set(
-                    server_record.id,
-                    &CipherRecord {
-                        user_id: server.user_id,
-                        cipher_record_id: server_record.id,
-                        ver: server_record.ver,
-                        cipher_options: vec![], // Using the same cipher options as local DB
-                        data: server_record.data,
-                    },
-                )
-                .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
-        } else {
-            // Check if server version is newer
-            let local_record = session
-                .user_db
-                .storage
-                .get(server_record.id)
-                .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
-            if server_record.ver > local_record.ver {
-                // Update local record
-                session
-                    .user_db
-                    .storage
-                    .up(
-                        server_record.id,
-                        &CipherRecord {
-                            user_id: server.user_id,
-                            cipher_record_id: server_record.id,
-                            ver: server_record.ver,
-                            cipher_options: vec![], // Using the same cipher options as local DB
-                            data: server_record.data,
-                        },
-                    )
-                    .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
+    // 2. Pull records missing here or newer on the server. Those the planning stream
+    // didn't keep come from a second pass over it, which only takes the version the plan
+    // saw; a copy changed since is left for the next sync.
+    let storage = &session.user_db.storage;
+    let to_update: HashSet<u64> = plan.to_update.iter().copied().collect();
+    for record in &pulls.kept {
+        apply_pull(storage, &to_update, server.user_id, record)?;
+        report.pulled += 1;
+    }
+    let mut deferred = pulls.deferred;
+    if !deferred.is_empty() {
+        let mut stream = open_record_stream(server).await?;
+        while let Some(record) = stream.message().await? {
+            if deferred.get(&record.id) != Some(&record.ver) {
+                continue;
+            }
+            deferred.remove(&record.id);
+            if let Err(e) = server.verify_record(&record) {
+                eprintln!("Skipping record from server: {e}");
+                report.rejected += 1;
+                continue;
             }
+            apply_pull(storage, &to_update, server.user_id, &record)?;
+            report.pulled += 1;
+            if deferred.is_empty() {
+                break;
+            }
+        }
+    }
+    // A conflicting copy behind the server's version is lifted to it, so that editing it
+    // again makes it the newer copy, as with edits at the same version. Only one edited
+    // since the last sync is, an unchanged copy must not pass for the server's version.
+    for conflict in &report.conflicts {
+        let local_record = storage
+            .get(conflict.id)
+            .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
+        if local_record.ver < conflict.ver && local_record.updated > last_sync {
+            let lifted = CipherRecord {
+                ver: conflict.ver,
+                ..local_record.clone()
+            };
+            storage
+                .up(conflict.id, &lifted, &local_record)
+                .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
         }
     }
 
-    // 5. Push local changes
-    for local_id in local_records {
-        let local_record = session
+    // 3. Deletions on either side since the last sync
+    for &record_id in &plan.deletions.delete_on_server {
+        let request = DeleteByIdRequest {
+            auth: None,
+            record_id,
+            idempotency_key: uuid::Uuid::new_v4().to_string(),
+        };
+        send_idempotent(server, request).await?;
+        report.deleted_remote += 1;
+    }
+    for &record_id in &plan.deletions.delete_locally {
+        session
             .user_db
-            .storage
+            .apply_remote_delete(record_id)
+            .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
+        report.deleted_local += 1;
+    }
+    // Every tombstone is settled now: propagated, superseded by a newer server copy, or moot
+    for tombstone in &tombstones {
+        session
+            .user_db
+            .clear_tombstone(tombstone.id)
+            .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
+    }
+
+    // 4. Push local changes made since the last sync
+    for &local_id in &plan.to_push {
+        let local_record = storage
             .get(local_id)
             .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
-        let record = passmgr_rpc::rpc_passmgr::Record {
+        let mut record = RpcRecord {
             id: local_id,
             ver: local_record.ver,
             user_id: server.user_id.to_vec(),
             data: local_record.data,
+            signature: vec![],
+            updated: local_record.updated,
+            cipher_options: local_record.cipher_options,
         };
+        server.sign_record(&mut record)?;
 
         let request = SetOneRequest {
             auth: None,
             record: Some(record),
+            idempotency_key: uuid::Uuid::new_v4().to_string(),
         };
-        let auth = server.sign_request(&request, "SetOne")?;
-        let request_with_auth = SetOneRequest {
-            auth: Some(auth),
-            record: request.record,
-        };
+        send_idempotent(server, request).await?;
+        report.pushed += 1;
+    }
 
-        // Get client reference only for this operation
-        let client = match &mut server.client {
-            Some(client) => client,
-            None => return Err(PassmgrError::Server("Not connected to server".into())),
-        };
+    session
+        .user_db
+        .set_last_sync(sync_started)
+        .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
+    Ok(report)
+}
 
-        client.set_one(request_with_auth).await?;
+// Write a verified server record over the local copy, or as a new record
+fn apply_pull(
+    storage: &Storage,
+    to_update: &HashSet<u64>,
+    user_id: UserId,
+    record: &RpcRecord,
+) -> Result<(), PassmgrError> {
+    let server_copy = cipher_record(user_id, record);
+    if to_update.contains(&record.id) {
+        let local_record = storage
+            .get(record.id)
+            .map_err(|e| PassmgrError::UserDb(e.to_string()))?;
+        storage
+            .up(record.id, &server_copy, &local_record)
+            .map_err(|e| PassmgrError::UserDb(e.to_string()))
+    } else {
+        storage
+            .set(record.id, &server_copy)
+            .map_err(|e| PassmgrError::UserDb(e.to_string()))
     }
+}
 
-    Ok(())
+fn cipher_record(user_id: UserId, record: &RpcRecord) -> CipherRecord {
+    CipherRecord {
+        user_id,
+        cipher_record_id: record.id,
+        ver: record.ver,
+        cipher_options: record.cipher_options.clone(),
+        data: record.data.clone(),
+        signature: record.signature.clone(),
+        updated: record.updated,
+    }
 }
 
 async fn delete_all_on_server(server: &mut ServerSession) -> Result<(), PassmgrError> {
-    let request = DeleteAllRequest { auth: None };
-    let auth = server.sign_request(&request, "DeleteAll")?;
-    let request_with_auth = DeleteAllRequest { auth: Some(auth) };
-
-    let client = match &mut server.client {
-        Some(client) => client,
-        None => return Err(PassmgrError::Server("Not connected to server".into())),
+    let request = DeleteAllRequest {
+        auth: None,
+        idempotency_key: uuid::Uuid::new_v4().to_string(),
     };
-
-    client.delete_all(request_with_auth).await?;
+    send_idempotent(server, request).await?;
     Ok(())
 }
 
@@ -756,11 +2189,885 @@ async fn get_all_ids_server(server: &mut ServerSession) -> Result<(), PassmgrErr
         None => return Err(PassmgrError::Server("Not connected to server".into())),
     };
 
-    let response = client.get_list(request_with_auth).await?;
-    let records = response.into_inner().record_i_ds;
+    let response = client.get_list(request_with_auth).await?.into_inner();
+    server.adopt_nonce(response.nonce);
+    let records = response.record_i_ds;
 
     for record in records {
         println!("ID: {}, Version: {}", record.id, record.ver);
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::AssymetricKeypair;
+    use prost::Message;
+    use tempdir::TempDir;
+
+    fn test_session() -> ServerSession {
+        ServerSession {
+            config: ServerConfig::default(),
+            client: None,
+            user_id: [7; 32],
+            signer: Some(Box::new(AssymetricKeypair::generate_dilithium2(&[7; 32]))),
+            nonce: Nonce::default(),
+        }
+    }
+
+    #[test]
+    fn test_entropy_strength() {
+        let seed = Derivation::Seed;
+        assert_eq!(entropy_strength("1", seed), Some(128));
+        assert_eq!(entropy_strength("2", seed), Some(160));
+        assert_eq!(entropy_strength("3", seed), Some(192));
+        assert_eq!(entropy_strength("4", seed), Some(224));
+        assert_eq!(entropy_strength("5", seed), Some(256));
+        assert_eq!(entropy_strength("", seed), Some(256));
+        for input in ["0", "6", "-1", "256", "five"] {
+            assert_eq!(entropy_strength(input, seed), None, "{input}");
+        }
+        // Entropy derivation only takes 24 words
+        let entropy = Derivation::Entropy;
+        assert_eq!(entropy_strength("1", entropy), Some(256));
+        assert_eq!(entropy_strength("", entropy), Some(256));
+        assert_eq!(entropy_strength("2", entropy), None);
+        // Each option produces the advertised number of words
+        for (words, bits) in ENTROPY_STRENGTHS {
+            let bip39 = Bip39::new(bits).unwrap();
+            assert_eq!(bip39.get_mnemonic().split_whitespace().count(), words);
+        }
+    }
+
+    #[test]
+    fn test_every_strength_option_derives_keys() {
+        for derivation in [Derivation::Entropy, Derivation::Seed] {
+            for (words, bits) in strength_options(derivation) {
+                let bip39 = Bip39::new(bits).unwrap();
+                let keys = derive_master_keys(&bip39, derivation, "");
+                assert!(keys.is_ok(), "{derivation:?}, {words} words: {keys:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_server_endpoint_plaintext_opt_in() {
+        let mut config = ServerConfig {
+            url: "http://127.0.0.1:50051".to_string(),
+            ..Default::default()
+        };
+        assert!(server_endpoint(&config).is_err());
+        config.plaintext = true;
+        assert!(server_endpoint(&config).is_ok());
+
+        config.url = "ftp://127.0.0.1:50051".to_string();
+        assert!(server_endpoint(&config).is_err());
+        assert!(server_endpoint(&ServerConfig::default()).is_ok());
+    }
+
+    fn signed_record(session: &ServerSession, ver: u64, data: &[u8]) -> RpcRecord {
+        let mut record = RpcRecord {
+            id: 1,
+            ver,
+            user_id: session.user_id.to_vec(),
+            data: data.to_vec(),
+            signature: vec![],
+            updated: 0,
+            cipher_options: vec![],
+        };
+        session.sign_record(&mut record).unwrap();
+        record
+    }
+
+    #[test]
+    fn test_record_signature_roundtrip() {
+        let session = test_session();
+        let record = signed_record(&session, 2, b"new data");
+        assert!(session.verify_record(&record).is_ok());
+    }
+
+    #[test]
+    fn test_record_rollback_detected() {
+        let session = test_session();
+        let old = signed_record(&session, 1, b"old data");
+        let new = signed_record(&session, 2, b"new data");
+
+        // Older data served under the newer version
+        let mut rolled_back = new.clone();
+        rolled_back.data = old.data.clone();
+        assert!(session.verify_record(&rolled_back).is_err());
+
+        // Newer data served under the older version
+        let mut rolled_back = old.clone();
+        rolled_back.data = new.data.clone();
+        assert!(session.verify_record(&rolled_back).is_err());
+
+        // Version bumped without a matching signature
+        let mut bumped = old;
+        bumped.ver = 3;
+        assert!(session.verify_record(&bumped).is_err());
+
+        let mut unsigned = new;
+        unsigned.signature.clear();
+        assert!(session.verify_record(&unsigned).is_err());
+    }
+
+    #[test]
+    fn test_sign_request_advances_nonce() {
+        let mut session = test_session();
+        let request = GetListRequest { auth: None };
+
+        let first = session.sign_request(&request, "GetList").unwrap();
+        let second = session.sign_request(&request, "GetList").unwrap();
+        assert_eq!(second.nonce, first.nonce + 1);
+        assert_eq!(session.nonce.get(), second.nonce + 1);
+    }
+
+    #[test]
+    fn test_sign_request_matches_server() {
+        let mut session = test_session();
+        let request = GetAllRequest {
+            auth: None,
+            limit: 10,
+            ..Default::default()
+        };
+        let public_key = session.signer.as_ref().unwrap().public_key();
+        let auth = session.sign_request(&request, "GetAll").unwrap();
+
+        // What the server's validate_auth verifies
+        let sign_data = signing_payload("GetAll", auth.nonce, &request.encode_to_vec());
+        assert!(verify_signature(&public_key, &sign_data, &auth.signature));
+
+        // Bound to the method it was made for
+        let mut other_method = b"GetList".to_vec();
+        other_method.extend_from_slice(&sign_data[b"GetAll".len()..]);
+        assert!(!verify_signature(
+            &public_key,
+            &other_method,
+            &auth.signature
+        ));
+    }
+
+    #[test]
+    fn test_resync_corrects_stale_nonce() {
+        let mut session = test_session();
+        let public_key = session.signer.as_ref().unwrap().public_key();
+        let request = GetListRequest { auth: None };
+        let server_nonce = 41;
+        session.adopt_nonce(7);
+
+        // Signed with the drifted nonce, the server's check fails
+        let stale = session.sign_request(&request, "GetList").unwrap();
+        let expected = signing_payload("GetList", server_nonce, &request.encode_to_vec());
+        assert!(!verify_signature(&public_key, &expected, &stale.signature));
+
+        assert_eq!(session.adopt_nonce(server_nonce), 8);
+        let auth = session.sign_request(&request, "GetList").unwrap();
+        assert_eq!(auth.nonce, server_nonce);
+        assert!(verify_signature(&public_key, &expected, &auth.signature));
+
+        // Rejected after resyncing: the key or registration is at fault
+        let rejected = resync_failure(tonic::Status::unauthenticated("Invalid signature").into());
+        assert!(matches!(rejected, PassmgrError::Server(_)));
+        let unreachable = resync_failure(tonic::Status::unavailable("down").into());
+        assert!(matches!(unreachable, PassmgrError::TonicStatus(_)));
+    }
+
+    #[test]
+    fn test_undo_delete() {
+        let tmp_dir = TempDir::new("cli_undo_test").unwrap();
+        let master_keys = MasterKeys::from_entropy_for(&[3; 32], &CIPHER_CHAIN).unwrap();
+        let user_db = UserDb::new(
+            tmp_dir.path(),
+            master_keys.user_id,
+            &master_keys,
+            CIPHER_CHAIN.to_vec(),
+        )
+        .unwrap();
+        let mut undo = UndoStack::default();
+
+        let record = Record {
+            icon: String::from("icon"),
+            created: 1,
+            updated: 1,
+            fields: vec![Item {
+                title: String::from("Password"),
+                value: String::from("secret"),
+                types: vec![Atributes::Hide],
+            }],
+            last_accessed: 0,
+        };
+        let id = user_db.create(record.clone()).unwrap();
+        undo.push(UndoOp::Created(id));
+
+        let prior = user_db.read_raw(id).unwrap();
+        user_db.delete(id).unwrap();
+        undo.push(UndoOp::Replaced(prior.clone()));
+        assert!(user_db.read(id).is_err());
+
+        assert_eq!(undo.undo(&user_db).unwrap(), Some(id));
+        assert_eq!(user_db.read(id).unwrap(), record);
+        assert_eq!(user_db.read_raw(id).unwrap().ver, prior.ver + 1);
+
+        // Undoing the create removes the record again
+        assert_eq!(undo.undo(&user_db).unwrap(), Some(id));
+        assert!(user_db.read(id).is_err());
+        assert_eq!(undo.undo(&user_db).unwrap(), None);
+    }
+
+    #[test]
+    fn test_sync_report_empty_server() {
+        let both_empty = SyncReport::default();
+        assert_eq!(both_empty.outcome(), SyncOutcome::BothEmpty);
+        assert!(both_empty.summary().contains("right account"));
+
+        let server_empty = SyncReport {
+            local_records: 3,
+            pushed: 3,
+            ..Default::default()
+        };
+        assert_eq!(server_empty.outcome(), SyncOutcome::ServerEmpty);
+        assert!(server_empty.summary().contains("Server has no records"));
+    }
+
+    #[test]
+    fn test_sync_report_empty_local() {
+        let restored = SyncReport {
+            server_records: 2,
+            pulled: 2,
+            ..Default::default()
+        };
+        assert_eq!(restored.outcome(), SyncOutcome::LocalEmpty);
+        assert_eq!(restored.summary(), "Restored 2 records from the server");
+
+        let rejected = SyncReport {
+            server_records: 2,
+            pulled: 1,
+            rejected: 1,
+            ..Default::default()
+        };
+        assert!(rejected
+            .summary()
+            .ends_with("1 records failed verification"));
+    }
+
+    #[test]
+    fn test_plan_deletions_local_delete() {
+        let tombstone = Tombstone {
+            id: 1,
+            ver: 3,
+            deleted: 500,
+        };
+        // Server still has the version that was deleted here
+        let server = HashMap::from([(1, (2, 100))]);
+        let plan = plan_deletions(&server, &[], &[tombstone.clone()], 200);
+        assert_eq!(plan.delete_on_server, vec![1]);
+
+        // Edited elsewhere after the delete: the newer server copy wins
+        let server = HashMap::from([(1, (4, 600))]);
+        let plan = plan_deletions(&server, &[], &[tombstone.clone()], 200);
+        assert_eq!(plan, DeletionPlan::default());
+
+        // Same version, the later of the two changes wins
+        let server = HashMap::from([(1, (3, 400))]);
+        let plan = plan_deletions(&server, &[], &[tombstone.clone()], 200);
+        assert_eq!(plan.delete_on_server, vec![1]);
+
+        // Already gone on the server
+        let plan = plan_deletions(&HashMap::new(), &[], &[tombstone], 200);
+        assert_eq!(plan, DeletionPlan::default());
+    }
+
+    #[test]
+    fn test_plan_deletions_server_delete() {
+        let server = HashMap::from([(1, (1, 100))]);
+        // 2 was synced before and is gone from the server, 3 is a local change since
+        let local = [(1, 1, 100), (2, 1, 150), (3, 1, 300)];
+
+        let plan = plan_deletions(&server, &local, &[], 200);
+        assert_eq!(plan.delete_locally, vec![2]);
+        assert!(plan.delete_on_server.is_empty());
+
+        // First sync of the session: nothing is deleted locally
+        let plan = plan_deletions(&server, &local, &[], 0);
+        assert_eq!(plan, DeletionPlan::default());
+    }
+
+    fn test_keys() -> MasterKeys {
+        MasterKeys::from_entropy_for(&[3; 32], &CIPHER_CHAIN).unwrap()
+    }
+
+    fn test_user_db<'a>(tmp_dir: &TempDir, master_keys: &'a MasterKeys) -> UserDb<'a> {
+        UserDb::new(
+            tmp_dir.path(),
+            master_keys.user_id,
+            master_keys,
+            CIPHER_CHAIN.to_vec(),
+        )
+        .unwrap()
+    }
+
+    fn local_record(id: u64, ver: u64, data: &[u8], updated: u64) -> CipherRecord {
+        CipherRecord {
+            user_id: [7; 32],
+            cipher_record_id: id,
+            ver,
+            cipher_options: vec![],
+            data: data.to_vec(),
+            signature: vec![],
+            updated,
+        }
+    }
+
+    // `plan_sync` without the server, records fed as if streamed
+    fn plan_records(server: &ServerSession, user_db: &UserDb, records: &[RpcRecord]) -> SyncPlan {
+        let mut planner = SyncPlanner::new(server, user_db).unwrap();
+        for record in records {
+            planner.add(record).unwrap();
+        }
+        assert_eq!(planner.server_records(), records.len());
+        planner.finish().unwrap()
+    }
+
+    #[test]
+    fn test_plan_sync() {
+        let tmp_dir = TempDir::new("cli_sync_test").unwrap();
+        let keys = test_keys();
+        let user_db = test_user_db(&tmp_dir, &keys);
+        let session = test_session();
+        user_db.set_last_sync(50).unwrap();
+
+        // 1 exists only here, 3 was synced before and edited on another device since
+        user_db
+            .storage
+            .set(1, &local_record(1, 1, b"local only", 100))
+            .unwrap();
+        user_db
+            .storage
+            .set(3, &local_record(3, 1, b"synced", 10))
+            .unwrap();
+
+        let mut server_only = signed_record(&session, 1, b"server only");
+        server_only.id = 2;
+        session.sign_record(&mut server_only).unwrap();
+        let mut diverged = signed_record(&session, 2, b"edited elsewhere");
+        diverged.id = 3;
+        session.sign_record(&mut diverged).unwrap();
+
+        let plan = plan_records(&session, &user_db, &[server_only, diverged]);
+        assert_eq!(
+            plan,
+            SyncPlan {
+                to_pull: vec![2],
+                to_push: vec![1],
+                to_update: vec![3],
+                ..Default::default()
+            }
+        );
+
+        // Planning writes nothing
+        assert_eq!(user_db.list_records().unwrap(), vec![1, 3]);
+        assert_eq!(user_db.storage.get(3).unwrap().ver, 1);
+    }
+
+    #[test]
+    fn test_divergent_edits_reported() {
+        let tmp_dir = TempDir::new("cli_sync_test").unwrap();
+        let keys = test_keys();
+        let user_db = test_user_db(&tmp_dir, &keys);
+        let session = test_session();
+
+        let local = local_record(1, 3, b"edited here", 500);
+        user_db.storage.set(1, &local).unwrap();
+
+        // Edited on another device from the same version 2
+        let mut edited_elsewhere = signed_record(&session, 3, b"edited elsewhere");
+        edited_elsewhere.updated = 400;
+        let plan = plan_records(&session, &user_db, &[edited_elsewhere]);
+        // Neither pulled nor pushed over the server copy
+        assert!(plan.to_update.is_empty());
+        assert!(plan.to_push.is_empty());
+        let report = SyncReport {
+            conflicts: plan.conflicts,
+            ..Default::default()
+        };
+        assert_eq!(
+            report.conflicts,
+            vec![SyncConflict {
+                id: 1,
+                ver: 3,
+                local_updated: 500,
+                server_updated: 400,
+            }]
+        );
+        assert_eq!(user_db.storage.get(1).unwrap(), local);
+        assert!(report.summary().contains("Conflict on record 1"));
+
+        // The same copy on both sides, or a newer one on the server, is no conflict
+        let same = CipherRecord {
+            signature: vec![1],
+            ..local.clone()
+        };
+        assert_eq!(check_versions(&local, &same, 0), VersionCheck::Keep);
+        let newer = CipherRecord {
+            ver: 4,
+            ..local.clone()
+        };
+        assert_eq!(check_versions(&local, &newer, 500), VersionCheck::Pull);
+    }
+
+    #[test]
+    fn test_edit_behind_newer_server_copy_reported() {
+        let tmp_dir = TempDir::new("cli_sync_test").unwrap();
+        let keys = test_keys();
+        let user_db = test_user_db(&tmp_dir, &keys);
+        let session = test_session();
+        user_db.set_last_sync(300).unwrap();
+
+        // Edited here at version 2 after the last sync, meanwhile the server got to 4
+        let local = local_record(1, 2, b"edited here", 500);
+        user_db.storage.set(1, &local).unwrap();
+        let mut edited_elsewhere = signed_record(&session, 4, b"edited elsewhere");
+        edited_elsewhere.updated = 400;
+
+        let plan = plan_records(&session, &user_db, &[edited_elsewhere]);
+        assert!(plan.to_update.is_empty());
+        assert!(plan.to_push.is_empty());
+        assert_eq!(
+            plan.conflicts,
+            vec![SyncConflict {
+                id: 1,
+                ver: 4,
+                local_updated: 500,
+                server_updated: 400,
+            }]
+        );
+
+        // Unchanged here since the last sync, the server copy is simply newer
+        user_db.set_last_sync(600).unwrap();
+        let plan = plan_records(
+            &session,
+            &user_db,
+            &[signed_record(&session, 4, b"edited elsewhere")],
+        );
+        assert_eq!(plan.to_update, vec![1]);
+        assert!(plan.conflicts.is_empty());
+    }
+
+    /// In-process server answering the RPCs a test drives, everything else is unimplemented
+    mod mock_server {
+        use passmgr_rpc::rpc_passmgr::rpc_passmgr_server::{RpcPassmgr, RpcPassmgrServer};
+        use passmgr_rpc::rpc_passmgr::*;
+        use std::sync::{Arc, Mutex};
+        use tonic::{Request, Response, Status};
+
+        #[derive(Default)]
+        pub(super) struct MockServer {
+            pub(super) state: Mutex<MockState>,
+        }
+
+        #[derive(Default)]
+        pub(super) struct MockState {
+            pub(super) nonce: u64,
+            // Idempotency key of every SetOne received, and of those applied
+            pub(super) received: Vec<String>,
+            pub(super) applied: Vec<String>,
+            // Lose the response of the next applied SetOne, like a dropped connection
+            pub(super) drop_next_response: bool,
+            // What GetAllStream sends, and how often it was called
+            pub(super) records: Vec<Record>,
+            pub(super) streams: usize,
+        }
+
+        impl MockServer {
+            /// Serve on a free local port, returning the URL
+            pub(super) async fn start(self: &Arc<Self>) -> String {
+                let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let addr = listener.local_addr().unwrap();
+                tokio::spawn(
+                    tonic::transport::Server::builder()
+                        .add_service(RpcPassmgrServer::from_arc(Arc::clone(self)))
+                        .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(
+                            listener,
+                        )),
+                );
+                format!("http://{addr}")
+            }
+
+            fn check_nonce(&self, auth: Option<&AuthSignature>) -> Result<u64, Status> {
+                let mut state = self.state.lock().unwrap();
+                match auth {
+                    Some(auth) if auth.nonce == state.nonce => {
+                        state.nonce += 1;
+                        Ok(state.nonce)
+                    }
+                    _ => Err(Status::invalid_argument("Invalid nonce")),
+                }
+            }
+        }
+
+        #[tonic::async_trait]
+        impl RpcPassmgr for MockServer {
+            type GetAllStreamStream =
+                futures::stream::Iter<std::vec::IntoIter<Result<Record, Status>>>;
+
+            async fn register(
+                &self,
+                _: Request<RegisterRequest>,
+            ) -> Result<Response<RegisterResponse>, Status> {
+                Err(Status::unimplemented("Register"))
+            }
+
+            async fn health(
+                &self,
+                _: Request<HealthRequest>,
+            ) -> Result<Response<HealthResponse>, Status> {
+                Err(Status::unimplemented("Health"))
+            }
+
+            async fn get_list(
+                &self,
+                _: Request<GetListRequest>,
+            ) -> Result<Response<RecordListResponse>, Status> {
+                Err(Status::unimplemented("GetList"))
+            }
+
+            async fn get_all(
+                &self,
+                _: Request<GetAllRequest>,
+            ) -> Result<Response<RecordsResponse>, Status> {
+                Err(Status::unimplemented("GetAll"))
+            }
+
+            async fn get_all_stream(
+                &self,
+                request: Request<GetAllRequest>,
+            ) -> Result<Response<Self::GetAllStreamStream>, Status> {
+                self.check_nonce(request.get_ref().auth.as_ref())?;
+                let mut state = self.state.lock().unwrap();
+                state.streams += 1;
+                let records: Vec<_> = state.records.iter().cloned().map(Ok).collect();
+                Ok(Response::new(futures::stream::iter(records)))
+            }
+
+            async fn get_by_id(
+                &self,
+                _: Request<GetByIdRequest>,
+            ) -> Result<Response<OneRecordResponse>, Status> {
+                Err(Status::unimplemented("GetById"))
+            }
+
+            async fn list_changed_since(
+                &self,
+                _: Request<ListChangedSinceRequest>,
+            ) -> Result<Response<RecordListResponse>, Status> {
+                Err(Status::unimplemented("ListChangedSince"))
+            }
+
+            // Any signature passes, the challenge exchange is what the client must follow
+            async fn get_nonce(
+                &self,
+                request: Request<GetNonceRequest>,
+            ) -> Result<Response<GetNonceResponse>, Status> {
+                let request = request.into_inner();
+                if request.signature.is_empty() {
+                    return Ok(Response::new(GetNonceResponse {
+                        nonce: 0,
+                        challenge: vec![1; 40],
+                    }));
+                }
+                Ok(Response::new(GetNonceResponse {
+                    nonce: self.state.lock().unwrap().nonce,
+                    challenge: Vec::new(),
+                }))
+            }
+
+            async fn get_stats(
+                &self,
+                _: Request<GetStatsRequest>,
+            ) -> Result<Response<StatsResponse>, Status> {
+                Err(Status::unimplemented("GetStats"))
+            }
+
+            async fn delete_all(
+                &self,
+                _: Request<DeleteAllRequest>,
+            ) -> Result<Response<DeleteResponse>, Status> {
+                Err(Status::unimplemented("DeleteAll"))
+            }
+
+            async fn delete_by_id(
+                &self,
+                _: Request<DeleteByIdRequest>,
+            ) -> Result<Response<DeleteResponse>, Status> {
+                Err(Status::unimplemented("DeleteById"))
+            }
+
+            async fn deregister(
+                &self,
+                _: Request<DeregisterRequest>,
+            ) -> Result<Response<DeregisterResponse>, Status> {
+                Err(Status::unimplemented("Deregister"))
+            }
+
+            async fn set_one(
+                &self,
+                request: Request<SetOneRequest>,
+            ) -> Result<Response<SetOneResponse>, Status> {
+                let request = request.into_inner();
+                let nonce = self.check_nonce(request.auth.as_ref())?;
+                let mut state = self.state.lock().unwrap();
+                let key = request.idempotency_key;
+                state.received.push(key.clone());
+                if state.applied.contains(&key) {
+                    return Ok(Response::new(SetOneResponse { nonce }));
+                }
+                state.applied.push(key);
+                if std::mem::take(&mut state.drop_next_response) {
+                    return Err(Status::unavailable("connection reset"));
+                }
+                Ok(Response::new(SetOneResponse { nonce }))
+            }
+
+            async fn set_records(
+                &self,
+                _: Request<SetRecordsRequest>,
+            ) -> Result<Response<SetRecordsResponse>, Status> {
+                Err(Status::unimplemented("SetRecords"))
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_idempotent_retries_with_same_key() {
+        let mock = std::sync::Arc::new(mock_server::MockServer::default());
+        {
+            let mut state = mock.state.lock().unwrap();
+            state.nonce = 10;
+            state.drop_next_response = true;
+        }
+        let mut session = test_session();
+        session.config.url = mock.start().await;
+        session.config.plaintext = true;
+        session.nonce = Nonce::new(10);
+        connect_to_server(&mut session).await.unwrap();
+
+        let request = SetOneRequest {
+            auth: None,
+            record: Some(RpcRecord::default()),
+            idempotency_key: "3c1d9e7a-5b2f-4a86-9d0e-7f4b2c6a1e53".to_string(),
+        };
+        let response = send_idempotent(&mut session, request).await.unwrap();
+
+        // Applied once, the resend under the same key was answered from the cache
+        let state = mock.state.lock().unwrap();
+        assert_eq!(state.received.len(), 2);
+        assert_eq!(state.received[0], state.received[1]);
+        assert_eq!(state.applied.len(), 1);
+        // The resend was signed with the resynced nonce, and its answer adopted
+        assert_eq!(response.nonce, 12);
+        assert_eq!(session.nonce.get(), 12);
+    }
+
+    #[tokio::test]
+    async fn test_sync_pulls_from_the_stream() {
+        let mut session = test_session();
+        let records: Vec<RpcRecord> = (1..=3)
+            .map(|id| {
+                let mut record = signed_record(&session, 1, format!("record {id}").as_bytes());
+                record.id = id;
+                session.sign_record(&mut record).unwrap();
+                record
+            })
+            .collect();
+        let mock = std::sync::Arc::new(mock_server::MockServer::default());
+        mock.state.lock().unwrap().records = records.clone();
+        session.config.url = mock.start().await;
+        session.config.plaintext = true;
+        connect_to_server(&mut session).await.unwrap();
+
+        let tmp_dir = TempDir::new("cli_sync_test").unwrap();
+        let keys = test_keys();
+        let user_session = UserSession::new(test_user_db(&tmp_dir, &keys));
+        let report = sync_with_server(&mut session, &user_session).await.unwrap();
+
+        // One stream and no per-record fetch, GetById isn't even implemented
+        assert_eq!(report.pulled, 3);
+        assert_eq!(mock.state.lock().unwrap().streams, 1);
+        for record in &records {
+            assert_eq!(
+                user_session.user_db.storage.get(record.id).unwrap().data,
+                record.data
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_first_sync_pulls_newer_server_copy() {
+        let mut session = test_session();
+        let mut newer = signed_record(&session, 2, b"edited elsewhere");
+        newer.updated = 400;
+        let mock = std::sync::Arc::new(mock_server::MockServer::default());
+        mock.state.lock().unwrap().records = vec![newer.clone()];
+        session.config.url = mock.start().await;
+        session.config.plaintext = true;
+        connect_to_server(&mut session).await.unwrap();
+
+        // Never synced, with an older copy of the record
+        let tmp_dir = TempDir::new("cli_sync_test").unwrap();
+        let keys = test_keys();
+        let user_session = UserSession::new(test_user_db(&tmp_dir, &keys));
+        let stale = local_record(1, 1, b"stale", 500);
+        user_session.user_db.storage.set(1, &stale).unwrap();
+        assert_eq!(user_session.user_db.last_sync().unwrap(), 0);
+        assert_eq!(
+            check_versions(&stale, &cipher_record(session.user_id, &newer), 0),
+            VersionCheck::Pull
+        );
+
+        let report = sync_with_server(&mut session, &user_session).await.unwrap();
+        assert!(report.conflicts.is_empty());
+        assert_eq!(report.pulled, 1);
+        assert_eq!(report.pushed, 0);
+        assert!(mock.state.lock().unwrap().received.is_empty());
+        let local = user_session.user_db.storage.get(1).unwrap();
+        assert_eq!(local.ver, 2);
+        assert_eq!(local.data, newer.data);
+    }
+
+    #[tokio::test]
+    async fn test_server_delete_survives_a_new_session() {
+        let mut session = test_session();
+        let records: Vec<RpcRecord> = (1..=2)
+            .map(|id| {
+                let mut record = signed_record(&session, 1, format!("record {id}").as_bytes());
+                record.id = id;
+                session.sign_record(&mut record).unwrap();
+                record
+            })
+            .collect();
+        let mock = std::sync::Arc::new(mock_server::MockServer::default());
+        mock.state.lock().unwrap().records = records;
+        let url = mock.start().await;
+        session.config.url = url.clone();
+        session.config.plaintext = true;
+        connect_to_server(&mut session).await.unwrap();
+
+        let tmp_dir = TempDir::new("cli_sync_test").unwrap();
+        let keys = test_keys();
+        {
+            let user_session = UserSession::new(test_user_db(&tmp_dir, &keys));
+            let report = sync_with_server(&mut session, &user_session).await.unwrap();
+            assert_eq!(report.pulled, 2);
+        }
+
+        // Deleted on another device, then synced from a fresh run of the CLI
+        mock.state
+            .lock()
+            .unwrap()
+            .records
+            .retain(|record| record.id != 2);
+        let mut session = test_session();
+        session.config.url = url;
+        session.config.plaintext = true;
+        connect_to_server(&mut session).await.unwrap();
+        let nonce = get_nonce_from_server(&mut session).await.unwrap();
+        session.adopt_nonce(nonce);
+
+        let user_session = UserSession::new(test_user_db(&tmp_dir, &keys));
+        let report = sync_with_server(&mut session, &user_session).await.unwrap();
+        assert_eq!(report.pushed, 0);
+        assert_eq!(report.deleted_local, 1);
+        assert!(mock.state.lock().unwrap().received.is_empty());
+        assert_eq!(user_session.user_db.list_records().unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn test_switch_user_resets_the_nonce() {
+        let mut session = test_session();
+        session.nonce = Nonce::new(42);
+        session.switch_user(
+            [8; 32],
+            Box::new(AssymetricKeypair::generate_dilithium2(&[8; 32])),
+        );
+        assert_eq!(session.user_id, [8; 32]);
+        assert_eq!(session.nonce, Nonce::default());
+        assert!(session.client.is_none());
+    }
+
+    #[test]
+    fn test_reimported_csv_deduplicated() {
+        let tmp_dir = TempDir::new("cli_import_test").unwrap();
+        let keys = test_keys();
+        let user_db = test_user_db(&tmp_dir, &keys);
+        let mapping = ColumnMapping::default();
+        let export = "name,url,username,password\n\
+            Mail,https://mail.example,alice,hunter2\n\
+            VPN,vpn.example,bob,letmein\n";
+        let import = csv_import::parse_records(export, &mapping, 1).unwrap();
+        let report = user_db
+            .import_records(import.records, &Dedup::Content.policy())
+            .unwrap();
+        assert_eq!(report.added, 2);
+
+        // The same export again adds nothing
+        let import = csv_import::parse_records(export, &mapping, 2).unwrap();
+        let report = user_db
+            .import_records(import.records, &Dedup::Content.policy())
+            .unwrap();
+        assert_eq!((report.added, report.skipped, report.merged), (0, 2, 0));
+
+        // A later export with a changed password updates the entry by its URL and login
+        let changed = export.replace("hunter2", "hunter3");
+        let import = csv_import::parse_records(&changed, &mapping, 3).unwrap();
+        let report = user_db
+            .import_records(import.records, &Dedup::UrlLogin.policy())
+            .unwrap();
+        assert_eq!((report.added, report.skipped, report.merged), (0, 1, 1));
+        assert_eq!(user_db.list_records().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_pending_pulls_budget() {
+        let record = |id, len| RpcRecord {
+            id,
+            ver: 2,
+            data: vec![0; len],
+            ..Default::default()
+        };
+        let mut pulls = PendingPulls::default();
+        pulls.add(&record(1, SYNC_PULL_BUDGET - 10));
+        pulls.add(&record(2, 10));
+        // Over the budget, only its id and version are kept for a second pass
+        pulls.add(&record(3, 1));
+
+        let kept: Vec<u64> = pulls.kept.iter().map(|record| record.id).collect();
+        assert_eq!(kept, vec![1, 2]);
+        assert_eq!(pulls.deferred, HashMap::from([(3, 2)]));
+    }
+
+    #[test]
+    fn test_truncate_value() {
+        assert_eq!(truncate_value("short", 10), "short");
+        assert_eq!(truncate_value("exactly10!", 10), "exactly10!");
+        assert_eq!(
+            truncate_value("abcdefghijklmno", 10),
+            "abcdefghij... (5 more chars, press v to view full)"
+        );
+        // Counts characters, not bytes
+        assert_eq!(
+            truncate_value(&"ж".repeat(12), 10),
+            format!("{}... (2 more chars, press v to view full)", "ж".repeat(10))
+        );
+
+        let note = "x".repeat(5000);
+        assert!(mask_value(&note, &[]).starts_with(&"x".repeat(MAX_VALUE_CHARS)));
+        assert!(mask_value(&note, &[]).ends_with("(4920 more chars, press v to view full)"));
+        // Hidden values stay masked and capped
+        assert_eq!(
+            mask_value(&note, &[Atributes::Hide]),
+            "*".repeat(MAX_VALUE_CHARS)
+        );
+    }
+}
@@ -0,0 +1,159 @@
+//! Seed phrase kept next to the vault, encrypted under a master password, so day to day
+//! unlocking doesn't need the phrase itself
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use crypto::master_password::{MasterPassword, MasterPasswordError};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+const LOCK_FILE: &str = "master_password";
+const LOCK_HEADER: &str = "PASSMGR_LOCK_V1";
+
+#[derive(Debug, Error)]
+pub enum VaultLockError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Wrong master password")]
+    WrongPassword,
+    #[error("A master password is already set for {0}")]
+    AlreadyLocked(String),
+    #[error("Malformed master password file: {0}")]
+    Malformed(String),
+    #[error("Master password error: {0}")]
+    MasterPassword(String),
+}
+
+impl From<MasterPasswordError> for VaultLockError {
+    fn from(e: MasterPasswordError) -> Self {
+        match e {
+            MasterPasswordError::VerificationError => Self::WrongPassword,
+            e => Self::MasterPassword(e.to_string()),
+        }
+    }
+}
+
+fn lock_path(db_path: &Path) -> PathBuf {
+    db_path.join(LOCK_FILE)
+}
+
+pub fn is_locked(db_path: &Path) -> bool {
+    lock_path(db_path).is_file()
+}
+
+/// Store `mnemonic` encrypted under `password`, with the password hash to check it against.
+/// One per vault directory, an existing one is never replaced.
+pub fn lock(db_path: &Path, password: &str, mnemonic: &str) -> Result<(), VaultLockError> {
+    let path = lock_path(db_path);
+    if path.exists() {
+        return Err(VaultLockError::AlreadyLocked(db_path.display().to_string()));
+    }
+    let master_password = MasterPassword::new(password)?;
+    let wrapped = master_password.encrypt(mnemonic.as_bytes())?;
+    let contents = format!(
+        "{}\n{}\n{}\n",
+        LOCK_HEADER,
+        master_password.get_hash(),
+        STANDARD.encode(wrapped)
+    );
+
+    // Write then rename, a torn file would lock the user out of the shortcut
+    let tmp = db_path.join(format!("{LOCK_FILE}.tmp"));
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+/// The seed phrase stored by `lock`, `WrongPassword` if `password` doesn't match
+pub fn unlock(db_path: &Path, password: &str) -> Result<String, VaultLockError> {
+    let contents = std::fs::read_to_string(lock_path(db_path))?;
+    let mut lines = contents.lines();
+    if lines.next() != Some(LOCK_HEADER) {
+        return Err(VaultLockError::Malformed("unknown header".to_string()));
+    }
+    let (Some(hash), Some(wrapped)) = (lines.next(), lines.next()) else {
+        return Err(VaultLockError::Malformed("missing fields".to_string()));
+    };
+    let wrapped = STANDARD
+        .decode(wrapped)
+        .map_err(|e| VaultLockError::Malformed(e.to_string()))?;
+
+    let master_password = MasterPassword::load(password, hash)?;
+    let mnemonic = master_password.decrypt(&wrapped)?;
+    String::from_utf8(mnemonic).map_err(|e| VaultLockError::Malformed(e.to_string()))
+}
+
+/// Forget the master password, the vault then opens with the seed phrase only
+pub fn remove(db_path: &Path) -> Result<(), VaultLockError> {
+    std::fs::remove_file(lock_path(db_path))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::{bip39::Bip39, CipherOption, MasterKeys};
+    use storage::{structures::Record, user_db::UserDb};
+    use tempdir::TempDir;
+
+    // 24 words, `MasterKeys::from_entropy` needs 32 bytes of entropy
+    const MNEMONIC: &str = "abandon abandon abandon abandon abandon abandon abandon abandon \
+                            abandon abandon abandon abandon abandon abandon abandon abandon \
+                            abandon abandon abandon abandon abandon abandon abandon art";
+
+    fn master_keys(mnemonic: &str) -> MasterKeys {
+        let bip39 = Bip39::from_mnemonic(mnemonic).unwrap();
+        MasterKeys::from_entropy(bip39.get_entropy()).unwrap()
+    }
+
+    #[test]
+    fn test_lock_unlock() {
+        let temp_dir = TempDir::new("vault_lock_test").unwrap();
+        let db_path = temp_dir.path();
+        assert!(!is_locked(db_path));
+
+        let chain = vec![CipherOption::AES256, CipherOption::XChaCha20];
+        let keys = master_keys(MNEMONIC);
+        let record = Record {
+            icon: String::new(),
+            created: 1,
+            updated: 1,
+            fields: Vec::new(),
+            last_accessed: 0,
+        };
+        let id = {
+            let user_db =
+                UserDb::create_vault(db_path, keys.user_id, &keys, chain.clone(), false).unwrap();
+            user_db.create(record.clone()).unwrap()
+        };
+
+        lock(db_path, "correct horse", MNEMONIC).unwrap();
+        assert!(is_locked(db_path));
+        assert!(matches!(
+            lock(db_path, "other", MNEMONIC),
+            Err(VaultLockError::AlreadyLocked(_))
+        ));
+
+        // The unwrapped phrase opens the vault again
+        let keys = master_keys(&unlock(db_path, "correct horse").unwrap());
+        let user_db = UserDb::new(db_path, keys.user_id, &keys, chain).unwrap();
+        assert_eq!(user_db.read(id).unwrap(), record);
+
+        assert!(matches!(
+            unlock(db_path, "wrong horse"),
+            Err(VaultLockError::WrongPassword)
+        ));
+
+        remove(db_path).unwrap();
+        assert!(!is_locked(db_path));
+    }
+
+    #[test]
+    fn test_malformed_lock_file() {
+        let temp_dir = TempDir::new("vault_lock_test").unwrap();
+        std::fs::write(lock_path(temp_dir.path()), "garbage\n").unwrap();
+        assert!(matches!(
+            unlock(temp_dir.path(), "password"),
+            Err(VaultLockError::Malformed(_))
+        ));
+    }
+}
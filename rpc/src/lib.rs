@@ -1,3 +1,48 @@
+use prost::Message;
+
 pub mod rpc_passmgr {
     tonic::include_proto!("rpc_passmgr");
 }
+
+/// Response metadata key carrying the next nonce of a streaming RPC (GetAllStream),
+/// which has no response message to put it in. The value is the nonce in decimal.
+pub const NONCE_METADATA_KEY: &str = "passmgr-nonce";
+
+/// Bytes an authenticated request's signature covers: the RPC method name, the nonce
+/// (big-endian) and the request encoded with its `auth` field cleared. Clients sign and
+/// the server verifies this exact layout, so both must build it here.
+pub fn signing_payload(method: &str, nonce: u64, body: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(method.len() + 8 + body.len());
+    payload.extend_from_slice(method.as_bytes());
+    payload.extend_from_slice(&nonce.to_be_bytes());
+    payload.extend_from_slice(body);
+    payload
+}
+
+/// Bytes signed to answer a `GetNonce` challenge. No nonce is known yet, so 0 stands in.
+pub fn nonce_challenge_payload(user_id: &[u8], challenge: &[u8]) -> Vec<u8> {
+    let request = rpc_passmgr::GetNonceRequest {
+        user_id: user_id.to_vec(),
+        challenge: challenge.to_vec(),
+        signature: Vec::new(),
+    };
+    signing_payload("GetNonce", 0, &request.encode_to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signing_payload_layout() {
+        let payload = signing_payload("SetOne", 0x0102_0304_0506_0708, &[0xaa, 0xbb]);
+        assert_eq!(
+            payload,
+            [b'S', b'e', b't', b'O', b'n', b'e', 1, 2, 3, 4, 5, 6, 7, 8, 0xaa, 0xbb]
+        );
+        assert_eq!(
+            signing_payload("GetList", 0, &[]),
+            b"GetList\0\0\0\0\0\0\0\0"
+        );
+    }
+}
@@ -0,0 +1,174 @@
+use crypto::UserId;
+use std::collections::{HashMap, VecDeque};
+
+/// Encoded responses of recent mutating requests, so a retry carrying the same
+/// idempotency key gets the original outcome instead of applying the request again.
+/// Entries are kept per user and keyed by (method, key); each user holds at most
+/// `per_user` of them, least recently used evicted first, so one busy account can't
+/// push out another's.
+pub(crate) struct IdempotencyCache {
+    per_user: usize,
+    users: HashMap<UserId, UserEntries>,
+}
+
+#[derive(Default)]
+struct UserEntries {
+    entries: HashMap<(String, String), CachedResponse>,
+    // Least recently used first
+    order: VecDeque<(String, String)>,
+}
+
+struct CachedResponse {
+    // Of the request as signed, so a key reused for other content is caught
+    request_hash: blake3::Hash,
+    response: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Lookup {
+    /// Not seen before, the request has to be applied
+    Miss,
+    /// Seen with the same content, this is the encoded response it produced
+    Hit(Vec<u8>),
+    /// The key was used before for a request with other content
+    Mismatch,
+}
+
+impl IdempotencyCache {
+    pub(crate) fn new(per_user: usize) -> Self {
+        Self {
+            per_user,
+            users: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn get(
+        &mut self,
+        user_id: &UserId,
+        method: &str,
+        key: &str,
+        request: &[u8],
+    ) -> Lookup {
+        let Some(user) = self.users.get_mut(user_id) else {
+            return Lookup::Miss;
+        };
+        let entry_key = (method.to_string(), key.to_string());
+        let Some(cached) = user.entries.get(&entry_key) else {
+            return Lookup::Miss;
+        };
+        // blake3::Hash compares in constant time
+        if cached.request_hash != blake3::hash(request) {
+            return Lookup::Mismatch;
+        }
+        let response = cached.response.clone();
+        user.touch(&entry_key);
+        Lookup::Hit(response)
+    }
+
+    pub(crate) fn insert(
+        &mut self,
+        user_id: UserId,
+        method: &str,
+        key: &str,
+        request: &[u8],
+        response: Vec<u8>,
+    ) {
+        if self.per_user == 0 {
+            return;
+        }
+        let user = self.users.entry(user_id).or_default();
+        let entry_key = (method.to_string(), key.to_string());
+        let cached = CachedResponse {
+            request_hash: blake3::hash(request),
+            response,
+        };
+        if user.entries.insert(entry_key.clone(), cached).is_some() {
+            user.touch(&entry_key);
+            return;
+        }
+        user.order.push_back(entry_key);
+        while user.order.len() > self.per_user {
+            if let Some(evicted) = user.order.pop_front() {
+                user.entries.remove(&evicted);
+            }
+        }
+    }
+
+    /// Drop everything cached for a user, e.g. when the account is purged
+    pub(crate) fn forget_user(&mut self, user_id: &UserId) {
+        self.users.remove(user_id);
+    }
+}
+
+impl UserEntries {
+    // Mark as most recently used
+    fn touch(&mut self, entry_key: &(String, String)) {
+        if let Some(pos) = self.order.iter().position(|k| k == entry_key) {
+            if let Some(k) = self.order.remove(pos) {
+                self.order.push_back(k);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hit_miss_and_mismatch() {
+        let mut cache = IdempotencyCache::new(4);
+        cache.insert([1; 32], "SetOne", "key", b"request", b"response".to_vec());
+
+        assert_eq!(
+            cache.get(&[1; 32], "SetOne", "key", b"request"),
+            Lookup::Hit(b"response".to_vec())
+        );
+        assert_eq!(
+            cache.get(&[1; 32], "SetOne", "key", b"other request"),
+            Lookup::Mismatch
+        );
+        // Keys are scoped by user and method
+        assert_eq!(
+            cache.get(&[2; 32], "SetOne", "key", b"request"),
+            Lookup::Miss
+        );
+        assert_eq!(
+            cache.get(&[1; 32], "DeleteById", "key", b"request"),
+            Lookup::Miss
+        );
+
+        cache.forget_user(&[1; 32]);
+        assert_eq!(
+            cache.get(&[1; 32], "SetOne", "key", b"request"),
+            Lookup::Miss
+        );
+    }
+
+    #[test]
+    fn test_eviction_is_per_user() {
+        let mut cache = IdempotencyCache::new(2);
+        cache.insert([1; 32], "SetOne", "a", b"a", vec![1]);
+        cache.insert([1; 32], "SetOne", "b", b"b", vec![2]);
+        // A busy user evicts only their own entries
+        for i in 0..10 {
+            cache.insert([2; 32], "SetOne", &i.to_string(), b"x", vec![i]);
+        }
+        assert_eq!(
+            cache.get(&[1; 32], "SetOne", "a", b"a"),
+            Lookup::Hit(vec![1])
+        );
+
+        // "a" was just used, so "b" is the least recently used
+        cache.insert([1; 32], "SetOne", "c", b"c", vec![3]);
+        assert_eq!(cache.get(&[1; 32], "SetOne", "b", b"b"), Lookup::Miss);
+        assert_eq!(
+            cache.get(&[1; 32], "SetOne", "a", b"a"),
+            Lookup::Hit(vec![1])
+        );
+        assert_eq!(
+            cache.get(&[1; 32], "SetOne", "c", b"c"),
+            Lookup::Hit(vec![3])
+        );
+    }
+}
@@ -1,47 +1,255 @@
+mod idempotency;
+
 use bincode::{deserialize, serialize};
 use blake3::Hasher;
-use crypto::UserId;
+use clap::Parser;
+use crypto::{Nonce, UserId};
 use crystals_dilithium::dilithium2;
+use idempotency::{IdempotencyCache, Lookup};
+use passmgr_rpc::rpc_passmgr::health_response::ServingStatus;
+use passmgr_rpc::rpc_passmgr::rpc_passmgr_admin_server::{RpcPassmgrAdmin, RpcPassmgrAdminServer};
 use passmgr_rpc::rpc_passmgr::rpc_passmgr_server::{RpcPassmgr, RpcPassmgrServer};
 use passmgr_rpc::rpc_passmgr::{
-    AuthSignature, DeleteAllRequest, DeleteByIdRequest, DeleteResponse, GetAllRequest,
-    GetByIdRequest, GetListRequest, GetNonceRequest, GetNonceResponse, OneRecordResponse, Record,
-    RecordId, RecordListResponse, RecordsResponse, RegisterRequest, RegisterResponse,
-    SetOneRequest, SetOneResponse, SetRecordsRequest, SetRecordsResponse,
+    AuthSignature, DeleteAllRequest, DeleteByIdRequest, DeleteResponse, DeregisterRequest,
+    DeregisterResponse, GetAllRequest, GetByIdRequest, GetListRequest, GetNonceRequest,
+    GetNonceResponse, GetStatsRequest, HealthRequest, HealthResponse, ListChangedSinceRequest,
+    ListUsersRequest, ListUsersResponse, OneRecordResponse, Record, RecordId, RecordListResponse,
+    RecordsResponse, RegisterRequest, RegisterResponse, SetOneRequest, SetOneResponse,
+    SetRecordsRequest, SetRecordsResponse, StatsResponse, UserSummary,
 };
+use passmgr_rpc::{nonce_challenge_payload, signing_payload, NONCE_METADATA_KEY};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
-use storage::db::Storage;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use storage::db::{ensure_dir, Storage};
 use storage::error::StorageError;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::{Identity, ServerTlsConfig};
 use tonic::{Request, Response, Status};
+use tracing::field::Empty;
+use tracing::{debug, error, info, warn, Span};
+use tracing_subscriber::EnvFilter;
 
 pub const CHALLENGE_ZERO_BITS: usize = 3; // adjustable
+pub const IDEMPOTENCY_CACHE_PER_USER: usize = 32;
+pub const RETENTION_SWEEP_INTERVAL_SECS: u64 = 60 * 60;
+const DAY_SECS: u64 = 24 * 60 * 60;
+pub const GET_ALL_MAX_PAGE: u32 = 1000;
+pub const GET_ALL_STREAM_BUFFER: usize = 16;
+pub const AUTH_FAILURE_LIMIT: u32 = 5; // bad signatures within the window before a lockout
+pub const AUTH_FAILURE_WINDOW_SECS: u64 = 5 * 60;
+pub const AUTH_LOCKOUT_SECS: u64 = 60; // doubled for every further failure, up to the max
+pub const AUTH_LOCKOUT_MAX_SECS: u64 = 60 * 60;
+pub const NONCE_CHALLENGE_TTL_SECS: u64 = 60;
+
 struct PassmgrService {
     auth_db: sled::Db,
     data_dir: PathBuf,
+    // Encoded responses of recent requests per user, by (method, idempotency key)
+    idempotency_cache: Mutex<IdempotencyCache>,
+    // Store identical record data once per user (see Storage::open_content_addressed)
+    content_addressed: bool,
+    // user id -> unix seconds of the last authenticated request
+    last_active: sled::Tree,
+    // user id -> unix seconds when the account was flagged as inactive
+    flagged: sled::Tree,
+    // user id -> AuthEntry as of its Deregister, to recognize a retry of that request
+    deregistered: sled::Tree,
+    // Purging inactive accounts is opt-in
+    retention: Option<RetentionPolicy>,
+    // Consecutive signature failures per user, cleared by a valid request
+    auth_failures: Mutex<HashMap<UserId, AuthFailures>>,
+    // Keys the GetNonce challenges, which are checked against it instead of being kept.
+    // New on every start, so challenges don't outlive the process.
+    challenge_key: [u8; 32],
+}
+
+/// Unix seconds throughout
+#[derive(Clone, Copy, Debug)]
+struct AuthFailures {
+    count: u32,
+    window_start: u64,
+    locked_until: u64,
+}
+
+/// Accounts without authenticated activity for `inactive_secs` are flagged, and purged
+/// together with their records once still inactive `grace_secs` after flagging
+#[derive(Clone, Copy, Debug)]
+struct RetentionPolicy {
+    inactive_secs: u64,
+    grace_secs: u64,
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct RetentionSweep {
+    flagged: Vec<UserId>,
+    purged: Vec<UserId>,
 }
 
 #[derive(Deserialize, Serialize)]
 struct AuthEntry {
-    nonce: u64,
+    nonce: Nonce,
     public_key: Vec<u8>,
 }
 
 impl PassmgrService {
     fn new(auth_db_path: PathBuf, data_dir: PathBuf) -> anyhow::Result<Self> {
+        ensure_dir(&auth_db_path)?;
+        ensure_dir(&data_dir)?;
         let auth_db = sled::open(auth_db_path)?;
-        std::fs::create_dir_all(&data_dir)?;
+        let last_active = auth_db.open_tree("last_active")?;
+        let flagged = auth_db.open_tree("flagged")?;
+        let deregistered = auth_db.open_tree("deregistered")?;
+
+        Ok(Self {
+            auth_db,
+            data_dir,
+            idempotency_cache: Mutex::new(IdempotencyCache::new(IDEMPOTENCY_CACHE_PER_USER)),
+            content_addressed: false,
+            last_active,
+            flagged,
+            deregistered,
+            retention: None,
+            auth_failures: Mutex::new(HashMap::new()),
+            challenge_key: rand::thread_rng().gen(),
+        })
+    }
+
+    /// Write out everything sled still buffers. User stores are opened per request,
+    /// so only the auth database outlives one.
+    fn flush(&self) -> anyhow::Result<()> {
+        let bytes = self.auth_db.flush()?;
+        debug!(bytes, "flushed auth database");
+        Ok(())
+    }
+
+    // The auth database answers and a file can be created in the data directory
+    fn is_healthy(&self) -> bool {
+        if self.auth_db.size_on_disk().is_err() {
+            return false;
+        }
+        match std::fs::metadata(&self.data_dir) {
+            Ok(metadata) if !metadata.permissions().readonly() => {}
+            _ => return false,
+        }
+        let probe = self.data_dir.join(".health");
+        let writable = std::fs::write(&probe, b"").is_ok();
+        let _ = std::fs::remove_file(&probe);
+        writable
+    }
 
-        Ok(Self { auth_db, data_dir })
+    fn record_activity(&self, user_id: &UserId) -> Result<(), Status> {
+        self.last_active
+            .insert(user_id, &current_timestamp().to_be_bytes()[..])
+            .map_err(|e| Status::internal(format!("Failed to record activity: {}", e)))?;
+        // Activity clears a pending purge
+        self.flagged
+            .remove(user_id)
+            .map_err(|e| Status::internal(format!("Failed to record activity: {}", e)))?;
+        Ok(())
+    }
+
+    /// Flag accounts inactive past the policy and purge flagged ones whose grace
+    /// window is over. A no-op without a retention policy.
+    fn apply_retention(&self, now: u64) -> anyhow::Result<RetentionSweep> {
+        let mut sweep = RetentionSweep::default();
+        let Some(policy) = self.retention else {
+            return Ok(sweep);
+        };
+
+        for item in self.auth_db.iter() {
+            let (key, _) = item?;
+            let Ok(user_id) = UserId::try_from(key.as_ref()) else {
+                continue;
+            };
+
+            let Some(last_active) = read_timestamp(&self.last_active, &user_id)? else {
+                // Accounts from before activity tracking get a full window from now
+                self.last_active.insert(user_id, &now.to_be_bytes()[..])?;
+                continue;
+            };
+            if now.saturating_sub(last_active) < policy.inactive_secs {
+                continue;
+            }
+
+            match read_timestamp(&self.flagged, &user_id)? {
+                None => {
+                    self.flagged.insert(user_id, &now.to_be_bytes()[..])?;
+                    info!(user = %user_tag(&user_id), "retention: flagged inactive account");
+                    sweep.flagged.push(user_id);
+                }
+                Some(flagged_at) if now.saturating_sub(flagged_at) >= policy.grace_secs => {
+                    self.purge_user(&user_id)?;
+                    info!(user = %user_tag(&user_id), "retention: purged inactive account");
+                    sweep.purged.push(user_id);
+                }
+                Some(_) => {}
+            }
+        }
+
+        Ok(sweep)
+    }
+
+    fn purge_user(&self, user_id: &UserId) -> anyhow::Result<()> {
+        let user_data_dir = self.user_data_dir(user_id);
+        if user_data_dir.exists() {
+            std::fs::remove_dir_all(&user_data_dir)?;
+        }
+        self.auth_db.remove(user_id)?;
+        self.last_active.remove(user_id)?;
+        self.flagged.remove(user_id)?;
+        // A later account under the same id must not replay this one's responses
+        self.idempotency_cache.lock().unwrap().forget_user(user_id);
+        Ok(())
+    }
+
+    fn check_lockout(&self, user_id: &UserId, now: u64) -> Result<(), Status> {
+        let failures = self.auth_failures.lock().unwrap();
+        match failures.get(user_id) {
+            Some(entry) if entry.locked_until > now => Err(Status::resource_exhausted(format!(
+                "Too many failed attempts, retry in {} seconds",
+                entry.locked_until - now
+            ))),
+            _ => Ok(()),
+        }
     }
 
+    fn record_auth_failure(&self, user_id: UserId, now: u64) {
+        let mut failures = self.auth_failures.lock().unwrap();
+        // Forget users whose failures no longer matter, keeping the map small
+        failures.retain(|_, entry| {
+            now < entry.window_start + AUTH_FAILURE_WINDOW_SECS || now < entry.locked_until
+        });
+
+        let entry = failures.entry(user_id).or_insert(AuthFailures {
+            count: 0,
+            window_start: now,
+            locked_until: 0,
+        });
+        if now >= entry.window_start + AUTH_FAILURE_WINDOW_SECS && now >= entry.locked_until {
+            entry.count = 0;
+            entry.window_start = now;
+        }
+        entry.count += 1;
+        if entry.count >= AUTH_FAILURE_LIMIT {
+            let doublings = (entry.count - AUTH_FAILURE_LIMIT).min(16);
+            entry.locked_until = now + (AUTH_LOCKOUT_SECS << doublings).min(AUTH_LOCKOUT_MAX_SECS);
+        }
+    }
+
+    /// Check the signature and nonce of a request and advance the nonce, returning the
+    /// user and the nonce their next request must use. Records the user and nonce on the
+    /// handler's span and logs rejections, never the signature itself.
     fn validate_auth<T>(
         &self,
         auth: &AuthSignature,
         request_without_auth: &T,
         method_name: &str,
-    ) -> Result<UserId, Status>
+    ) -> Result<(UserId, u64), Status>
     where
         T: prost::Message,
     {
@@ -50,6 +258,39 @@ impl PassmgrService {
             .as_slice()
             .try_into()
             .map_err(|_| Status::invalid_argument("Invalid user_id length"))?;
+        Span::current()
+            .record("user", user_tag(&user_id).as_str())
+            .record("nonce", auth.nonce);
+
+        match self.authenticate(user_id, auth, request_without_auth, method_name) {
+            Ok(next_nonce) => {
+                debug!(method = method_name, "authenticated");
+                Ok((user_id, next_nonce))
+            }
+            Err(status) => {
+                warn!(
+                    method = method_name,
+                    code = ?status.code(),
+                    reason = status.message(),
+                    "authentication failed"
+                );
+                Err(status)
+            }
+        }
+    }
+
+    fn authenticate<T>(
+        &self,
+        user_id: UserId,
+        auth: &AuthSignature,
+        request_without_auth: &T,
+        method_name: &str,
+    ) -> Result<u64, Status>
+    where
+        T: prost::Message,
+    {
+        let now = current_timestamp();
+        self.check_lockout(&user_id, now)?;
 
         let mut hasher = Hasher::new();
         hasher.update(&auth.nonce.to_be_bytes());
@@ -64,53 +305,291 @@ impl PassmgrService {
             .auth_db
             .get(&user_id)
             .map_err(|e| Status::internal(format!("Failed to retrieve user: {}", e)))?
-            .ok_or_else(|| Status::not_found("User not found"))?;
+            // Nonces are random, so an unknown user looks like a wrong nonce
+            .ok_or_else(|| Status::invalid_argument("Invalid nonce"))?;
 
-        let auth_entry: AuthEntry = deserialize(&auth_entry_bytes)
+        let mut auth_entry: AuthEntry = deserialize(&auth_entry_bytes)
             .map_err(|_| Status::internal("Auth entry deserialization failed"))?;
 
         // Verify nonce
-        if auth.nonce != auth_entry.nonce {
+        if auth.nonce != auth_entry.nonce.get() {
             return Err(Status::invalid_argument("Invalid nonce"));
         }
 
         let public_key = dilithium2::PublicKey::from_bytes(&auth_entry.public_key);
 
-        // Verify signature start
-        let mut sign_data = method_name.as_bytes().to_vec();
-        sign_data.extend_from_slice(&auth.nonce.to_be_bytes());
-
-        // Encode request data
-        sign_data.extend_from_slice(&request_without_auth.encode_to_vec());
-
+        let sign_data = signing_payload(
+            method_name,
+            auth.nonce,
+            &request_without_auth.encode_to_vec(),
+        );
         let is_valid = public_key.verify(&sign_data, &auth.signature);
         if !is_valid {
+            self.record_auth_failure(user_id, now);
             return Err(Status::unauthenticated("Invalid signature"));
         }
+        self.auth_failures.lock().unwrap().remove(&user_id);
 
         // Increment and store new nonce
-        let _ = auth_entry.nonce.wrapping_add(1);
+        auth_entry
+            .nonce
+            .increment()
+            .map_err(|e| Status::resource_exhausted(e.to_string()))?;
 
         self.auth_db
             .insert(user_id.to_vec(), serialize(&auth_entry).unwrap())
             .map_err(|e| Status::internal(format!("Failed to save nonce: {}", e)))?;
+        self.record_activity(&user_id)?;
+
+        Ok(auth_entry.nonce.get())
+    }
+
+    /// GetNonce challenge for `user_id` valid until `expires`: the expiry (u64 BE)
+    /// followed by a keyed hash of both, so it can be checked without being stored
+    fn nonce_challenge(&self, user_id: &UserId, expires: u64) -> Vec<u8> {
+        let mut challenge = expires.to_be_bytes().to_vec();
+        challenge.extend_from_slice(self.challenge_mac(user_id, expires).as_bytes());
+        challenge
+    }
+
+    // Issued to `user_id` by this process and not expired
+    fn check_nonce_challenge(&self, user_id: &UserId, challenge: &[u8], now: u64) -> bool {
+        if challenge.len() != 8 + blake3::OUT_LEN {
+            return false;
+        }
+        let expires = u64::from_be_bytes(challenge[..8].try_into().unwrap());
+        // blake3::Hash compares in constant time
+        now < expires && self.challenge_mac(user_id, expires) == challenge[8..]
+    }
+
+    fn challenge_mac(&self, user_id: &UserId, expires: u64) -> blake3::Hash {
+        let mut hasher = Hasher::new_keyed(&self.challenge_key);
+        hasher.update(user_id);
+        hasher.update(&expires.to_be_bytes());
+        hasher.finalize()
+    }
 
-        Ok(user_id)
+    /// The Deregister that removed the account, sent again: signed with the account's
+    /// key and the nonce that request used up
+    fn is_deregister_retry(&self, auth: &AuthSignature, request: &DeregisterRequest) -> bool {
+        let Ok(Some(entry)) = self.deregistered.get(&auth.user_id) else {
+            return false;
+        };
+        let Ok(entry) = deserialize::<AuthEntry>(&entry) else {
+            return false;
+        };
+        let payload = signing_payload(
+            "Deregister",
+            auth.nonce,
+            &prost::Message::encode_to_vec(request),
+        );
+        auth.nonce.checked_add(1) == Some(entry.nonce.get())
+            && dilithium2::PublicKey::from_bytes(&entry.public_key)
+                .verify(&payload, &auth.signature)
+    }
+
+    fn user_data_dir(&self, user_id: &UserId) -> PathBuf {
+        let hex_id = hex_id(user_id);
+        self.data_dir.join(hex_id)
     }
 
     fn get_user_storage(&self, user_id: UserId) -> Result<Storage, Status> {
-        let hex_id = user_id.iter().fold(String::new(), |mut acc, b| {
-            acc.push_str(&format!("{:02x}", b));
-            acc
-        });
-        let user_data_dir = self.data_dir.join(hex_id);
-        Storage::open(&user_data_dir, user_id)
-            .map_err(|e| Status::internal(format!("Failed to open user storage: {}", e)))
+        let user_data_dir = self.user_data_dir(&user_id);
+        // Registered but the directory is gone, e.g. removed by hand: start over empty
+        if !user_data_dir.exists() {
+            warn!(user = %user_tag(&user_id), "user data directory missing, recreating it");
+        }
+        ensure_dir(&user_data_dir).map_err(|e| {
+            Status::failed_precondition(format!("User data directory unusable: {}", e))
+        })?;
+        let mut storage = Storage::open(&user_data_dir, user_id)
+            .map_err(|e| Status::internal(format!("Failed to open user storage: {}", e)))?;
+        if storage.record_tree_name() != user_id {
+            return Err(Status::internal("User storage opened for another user"));
+        }
+
+        // Only empty stores are switched, existing users keep their plain layout
+        let is_empty = storage.list_ids().is_ok_and(|ids| ids.is_empty());
+        if self.content_addressed && !storage.is_content_addressed() && is_empty {
+            storage
+                .enable_content_addressing(user_id)
+                .map_err(|e| Status::internal(e.to_string()))?;
+        }
+        Ok(storage)
+    }
+
+    /// Response cached for a retry of the request under the same method and key. A key
+    /// already used for a request with other content is rejected.
+    fn cached_response<R, T>(
+        &self,
+        user_id: &UserId,
+        method_name: &str,
+        key: &str,
+        request_without_auth: &T,
+    ) -> Result<Option<R>, Status>
+    where
+        R: prost::Message + Default,
+        T: prost::Message,
+    {
+        if key.is_empty() {
+            return Ok(None);
+        }
+        let lookup = self
+            .idempotency_cache
+            .lock()
+            .map_err(|_| Status::internal("Idempotency cache poisoned"))?
+            .get(
+                user_id,
+                method_name,
+                key,
+                &request_without_auth.encode_to_vec(),
+            );
+
+        match lookup {
+            Lookup::Hit(encoded) => R::decode(encoded.as_slice())
+                .map(Some)
+                .map_err(|_| Status::internal("Cached response decoding failed")),
+            Lookup::Mismatch => Err(Status::invalid_argument(
+                "Idempotency key was already used for a different request",
+            )),
+            Lookup::Miss => Ok(None),
+        }
+    }
+
+    fn remember_response<R, T>(
+        &self,
+        user_id: UserId,
+        method_name: &str,
+        key: &str,
+        request_without_auth: &T,
+        response: &R,
+    ) where
+        R: prost::Message,
+        T: prost::Message,
+    {
+        if key.is_empty() {
+            return;
+        }
+        if let Ok(mut cache) = self.idempotency_cache.lock() {
+            cache.insert(
+                user_id,
+                method_name,
+                key,
+                &request_without_auth.encode_to_vec(),
+                response.encode_to_vec(),
+            );
+        }
+    }
+
+    // Reject records labeled for another user; an empty user_id means the client left it unset
+    fn check_record_owner(record: &Record, user_id: &UserId) -> Result<(), Status> {
+        if !record.user_id.is_empty() && record.user_id.as_slice() != user_id.as_slice() {
+            return Err(Status::permission_denied(
+                "Record user_id does not match authenticated user",
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn hex_id(user_id: &UserId) -> String {
+    user_id.iter().fold(String::new(), |mut acc, b| {
+        acc.push_str(&format!("{:02x}", b));
+        acc
+    })
+}
+
+// Short stable tag for logs, the user id itself stays out of them
+fn user_tag(user_id: &UserId) -> String {
+    let hash = blake3::hash(user_id);
+    hash.as_bytes()[..8]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Decode a `GetAll` page cursor, the big-endian id of the last record already returned
+fn parse_cursor(cursor: &[u8]) -> Result<Option<u64>, Status> {
+    if cursor.is_empty() {
+        return Ok(None);
+    }
+    let bytes: [u8; 8] = cursor
+        .try_into()
+        .map_err(|_| Status::invalid_argument("Invalid page cursor"))?;
+    Ok(Some(u64::from_be_bytes(bytes)))
+}
+
+fn current_timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn read_timestamp(tree: &sled::Tree, user_id: &UserId) -> sled::Result<Option<u64>> {
+    Ok(tree.get(user_id)?.and_then(|value| {
+        <[u8; 8]>::try_from(value.as_ref())
+            .ok()
+            .map(u64::from_be_bytes)
+    }))
+}
+
+fn dir_size(path: &Path) -> std::io::Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+    Ok(size)
+}
+
+#[tonic::async_trait]
+impl RpcPassmgrAdmin for PassmgrService {
+    #[tracing::instrument(skip_all, fields(user = Empty, nonce = Empty), err(level = "warn"))]
+    async fn list_users(
+        &self,
+        _request: Request<ListUsersRequest>,
+    ) -> Result<Response<ListUsersResponse>, Status> {
+        let mut users = Vec::new();
+        for item in self.auth_db.iter() {
+            let (key, _) = item.map_err(|e| Status::internal(e.to_string()))?;
+            let Ok(user_id) = UserId::try_from(key.as_ref()) else {
+                continue;
+            };
+
+            let user_data_dir = self.user_data_dir(&user_id);
+            let (record_count, storage_bytes) = if user_data_dir.exists() {
+                let storage = self.get_user_storage(user_id)?;
+                let record_count = storage
+                    .list_ids()
+                    .map_err(|e| Status::internal(e.to_string()))?
+                    .len() as u64;
+                let storage_bytes =
+                    dir_size(&user_data_dir).map_err(|e| Status::internal(e.to_string()))?;
+                (record_count, storage_bytes)
+            } else {
+                (0, 0)
+            };
+
+            users.push(UserSummary {
+                user_id: user_id.to_vec(),
+                record_count,
+                storage_bytes,
+            });
+        }
+
+        Ok(Response::new(ListUsersResponse { users }))
     }
 }
 
 #[tonic::async_trait]
 impl RpcPassmgr for PassmgrService {
+    #[tracing::instrument(skip_all, fields(user = Empty, nonce = Empty), err(level = "warn"))]
     async fn register(
         &self,
         request: Request<RegisterRequest>,
@@ -121,6 +600,7 @@ impl RpcPassmgr for PassmgrService {
             .as_slice()
             .try_into()
             .map_err(|_| Status::invalid_argument("Invalid user_id length"))?;
+        Span::current().record("user", user_tag(&user_id).as_str());
 
         if self
             .auth_db
@@ -130,25 +610,26 @@ impl RpcPassmgr for PassmgrService {
         {
             return Err(Status::already_exists("User already registered"));
         }
-        let nonce: u64 = rand::thread_rng().gen();
+        // Leave the reserve free so a new user can't start out exhausted
+        let nonce: u64 = rand::thread_rng().gen_range(0..u64::MAX - Nonce::RESERVE);
         let auth_entry = AuthEntry {
             public_key: req.pub_key,
-            nonce,
+            nonce: Nonce::new(nonce),
         };
 
         self.auth_db
             .insert(user_id.to_vec(), serialize(&auth_entry).unwrap())
             .map_err(|e| Status::internal(format!("Failed to register user: {}", e)))?;
+        self.deregistered
+            .remove(user_id)
+            .map_err(|e| Status::internal(format!("Failed to register user: {}", e)))?;
 
-        let hex_id = user_id.iter().fold(String::new(), |mut acc, b| {
-            acc.push_str(&format!("{:02x}", b));
-            acc
-        });
-
-        let user_data_dir = self.data_dir.join(hex_id);
+        let user_data_dir = self.user_data_dir(&user_id);
         std::fs::create_dir_all(&user_data_dir).map_err(|e| {
             Status::internal(format!("Failed to create user data directory: {}", e))
         })?;
+        self.record_activity(&user_id)?;
+        info!("registered");
 
         Ok(Response::new(RegisterResponse {
             success: true,
@@ -156,6 +637,22 @@ impl RpcPassmgr for PassmgrService {
         }))
     }
 
+    async fn health(
+        &self,
+        _request: Request<HealthRequest>,
+    ) -> Result<Response<HealthResponse>, Status> {
+        let status = if self.is_healthy() {
+            ServingStatus::Serving
+        } else {
+            warn!("health check failed");
+            ServingStatus::NotServing
+        };
+        Ok(Response::new(HealthResponse {
+            status: status.into(),
+        }))
+    }
+
+    #[tracing::instrument(skip_all, fields(user = Empty, nonce = Empty), err(level = "warn"))]
     async fn get_nonce(
         &self,
         request: Request<GetNonceRequest>,
@@ -164,21 +661,49 @@ impl RpcPassmgr for PassmgrService {
         let user_id: UserId = req.user_id[..]
             .try_into()
             .map_err(|_| Status::invalid_argument("Invalid user_id length"))?;
+        Span::current().record("user", user_tag(&user_id).as_str());
+        let now = current_timestamp();
+
+        // Only the key owner learns the nonce, anyone else gets a challenge. Issued
+        // without looking the user up, so the answer doesn't tell who is registered,
+        // and without keeping anything, so asking for many costs the server nothing.
+        if req.signature.is_empty() {
+            return Ok(Response::new(GetNonceResponse {
+                nonce: 0,
+                challenge: self.nonce_challenge(&user_id, now + NONCE_CHALLENGE_TTL_SECS),
+            }));
+        }
+
+        self.check_lockout(&user_id, now)?;
+        // Reusable until it expires, answering again only returns the nonce again
+        if !self.check_nonce_challenge(&user_id, &req.challenge, now) {
+            return Err(Status::permission_denied("Unknown or expired challenge"));
+        }
 
+        // An unknown user fails like a bad signature
         let auth_entry_bytes = self
             .auth_db
             .get(&user_id)
             .map_err(|e| Status::internal(format!("Failed to retrieve user: {}", e)))?
-            .ok_or_else(|| Status::not_found("User not found"))?;
-
+            .ok_or_else(|| Status::unauthenticated("Invalid signature"))?;
         let auth_entry: AuthEntry = deserialize(&auth_entry_bytes)
             .map_err(|_| Status::internal("Auth entry deserialization failed"))?;
 
+        let public_key = dilithium2::PublicKey::from_bytes(&auth_entry.public_key);
+        let payload = nonce_challenge_payload(&req.user_id, &req.challenge);
+        // Not counted toward the lockout, or anyone knowing a user id could lock it
+        if !public_key.verify(&payload, &req.signature) {
+            warn!(method = "GetNonce", "authentication failed");
+            return Err(Status::unauthenticated("Invalid signature"));
+        }
+
         Ok(Response::new(GetNonceResponse {
-            nonce: auth_entry.nonce,
+            nonce: auth_entry.nonce.get(),
+            challenge: Vec::new(),
         }))
     }
 
+    #[tracing::instrument(skip_all, fields(user = Empty, nonce = Empty), err(level = "warn"))]
     async fn get_list(
         &self,
         request: Request<GetListRequest>,
@@ -187,7 +712,7 @@ impl RpcPassmgr for PassmgrService {
         let mut cloned_req = req.clone();
         cloned_req.auth = None;
 
-        let user_id = self.validate_auth(
+        let (user_id, nonce) = self.validate_auth(
             req.auth
                 .as_ref()
                 .ok_or_else(|| Status::invalid_argument("Missing auth"))?,
@@ -203,16 +728,83 @@ impl RpcPassmgr for PassmgrService {
 
         let record_i_ds = records
             .into_iter()
-            .map(|(id, ver, _)| RecordId {
+            .map(|(id, ver, updated)| RecordId {
                 id,
                 ver,
                 user_id: user_id.to_vec(),
+                updated,
             })
             .collect();
 
-        Ok(Response::new(RecordListResponse { record_i_ds }))
+        Ok(Response::new(RecordListResponse { record_i_ds, nonce }))
     }
 
+    #[tracing::instrument(skip_all, fields(user = Empty, nonce = Empty), err(level = "warn"))]
+    async fn get_stats(
+        &self,
+        request: Request<GetStatsRequest>,
+    ) -> Result<Response<StatsResponse>, Status> {
+        let req = request.into_inner();
+        let mut cloned_req = req.clone();
+        cloned_req.auth = None;
+
+        let (user_id, nonce) = self.validate_auth(
+            req.auth
+                .as_ref()
+                .ok_or_else(|| Status::invalid_argument("Missing auth"))?,
+            &cloned_req,
+            "GetStats",
+        )?;
+
+        let storage = self.get_user_storage(user_id)?;
+        let records = storage
+            .list_ids_with_metadata()
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        Ok(Response::new(StatsResponse {
+            record_count: records.len() as u64,
+            max_ver: records.iter().map(|(_, ver, _)| *ver).max().unwrap_or(0),
+            nonce,
+        }))
+    }
+
+    #[tracing::instrument(skip_all, fields(user = Empty, nonce = Empty), err(level = "warn"))]
+    async fn list_changed_since(
+        &self,
+        request: Request<ListChangedSinceRequest>,
+    ) -> Result<Response<RecordListResponse>, Status> {
+        let req = request.into_inner();
+        let mut cloned_req = req.clone();
+        cloned_req.auth = None;
+
+        let (user_id, nonce) = self.validate_auth(
+            req.auth
+                .as_ref()
+                .ok_or_else(|| Status::invalid_argument("Missing auth"))?,
+            &cloned_req,
+            "ListChangedSince",
+        )?;
+
+        let storage = self.get_user_storage(user_id)?;
+
+        let records = storage
+            .list_changed_since(req.since)
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        let record_i_ds = records
+            .into_iter()
+            .map(|(id, ver, updated)| RecordId {
+                id,
+                ver,
+                user_id: user_id.to_vec(),
+                updated,
+            })
+            .collect();
+
+        Ok(Response::new(RecordListResponse { record_i_ds, nonce }))
+    }
+
+    #[tracing::instrument(skip_all, fields(user = Empty, nonce = Empty), err(level = "warn"))]
     async fn get_by_id(
         &self,
         request: Request<GetByIdRequest>,
@@ -221,7 +813,7 @@ impl RpcPassmgr for PassmgrService {
         let mut cloned_req = req.clone();
         cloned_req.auth = None;
 
-        let user_id = self.validate_auth(
+        let (user_id, nonce) = self.validate_auth(
             req.auth
                 .as_ref()
                 .ok_or_else(|| Status::invalid_argument("Missing auth"))?,
@@ -242,10 +834,15 @@ impl RpcPassmgr for PassmgrService {
                 ver: record.ver,
                 user_id: user_id.to_vec(),
                 data: record.data,
+                signature: record.signature,
+                updated: record.updated,
+                cipher_options: record.cipher_options,
             }),
+            nonce,
         }))
     }
 
+    #[tracing::instrument(skip_all, fields(user = Empty, nonce = Empty), err(level = "warn"))]
     async fn get_all(
         &self,
         request: Request<GetAllRequest>,
@@ -254,7 +851,7 @@ impl RpcPassmgr for PassmgrService {
         let mut cloned_req = req.clone();
         cloned_req.auth = None;
 
-        let user_id = self.validate_auth(
+        let (user_id, nonce) = self.validate_auth(
             req.auth
                 .as_ref()
                 .ok_or_else(|| Status::invalid_argument("Missing auth"))?,
@@ -264,26 +861,111 @@ impl RpcPassmgr for PassmgrService {
 
         let storage = self.get_user_storage(user_id)?;
 
-        let record_ids = storage
-            .list_ids()
-            .map_err(|e| Status::internal(e.to_string()))?;
-
-        let mut records: Vec<Record> = Vec::new();
-        for record_id in record_ids {
-            let record = storage
-                .get(record_id)
+        // Pages follow the tree's record id order, so records inserted meanwhile
+        // can't shift a cursor
+        let (record_ids, next_cursor) = if req.limit == 0 {
+            let record_ids = storage
+                .list_ids()
+                .map_err(|e| Status::internal(e.to_string()))?;
+            (record_ids, Vec::new())
+        } else {
+            let limit = req.limit.min(GET_ALL_MAX_PAGE) as usize;
+            // One extra id tells whether another page follows
+            let mut record_ids = storage
+                .list_ids_page(parse_cursor(&req.cursor)?, limit + 1)
                 .map_err(|e| Status::internal(e.to_string()))?;
-            let new_record = Record {
+            let next_cursor = if record_ids.len() > limit {
+                record_ids.truncate(limit);
+                record_ids[limit - 1].to_be_bytes().to_vec()
+            } else {
+                Vec::new()
+            };
+            (record_ids, next_cursor)
+        };
+
+        // Records deleted since the ids were listed are left out
+        let records = storage
+            .get_many(&record_ids)
+            .map_err(|e| Status::internal(e.to_string()))?
+            .into_iter()
+            .map(|(_, record)| Record {
                 id: record.cipher_record_id,
                 ver: record.ver,
                 user_id: user_id.to_vec(),
                 data: record.data,
-            };
-            records.push(new_record);
-        }
-        Ok(Response::new(RecordsResponse { records }))
+                signature: record.signature,
+                updated: record.updated,
+                cipher_options: record.cipher_options,
+            })
+            .collect();
+        Ok(Response::new(RecordsResponse {
+            records,
+            next_cursor,
+            nonce,
+        }))
+    }
+
+    type GetAllStreamStream = ReceiverStream<Result<Record, Status>>;
+
+    #[tracing::instrument(skip_all, fields(user = Empty, nonce = Empty), err(level = "warn"))]
+    async fn get_all_stream(
+        &self,
+        request: Request<GetAllRequest>,
+    ) -> Result<Response<Self::GetAllStreamStream>, Status> {
+        let req = request.into_inner();
+        let mut cloned_req = req.clone();
+        cloned_req.auth = None;
+
+        let (user_id, nonce) = self.validate_auth(
+            req.auth
+                .as_ref()
+                .ok_or_else(|| Status::invalid_argument("Missing auth"))?,
+            &cloned_req,
+            "GetAllStream",
+        )?;
+
+        let storage = self.get_user_storage(user_id)?;
+        let record_ids = storage
+            .list_ids()
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        // The bounded channel holds back reads until the client catches up
+        let (tx, rx) = mpsc::channel(GET_ALL_STREAM_BUFFER);
+        tokio::spawn(async move {
+            for record_id in record_ids {
+                let item = match storage.get(record_id) {
+                    // Deleted since the ids were listed, as get_many skips it
+                    Err(StorageError::StorageDataNotFound(_)) => continue,
+                    other => other,
+                };
+                let item = item
+                    .map(|record| Record {
+                        id: record.cipher_record_id,
+                        ver: record.ver,
+                        user_id: user_id.to_vec(),
+                        data: record.data,
+                        signature: record.signature,
+                        updated: record.updated,
+                        cipher_options: record.cipher_options,
+                    })
+                    .map_err(|e| Status::internal(e.to_string()));
+                let failed = item.is_err();
+                // A closed channel means the client went away
+                if tx.send(item).await.is_err() || failed {
+                    return;
+                }
+            }
+        });
+
+        // Headers go out before the first record, so the client has the nonce early
+        let mut response = Response::new(ReceiverStream::new(rx));
+        response
+            .metadata_mut()
+            .insert(NONCE_METADATA_KEY, nonce.into());
+        Ok(response)
     }
 
+    #[tracing::instrument(skip_all, fields(user = Empty, nonce = Empty), err(level = "warn"))]
     async fn set_one(
         &self,
         request: Request<SetOneRequest>,
@@ -292,7 +974,7 @@ impl RpcPassmgr for PassmgrService {
         let mut cloned_req = req.clone();
         cloned_req.auth = None;
 
-        let user_id = self.validate_auth(
+        let (user_id, nonce) = self.validate_auth(
             req.auth
                 .as_ref()
                 .ok_or_else(|| Status::invalid_argument("Missing auth"))?,
@@ -300,26 +982,50 @@ impl RpcPassmgr for PassmgrService {
             "SetOne",
         )?;
 
+        // A retry gets the cached outcome but must sign its next request with the fresh nonce
+        if let Some(mut response) = self.cached_response::<SetOneResponse, _>(
+            &user_id,
+            "SetOne",
+            &req.idempotency_key,
+            &cloned_req,
+        )? {
+            response.nonce = nonce;
+            return Ok(Response::new(response));
+        }
+
         let storage = self.get_user_storage(user_id)?;
 
         let record = req
             .record
             .ok_or(Status::invalid_argument("Missing record"))?;
+        Self::check_record_owner(&record, &user_id)?;
+
         let cipher_record = storage::structures::CipherRecord {
             user_id,
             cipher_record_id: record.id,
             ver: record.ver,
-            cipher_options: vec![], // Adjust based on client's cipher chain
+            cipher_options: record.cipher_options,
             data: record.data,
+            signature: record.signature,
+            updated: record.updated,
         };
 
         storage
             .set(record.id, &cipher_record)
             .map_err(|e| Status::internal(e.to_string()))?;
 
-        Ok(Response::new(SetOneResponse {}))
+        let response = SetOneResponse { nonce };
+        self.remember_response(
+            user_id,
+            "SetOne",
+            &req.idempotency_key,
+            &cloned_req,
+            &response,
+        );
+        Ok(Response::new(response))
     }
 
+    #[tracing::instrument(skip_all, fields(user = Empty, nonce = Empty), err(level = "warn"))]
     async fn set_records(
         &self,
         request: Request<SetRecordsRequest>,
@@ -328,7 +1034,7 @@ impl RpcPassmgr for PassmgrService {
         let mut cloned_req = req.clone();
         cloned_req.auth = None;
 
-        let user_id = self.validate_auth(
+        let (user_id, nonce) = self.validate_auth(
             req.auth
                 .as_ref()
                 .ok_or_else(|| Status::invalid_argument("Missing auth"))?,
@@ -336,23 +1042,55 @@ impl RpcPassmgr for PassmgrService {
             "SetRecords",
         )?;
 
-        let storage = self.get_user_storage(user_id)?;
+        if let Some(mut response) = self.cached_response::<SetRecordsResponse, _>(
+            &user_id,
+            "SetRecords",
+            &req.idempotency_key,
+            &cloned_req,
+        )? {
+            response.nonce = nonce;
+            return Ok(Response::new(response));
+        }
 
-        for record in req.records {
-            let cipher_record = storage::structures::CipherRecord {
-                user_id,
-                cipher_record_id: record.id,
-                ver: record.ver,
-                cipher_options: vec![], // Adjust based on client's cipher chain
-                data: record.data,
-            };
-            storage
-                .set(record.id, &cipher_record)
-                .map_err(|e| Status::internal(e.to_string()))?;
+        for record in &req.records {
+            Self::check_record_owner(record, &user_id)?;
         }
-        Ok(Response::new(SetRecordsResponse {}))
+
+        let storage = self.get_user_storage(user_id)?;
+
+        let records: Vec<_> = req
+            .records
+            .into_iter()
+            .map(|record| {
+                let cipher_record = storage::structures::CipherRecord {
+                    user_id,
+                    cipher_record_id: record.id,
+                    ver: record.ver,
+                    cipher_options: record.cipher_options,
+                    data: record.data,
+                    signature: record.signature,
+                    updated: record.updated,
+                };
+                (record.id, cipher_record)
+            })
+            .collect();
+        // All or nothing, a failed batch leaves no partial writes behind
+        storage
+            .set_batch(&records)
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        let response = SetRecordsResponse { nonce };
+        self.remember_response(
+            user_id,
+            "SetRecords",
+            &req.idempotency_key,
+            &cloned_req,
+            &response,
+        );
+        Ok(Response::new(response))
     }
 
+    #[tracing::instrument(skip_all, fields(user = Empty, nonce = Empty), err(level = "warn"))]
     async fn delete_by_id(
         &self,
         request: Request<DeleteByIdRequest>,
@@ -361,7 +1099,7 @@ impl RpcPassmgr for PassmgrService {
         let mut cloned_req = req.clone();
         cloned_req.auth = None;
 
-        let user_id = self.validate_auth(
+        let (user_id, nonce) = self.validate_auth(
             req.auth
                 .as_ref()
                 .ok_or_else(|| Status::invalid_argument("Missing auth"))?,
@@ -369,15 +1107,34 @@ impl RpcPassmgr for PassmgrService {
             "DeleteById",
         )?;
 
+        if let Some(mut response) = self.cached_response::<DeleteResponse, _>(
+            &user_id,
+            "DeleteById",
+            &req.idempotency_key,
+            &cloned_req,
+        )? {
+            response.nonce = nonce;
+            return Ok(Response::new(response));
+        }
+
         let storage = self.get_user_storage(user_id)?;
 
         storage
             .remove(req.record_id)
             .map_err(|e| Status::internal(e.to_string()))?;
 
-        Ok(Response::new(DeleteResponse {}))
+        let response = DeleteResponse { nonce };
+        self.remember_response(
+            user_id,
+            "DeleteById",
+            &req.idempotency_key,
+            &cloned_req,
+            &response,
+        );
+        Ok(Response::new(response))
     }
 
+    #[tracing::instrument(skip_all, fields(user = Empty, nonce = Empty), err(level = "warn"))]
     async fn delete_all(
         &self,
         request: Request<DeleteAllRequest>,
@@ -386,7 +1143,7 @@ impl RpcPassmgr for PassmgrService {
         let mut cloned_req = req.clone();
         cloned_req.auth = None;
 
-        let user_id = self.validate_auth(
+        let (user_id, nonce) = self.validate_auth(
             req.auth
                 .as_ref()
                 .ok_or_else(|| Status::invalid_argument("Missing auth"))?,
@@ -394,6 +1151,16 @@ impl RpcPassmgr for PassmgrService {
             "DeleteAll",
         )?;
 
+        if let Some(mut response) = self.cached_response::<DeleteResponse, _>(
+            &user_id,
+            "DeleteAll",
+            &req.idempotency_key,
+            &cloned_req,
+        )? {
+            response.nonce = nonce;
+            return Ok(Response::new(response));
+        }
+
         let storage = self.get_user_storage(user_id)?;
         let records = storage
             .list_ids()
@@ -403,30 +1170,1328 @@ impl RpcPassmgr for PassmgrService {
                 .remove(record_id)
                 .map_err(|e| Status::internal(e.to_string()))?;
         }
-        Ok(Response::new(DeleteResponse {}))
+
+        let response = DeleteResponse { nonce };
+        self.remember_response(
+            user_id,
+            "DeleteAll",
+            &req.idempotency_key,
+            &cloned_req,
+            &response,
+        );
+        Ok(Response::new(response))
+    }
+
+    #[tracing::instrument(skip_all, fields(user = Empty, nonce = Empty), err(level = "warn"))]
+    async fn deregister(
+        &self,
+        request: Request<DeregisterRequest>,
+    ) -> Result<Response<DeregisterResponse>, Status> {
+        let req = request.into_inner();
+        let mut cloned_req = req.clone();
+        cloned_req.auth = None;
+
+        let auth = req
+            .auth
+            .as_ref()
+            .ok_or_else(|| Status::invalid_argument("Missing auth"))?;
+        let (user_id, _) = match self.validate_auth(auth, &cloned_req, "Deregister") {
+            Ok(authenticated) => authenticated,
+            // Already deregistered, e.g. a retry after a lost response
+            Err(_) if self.is_deregister_retry(auth, &cloned_req) => {
+                return Ok(Response::new(DeregisterResponse {}));
+            }
+            Err(status) => return Err(status),
+        };
+
+        let auth_entry = self
+            .auth_db
+            .get(user_id)
+            .map_err(|e| Status::internal(format!("Failed to deregister user: {}", e)))?;
+        if let Some(auth_entry) = auth_entry {
+            self.deregistered
+                .insert(user_id, auth_entry)
+                .map_err(|e| Status::internal(format!("Failed to deregister user: {}", e)))?;
+        }
+        self.purge_user(&user_id)
+            .map_err(|e| Status::internal(format!("Failed to deregister user: {}", e)))?;
+        info!("deregistered");
+        Ok(Response::new(DeregisterResponse {}))
+    }
+}
+
+#[derive(Parser, Clone, Debug)]
+#[clap(author, version, about, long_about = None)]
+pub struct Opt {
+    /// Address to serve the public RPC service on
+    #[clap(long, default_value = "0.0.0.0:50051")]
+    pub addr: SocketAddr,
+
+    /// Directory of the authentication database, defaults to `auth_db` in the user data dir
+    #[clap(long)]
+    pub auth_db: Option<PathBuf>,
+
+    /// Directory holding the per-user record stores, defaults to `data` in the user data dir
+    #[clap(long)]
+    pub data_dir: Option<PathBuf>,
+}
+
+fn default_path(name: &str) -> PathBuf {
+    dirs::data_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join(name)
+}
+
+/// Create `dir` if needed and prove it takes writes, so a read-only mount fails at startup
+/// instead of on the first record
+fn check_writable(dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let probe = dir.join(".passmgr-write-check");
+    ensure_dir(dir)
+        .map_err(|e| e.to_string())
+        .and_then(|_| std::fs::write(&probe, b"").map_err(|e| e.to_string()))
+        .and_then(|_| std::fs::remove_file(&probe).map_err(|e| e.to_string()))
+        .map_err(|e| format!("{} is not writable: {}", dir.display(), e).into())
+}
+
+fn open_service(opt: &Opt) -> Result<PassmgrService, Box<dyn std::error::Error>> {
+    let auth_db_path = opt
+        .auth_db
+        .clone()
+        .unwrap_or_else(|| default_path("auth_db"));
+    let data_dir = opt.data_dir.clone().unwrap_or_else(|| default_path("data"));
+    check_writable(&auth_db_path)?;
+    check_writable(&data_dir)?;
+    Ok(PassmgrService::new(auth_db_path, data_dir)?)
+}
+
+/// TLS identity from the PEM files at PASSMGR_TLS_CERT and PASSMGR_TLS_KEY. Serving
+/// plaintext instead takes an explicit PASSMGR_PLAINTEXT=1, meant for local development.
+fn tls_config_from_env() -> Result<Option<ServerTlsConfig>, Box<dyn std::error::Error>> {
+    match (
+        std::env::var("PASSMGR_TLS_CERT"),
+        std::env::var("PASSMGR_TLS_KEY"),
+    ) {
+        (Ok(cert), Ok(key)) => {
+            let identity = Identity::from_pem(std::fs::read(cert)?, std::fs::read(key)?);
+            Ok(Some(ServerTlsConfig::new().identity(identity)))
+        }
+        (Err(_), Err(_)) if std::env::var("PASSMGR_PLAINTEXT").is_ok_and(|v| v == "1") => Ok(None),
+        (Err(_), Err(_)) => Err(
+            "Set PASSMGR_TLS_CERT and PASSMGR_TLS_KEY, or PASSMGR_PLAINTEXT=1 for local development"
+                .into(),
+        ),
+        _ => Err("PASSMGR_TLS_CERT and PASSMGR_TLS_KEY must be set together".into()),
     }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let auth_db_path = dirs::data_dir()
-        .unwrap_or_else(|| PathBuf::from("."))
-        .join("auth_db");
-    let data_dir = dirs::data_dir()
-        .unwrap_or_else(|| PathBuf::from("."))
-        .join("data");
+    // Level and targets from PASSMGR_LOG, e.g. PASSMGR_LOG=debug
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_env("PASSMGR_LOG").unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+
+    let opt = Opt::parse();
+    let tls = tls_config_from_env()?;
+
+    let mut service = open_service(&opt)?;
+    // Deduplicated storage for users whose store is still empty
+    service.content_addressed = std::env::var("PASSMGR_CONTENT_ADDRESSED").is_ok_and(|v| v == "1");
+    // Purge accounts inactive for PASSMGR_RETENTION_DAYS, after a grace window
+    if let Ok(days) = std::env::var("PASSMGR_RETENTION_DAYS") {
+        let grace_days: u64 =
+            std::env::var("PASSMGR_RETENTION_GRACE_DAYS").map_or(Ok(30), |v| v.parse())?;
+        service.retention = Some(RetentionPolicy {
+            inactive_secs: days.parse::<u64>()? * DAY_SECS,
+            grace_secs: grace_days * DAY_SECS,
+        });
+        info!(
+            "Retention enabled: accounts inactive for {} days are purged after {} more days",
+            days, grace_days
+        );
+    }
 
-    let service = PassmgrService::new(auth_db_path, data_dir)?;
+    let service = Arc::new(service);
+
+    if service.retention.is_some() {
+        let service = service.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(
+                RETENTION_SWEEP_INTERVAL_SECS,
+            ));
+            loop {
+                interval.tick().await;
+                if let Err(e) = service.apply_retention(current_timestamp()) {
+                    error!("Retention sweep failed: {}", e);
+                }
+            }
+        });
+    }
 
-    let addr = "0.0.0.0:50051".parse()?;
-    let server = RpcPassmgrServer::new(service);
+    let listener = tokio::net::TcpListener::bind(opt.addr).await?;
+    let addr = listener.local_addr()?;
 
-    println!("Server listening on {}", addr);
+    // The admin service has no per-user auth, so it is only ever bound to loopback
+    let admin_addr: SocketAddr = std::env::var("PASSMGR_ADMIN_ADDR")
+        .unwrap_or_else(|_| "127.0.0.1:50052".to_string())
+        .parse()?;
+    if !admin_addr.ip().is_loopback() {
+        return Err("PASSMGR_ADMIN_ADDR must be a loopback address".into());
+    }
+    let admin_listener = tokio::net::TcpListener::bind(admin_addr).await?;
 
-    tonic::transport::Server::builder()
-        .add_service(server)
-        .serve(addr)
-        .await?;
+    let mut builder = tonic::transport::Server::builder();
+    match tls {
+        Some(tls) => {
+            builder = builder.tls_config(tls)?;
+            info!("Server listening on {} (TLS)", addr);
+        }
+        None => info!(
+            "Server listening on {} (plaintext, local development only)",
+            addr
+        ),
+    }
+    info!("Admin service listening on {}", admin_addr);
+
+    serve(
+        service,
+        builder,
+        listener,
+        admin_listener,
+        shutdown_signal(),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Resolves on SIGINT (Ctrl-C) or SIGTERM
+async fn shutdown_signal() {
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                error!("Can't listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await
+            }
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("Received SIGINT"),
+        _ = terminate => info!("Received SIGTERM"),
+    }
+}
 
+/// Serve both services until `shutdown` resolves, let in-flight requests finish,
+/// then flush the auth database so no acknowledged write is lost
+async fn serve(
+    service: Arc<PassmgrService>,
+    mut builder: tonic::transport::Server,
+    listener: tokio::net::TcpListener,
+    admin_listener: tokio::net::TcpListener,
+    shutdown: impl std::future::Future<Output = ()>,
+) -> anyhow::Result<()> {
+    let (stop_tx, stop_rx) = tokio::sync::watch::channel(());
+    let stopped = |mut stop_rx: tokio::sync::watch::Receiver<()>| async move {
+        let _ = stop_rx.changed().await;
+    };
+
+    // The admin service stays plaintext, it never leaves loopback
+    tokio::try_join!(
+        builder
+            .add_service(RpcPassmgrServer::from_arc(service.clone()))
+            .serve_with_incoming_shutdown(
+                tokio_stream::wrappers::TcpListenerStream::new(listener),
+                stopped(stop_rx.clone()),
+            ),
+        tonic::transport::Server::builder()
+            .add_service(RpcPassmgrAdminServer::from_arc(service.clone()))
+            .serve_with_incoming_shutdown(
+                tokio_stream::wrappers::TcpListenerStream::new(admin_listener),
+                stopped(stop_rx),
+            ),
+        async {
+            shutdown.await;
+            info!("Shutting down, waiting for in-flight requests");
+            let _ = stop_tx.send(());
+            Ok::<(), tonic::transport::Error>(())
+        },
+    )?;
+
+    service.flush()?;
+    info!("Shutdown complete");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::{AssymetricKeypair, RequestSigner};
+    use prost::Message;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tempdir::TempDir;
+    use tokio_stream::StreamExt;
+
+    struct TestUser {
+        user_id: UserId,
+        signer: Box<dyn RequestSigner>,
+        nonce: u64,
+    }
+
+    fn test_service(tmp_dir: &TempDir) -> PassmgrService {
+        PassmgrService::new(tmp_dir.path().join("auth_db"), tmp_dir.path().join("data")).unwrap()
+    }
+
+    async fn register_user(service: &PassmgrService, seed: u8) -> TestUser {
+        let keys = AssymetricKeypair::generate_dilithium2(&[seed; 32]);
+        register_user_with_signer(service, [seed; 32], Box::new(keys)).await
+    }
+
+    async fn register_user_with_signer(
+        service: &PassmgrService,
+        user_id: UserId,
+        signer: Box<dyn RequestSigner>,
+    ) -> TestUser {
+        let response = service
+            .register(Request::new(RegisterRequest {
+                user_id: user_id.to_vec(),
+                pub_key: signer.public_key(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+
+        TestUser {
+            user_id,
+            signer,
+            nonce: response.nonce,
+        }
+    }
+
+    fn find_challenge(user_id: &[u8], nonce: u64) -> (u64, [u8; 32]) {
+        for challenge_num in 0u64.. {
+            let mut hasher = Hasher::new();
+            hasher.update(&nonce.to_be_bytes());
+            hasher.update(&challenge_num.to_be_bytes());
+            hasher.update(user_id);
+            let hash = hasher.finalize();
+
+            if hash.as_bytes()[31] & ((1 << CHALLENGE_ZERO_BITS) - 1) == 0 {
+                return (challenge_num, *hash.as_bytes());
+            }
+        }
+        unreachable!("u64::MAX should be enough");
+    }
+
+    // Signs with the user's current nonce and advances it, as the server will
+    fn sign<T: Message>(user: &mut TestUser, request: &T, method_name: &str) -> AuthSignature {
+        let (challenge_num, challenge) = find_challenge(&user.user_id, user.nonce);
+
+        let sign_data = signing_payload(method_name, user.nonce, &request.encode_to_vec());
+
+        let auth = AuthSignature {
+            user_id: user.user_id.to_vec(),
+            nonce: user.nonce,
+            signature: user.signer.sign(&sign_data),
+            challenge_num,
+            challenge: challenge.to_vec(),
+        };
+        user.nonce += 1;
+        auth
+    }
+
+    fn test_record(id: u64, user_id: &[u8]) -> Record {
+        Record {
+            id,
+            ver: 1,
+            user_id: user_id.to_vec(),
+            data: vec![0, 42, 0, 42],
+            signature: vec![],
+            updated: 0,
+            cipher_options: vec![1, 13],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_set_one_rejects_mislabeled_record() {
+        let tmp_dir = TempDir::new("server_test").unwrap();
+        let service = test_service(&tmp_dir);
+        let mut user = register_user(&service, 1).await;
+
+        let mut request = SetOneRequest {
+            auth: None,
+            record: Some(test_record(1, &[2; 32])),
+            idempotency_key: String::new(),
+        };
+        request.auth = Some(sign(&mut user, &request, "SetOne"));
+
+        let status = service.set_one(Request::new(request)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn test_set_records_rejects_mislabeled_record() {
+        let tmp_dir = TempDir::new("server_test").unwrap();
+        let service = test_service(&tmp_dir);
+        let mut user = register_user(&service, 1).await;
+
+        let mut request = SetRecordsRequest {
+            auth: None,
+            records: vec![test_record(1, &user.user_id), test_record(2, &[2; 32])],
+            idempotency_key: String::new(),
+        };
+        request.auth = Some(sign(&mut user, &request, "SetRecords"));
+
+        let status = service
+            .set_records(Request::new(request))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+        // Nothing from the rejected batch may have been written
+        let storage = service.get_user_storage(user.user_id).unwrap();
+        assert!(storage.list_ids().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_idempotent_set_one_replay() {
+        let tmp_dir = TempDir::new("server_test").unwrap();
+        let service = test_service(&tmp_dir);
+        let mut user = register_user(&service, 1).await;
+
+        let mut first = SetOneRequest {
+            auth: None,
+            record: Some(test_record(7, &user.user_id)),
+            idempotency_key: "5f0c6b8e-2a4e-4c1f-9d43-1f6c1c2b9a11".to_string(),
+        };
+        first.auth = Some(sign(&mut user, &first, "SetOne"));
+        service.set_one(Request::new(first.clone())).await.unwrap();
+
+        // A later write under a different key changes the stored record
+        let mut newer_record = test_record(7, &user.user_id);
+        newer_record.data = vec![1, 2, 3];
+        let mut second = SetOneRequest {
+            auth: None,
+            record: Some(newer_record),
+            idempotency_key: "0e7d1c55-8f1b-4b7a-a3a2-6a5d0c7e3f20".to_string(),
+        };
+        second.auth = Some(sign(&mut user, &second, "SetOne"));
+        service.set_one(Request::new(second)).await.unwrap();
+
+        // Retrying the first request (re-signed, same key) must not re-apply it
+        let mut replay = first;
+        replay.auth = None;
+        replay.auth = Some(sign(&mut user, &replay, "SetOne"));
+        let replay_response = service
+            .set_one(Request::new(replay))
+            .await
+            .unwrap()
+            .into_inner();
+        // The cached outcome still carries the nonce that follows this retry
+        assert_eq!(replay_response, SetOneResponse { nonce: user.nonce });
+
+        let storage = service.get_user_storage(user.user_id).unwrap();
+        assert_eq!(storage.get(7).unwrap().data, vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_key_reuse() {
+        let tmp_dir = TempDir::new("server_test").unwrap();
+        let service = test_service(&tmp_dir);
+        let mut user = register_user(&service, 1).await;
+        let key = "9b2f4a63-1c7e-4d58-8e0a-3f6d2b7c9e14".to_string();
+
+        let mut first = SetOneRequest {
+            auth: None,
+            record: Some(test_record(7, &user.user_id)),
+            idempotency_key: key.clone(),
+        };
+        first.auth = Some(sign(&mut user, &first, "SetOne"));
+        service.set_one(Request::new(first)).await.unwrap();
+
+        // The same key with another record is an error, not the old success
+        let mut other_record = test_record(7, &user.user_id);
+        other_record.data = vec![1, 2, 3];
+        let mut reused = SetOneRequest {
+            auth: None,
+            record: Some(other_record),
+            idempotency_key: key.clone(),
+        };
+        reused.auth = Some(sign(&mut user, &reused, "SetOne"));
+        let status = service.set_one(Request::new(reused)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        // Keys are scoped by method, a delete under the same key is applied
+        let mut delete = DeleteByIdRequest {
+            auth: None,
+            record_id: 7,
+            idempotency_key: key,
+        };
+        delete.auth = Some(sign(&mut user, &delete, "DeleteById"));
+        service.delete_by_id(Request::new(delete)).await.unwrap();
+
+        let storage = service.get_user_storage(user.user_id).unwrap();
+        assert!(storage.list_ids().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_signed_request_replay_rejected() {
+        let tmp_dir = TempDir::new("server_test").unwrap();
+        let service = test_service(&tmp_dir);
+        let mut user = register_user(&service, 1).await;
+
+        let mut request = GetListRequest { auth: None };
+        request.auth = Some(sign(&mut user, &request, "GetList"));
+        service
+            .get_list(Request::new(request.clone()))
+            .await
+            .unwrap();
+
+        let status = service.get_list(Request::new(request)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert_eq!(status.message(), "Invalid nonce");
+
+        // The next nonce is still accepted
+        let mut request = GetListRequest { auth: None };
+        request.auth = Some(sign(&mut user, &request, "GetList"));
+        service.get_list(Request::new(request)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_auth_failure_lockout() {
+        let tmp_dir = TempDir::new("server_test").unwrap();
+        let service = test_service(&tmp_dir);
+        let mut user = register_user(&service, 1).await;
+        let mut attacker = TestUser {
+            user_id: user.user_id,
+            signer: Box::new(AssymetricKeypair::generate_dilithium2(&[9; 32])),
+            nonce: user.nonce,
+        };
+
+        for _ in 0..AUTH_FAILURE_LIMIT {
+            // Failed requests don't consume the nonce
+            attacker.nonce = user.nonce;
+            let mut request = GetListRequest { auth: None };
+            request.auth = Some(sign(&mut attacker, &request, "GetList"));
+            let status = service.get_list(Request::new(request)).await.unwrap_err();
+            assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        }
+
+        // Locked out, even with a valid signature
+        let mut request = GetListRequest { auth: None };
+        request.auth = Some(sign(&mut user, &request, "GetList"));
+        let status = service.get_list(Request::new(request)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+
+        // Other users are unaffected
+        let mut other = register_user(&service, 2).await;
+        let mut request = GetListRequest { auth: None };
+        request.auth = Some(sign(&mut other, &request, "GetList"));
+        service.get_list(Request::new(request)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_all_pagination() {
+        let tmp_dir = TempDir::new("server_test").unwrap();
+        let service = test_service(&tmp_dir);
+        let mut user = register_user(&service, 1).await;
+
+        let mut request = SetRecordsRequest {
+            auth: None,
+            records: (1..=500).map(|id| test_record(id, &user.user_id)).collect(),
+            idempotency_key: String::new(),
+        };
+        request.auth = Some(sign(&mut user, &request, "SetRecords"));
+        service.set_records(Request::new(request)).await.unwrap();
+
+        let mut ids = Vec::new();
+        let mut pages = 0;
+        let mut cursor = Vec::new();
+        loop {
+            let mut request = GetAllRequest {
+                auth: None,
+                limit: 100,
+                cursor,
+            };
+            request.auth = Some(sign(&mut user, &request, "GetAll"));
+            let page = service
+                .get_all(Request::new(request))
+                .await
+                .unwrap()
+                .into_inner();
+            pages += 1;
+            assert!(page.records.len() <= 100);
+            ids.extend(page.records.iter().map(|r| r.id));
+            if page.next_cursor.is_empty() {
+                break;
+            }
+            cursor = page.next_cursor;
+        }
+
+        assert_eq!(pages, 5);
+        assert_eq!(ids, (1..=500).collect::<Vec<u64>>());
+
+        let mut request = GetAllRequest {
+            auth: None,
+            limit: 100,
+            cursor: vec![1, 2, 3],
+        };
+        request.auth = Some(sign(&mut user, &request, "GetAll"));
+        let status = service.get_all(Request::new(request)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+
+    async fn collect_stream(service: &PassmgrService, user: &mut TestUser) -> Vec<Record> {
+        let mut request = GetAllRequest::default();
+        request.auth = Some(sign(user, &request, "GetAllStream"));
+        let response = service.get_all_stream(Request::new(request)).await.unwrap();
+        let nonce = response.metadata().get(NONCE_METADATA_KEY).unwrap();
+        assert_eq!(nonce.to_str().unwrap(), user.nonce.to_string());
+        let mut stream = response.into_inner();
+
+        let mut records = Vec::new();
+        while let Some(item) = stream.next().await {
+            records.push(item.unwrap());
+        }
+        records
+    }
+
+    #[tokio::test]
+    async fn test_get_all_stream() {
+        let tmp_dir = TempDir::new("server_test").unwrap();
+        let service = test_service(&tmp_dir);
+        let mut user = register_user(&service, 1).await;
+
+        assert!(collect_stream(&service, &mut user).await.is_empty());
+
+        let mut request = SetRecordsRequest {
+            auth: None,
+            records: (1..=50).map(|id| test_record(id, &user.user_id)).collect(),
+            idempotency_key: String::new(),
+        };
+        request.auth = Some(sign(&mut user, &request, "SetRecords"));
+        service.set_records(Request::new(request)).await.unwrap();
+
+        let records = collect_stream(&service, &mut user).await;
+        assert_eq!(
+            records.iter().map(|r| r.id).collect::<Vec<u64>>(),
+            (1..=50).collect::<Vec<u64>>()
+        );
+        assert_eq!(records[0], test_record(1, &user.user_id));
+    }
+
+    #[tokio::test]
+    async fn test_tls_get_nonce() {
+        use passmgr_rpc::rpc_passmgr::rpc_passmgr_client::RpcPassmgrClient;
+        use tonic::transport::{Certificate, Channel, ClientTlsConfig};
+
+        let tmp_dir = TempDir::new("server_test").unwrap();
+        let service = test_service(&tmp_dir);
+
+        let identity = Identity::from_pem(
+            include_bytes!("../testdata/tls/server.pem"),
+            include_bytes!("../testdata/tls/server.key"),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .tls_config(ServerTlsConfig::new().identity(identity))
+                .unwrap()
+                .add_service(RpcPassmgrServer::new(service))
+                .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)),
+        );
+
+        let tls = ClientTlsConfig::new()
+            .ca_certificate(Certificate::from_pem(include_bytes!(
+                "../testdata/tls/ca.pem"
+            )))
+            .domain_name("localhost");
+        let channel = Channel::from_shared(format!("https://{addr}"))
+            .unwrap()
+            .tls_config(tls)
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut client = RpcPassmgrClient::new(channel);
+
+        let keys = AssymetricKeypair::generate_dilithium2(&[1; 32]);
+        let response = client
+            .register(RegisterRequest {
+                user_id: vec![1; 32],
+                pub_key: keys.public_key(),
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(response.success);
+
+        let challenge = client
+            .get_nonce(GetNonceRequest {
+                user_id: vec![1; 32],
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+            .into_inner()
+            .challenge;
+        let nonce = client
+            .get_nonce(GetNonceRequest {
+                user_id: vec![1; 32],
+                signature: keys.sign(&nonce_challenge_payload(&[1; 32], &challenge)),
+                challenge,
+            })
+            .await
+            .unwrap()
+            .into_inner()
+            .nonce;
+        assert_eq!(nonce, response.nonce);
+
+        // A signed request built on that nonce goes through the same channel
+        let mut user = TestUser {
+            user_id: [1; 32],
+            signer: Box::new(keys),
+            nonce,
+        };
+        let mut request = GetListRequest { auth: None };
+        request.auth = Some(sign(&mut user, &request, "GetList"));
+        let listed = client.get_list(request).await.unwrap().into_inner();
+        assert!(listed.record_i_ds.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_server_smoke() {
+        use passmgr_rpc::rpc_passmgr::rpc_passmgr_client::RpcPassmgrClient;
+
+        let tmp_dir = TempDir::new("server_test").unwrap();
+        let auth_db = tmp_dir.path().join("auth_db");
+        let data_dir = tmp_dir.path().join("data");
+        let opt = Opt::try_parse_from([
+            "server",
+            "--addr",
+            "127.0.0.1:0",
+            "--auth-db",
+            auth_db.to_str().unwrap(),
+            "--data-dir",
+            data_dir.to_str().unwrap(),
+        ])
+        .unwrap();
+
+        let service = open_service(&opt).unwrap();
+        let listener = tokio::net::TcpListener::bind(opt.addr).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert_ne!(addr.port(), 0);
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(RpcPassmgrServer::new(service))
+                .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)),
+        );
+
+        let mut client = RpcPassmgrClient::connect(format!("http://{addr}"))
+            .await
+            .unwrap();
+        // Unknown users get a challenge like everyone else
+        let response = client
+            .get_nonce(GetNonceRequest {
+                user_id: vec![1; 32],
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.challenge.len(), 40);
+    }
+
+    #[test]
+    fn test_unwritable_data_dir_rejected() {
+        let tmp_dir = TempDir::new("server_test").unwrap();
+        let file = tmp_dir.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+
+        let opt = Opt {
+            addr: "127.0.0.1:0".parse().unwrap(),
+            auth_db: Some(tmp_dir.path().join("auth_db")),
+            data_dir: Some(file.join("data")),
+        };
+        let err = open_service(&opt).err().unwrap();
+        assert!(err.to_string().contains("is not writable"));
+    }
+
+    // Stands in for a hardware token: the key stays inside, only signatures come out
+    struct MockTokenSigner {
+        keys: AssymetricKeypair,
+        sign_calls: Arc<AtomicUsize>,
+    }
+
+    impl RequestSigner for MockTokenSigner {
+        fn sign(&self, msg: &[u8]) -> Vec<u8> {
+            self.sign_calls.fetch_add(1, Ordering::SeqCst);
+            self.keys.sign(msg)
+        }
+
+        fn public_key(&self) -> Vec<u8> {
+            self.keys.public_key()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mock_signer_accepted() {
+        let tmp_dir = TempDir::new("server_test").unwrap();
+        let service = test_service(&tmp_dir);
+
+        let sign_calls = Arc::new(AtomicUsize::new(0));
+        let signer = MockTokenSigner {
+            keys: AssymetricKeypair::generate_dilithium2(&[9; 32]),
+            sign_calls: sign_calls.clone(),
+        };
+        let mut user = register_user_with_signer(&service, [9; 32], Box::new(signer)).await;
+
+        let mut request = SetOneRequest {
+            auth: None,
+            record: Some(test_record(1, &user.user_id)),
+            idempotency_key: String::new(),
+        };
+        request.auth = Some(sign(&mut user, &request, "SetOne"));
+        service.set_one(Request::new(request)).await.unwrap();
+
+        let mut request = GetListRequest { auth: None };
+        request.auth = Some(sign(&mut user, &request, "GetList"));
+        let list = service
+            .get_list(Request::new(request))
+            .await
+            .unwrap()
+            .into_inner();
+
+        assert_eq!(list.record_i_ds.len(), 1);
+        assert_eq!(sign_calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_admin_list_users() {
+        let tmp_dir = TempDir::new("server_test").unwrap();
+        let service = test_service(&tmp_dir);
+        let mut alice = register_user(&service, 1).await;
+        let bob = register_user(&service, 2).await;
+
+        let mut request = SetRecordsRequest {
+            auth: None,
+            records: vec![
+                test_record(1, &alice.user_id),
+                test_record(2, &alice.user_id),
+            ],
+            idempotency_key: String::new(),
+        };
+        request.auth = Some(sign(&mut alice, &request, "SetRecords"));
+        service.set_records(Request::new(request)).await.unwrap();
+
+        let users = service
+            .list_users(Request::new(ListUsersRequest {}))
+            .await
+            .unwrap()
+            .into_inner()
+            .users;
+        assert_eq!(users.len(), 2);
+
+        let summary = |user: &TestUser| {
+            users
+                .iter()
+                .find(|summary| summary.user_id == user.user_id.to_vec())
+                .unwrap()
+                .clone()
+        };
+        assert_eq!(summary(&alice).record_count, 2);
+        assert!(summary(&alice).storage_bytes > 0);
+        assert_eq!(summary(&bob).record_count, 0);
+    }
+
+    #[test]
+    fn test_data_dir_is_file() {
+        let tmp_dir = TempDir::new("server_test").unwrap();
+        let data_file = tmp_dir.path().join("data");
+        std::fs::write(&data_file, b"not a directory").unwrap();
+
+        let err = PassmgrService::new(tmp_dir.path().join("auth_db"), data_file)
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<StorageError>(),
+            Some(StorageError::StorageNotADirectory(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_retention_purges_inactive_account() {
+        let tmp_dir = TempDir::new("server_test").unwrap();
+        let mut service = test_service(&tmp_dir);
+        let stale = register_user(&service, 1).await;
+        let active = register_user(&service, 2).await;
+
+        let now = current_timestamp();
+        // Without a policy nothing is ever purged
+        assert_eq!(
+            service.apply_retention(now + 1000 * DAY_SECS).unwrap(),
+            RetentionSweep::default()
+        );
+
+        service.retention = Some(RetentionPolicy {
+            inactive_secs: 90 * DAY_SECS,
+            grace_secs: 7 * DAY_SECS,
+        });
+        let stale_since = now - 100 * DAY_SECS;
+        service
+            .last_active
+            .insert(stale.user_id, &stale_since.to_be_bytes()[..])
+            .unwrap();
+
+        let sweep = service.apply_retention(now).unwrap();
+        assert_eq!(sweep.flagged, vec![stale.user_id]);
+        assert!(sweep.purged.is_empty());
+
+        // Still inactive once the grace window is over
+        let sweep = service.apply_retention(now + 7 * DAY_SECS).unwrap();
+        assert_eq!(sweep.purged, vec![stale.user_id]);
+        assert!(service.auth_db.get(stale.user_id).unwrap().is_none());
+        assert!(!service.user_data_dir(&stale.user_id).exists());
+
+        assert!(service.auth_db.get(active.user_id).unwrap().is_some());
+        assert!(service.user_data_dir(&active.user_id).exists());
+    }
+
+    #[tokio::test]
+    async fn test_deregister() {
+        let tmp_dir = TempDir::new("server_test").unwrap();
+        let service = test_service(&tmp_dir);
+        let mut user = register_user(&service, 1).await;
+        let other = register_user(&service, 2).await;
+
+        let mut request = SetOneRequest {
+            auth: None,
+            record: Some(test_record(1, &user.user_id)),
+            idempotency_key: String::new(),
+        };
+        request.auth = Some(sign(&mut user, &request, "SetOne"));
+        service.set_one(Request::new(request)).await.unwrap();
+
+        // Replaying a used nonce is rejected and the account stays
+        user.nonce -= 1;
+        let mut request = DeregisterRequest { auth: None };
+        request.auth = Some(sign(&mut user, &request, "Deregister"));
+        let err = service.deregister(Request::new(request)).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        assert!(service.user_data_dir(&user.user_id).exists());
+
+        // A retry after a lost response finds the account already gone
+        let mut request = DeregisterRequest { auth: None };
+        request.auth = Some(sign(&mut user, &request, "Deregister"));
+        for _ in 0..2 {
+            service
+                .deregister(Request::new(request.clone()))
+                .await
+                .unwrap();
+        }
+        assert!(!service.user_data_dir(&user.user_id).exists());
+        assert!(!service.auth_db.contains_key(user.user_id).unwrap());
+
+        // Any other request for the gone account fails like a wrong nonce
+        let mut request = DeregisterRequest { auth: None };
+        request.auth = Some(sign(&mut user, &request, "Deregister"));
+        let err = service.deregister(Request::new(request)).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        assert_eq!(err.message(), "Invalid nonce");
+
+        // Other accounts are untouched
+        assert!(service.auth_db.contains_key(other.user_id).unwrap());
+        assert!(service.user_data_dir(&other.user_id).exists());
+    }
+
+    // Collects formatted log output in memory
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> tracing_subscriber::fmt::MakeWriter<'a> for LogBuffer {
+        type Writer = LogBuffer;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_auth_logged_without_secrets() {
+        let logs = LogBuffer::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(logs.clone())
+            .with_ansi(false)
+            .with_max_level(tracing::Level::DEBUG)
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let tmp_dir = TempDir::new("server_test").unwrap();
+        let service = test_service(&tmp_dir);
+        let mut user = register_user(&service, 1).await;
+
+        let mut request = GetListRequest { auth: None };
+        let mut auth = sign(&mut user, &request, "GetList");
+        auth.signature[0] ^= 1;
+        let signature = auth.signature.clone();
+        request.auth = Some(auth);
+        let err = service.get_list(Request::new(request)).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::Unauthenticated);
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(logs
+            .lines()
+            .any(|line| line.contains("WARN") && line.contains("authentication failed")));
+        assert!(logs.contains(&user_tag(&user.user_id)));
+
+        assert!(!logs.contains(&hex_id(&user.user_id)));
+        let signature_hex: String = signature[..16]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        assert!(!logs.contains(&signature_hex));
+        let signature_debug = format!("{:?}", &signature[..4]);
+        assert!(!logs.contains(signature_debug.trim_end_matches(']')));
+    }
+
+    #[tokio::test]
+    async fn test_get_nonce_requires_signature() {
+        let tmp_dir = TempDir::new("server_test").unwrap();
+        let service = test_service(&tmp_dir);
+        let user = register_user(&service, 1).await;
+
+        // Unsigned: a challenge, not the nonce
+        let response = service
+            .get_nonce(Request::new(GetNonceRequest {
+                user_id: user.user_id.to_vec(),
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.nonce, 0);
+        assert_eq!(response.challenge.len(), 40);
+
+        // Someone else's key can't answer it, however often they try
+        let intruder = AssymetricKeypair::generate_dilithium2(&[2; 32]);
+        let payload = nonce_challenge_payload(&user.user_id, &response.challenge);
+        for _ in 0..=AUTH_FAILURE_LIMIT {
+            let err = service
+                .get_nonce(Request::new(GetNonceRequest {
+                    user_id: user.user_id.to_vec(),
+                    challenge: response.challenge.clone(),
+                    signature: intruder.sign(&payload),
+                }))
+                .await
+                .unwrap_err();
+            assert_eq!(err.code(), tonic::Code::Unauthenticated);
+        }
+
+        // The owner answers a fresh challenge and gets the nonce, once
+        let challenge = service
+            .get_nonce(Request::new(GetNonceRequest {
+                user_id: user.user_id.to_vec(),
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .challenge;
+        let payload = nonce_challenge_payload(&user.user_id, &challenge);
+        let answer = GetNonceRequest {
+            user_id: user.user_id.to_vec(),
+            challenge,
+            signature: user.signer.sign(&payload),
+        };
+        let response = service
+            .get_nonce(Request::new(answer.clone()))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.nonce, user.nonce);
+
+        // Altered or expired challenges are refused
+        let mut altered = answer.clone();
+        altered.challenge[0] ^= 1;
+        let err = service.get_nonce(Request::new(altered)).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+        let expired = service.nonce_challenge(&user.user_id, current_timestamp() - 1);
+        let err = service
+            .get_nonce(Request::new(GetNonceRequest {
+                signature: user
+                    .signer
+                    .sign(&nonce_challenge_payload(&user.user_id, &expired)),
+                challenge: expired,
+                ..answer
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_user_looks_like_wrong_nonce() {
+        let tmp_dir = TempDir::new("server_test").unwrap();
+        let service = test_service(&tmp_dir);
+        let mut registered = register_user(&service, 1).await;
+        let mut stranger = TestUser {
+            user_id: [9; 32],
+            signer: Box::new(AssymetricKeypair::generate_dilithium2(&[9; 32])),
+            nonce: 5,
+        };
+
+        // Someone without the key can't tell an account from a missing one
+        registered.nonce ^= 1 << 40;
+        for user in [&mut registered, &mut stranger] {
+            let mut request = GetListRequest { auth: None };
+            request.auth = Some(sign(user, &request, "GetList"));
+            let err = service.get_list(Request::new(request)).await.unwrap_err();
+            assert_eq!(err.code(), tonic::Code::InvalidArgument);
+            assert_eq!(err.message(), "Invalid nonce");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_nonce_challenges() {
+        let tmp_dir = TempDir::new("server_test").unwrap();
+        let service = test_service(&tmp_dir);
+        let user = register_user(&service, 1).await;
+        let challenge_for = |user_id: [u8; 32]| {
+            service.get_nonce(Request::new(GetNonceRequest {
+                user_id: user_id.to_vec(),
+                ..Default::default()
+            }))
+        };
+
+        // An unknown user gets a challenge too and only fails once it is answered
+        let stranger = AssymetricKeypair::generate_dilithium2(&[3; 32]);
+        let challenge = challenge_for([3; 32]).await.unwrap().into_inner().challenge;
+        assert_eq!(challenge.len(), 40);
+        let err = service
+            .get_nonce(Request::new(GetNonceRequest {
+                user_id: vec![3; 32],
+                signature: stranger.sign(&nonce_challenge_payload(&[3; 32], &challenge)),
+                challenge,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::Unauthenticated);
+
+        // A newer challenge doesn't cancel an older one of the same user
+        let first = challenge_for(user.user_id)
+            .await
+            .unwrap()
+            .into_inner()
+            .challenge;
+        let second = challenge_for(user.user_id)
+            .await
+            .unwrap()
+            .into_inner()
+            .challenge;
+        for challenge in [first, second] {
+            let response = service
+                .get_nonce(Request::new(GetNonceRequest {
+                    user_id: user.user_id.to_vec(),
+                    signature: user
+                        .signer
+                        .sign(&nonce_challenge_payload(&user.user_id, &challenge)),
+                    challenge,
+                }))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(response.nonce, user.nonce);
+        }
+
+        // A challenge only counts for the user it was issued to
+        let challenge = challenge_for([3; 32]).await.unwrap().into_inner().challenge;
+        let err = service
+            .get_nonce(Request::new(GetNonceRequest {
+                user_id: user.user_id.to_vec(),
+                signature: user
+                    .signer
+                    .sign(&nonce_challenge_payload(&user.user_id, &challenge)),
+                challenge,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn test_missing_user_dir_recreated() {
+        let tmp_dir = TempDir::new("server_test").unwrap();
+        let service = test_service(&tmp_dir);
+        let mut user = register_user(&service, 1).await;
+
+        let user_data_dir = service.user_data_dir(&user.user_id);
+        std::fs::remove_dir_all(&user_data_dir).unwrap();
+
+        let mut request = GetListRequest { auth: None };
+        request.auth = Some(sign(&mut user, &request, "GetList"));
+        let listed = service
+            .get_list(Request::new(request))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(listed.record_i_ds.is_empty());
+        assert!(user_data_dir.is_dir());
+
+        // A file in its place can't be used
+        std::fs::remove_dir_all(&user_data_dir).unwrap();
+        std::fs::write(&user_data_dir, b"").unwrap();
+        let err = service.get_user_storage(user.user_id).err().unwrap();
+        assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+    }
+
+    async fn get_stats(service: &PassmgrService, user: &mut TestUser) -> StatsResponse {
+        let mut request = GetStatsRequest { auth: None };
+        request.auth = Some(sign(user, &request, "GetStats"));
+        service
+            .get_stats(Request::new(request))
+            .await
+            .unwrap()
+            .into_inner()
+    }
+
+    #[tokio::test]
+    async fn test_get_stats() {
+        let tmp_dir = TempDir::new("server_test").unwrap();
+        let service = test_service(&tmp_dir);
+        let mut user = register_user(&service, 1).await;
+        let stats = get_stats(&service, &mut user).await;
+        assert_eq!((stats.record_count, stats.max_ver), (0, 0));
+
+        let mut records: Vec<Record> = (1..=3).map(|id| test_record(id, &user.user_id)).collect();
+        records[1].ver = 7;
+        let mut request = SetRecordsRequest {
+            auth: None,
+            records,
+            idempotency_key: String::new(),
+        };
+        request.auth = Some(sign(&mut user, &request, "SetRecords"));
+        service.set_records(Request::new(request)).await.unwrap();
+
+        let stats = get_stats(&service, &mut user).await;
+        assert_eq!(stats.record_count, 3);
+        assert_eq!(stats.max_ver, 7);
+
+        let mut request = DeleteByIdRequest {
+            auth: None,
+            record_id: 2,
+            idempotency_key: String::new(),
+        };
+        request.auth = Some(sign(&mut user, &request, "DeleteById"));
+        service.delete_by_id(Request::new(request)).await.unwrap();
+
+        let stats = get_stats(&service, &mut user).await;
+        assert_eq!(stats.record_count, 2);
+        assert_eq!(stats.max_ver, 1);
+    }
+
+    #[tokio::test]
+    async fn test_responses_carry_next_nonce() {
+        let tmp_dir = TempDir::new("server_test").unwrap();
+        let service = test_service(&tmp_dir);
+        let mut user = register_user(&service, 1).await;
+
+        // Each request signs with whatever the previous response handed back
+        let mut request = GetListRequest { auth: None };
+        request.auth = Some(sign(&mut user, &request, "GetList"));
+        let response = service
+            .get_list(Request::new(request))
+            .await
+            .unwrap()
+            .into_inner();
+        user.nonce = response.nonce;
+
+        let mut request = SetOneRequest {
+            auth: None,
+            record: Some(test_record(1, &user.user_id)),
+            idempotency_key: String::new(),
+        };
+        request.auth = Some(sign(&mut user, &request, "SetOne"));
+        let response = service
+            .set_one(Request::new(request))
+            .await
+            .unwrap()
+            .into_inner();
+        user.nonce = response.nonce;
+
+        let stats = get_stats(&service, &mut user).await;
+        assert_eq!(stats.record_count, 1);
+        assert_eq!(stats.nonce, user.nonce);
+    }
+
+    #[tokio::test]
+    async fn test_health() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp_dir = TempDir::new("server_test").unwrap();
+        let service = test_service(&tmp_dir);
+        let response = service
+            .health(Request::new(HealthRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.status(), ServingStatus::Serving);
+
+        let data_dir = service.data_dir.clone();
+        std::fs::set_permissions(&data_dir, std::fs::Permissions::from_mode(0o555)).unwrap();
+        let response = service
+            .health(Request::new(HealthRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+        std::fs::set_permissions(&data_dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(response.status(), ServingStatus::NotServing);
+    }
+
+    #[tokio::test]
+    async fn test_graceful_shutdown() {
+        let tmp_dir = TempDir::new("server_test").unwrap();
+        let service = Arc::new(test_service(&tmp_dir));
+        register_user(&service, 1).await;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let admin_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (signal_tx, signal_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(
+            service.clone(),
+            tonic::transport::Server::builder(),
+            listener,
+            admin_listener,
+            async {
+                let _ = signal_rx.await;
+            },
+        ));
+
+        signal_tx.send(()).unwrap();
+        let result = tokio::time::timeout(std::time::Duration::from_secs(5), server)
+            .await
+            .expect("server didn't stop")
+            .unwrap();
+        assert!(result.is_ok());
+
+        // Flushed, so the registration survives reopening
+        drop(service);
+        let reopened = test_service(&tmp_dir);
+        assert!(reopened.auth_db.contains_key([1u8; 32]).unwrap());
+    }
+}
@@ -0,0 +1,59 @@
+//! Compares fetching every record of a vault with one `Storage::get` per id, as
+//! `get_all` used to, against a single `Storage::get_many` call.
+//!
+//! `get_many` walks the id span once with a range iterator instead of descending the
+//! tree for each id, so the gap grows with the number of records. It still decodes
+//! every record, so the saving is the lookups, not the deserialization.
+//!
+//! Run with `cargo bench -p storage --bench get_many`.
+
+use std::hint::black_box;
+use std::time::Instant;
+use storage::db::Storage;
+use storage::structures::CipherRecord;
+use tempdir::TempDir;
+
+const RECORDS: u64 = 5_000;
+const RECORD_SIZE: usize = 1024;
+const ROUNDS: u32 = 10;
+
+fn measure(name: &str, mut run: impl FnMut() -> usize) {
+    let start = Instant::now();
+    let mut fetched = 0;
+    for _ in 0..ROUNDS {
+        fetched = run();
+    }
+    println!(
+        "{name:<12} {:>8.2?} per pass, {fetched} records",
+        start.elapsed() / ROUNDS
+    );
+}
+
+fn main() {
+    let tmp_dir = TempDir::new("get_many_bench").unwrap();
+    let storage = Storage::open(tmp_dir.path(), [1; 32]).unwrap();
+    for id in 0..RECORDS {
+        let record = CipherRecord {
+            user_id: [1; 32],
+            cipher_record_id: id,
+            ver: 1,
+            cipher_options: vec![0, 1, 2],
+            data: vec![42; RECORD_SIZE],
+            signature: vec![0; 2420],
+            updated: id,
+        };
+        storage.set(id, &record).unwrap();
+    }
+    let ids = storage.list_ids().unwrap();
+
+    measure("get per id", || {
+        ids.iter().for_each(|id| {
+            black_box(storage.get(*id).unwrap());
+        });
+        ids.len()
+    });
+
+    measure("get_many", || {
+        black_box(storage.get_many(&ids).unwrap()).len()
+    });
+}
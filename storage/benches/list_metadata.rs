@@ -0,0 +1,80 @@
+//! Compares listing record metadata by decoding every full record against
+//! `Storage::list_ids_with_metadata`, which only decodes the fixed-size fields.
+//!
+//! Run with `cargo bench -p storage --bench list_metadata`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use storage::db::Storage;
+use storage::structures::CipherRecord;
+use tempdir::TempDir;
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const RECORDS: u64 = 10_000;
+const RECORD_SIZE: usize = 1024;
+
+fn measure(name: &str, run: impl FnOnce() -> usize) {
+    ALLOCATIONS.store(0, Ordering::Relaxed);
+    ALLOCATED_BYTES.store(0, Ordering::Relaxed);
+    let start = Instant::now();
+
+    let listed = run();
+
+    println!(
+        "{name:<16} {:>8.2?} {listed} records {:>8} allocations {:>8} KiB allocated",
+        start.elapsed(),
+        ALLOCATIONS.load(Ordering::Relaxed),
+        ALLOCATED_BYTES.load(Ordering::Relaxed) / 1024
+    );
+}
+
+fn main() {
+    let tmp_dir = TempDir::new("list_metadata_bench").unwrap();
+    let storage = Storage::open(tmp_dir.path(), [1; 32]).unwrap();
+    for id in 0..RECORDS {
+        let record = CipherRecord {
+            user_id: [1; 32],
+            cipher_record_id: id,
+            ver: 1,
+            cipher_options: vec![0, 1, 2],
+            data: vec![42; RECORD_SIZE],
+            signature: vec![0; 2420],
+            updated: id,
+        };
+        storage.set(id, &record).unwrap();
+    }
+
+    measure("full records", || {
+        let ids = storage.list_ids().unwrap();
+        ids.into_iter()
+            .map(|id| {
+                let record = storage.get(id).unwrap();
+                (record.cipher_record_id, record.ver, record.updated)
+            })
+            .count()
+    });
+
+    measure("metadata only", || {
+        storage.list_ids_with_metadata().unwrap().len()
+    });
+}
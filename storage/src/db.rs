@@ -3,14 +3,234 @@ use crate::{
     structures::CipherRecord,
 };
 
-use bincode::{deserialize, serialize};
-use sled::{Config, Db, Tree};
+use bincode::{serialize, Options};
+use serde::Deserialize;
+use sled::transaction::{ConflictableTransactionError, TransactionError, TransactionalTree};
+use sled::{Config, Db, Transactional, Tree};
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
+use subtle::ConstantTimeEq;
+
+const CONTENT_ADDRESSED_META: &str = "content_addressed";
 
 pub struct Storage {
     db: Db,
     path: PathBuf,
+    // Record id (u64 BE, same width as `CipherRecord::cipher_record_id`) -> CipherRecord
     user_db: Tree,
+    // Per-user vault metadata (key generations, settings)
+    meta: Tree,
+    // Content-addressed mode: blake3(data) -> refcount (u64 BE) || data,
+    // records in `user_db` then hold the hash in place of their data
+    blocks: Option<Tree>,
+}
+
+/// sled tuning for `Storage::open_with_config`
+#[derive(Clone, Debug)]
+pub struct StorageConfig {
+    /// Page cache size, must be non-zero
+    pub cache_bytes: u64,
+    /// Background flush interval, `None` leaves flushing to `Storage::flush`
+    pub flush_ms: Option<u64>,
+    pub mode: sled::Mode,
+}
+
+impl Default for StorageConfig {
+    /// What `Storage::open` uses: 128MB cache, flushed every second
+    fn default() -> Self {
+        Self {
+            cache_bytes: 1024 * 1024 * 128,
+            flush_ms: Some(1000),
+            mode: sled::Mode::HighThroughput,
+        }
+    }
+}
+
+type TxResult<T> = std::result::Result<T, ConflictableTransactionError<StorageError>>;
+
+/// Make sure `path` can hold a store: create it when absent, otherwise it must be
+/// a writable directory. Catches misconfigured paths up front instead of on first write.
+pub fn ensure_dir(path: &Path) -> Result<()> {
+    match std::fs::metadata(path) {
+        Ok(metadata) if !metadata.is_dir() => {
+            return Err(StorageError::StorageNotADirectory(
+                path.display().to_string(),
+            ))
+        }
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => std::fs::create_dir_all(path)
+            .map_err(|e| {
+                StorageError::StorageAccessError(format!("cannot create {}: {}", path.display(), e))
+            })?,
+        Err(e) => {
+            return Err(StorageError::StorageAccessError(format!(
+                "{}: {}",
+                path.display(),
+                e
+            )))
+        }
+    }
+
+    let probe = path.join(".passmgr_write_probe");
+    std::fs::write(&probe, b"")
+        .and_then(|_| std::fs::remove_file(&probe))
+        .map_err(|e| {
+            StorageError::StorageAccessError(format!("{} is not writable: {}", path.display(), e))
+        })
+}
+
+fn record_key(key: &[u8]) -> Result<u64> {
+    let bytes = key
+        .try_into()
+        .map_err(|e: std::array::TryFromSliceError| StorageError::StorageKeyError(e.to_string()))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn meta_tree_name(uid: &[u8]) -> Vec<u8> {
+    let mut name = b"meta:".to_vec();
+    name.extend_from_slice(uid);
+    name
+}
+
+fn blocks_tree_name(uid: &[u8]) -> Vec<u8> {
+    let mut name = b"blocks:".to_vec();
+    name.extend_from_slice(uid);
+    name
+}
+
+fn tx_error(e: TransactionError<StorageError>) -> StorageError {
+    match e {
+        TransactionError::Abort(e) => e,
+        TransactionError::Storage(e) => StorageError::StorageWriteError(e.to_string()),
+    }
+}
+
+fn tx_abort(e: StorageError) -> ConflictableTransactionError<StorageError> {
+    ConflictableTransactionError::Abort(e)
+}
+
+// A block is `[reference count, 8 bytes BE][data]`
+const BLOCK_HEADER_LEN: usize = 8;
+
+fn block_refs(block: &[u8]) -> Result<u64> {
+    block
+        .get(..BLOCK_HEADER_LEN)
+        .and_then(|header| header.try_into().ok())
+        .map(u64::from_be_bytes)
+        .ok_or_else(|| StorageError::StorageReadError("truncated block".to_string()))
+}
+
+fn tx_retain_block(blocks: &TransactionalTree, hash: &[u8], data: &[u8]) -> TxResult<()> {
+    let block = match blocks.get(hash)? {
+        Some(block) => {
+            let mut block = block.to_vec();
+            let refs = block_refs(&block).map_err(tx_abort)? + 1;
+            block[..BLOCK_HEADER_LEN].copy_from_slice(&refs.to_be_bytes());
+            block
+        }
+        None => {
+            let mut block = 1u64.to_be_bytes().to_vec();
+            block.extend_from_slice(data);
+            block
+        }
+    };
+    blocks.insert(hash, block)?;
+    Ok(())
+}
+
+fn tx_release_block(blocks: &TransactionalTree, hash: &[u8]) -> TxResult<()> {
+    if let Some(block) = blocks.get(hash)? {
+        let refs = block_refs(&block).map_err(tx_abort)?;
+        if refs <= 1 {
+            blocks.remove(hash)?;
+        } else {
+            let mut block = block.to_vec();
+            block[..BLOCK_HEADER_LEN].copy_from_slice(&(refs - 1).to_be_bytes());
+            blocks.insert(hash, block)?;
+        }
+    }
+    Ok(())
+}
+
+// Encoded like `CipherRecord`, borrowing the byte fields instead of copying them
+#[derive(Deserialize)]
+struct RecordHeader<'a> {
+    user_id: [u8; 32],
+    cipher_record_id: u64,
+    ver: u64,
+    _cipher_options: &'a [u8],
+    _data: &'a [u8],
+    _signature: &'a [u8],
+    updated: u64,
+}
+
+// `RecordHeader` of the `CipherRecordV0` layout, which has no `updated`
+#[derive(Deserialize)]
+struct RecordHeaderV0<'a> {
+    user_id: [u8; 32],
+    cipher_record_id: u64,
+    ver: u64,
+    _cipher_options: &'a [u8],
+    _data: &'a [u8],
+}
+
+// Owner and (record_id, version, updated) of an encoded record, 0 for `updated` when
+// it predates the field. Strict like `CipherRecord::decode`, so neither layout passes
+// for the other.
+fn record_metadata(value: &[u8]) -> Option<([u8; 32], (u64, u64, u64))> {
+    let strict = bincode::DefaultOptions::new().with_fixint_encoding();
+    if let Ok(header) = strict.deserialize::<RecordHeader>(value) {
+        return Some((
+            header.user_id,
+            (header.cipher_record_id, header.ver, header.updated),
+        ));
+    }
+    strict
+        .deserialize::<RecordHeaderV0>(value)
+        .ok()
+        .map(|header| (header.user_id, (header.cipher_record_id, header.ver, 0)))
+}
+
+// Drop the reference held by the record stored under `key`, if any
+fn tx_release_record(
+    user_db: &TransactionalTree,
+    blocks: &TransactionalTree,
+    key: u64,
+) -> TxResult<()> {
+    if let Some(old) = user_db.get(key.to_be_bytes())? {
+        let old = CipherRecord::decode(&old)
+            .map_err(|e| tx_abort(StorageError::StorageReadError(e.to_string())))?;
+        tx_release_block(blocks, &old.data)?;
+    }
+    Ok(())
+}
+
+fn check_record_id(key: u64, payload: &CipherRecord) -> Result<()> {
+    if payload.cipher_record_id != key {
+        return Err(StorageError::StorageRecordIdMismatch(key.to_string()));
+    }
+    Ok(())
+}
+
+fn tx_put_record(
+    user_db: &TransactionalTree,
+    blocks: &TransactionalTree,
+    key: u64,
+    payload: &CipherRecord,
+) -> TxResult<()> {
+    tx_release_record(user_db, blocks, key)?;
+
+    let hash = blake3::hash(&payload.data);
+    tx_retain_block(blocks, hash.as_bytes(), &payload.data)?;
+
+    let block_ref = CipherRecord {
+        data: hash.as_bytes().to_vec(),
+        ..payload.clone()
+    };
+    let encoded = serialize(&block_ref)
+        .map_err(|e| tx_abort(StorageError::StorageWriteError(e.to_string())))?;
+    user_db.insert(&key.to_be_bytes()[..], encoded)?;
+    Ok(())
 }
 
 impl Storage {
@@ -23,26 +243,129 @@ impl Storage {
         //         path
         //     )));
         // }
-        let config = Config::new()
-            .path(&path)
-            .mode(sled::Mode::HighThroughput)
-            .cache_capacity(1024 * 1024 * 128) // 128MB cache
-            .flush_every_ms(Some(1000));
-        let db = config
+        Self::open_with_config(path, uid, StorageConfig::default())
+    }
+
+    /// `open` with a chosen cache size and flush interval, e.g. a small cache for a
+    /// vault on a phone
+    pub fn open_with_config(path: &Path, uid: [u8; 32], config: StorageConfig) -> Result<Self> {
+        if config.cache_bytes == 0 {
+            return Err(StorageError::StorageOpenError(String::from(
+                "cache size must be greater than 0",
+            )));
+        }
+        let db = Config::new()
+            .path(path)
+            .mode(config.mode)
+            .cache_capacity(config.cache_bytes)
+            .flush_every_ms(config.flush_ms)
             .open()
             .map_err(|e| StorageError::StorageOpenError(e.to_string()))?;
         let user_db = db
             .open_tree(uid)
             .map_err(|e| StorageError::StorageOpenError(e.to_string()))?;
+        let meta = db
+            .open_tree(meta_tree_name(&uid))
+            .map_err(|e| StorageError::StorageOpenError(e.to_string()))?;
+
+        // A store created in content-addressed mode stays in it
+        let content_addressed = meta
+            .contains_key(CONTENT_ADDRESSED_META)
+            .map_err(|e| StorageError::StorageOpenError(e.to_string()))?;
+        let blocks = if content_addressed {
+            Some(
+                db.open_tree(blocks_tree_name(&uid))
+                    .map_err(|e| StorageError::StorageOpenError(e.to_string()))?,
+            )
+        } else {
+            None
+        };
+
         Ok(Self {
             db,
             path: path.to_path_buf(),
             user_db,
+            meta,
+            blocks,
+        })
+    }
+
+    /// Store of another user in the same database, in the plain layout
+    pub fn open_sibling(&self, uid: [u8; 32]) -> Result<Self> {
+        let user_db = self
+            .db
+            .open_tree(uid)
+            .map_err(|e| StorageError::StorageOpenError(e.to_string()))?;
+        let meta = self
+            .db
+            .open_tree(meta_tree_name(&uid))
+            .map_err(|e| StorageError::StorageOpenError(e.to_string()))?;
+        Ok(Self {
+            db: self.db.clone(),
+            path: self.path.clone(),
+            user_db,
+            meta,
+            blocks: None,
         })
     }
+
+    /// Remove this user's records and metadata from the database for good
+    pub fn drop_trees(self) -> Result<()> {
+        let mut names = vec![self.user_db.name(), self.meta.name()];
+        if let Some(blocks) = &self.blocks {
+            names.push(blocks.name());
+        }
+        for name in names {
+            self.db
+                .drop_tree(name)
+                .map_err(|e| StorageError::StorageWriteError(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    /// Open in content-addressed mode: identical record data is stored once and
+    /// reference counted. Only possible for a store without plain records.
+    pub fn open_content_addressed(path: &Path, uid: [u8; 32]) -> Result<Self> {
+        let mut storage = Self::open(path, uid)?;
+        storage.enable_content_addressing(uid)?;
+        Ok(storage)
+    }
+
+    /// Switch an empty store to content-addressed mode, a no-op if it already is
+    pub fn enable_content_addressing(&mut self, uid: [u8; 32]) -> Result<()> {
+        if self.blocks.is_some() {
+            return Ok(());
+        }
+        if !self.user_db.is_empty() {
+            return Err(StorageError::StorageOpenError(
+                "Storage already holds records that are not content-addressed".to_string(),
+            ));
+        }
+        self.set_meta(CONTENT_ADDRESSED_META, &[1])?;
+        self.blocks = Some(
+            self.db
+                .open_tree(blocks_tree_name(&uid))
+                .map_err(|e| StorageError::StorageOpenError(e.to_string()))?,
+        );
+        Ok(())
+    }
+
+    pub fn is_content_addressed(&self) -> bool {
+        self.blocks.is_some()
+    }
+
+    /// Name of the record tree, the user id the store was opened for
+    pub fn record_tree_name(&self) -> Vec<u8> {
+        self.user_db.name().to_vec()
+    }
+
+    /// Whether `path` already holds a sled database, checked without opening it
+    pub fn exists_at(path: &Path) -> bool {
+        path.join("conf").is_file() || path.join("db").is_file()
+    }
     //TODO check path don't exist and create new db, fix errors
 
-    pub fn init(path: &Path, uid: u128) -> Result<Self> {
+    pub fn init(path: &Path, uid: [u8; 32]) -> Result<Self> {
         // Check if the path exists
         if path.exists() {
             return Err(StorageError::SrorageExistError(format!(
@@ -60,16 +383,27 @@ impl Storage {
             .open()
             .map_err(|e| StorageError::StorageOpenError(e.to_string()))?;
         let user_db = db
-            .open_tree(uid.to_le_bytes())
+            .open_tree(uid)
+            .map_err(|e| StorageError::StorageOpenError(e.to_string()))?;
+        let meta = db
+            .open_tree(meta_tree_name(&uid))
             .map_err(|e| StorageError::StorageOpenError(e.to_string()))?;
         Ok(Self {
             db,
             path: path.to_path_buf(),
             user_db,
+            meta,
+            blocks: None,
         })
     }
 
     pub fn set(&self, key: u64, payload: &CipherRecord) -> Result<()> {
+        if let Some(blocks) = &self.blocks {
+            return (&self.user_db, blocks)
+                .transaction(|(user_db, blocks)| tx_put_record(user_db, blocks, key, payload))
+                .map_err(tx_error);
+        }
+
         self.user_db
             .insert(key.to_be_bytes(), serialize(payload).unwrap())
             .map_err(|e| StorageError::StorageWriteError(e.to_string()))?;
@@ -82,24 +416,114 @@ impl Storage {
             .get(key.to_be_bytes())
             .map_err(|e| StorageError::StorageReadError(e.to_string()))?
             .ok_or(StorageError::StorageDataNotFound(key.to_string()))?;
-        Ok(deserialize(&some_value).unwrap())
+        let record = CipherRecord::decode(&some_value)
+            .map_err(|e| StorageError::StorageReadError(e.to_string()))?;
+        self.resolve_block(key, record)
     }
-    //TODO implement it  /*old_payload: &CipherRecord*/
-    pub fn up(&self, key: u64, payload: &CipherRecord) -> Result<()> {
-        // match self.user_db.compare_and_swap(key.to_be_bytes(), old_payload, payload)?
+    /// Records under `keys` in the order given, keys without a record are skipped and a
+    /// repeated key is returned once. Scans the span between the smallest and largest key
+    /// once instead of looking every key up, which pays off for dense id lists such as a
+    /// page of `list_ids_page`; see `benches/get_many.rs`.
+    pub fn get_many(&self, keys: &[u64]) -> Result<Vec<(u64, CipherRecord)>> {
+        let (Some(&first), Some(&last)) = (keys.iter().min(), keys.iter().max()) else {
+            return Ok(Vec::new());
+        };
+        let wanted: HashSet<u64> = keys.iter().copied().collect();
 
-        self.user_db
-            .remove(key.to_be_bytes())
-            .map_err(|e| StorageError::StorageWriteError(e.to_string()))?;
+        let mut found = HashMap::with_capacity(wanted.len());
+        for item in self.user_db.range(first.to_be_bytes()..=last.to_be_bytes()) {
+            let (key, value) = item.map_err(|e| StorageError::StorageReadError(e.to_string()))?;
+            let key = record_key(&key)?;
+            if !wanted.contains(&key) {
+                continue;
+            }
+            let record = CipherRecord::decode(&value)
+                .map_err(|e| StorageError::StorageReadError(e.to_string()))?;
+            found.insert(key, self.resolve_block(key, record)?);
+        }
 
-        self.user_db
-            .insert(key.to_be_bytes(), serialize(payload).unwrap())
-            .map_err(|e| StorageError::StorageWriteError(e.to_string()))?;
+        Ok(keys
+            .iter()
+            .filter_map(|key| found.remove(key).map(|record| (*key, record)))
+            .collect())
+    }
+    // Content-addressed records hold a block hash in `data`, swap in the block's contents
+    fn resolve_block(&self, key: u64, mut record: CipherRecord) -> Result<CipherRecord> {
+        if let Some(blocks) = &self.blocks {
+            let block = blocks
+                .get(&record.data)
+                .map_err(|e| StorageError::StorageReadError(e.to_string()))?
+                .ok_or(StorageError::StorageDataNotFound(format!(
+                    "block of record {}",
+                    key
+                )))?;
+            block_refs(&block)?;
+            record.data = block[BLOCK_HEADER_LEN..].to_vec();
+        }
+        Ok(record)
+    }
+    /// Replace the record under `key` with `payload`, only if it is still `expected`
+    /// (as returned by `get`). A record changed in between is left alone and reported
+    /// as `StorageConflict`, so concurrent writers can't clobber each other.
+    pub fn up(&self, key: u64, payload: &CipherRecord, expected: &CipherRecord) -> Result<()> {
+        if let Some(blocks) = &self.blocks {
+            // Stored records reference their block by hash
+            let expected = CipherRecord {
+                data: blake3::hash(&expected.data).as_bytes().to_vec(),
+                ..expected.clone()
+            };
+            return (&self.user_db, blocks)
+                .transaction(|(user_db, blocks)| {
+                    let stored = match user_db.get(key.to_be_bytes())? {
+                        Some(bytes) => Some(CipherRecord::decode(&bytes).map_err(|e| {
+                            tx_abort(StorageError::StorageReadError(e.to_string()))
+                        })?),
+                        None => None,
+                    };
+                    if stored.as_ref() != Some(&expected) {
+                        return Err(tx_abort(StorageError::StorageConflict(key.to_string())));
+                    }
+                    tx_put_record(user_db, blocks, key, payload)
+                })
+                .map_err(tx_error);
+        }
 
-        Ok(())
+        // Compared decoded, so a record still in an older layout matches what `get`
+        // returned; the swap then checks the bytes didn't change since
+        let stored = self
+            .user_db
+            .get(key.to_be_bytes())
+            .map_err(|e| StorageError::StorageReadError(e.to_string()))?;
+        let unchanged = match &stored {
+            Some(bytes) => {
+                CipherRecord::decode(bytes)
+                    .map_err(|e| StorageError::StorageReadError(e.to_string()))?
+                    == *expected
+            }
+            None => false,
+        };
+        if !unchanged {
+            return Err(StorageError::StorageConflict(key.to_string()));
+        }
+        let payload =
+            serialize(payload).map_err(|e| StorageError::StorageWriteError(e.to_string()))?;
+        self.user_db
+            .compare_and_swap(key.to_be_bytes(), stored, Some(payload))
+            .map_err(|e| StorageError::StorageWriteError(e.to_string()))?
+            .map_err(|_| StorageError::StorageConflict(key.to_string()))
     }
     //TODO remove all old version `contains_key`
     pub fn remove(&self, key: u64) -> Result<()> {
+        if let Some(blocks) = &self.blocks {
+            return (&self.user_db, blocks)
+                .transaction(|(user_db, blocks)| {
+                    tx_release_record(user_db, blocks, key)?;
+                    user_db.remove(&key.to_be_bytes()[..])?;
+                    Ok::<(), ConflictableTransactionError<StorageError>>(())
+                })
+                .map_err(tx_error);
+        }
+
         self.user_db
             .remove(key.to_be_bytes())
             .map_err(|e| StorageError::StorageWriteError(e.to_string()))?;
@@ -110,33 +534,188 @@ impl Storage {
             .iter()
             .map(|item| {
                 item.map_err(|e| StorageError::StorageReadError(e.to_string()))
-                    .and_then(|(key, _value)| {
-                        let key_u64 = u64::from_be_bytes(key.as_ref().try_into().map_err(
-                            |e: std::array::TryFromSliceError| {
-                                StorageError::StorageKeyError(e.to_string())
-                            },
-                        )?);
-                        Ok(key_u64)
-                    })
+                    .and_then(|(key, _value)| record_key(&key))
             })
             .collect()
     }
-    // TODO refactor to sleed interface
-    pub fn list_ids_with_metadata(&self) -> Result<Vec<(u64, u64, [u8; 32])>> {
-        // Returns vector of (record_id, version, timestamp)
-        let ids = self
-            .list_ids()
-            .map_err(|e| StorageError::StorageReadError(e.to_string()))?;
+    /// Up to `limit` record ids in key order, starting after `after`. Reads keys only,
+    /// record bodies are neither fetched from blocks nor deserialized.
+    pub fn list_ids_page(&self, after: Option<u64>, limit: usize) -> Result<Vec<u64>> {
+        let start = match after {
+            Some(id) => match id.checked_add(1) {
+                Some(next) => next,
+                None => return Ok(Vec::new()),
+            },
+            None => 0,
+        };
+
+        self.user_db
+            .range(start.to_be_bytes()..)
+            .keys()
+            .take(limit)
+            .map(|key| {
+                key.map_err(|e| StorageError::StorageReadError(e.to_string()))
+                    .and_then(|key| record_key(&key))
+            })
+            .collect()
+    }
+    /// Write everything buffered to disk. sled otherwise flushes once a second, so a
+    /// crash can lose up to a second of writes; each call costs an fsync, a few
+    /// milliseconds on typical disks.
+    pub fn flush(&self) -> Result<()> {
+        self.db
+            .flush()
+            .map_err(|e| StorageError::StorageWriteError(e.to_string()))?;
+        Ok(())
+    }
+
+    /// `flush` without blocking the calling thread
+    pub async fn flush_async(&self) -> Result<()> {
+        self.db
+            .flush_async()
+            .await
+            .map_err(|e| StorageError::StorageWriteError(e.to_string()))?;
+        Ok(())
+    }
+
+    pub fn get_meta(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .meta
+            .get(key)
+            .map_err(|e| StorageError::StorageReadError(e.to_string()))?
+            .map(|value| value.to_vec()))
+    }
+
+    pub fn set_meta(&self, key: &str, value: &[u8]) -> Result<()> {
+        self.meta
+            .insert(key, value)
+            .map_err(|e| StorageError::StorageWriteError(e.to_string()))?;
+        Ok(())
+    }
+
+    pub fn remove_meta(&self, key: &str) -> Result<()> {
+        self.meta
+            .remove(key)
+            .map_err(|e| StorageError::StorageWriteError(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Write `records` in one transaction, either all of them land or none do.
+    /// A record whose id differs from its key aborts the whole batch.
+    pub fn set_batch(&self, records: &[(u64, CipherRecord)]) -> Result<()> {
+        if let Some(blocks) = &self.blocks {
+            return (&self.user_db, blocks)
+                .transaction(|(user_db, blocks)| -> TxResult<()> {
+                    for (key, payload) in records {
+                        check_record_id(*key, payload).map_err(tx_abort)?;
+                        tx_put_record(user_db, blocks, *key, payload)?;
+                    }
+                    Ok(())
+                })
+                .map_err(tx_error);
+        }
+
+        self.user_db
+            .transaction(|user_db| -> TxResult<()> {
+                for (key, payload) in records {
+                    check_record_id(*key, payload).map_err(tx_abort)?;
+                    let encoded = serialize(payload)
+                        .map_err(|e| tx_abort(StorageError::StorageWriteError(e.to_string())))?;
+                    user_db.insert(&key.to_be_bytes()[..], encoded)?;
+                }
+                Ok(())
+            })
+            .map_err(tx_error)
+    }
+
+    /// Write records together with a metadata entry in one transaction,
+    /// either all of them land or none do
+    pub fn set_records_with_meta(
+        &self,
+        records: &[(u64, CipherRecord)],
+        meta_entries: &[(&str, &[u8])],
+    ) -> Result<()> {
+        if let Some(blocks) = &self.blocks {
+            return (&self.user_db, &self.meta, blocks)
+                .transaction(|(user_db, meta, blocks)| {
+                    for (key, payload) in records {
+                        tx_put_record(user_db, blocks, *key, payload)?;
+                    }
+                    for (meta_key, meta_value) in meta_entries {
+                        meta.insert(*meta_key, *meta_value)?;
+                    }
+                    Ok::<(), ConflictableTransactionError<StorageError>>(())
+                })
+                .map_err(tx_error);
+        }
+
+        let encoded = records
+            .iter()
+            .map(|(key, payload)| {
+                serialize(payload)
+                    .map(|bytes| (key.to_be_bytes(), bytes))
+                    .map_err(|e| StorageError::StorageWriteError(e.to_string()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        (&self.user_db, &self.meta)
+            .transaction(|(user_db, meta)| {
+                for (key, bytes) in &encoded {
+                    user_db.insert(&key[..], bytes.as_slice())?;
+                }
+                for (meta_key, meta_value) in meta_entries {
+                    meta.insert(*meta_key, *meta_value)?;
+                }
+                Ok::<(), ConflictableTransactionError<()>>(())
+            })
+            .map_err(|e| StorageError::StorageWriteError(format!("{:?}", e)))?;
+        Ok(())
+    }
+
+    /// (record_id, version, updated) of every record. Only the fixed-size fields are
+    /// decoded, the ciphertext is neither copied nor, when content-addressed, looked up.
+    /// An undecodable entry fails the listing with `StorageReadError`, as `get` would.
+    pub fn list_ids_with_metadata(&self) -> Result<Vec<(u64, u64, u64)>> {
+        self.scan_metadata(|_, _| true)
+    }
 
+    /// Metadata of records written strictly after `ts` (unix seconds)
+    pub fn list_changed_since(&self, ts: u64) -> Result<Vec<(u64, u64, u64)>> {
+        self.scan_metadata(|_, (_, _, updated)| *updated > ts)
+    }
+
+    /// `list_ids_with_metadata` of the records whose `user_id` is `owner`, compared in
+    /// constant time
+    pub fn list_owned_with_metadata(&self, owner: &[u8; 32]) -> Result<Vec<(u64, u64, u64)>> {
+        self.scan_metadata(|user_id, _| user_id.ct_eq(owner).into())
+    }
+
+    /// `list_changed_since` of the records whose `user_id` is `owner`
+    pub fn list_owned_changed_since(
+        &self,
+        owner: &[u8; 32],
+        ts: u64,
+    ) -> Result<Vec<(u64, u64, u64)>> {
+        self.scan_metadata(|user_id, (_, _, updated)| {
+            *updated > ts && bool::from(user_id.ct_eq(owner))
+        })
+    }
+
+    fn scan_metadata(
+        &self,
+        keep: impl Fn(&[u8; 32], &(u64, u64, u64)) -> bool,
+    ) -> Result<Vec<(u64, u64, u64)>> {
         let mut records = Vec::new();
-        for id_64 in ids {
-            if let Ok(record) = self.get(id_64) {
-                // if record.user_id == self.user_id {
-                records.push((record.cipher_record_id, record.ver, record.user_id));
-                // }
+        for item in self.user_db.iter() {
+            let (key, value) = item.map_err(|e| StorageError::StorageReadError(e.to_string()))?;
+            let id = record_key(&key)?;
+            let (user_id, metadata) = record_metadata(&value).ok_or_else(|| {
+                StorageError::StorageReadError(format!("record {id} can't be decoded"))
+            })?;
+            if keep(&user_id, &metadata) {
+                records.push(metadata);
             }
         }
-
         Ok(records)
     }
 }
@@ -162,6 +741,8 @@ mod storage_tests {
             ver: 1,
             cipher_options: [0].to_vec(),
             data: [0, 42, 0, 42].to_vec(),
+            signature: vec![],
+            updated: 0,
         };
 
         db.set(KEY, &payload).unwrap();
@@ -169,7 +750,238 @@ mod storage_tests {
         let out = db.get(KEY).unwrap();
 
         assert_eq!(out, payload);
+        assert_eq!(db.record_tree_name(), [42; 32]);
+    }
+    #[test]
+    fn test_open_with_config() {
+        let tmp_dir = TempDir::new("test_storage").unwrap();
+        let config = StorageConfig {
+            cache_bytes: 0,
+            ..StorageConfig::default()
+        };
+        assert!(matches!(
+            Storage::open_with_config(tmp_dir.path(), [42; 32], config),
+            Err(StorageError::StorageOpenError(_))
+        ));
+
+        let config = StorageConfig {
+            cache_bytes: 4096,
+            flush_ms: None,
+            mode: sled::Mode::LowSpace,
+        };
+        let db = Storage::open_with_config(tmp_dir.path(), [42; 32], config).unwrap();
+        let payload = CipherRecord {
+            user_id: [1; 32],
+            cipher_record_id: 7,
+            ver: 1,
+            cipher_options: vec![1],
+            data: vec![42; 1024],
+            signature: vec![],
+            updated: 0,
+        };
+        db.set(7, &payload).unwrap();
+        assert_eq!(db.get(7).unwrap(), payload);
+    }
+
+    #[test]
+    fn test_flush_reopen() {
+        let tmp_dir = TempDir::new("test_storage").unwrap();
+        let payload = CipherRecord {
+            user_id: [1; 32],
+            cipher_record_id: 7,
+            ver: 1,
+            cipher_options: vec![1],
+            data: vec![1, 2, 3],
+            signature: vec![],
+            updated: 0,
+        };
+        {
+            let db = Storage::open(tmp_dir.path(), [42; 32]).unwrap();
+            db.set(7, &payload).unwrap();
+            db.flush().unwrap();
+        }
+
+        let db = Storage::open(tmp_dir.path(), [42; 32]).unwrap();
+        assert_eq!(db.get(7).unwrap(), payload);
+    }
+
+    #[test]
+    fn test_list_ids_with_metadata() {
+        for content_addressed in [false, true] {
+            let tmp_dir = TempDir::new("test_storage").unwrap();
+            let db = if content_addressed {
+                Storage::open_content_addressed(tmp_dir.path(), [42; 32]).unwrap()
+            } else {
+                Storage::open(tmp_dir.path(), [42; 32]).unwrap()
+            };
+
+            let mut expected = Vec::new();
+            for id in 1..=5u64 {
+                let payload = CipherRecord {
+                    user_id: [1; 32],
+                    cipher_record_id: id,
+                    ver: id * 10,
+                    cipher_options: [0, 1].to_vec(),
+                    data: vec![id as u8; 100],
+                    signature: vec![7; 64],
+                    updated: 1000 + id,
+                };
+                db.set(id, &payload).unwrap();
+                expected.push((id, id * 10, 1000 + id));
+            }
+
+            assert_eq!(db.list_ids_with_metadata().unwrap(), expected);
+
+            // An entry that doesn't decode is reported instead of left out
+            db.user_db
+                .insert(6u64.to_be_bytes(), vec![0xff; 8])
+                .unwrap();
+            assert!(matches!(
+                db.list_ids_with_metadata(),
+                Err(StorageError::StorageReadError(_))
+            ));
+            assert!(matches!(
+                db.list_changed_since(0),
+                Err(StorageError::StorageReadError(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_list_owned_with_metadata() {
+        let tmp_dir = TempDir::new("test_storage").unwrap();
+        let db = Storage::open(tmp_dir.path(), [42; 32]).unwrap();
+        for (id, owner, updated) in [
+            (1u64, [1; 32], 100u64),
+            (2, [2; 32], 200),
+            (3, [1; 32], 300),
+        ] {
+            let payload = CipherRecord {
+                user_id: owner,
+                cipher_record_id: id,
+                ver: 1,
+                cipher_options: [0, 1].to_vec(),
+                data: vec![id as u8; 10],
+                signature: vec![],
+                updated,
+            };
+            db.set(id, &payload).unwrap();
+        }
+
+        assert_eq!(
+            db.list_owned_with_metadata(&[1; 32]).unwrap(),
+            vec![(1, 1, 100), (3, 1, 300)]
+        );
+        assert_eq!(
+            db.list_owned_changed_since(&[1; 32], 100).unwrap(),
+            vec![(3, 1, 300)]
+        );
+        assert!(db.list_owned_with_metadata(&[3; 32]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_get_many() {
+        for content_addressed in [false, true] {
+            let tmp_dir = TempDir::new("test_storage").unwrap();
+            let db = if content_addressed {
+                Storage::open_content_addressed(tmp_dir.path(), [42; 32]).unwrap()
+            } else {
+                Storage::open(tmp_dir.path(), [42; 32]).unwrap()
+            };
+
+            let record = |id: u64| CipherRecord {
+                user_id: [1; 32],
+                cipher_record_id: id,
+                ver: 1,
+                cipher_options: [0].to_vec(),
+                data: id.to_be_bytes().to_vec(),
+                signature: vec![],
+                updated: id,
+            };
+            for id in [2, 4, 6, 8, 10] {
+                db.set(id, &record(id)).unwrap();
+            }
+
+            let found = db.get_many(&[8, 3, 2, 11, 6, 8, 0]).unwrap();
+            assert_eq!(
+                found,
+                vec![(8, record(8)), (2, record(2)), (6, record(6))],
+                "content addressed: {}",
+                content_addressed
+            );
+            assert!(db.get_many(&[]).unwrap().is_empty());
+            assert!(db.get_many(&[1, 3, 5]).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_undecodable_record_is_read_error() {
+        let tmp_dir = TempDir::new("test_storage").unwrap();
+        let db = Storage::open(tmp_dir.path(), [42; 32]).unwrap();
+        db.user_db
+            .insert(7u64.to_be_bytes(), &[1, 2, 3][..])
+            .unwrap();
+
+        assert!(matches!(db.get(7), Err(StorageError::StorageReadError(_))));
+        assert!(matches!(
+            db.get_many(&[7]),
+            Err(StorageError::StorageReadError(_))
+        ));
     }
+
+    #[test]
+    fn test_truncated_block_is_read_error() {
+        let tmp_dir = TempDir::new("test_storage").unwrap();
+        let db = Storage::open_content_addressed(tmp_dir.path(), [42; 32]).unwrap();
+        let payload = CipherRecord {
+            user_id: [1; 32],
+            cipher_record_id: 1,
+            ver: 1,
+            cipher_options: [0].to_vec(),
+            data: [0, 42, 0, 42].to_vec(),
+            signature: vec![],
+            updated: 0,
+        };
+        db.set(1, &payload).unwrap();
+        let blocks = db.blocks.as_ref().unwrap();
+        let (hash, _) = blocks.first().unwrap().unwrap();
+        blocks.insert(&hash, &[1, 2, 3][..]).unwrap();
+
+        assert!(matches!(db.get(1), Err(StorageError::StorageReadError(_))));
+        assert!(matches!(
+            db.remove(1),
+            Err(StorageError::StorageReadError(_))
+        ));
+    }
+
+    #[test]
+    fn test_max_record_key() {
+        let tmp_dir = TempDir::new("test_storage").unwrap();
+        let db = Storage::open(tmp_dir.path(), [42; 32]).unwrap();
+        let keys = [u64::MAX - 1, u64::MAX, 1 << 63];
+        for key in keys {
+            let payload = CipherRecord {
+                user_id: [1; 32],
+                cipher_record_id: key,
+                ver: 1,
+                cipher_options: [0].to_vec(),
+                data: key.to_be_bytes().to_vec(),
+                signature: vec![],
+                updated: 0,
+            };
+            db.set(key, &payload).unwrap();
+            assert_eq!(db.get(key).unwrap(), payload);
+        }
+
+        let mut sorted = keys.to_vec();
+        sorted.sort();
+        assert_eq!(db.list_ids().unwrap(), sorted);
+        assert_eq!(
+            db.list_ids_page(Some(u64::MAX - 1), 10).unwrap(),
+            vec![u64::MAX]
+        );
+    }
+
     #[test]
     fn test_remove() {
         const KEY: u64 = 4242;
@@ -185,6 +997,8 @@ mod storage_tests {
             ver: 1,
             cipher_options: [0].to_vec(),
             data: [0, 42, 0, 42].to_vec(),
+            signature: vec![],
+            updated: 0,
         };
         db.set(KEY, &payload).unwrap();
         db.remove(KEY).unwrap();
@@ -202,4 +1016,191 @@ mod storage_tests {
             _ => panic!("Expected StorageDataNotFound error, but got: {:?}", result),
         }  */
     }
+
+    #[test]
+    fn test_content_addressed_dedup() {
+        let tmp_dir = TempDir::new("test_storage").unwrap();
+        let db = Storage::open_content_addressed(tmp_dir.path(), [42; 32]).unwrap();
+        let payload = CipherRecord {
+            user_id: [1; 32],
+            cipher_record_id: 1,
+            ver: 1,
+            cipher_options: [0].to_vec(),
+            data: [0, 42, 0, 42].to_vec(),
+            signature: vec![],
+            updated: 0,
+        };
+        let block_count = |db: &Storage| db.blocks.as_ref().unwrap().len();
+
+        db.set(1, &payload).unwrap();
+        db.set(
+            2,
+            &CipherRecord {
+                cipher_record_id: 2,
+                ..payload.clone()
+            },
+        )
+        .unwrap();
+        // A new version with identical content keeps sharing the block
+        db.up(
+            2,
+            &CipherRecord {
+                cipher_record_id: 2,
+                ver: 2,
+                ..payload.clone()
+            },
+            &db.get(2).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(block_count(&db), 1);
+
+        db.remove(1).unwrap();
+        assert_eq!(db.get(2).unwrap().data, payload.data);
+        assert_eq!(block_count(&db), 1);
+
+        db.remove(2).unwrap();
+        assert_eq!(block_count(&db), 0);
+        drop(db);
+
+        // The mode is persisted
+        let db = Storage::open(tmp_dir.path(), [42; 32]).unwrap();
+        assert!(db.is_content_addressed());
+    }
+
+    #[test]
+    fn test_baseline_layout_record() {
+        use crate::structures::CipherRecordV0;
+
+        let tmp_dir = TempDir::new("test_storage").unwrap();
+        let db = Storage::open(tmp_dir.path(), [42; 32]).unwrap();
+        let old = CipherRecordV0 {
+            user_id: [1; 32],
+            cipher_record_id: 3,
+            ver: 5,
+            cipher_options: [0].to_vec(),
+            data: [0, 42, 0, 42].to_vec(),
+        };
+        // Written as before `signature` and `updated` existed
+        db.user_db
+            .insert(3u64.to_be_bytes(), serialize(&old).unwrap())
+            .unwrap();
+
+        let upgraded = db.get(3).unwrap();
+        assert_eq!(upgraded, CipherRecord::from(old.clone()));
+        assert!(upgraded.signature.is_empty());
+        assert_eq!(upgraded.updated, 0);
+        assert_eq!(db.get_many(&[3]).unwrap(), vec![(3, upgraded.clone())]);
+
+        // Updating it writes the current layout
+        let update = CipherRecord {
+            ver: 6,
+            updated: 100,
+            ..upgraded.clone()
+        };
+        assert_eq!(db.list_ids_with_metadata().unwrap(), vec![(3, 5, 0)]);
+        db.up(3, &update, &upgraded).unwrap();
+        assert_eq!(db.get(3).unwrap(), update);
+        assert_eq!(db.list_ids_with_metadata().unwrap(), vec![(3, 6, 100)]);
+        assert!(matches!(
+            db.up(3, &update, &upgraded),
+            Err(StorageError::StorageConflict(_))
+        ));
+    }
+
+    #[test]
+    fn test_stale_update_conflict() {
+        for content_addressed in [false, true] {
+            let tmp_dir = TempDir::new("test_storage").unwrap();
+            let db = if content_addressed {
+                Storage::open_content_addressed(tmp_dir.path(), [42; 32]).unwrap()
+            } else {
+                Storage::open(tmp_dir.path(), [42; 32]).unwrap()
+            };
+            let payload = CipherRecord {
+                user_id: [1; 32],
+                cipher_record_id: 1,
+                ver: 1,
+                cipher_options: [0].to_vec(),
+                data: [0, 42, 0, 42].to_vec(),
+                signature: vec![],
+                updated: 0,
+            };
+            db.set(1, &payload).unwrap();
+
+            // Two writers read the same version
+            let first = db.get(1).unwrap();
+            let second = db.get(1).unwrap();
+
+            let first_update = CipherRecord {
+                ver: 2,
+                data: vec![1, 2, 3],
+                ..payload.clone()
+            };
+            db.up(1, &first_update, &first).unwrap();
+
+            let second_update = CipherRecord {
+                ver: 2,
+                data: vec![4, 5, 6],
+                ..payload.clone()
+            };
+            assert!(matches!(
+                db.up(1, &second_update, &second),
+                Err(StorageError::StorageConflict(_))
+            ));
+            assert_eq!(db.get(1).unwrap(), first_update);
+        }
+    }
+
+    #[test]
+    fn test_set_batch_all_or_nothing() {
+        for content_addressed in [false, true] {
+            let tmp_dir = TempDir::new("test_storage").unwrap();
+            let db = if content_addressed {
+                Storage::open_content_addressed(tmp_dir.path(), [42; 32]).unwrap()
+            } else {
+                Storage::open(tmp_dir.path(), [42; 32]).unwrap()
+            };
+            let record = |id: u64, ver: u64| CipherRecord {
+                user_id: [1; 32],
+                cipher_record_id: id,
+                ver,
+                cipher_options: [0].to_vec(),
+                data: vec![id as u8, ver as u8],
+                signature: vec![],
+                updated: ver,
+            };
+            db.set(1, &record(1, 1)).unwrap();
+
+            // The last record is filed under the wrong key, after the others were written
+            let batch = vec![(1, record(1, 2)), (2, record(2, 1)), (4, record(3, 1))];
+            assert!(matches!(
+                db.set_batch(&batch),
+                Err(StorageError::StorageRecordIdMismatch(_))
+            ));
+            assert_eq!(db.get(1).unwrap(), record(1, 1));
+            assert_eq!(db.list_ids().unwrap(), vec![1]);
+
+            let batch = vec![(1, record(1, 2)), (2, record(2, 1))];
+            db.set_batch(&batch).unwrap();
+            assert_eq!(db.get_many(&[1, 2]).unwrap(), batch);
+        }
+    }
+
+    #[test]
+    fn test_ensure_dir() {
+        let tmp_dir = TempDir::new("test_storage").unwrap();
+
+        let new_dir = tmp_dir.path().join("nested").join("db");
+        ensure_dir(&new_dir).unwrap();
+        assert!(new_dir.is_dir());
+        // An existing directory is accepted as is
+        ensure_dir(&new_dir).unwrap();
+
+        let file = tmp_dir.path().join("not_a_dir");
+        std::fs::write(&file, b"data").unwrap();
+        assert!(matches!(
+            ensure_dir(&file),
+            Err(StorageError::StorageNotADirectory(_))
+        ));
+    }
 }
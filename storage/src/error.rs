@@ -18,6 +18,14 @@ pub enum StorageError {
     StorageReadError(String),
     #[error("Storage write error: {0}")]
     StorageWriteError(String),
+    #[error("Path exists but is not a directory: {0}")]
+    StorageNotADirectory(String),
+    #[error("Storage directory is not accessible: {0}")]
+    StorageAccessError(String),
+    #[error("Record {0} was changed by another writer")]
+    StorageConflict(String),
+    #[error("Record stored under key {0} carries a different id")]
+    StorageRecordIdMismatch(String),
 }
 
 pub type Result<T> = std::result::Result<T, StorageError>;
@@ -1,4 +1,5 @@
 pub mod db;
+mod record_cache;
 pub mod structures;
 pub mod user_db;
 
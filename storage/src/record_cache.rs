@@ -0,0 +1,76 @@
+use crate::structures::Record;
+use std::collections::VecDeque;
+use zeroize::Zeroize;
+
+/// Bounded cache of decrypted records keyed by `(id, ver)`, least recently used evicted
+/// first. Evicted and dropped records have their strings wiped.
+pub(crate) struct RecordCache {
+    capacity: usize,
+    entries: VecDeque<(u64, u64, Record)>,
+}
+
+impl RecordCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub(crate) fn get(&mut self, id: u64, ver: u64) -> Option<Record> {
+        let pos = self
+            .entries
+            .iter()
+            .position(|(entry_id, entry_ver, _)| *entry_id == id && *entry_ver == ver)?;
+        let entry = self.entries.remove(pos)?;
+        let record = entry.2.clone();
+        self.entries.push_back(entry);
+        Some(record)
+    }
+
+    pub(crate) fn insert(&mut self, id: u64, ver: u64, record: Record) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.invalidate(id);
+        while self.entries.len() >= self.capacity {
+            if let Some((_, _, mut evicted)) = self.entries.pop_front() {
+                wipe(&mut evicted);
+            }
+        }
+        self.entries.push_back((id, ver, record));
+    }
+
+    /// Drop every cached version of a record
+    pub(crate) fn invalidate(&mut self, id: u64) {
+        self.entries.retain_mut(|(entry_id, _, record)| {
+            if *entry_id == id {
+                wipe(record);
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    pub(crate) fn clear(&mut self) {
+        for (_, _, record) in self.entries.iter_mut() {
+            wipe(record);
+        }
+        self.entries.clear();
+    }
+}
+
+impl Drop for RecordCache {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+fn wipe(record: &mut Record) {
+    record.icon.zeroize();
+    for item in record.fields.iter_mut() {
+        item.title.zeroize();
+        item.value.zeroize();
+    }
+}
@@ -1,6 +1,11 @@
+use bincode::Options;
+use crypto::totp::{totp, TotpError};
 use crypto::UserId;
 use serde::{Deserialize, Serialize};
 
+/// Schema version prefixed to an encoded `Record`, bumped whenever its layout changes
+pub const RECORD_SCHEMA_VERSION: u8 = 2;
+
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub enum Atributes {
     Hide,
@@ -29,6 +34,129 @@ pub struct Record {
     pub created: u64,
     pub updated: u64,
     pub fields: Vec<Item>,
+    // Unix seconds of the last `UserDb::read_and_touch`, 0 if never
+    pub last_accessed: u64,
+}
+
+/// `Record` as first stored, before `last_accessed` (schema version 1)
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct RecordV1 {
+    pub icon: String,
+    pub created: u64,
+    pub updated: u64,
+    pub fields: Vec<Item>,
+}
+
+impl From<RecordV1> for Record {
+    fn from(old: RecordV1) -> Self {
+        Self {
+            icon: old.icon,
+            created: old.created,
+            updated: old.updated,
+            fields: old.fields,
+            last_accessed: 0,
+        }
+    }
+}
+
+impl Record {
+    /// `[schema version][bincode record]`, the plaintext `UserDb` encrypts
+    pub fn encode_versioned(&self) -> bincode::Result<Vec<u8>> {
+        let mut bytes = vec![RECORD_SCHEMA_VERSION];
+        bincode::serialize_into(&mut bytes, self)?;
+        Ok(bytes)
+    }
+
+    /// Decode `encode_versioned` output of any known schema version, upgraded to the
+    /// current layout. Records written before the prefix existed are read as the
+    /// current layout, then as `RecordV1`.
+    pub fn decode_versioned(bytes: &[u8]) -> bincode::Result<Record> {
+        // Strict about trailing bytes, so an unprefixed record is unlikely to pass
+        // for a prefixed one
+        let strict = bincode::DefaultOptions::new().with_fixint_encoding();
+        let prefixed = match bytes.split_first() {
+            Some((2, rest)) => strict.deserialize::<Record>(rest),
+            Some((1, rest)) => strict.deserialize::<RecordV1>(rest).map(Record::from),
+            Some((version, _)) => Err(Box::new(bincode::ErrorKind::Custom(format!(
+                "unknown record schema version {}",
+                version
+            )))),
+            None => Err(Box::new(bincode::ErrorKind::Custom(String::from(
+                "empty record",
+            )))),
+        };
+        prefixed.or_else(|err| {
+            bincode::deserialize::<Record>(bytes)
+                .or_else(|_| bincode::deserialize::<RecordV1>(bytes).map(Record::from))
+                .map_err(|_| err)
+        })
+    }
+
+    /// Same icon and fields, ignoring the timestamps
+    pub fn content_eq(&self, other: &Record) -> bool {
+        self.icon == other.icon && self.fields == other.fields
+    }
+
+    /// Value of the first field with the given title
+    pub fn field_value(&self, title: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|item| item.title == title)
+            .map(|item| item.value.as_str())
+    }
+
+    /// Current TOTP code of each `Atributes::Reload` field, by title. The field value
+    /// is the Base32 secret; a malformed one yields an error for that field only.
+    pub fn totp_codes(&self, unix_time: u64) -> Vec<(&str, Result<String, TotpError>)> {
+        self.fields
+            .iter()
+            .filter(|item| item.types.contains(&Atributes::Reload))
+            .map(|item| (item.title.as_str(), totp(&item.value, unix_time)))
+            .collect()
+    }
+}
+
+/// Argon2id costs the vault keys were derived with
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Argon2Params {
+    pub memory_kib: u32,
+    pub time_cost: u32,
+    pub parallelism: u32,
+}
+
+/// Vault-wide settings, stored once per vault next to the records
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct VaultMeta {
+    pub schema_version: u32,
+    pub argon2_params: Argon2Params,
+    pub cipher_chain: Vec<u8>, // CipherOption codes, applied in order
+    pub canary: Vec<u8>,       // known plaintext encrypted deterministically, detects wrong keys
+    pub created: u64,
+}
+
+/// Left behind by a local delete until sync has propagated it to the server
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Tombstone {
+    pub id: u64,
+    pub ver: u64,     // version of the deletion, one past the deleted record's
+    pub deleted: u64, // unix seconds
+}
+
+/// Progress of `UserDb::migrate_chain`, kept until every record is re-encrypted.
+/// Records are migrated in id order, so the last id done is enough to resume.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct ChainMigration {
+    pub cipher_chain: Vec<u8>,  // CipherOption codes of the target chain
+    pub last_done: Option<u64>, // highest id already re-encrypted under it
+}
+
+/// Portable backup written by `UserDb::export_encrypted`, records stay encrypted
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct VaultExport {
+    pub format_version: u32,
+    pub user_id: UserId,
+    pub exported: u64, // unix seconds
+    pub records: Vec<CipherRecord>,
 }
 
 pub struct DataBase {
@@ -38,16 +166,71 @@ pub struct DataBase {
     records: Vec<Record>,
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub struct CipherRecord {
     pub user_id: UserId,
     pub cipher_record_id: u64,
     pub ver: u64, // TODO research
     pub cipher_options: Vec<u8>,
     pub data: Vec<u8>,
+    pub signature: Vec<u8>,
+    pub updated: u64, // unix seconds of the last write, kept in plaintext for sync
+}
+
+/// `CipherRecord` as first stored, before `signature` and `updated`
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct CipherRecordV0 {
+    pub user_id: UserId,
+    pub cipher_record_id: u64,
+    pub ver: u64,
+    pub cipher_options: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+impl From<CipherRecordV0> for CipherRecord {
+    fn from(old: CipherRecordV0) -> Self {
+        Self {
+            user_id: old.user_id,
+            cipher_record_id: old.cipher_record_id,
+            ver: old.ver,
+            cipher_options: old.cipher_options,
+            data: old.data,
+            signature: vec![],
+            updated: 0,
+        }
+    }
+}
+
+impl CipherRecord {
+    /// Decode a stored record, upgrading the `CipherRecordV0` layout. Records are
+    /// still written in the current layout, there is no version prefix: the old
+    /// layout is a strict prefix of the current one, so it never decodes as current.
+    pub fn decode(bytes: &[u8]) -> bincode::Result<CipherRecord> {
+        let strict = bincode::DefaultOptions::new().with_fixint_encoding();
+        strict.deserialize::<CipherRecord>(bytes).or_else(|err| {
+            strict
+                .deserialize::<CipherRecordV0>(bytes)
+                .map(CipherRecord::from)
+                .map_err(|_| err)
+        })
+    }
 }
 // TODO Add index cipher_record_id -> record_id + ver
 
+/// `CipherRecord::data` of a record written by `UserDb::create_indexed`
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct IndexedRecordData {
+    pub searchable: Vec<SearchableField>, // fields without `Atributes::Hide`, in field order
+    pub blob: Vec<u8>, // the record, searchable titles and values blanked, encrypted as usual
+}
+
+/// Title and value of a searchable field, each encrypted deterministically
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct SearchableField {
+    pub title: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
 pub struct CipherDataBase {
     version: u64,
     timestamp: u64,
@@ -1,16 +1,72 @@
 use crate::db::Storage;
 use crate::error::StorageError;
-use crate::structures::{CipherRecord, Record};
+use crate::record_cache::RecordCache;
+use crate::structures::{
+    Argon2Params, Atributes, ChainMigration, CipherRecord, IndexedRecordData, Record,
+    SearchableField, Tombstone, VaultExport, VaultMeta,
+};
 use bincode::{deserialize, serialize};
 use crypto::cipher_chain::CipherChain;
-use crypto::structures::{CipherOption, UserId};
+use crypto::structures::{chain_from_codes, CipherOption, UserId};
 use crypto::MasterKeys;
+use rand::{rngs::OsRng, RngCore};
+use std::io::{BufReader, BufWriter, Write};
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use subtle::ConstantTimeEq;
 
 pub struct UserDb<'a> {
     pub storage: Storage,
     ciphers: CipherChain<'a>,
     user_id: UserId,
+    cache: Mutex<Option<RecordCache>>,
+    decrypts: AtomicUsize,
+}
+
+/// How imported records are matched against ones already in the vault
+#[derive(Clone, Debug, PartialEq)]
+pub enum DedupPolicy {
+    /// Every imported record is added
+    Off,
+    /// Skip records whose content equals an existing record
+    Content,
+    /// Records with equal values for all the given field titles (e.g. URL + Login)
+    /// are the same entry: identical ones are skipped, differing ones merged
+    KeyFields(Vec<String>),
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ImportReport {
+    pub added: usize,
+    pub skipped: usize,
+    pub merged: usize,
+}
+
+/// Outcome of `UserDb::check_integrity`
+#[derive(Debug, Default)]
+pub struct IntegrityReport {
+    /// Records that decrypted and deserialized cleanly
+    pub ok: usize,
+    /// Ids of the records that didn't, with the reason
+    pub failed: Vec<(u64, UserDbError)>,
+}
+
+/// A record present in both vaults with different content
+#[derive(Debug, PartialEq)]
+pub struct RecordDiff {
+    pub id: u64,
+    pub self_ver: u64,
+    pub other_ver: u64,
+}
+
+/// How two vaults differ, by record id
+#[derive(Debug, Default, PartialEq)]
+pub struct VaultDiff {
+    pub only_in_self: Vec<u64>,
+    pub only_in_other: Vec<u64>,
+    pub differing: Vec<RecordDiff>,
+    pub unchanged: usize,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -23,8 +79,58 @@ pub enum UserDbError {
     EncryptionError,
     #[error("Decryption error")]
     DecryptionError,
+    #[error("Key generations don't match the vault, rotated keys must be restored first")]
+    KeyGenerationMismatch,
+    #[error("Keys don't match the vault, check the seed phrase")]
+    WrongKey,
+    #[error("Cipher chain doesn't match the one the vault was created with")]
+    CipherChainMismatch,
+    #[error("Stored cipher chain is missing ciphers of the expected one, the vault may have been tampered with")]
+    CipherChainDowngrade,
+    #[error("Vault schema version {0} is newer than supported")]
+    UnsupportedSchema(u32),
+    #[error("A vault already exists at {0}")]
+    VaultExists(String),
+    #[error("Couldn't find a free record id")]
+    RecordIdExhausted,
+    #[error("Record was encrypted with unknown cipher code {0}")]
+    UnknownCipher(u8),
+    #[error("No key derived for {0:?}, derive the keys of the new cipher chain first")]
+    MissingKey(CipherOption),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Export format version {0} is newer than supported")]
+    UnsupportedExport(u32),
+    #[error("An unfinished migration to another cipher chain must be completed first")]
+    ChainMigrationPending,
+    #[error("The new seed already has a vault in this database")]
+    RekeyTargetExists,
+    #[error("Deleted {} records, {} couldn't be checked or deleted", deleted.len(), failed.len())]
+    PartialDelete {
+        deleted: Vec<u64>,
+        failed: Vec<(u64, UserDbError)>,
+    },
 }
 
+const KEY_GENERATIONS_META: &str = "key_generations";
+const TOMBSTONES_META: &str = "tombstones";
+const VAULT_META: &str = "vault";
+const CHAIN_MIGRATION_META: &str = "chain_migration";
+// Unix seconds of the last completed sync with the server, u64 BE
+const LAST_SYNC_META: &str = "last_sync";
+// Prefix of the local-only access time of a record, encrypted under the record's chain
+const LAST_ACCESSED_META: &str = "last_accessed:";
+const VAULT_SCHEMA_VERSION: u32 = 1;
+const VAULT_CANARY: &[u8] = b"PASSMGR_VAULT_CANARY_V1";
+const RECORD_ID_ATTEMPTS: usize = 16;
+const EXPORT_FORMAT_VERSION: u32 = 1;
+// Leads the data of indexed records. Regular data starts with a random IV, which
+// matches it once in 2^64, and even then the rest would have to parse.
+const INDEXED_RECORD_MAGIC: &[u8] = b"PMINDEX1";
+// Domain bytes, so a title never encrypts the same as an equal value
+const SEARCHABLE_TITLE: u8 = 0;
+const SEARCHABLE_VALUE: u8 = 1;
+
 impl<'a> UserDb<'a> {
     pub fn new(
         path: &Path,
@@ -34,28 +140,160 @@ impl<'a> UserDb<'a> {
     ) -> Result<UserDb<'a>, UserDbError> {
         let storage = Storage::open(path, user_id).map_err(UserDbError::StorageError)?;
 
-        //let mut cipher_chain = CipherChain::new();
-        let ciphers = CipherChain {
-            cipher_chain,
-            keys: master_keys,
-        };
-        Ok(Self {
+        if Self::load_key_generations(&storage)? != Self::key_generations_of(master_keys) {
+            return Err(UserDbError::KeyGenerationMismatch);
+        }
+
+        let ciphers = CipherChain::new(master_keys, cipher_chain);
+        let user_db = Self {
             storage,
             ciphers,
             user_id,
-        })
+            cache: Mutex::new(None),
+            decrypts: AtomicUsize::new(0),
+        };
+
+        match user_db.vault_meta()? {
+            Some(meta) => user_db.validate_vault_meta(&meta)?,
+            // New vault, or one created before metadata existed
+            None => {
+                let meta = VaultMeta {
+                    schema_version: VAULT_SCHEMA_VERSION,
+                    argon2_params: Argon2Params {
+                        memory_kib: MasterKeys::MEMORY_SIZE,
+                        time_cost: MasterKeys::TIME_COST,
+                        parallelism: MasterKeys::PARALLELISM,
+                    },
+                    cipher_chain: user_db.get_cipher_options(),
+                    canary: Self::canary(&user_db.ciphers)?,
+                    created: current_timestamp(),
+                };
+                let bytes =
+                    serialize(&meta).map_err(|e| UserDbError::SerializationError(e.to_string()))?;
+                user_db
+                    .storage
+                    .set_meta(VAULT_META, &bytes)
+                    .map_err(UserDbError::StorageError)?;
+            }
+        }
+
+        Ok(user_db)
+    }
+
+    /// Open a vault for a freshly generated seed. Refuses a path that already holds a
+    /// vault unless `allow_existing`, so a new seed can't silently shadow existing data.
+    pub fn create_vault(
+        path: &Path,
+        user_id: UserId,
+        master_keys: &'a MasterKeys,
+        cipher_chain: Vec<CipherOption>,
+        allow_existing: bool,
+    ) -> Result<UserDb<'a>, UserDbError> {
+        if !allow_existing && Storage::exists_at(path) {
+            return Err(UserDbError::VaultExists(path.display().to_string()));
+        }
+        Self::new(path, user_id, master_keys, cipher_chain)
+    }
+
+    /// Key generations `rotate_key` stored for the vault at `path`, read without opening
+    /// it. Keys derived from the seed start at generation 0, so apply these with
+    /// `MasterKeys::set_generation` before opening a rotated vault.
+    pub fn stored_key_generations(
+        path: &Path,
+        user_id: UserId,
+    ) -> Result<Vec<(CipherOption, u32)>, UserDbError> {
+        let storage = Storage::open(path, user_id).map_err(UserDbError::StorageError)?;
+        Self::load_key_generations(&storage)?
+            .into_iter()
+            .map(|(code, generation)| {
+                CipherOption::from_code(code)
+                    .map(|cipher| (cipher, generation))
+                    .ok_or(UserDbError::UnknownCipher(code))
+            })
+            .collect()
+    }
+
+    /// Cipher chain of the vault at `path`, read without opening it, `None` for a new
+    /// vault. After `migrate_chain` this differs from the chain the vault was created
+    /// with, and `new` must be given it.
+    pub fn stored_cipher_chain(
+        path: &Path,
+        user_id: UserId,
+    ) -> Result<Option<Vec<CipherOption>>, UserDbError> {
+        let storage = Storage::open(path, user_id).map_err(UserDbError::StorageError)?;
+        let Some(bytes) = storage
+            .get_meta(VAULT_META)
+            .map_err(UserDbError::StorageError)?
+        else {
+            return Ok(None);
+        };
+        let meta: VaultMeta =
+            deserialize(&bytes).map_err(|e| UserDbError::SerializationError(e.to_string()))?;
+        chain_from_codes(&meta.cipher_chain)
+            .map(Some)
+            .map_err(|e| UserDbError::UnknownCipher(e.0))
+    }
+
+    /// Check a chain read with `stored_cipher_chain` against the ciphers the caller
+    /// requires, before any keys are derived for it. A tampered chain is otherwise only
+    /// caught by the canary, which can't tell it from a wrong seed.
+    pub fn check_chain_policy(
+        stored: &[CipherOption],
+        required: &[CipherOption],
+    ) -> Result<(), UserDbError> {
+        if required.iter().any(|cipher| !stored.contains(cipher)) {
+            return Err(UserDbError::CipherChainDowngrade);
+        }
+        Ok(())
+    }
+
+    /// Vault-wide metadata, written when the vault is first opened
+    pub fn vault_meta(&self) -> Result<Option<VaultMeta>, UserDbError> {
+        match self
+            .storage
+            .get_meta(VAULT_META)
+            .map_err(UserDbError::StorageError)?
+        {
+            Some(bytes) => deserialize(&bytes)
+                .map(Some)
+                .map_err(|e| UserDbError::SerializationError(e.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    /// Keep up to `capacity` decrypted records in memory so repeated reads skip
+    /// decryption. Off unless enabled; cached records are wiped when evicted.
+    pub fn enable_record_cache(&self, capacity: usize) {
+        *self.cache.lock().unwrap() = Some(RecordCache::new(capacity));
+    }
+
+    /// Wipe and drop the record cache
+    pub fn disable_record_cache(&self) {
+        self.cache.lock().unwrap().take();
     }
 
     pub fn create(&self, record: Record) -> Result<u64, UserDbError> {
-        // Generate new record ID
-        let record_id = self.generate_record_id();
+        self.create_with(record, false)
+    }
+
+    /// `create`, storing the fields without `Atributes::Hide` so they can be looked up
+    /// with `find_indexed` without decrypting records. Each such title and value is
+    /// encrypted on its own and deterministically: equal plaintext gives equal
+    /// ciphertext, across records and vaults sharing the keys. Anyone holding the
+    /// encrypted vault, the server included, can therefore tell which records share a
+    /// login or URL, count the visible fields and see their lengths, and confirm a
+    /// guessed value once they get one encrypted. Hidden fields, icon and timestamps
+    /// stay in the randomized blob and reveal nothing. Updates keep the layout.
+    pub fn create_indexed(&self, record: Record) -> Result<u64, UserDbError> {
+        self.create_with(record, true)
+    }
 
-        // Serialize the record
-        let mut data =
-            serialize(&record).map_err(|e| UserDbError::SerializationError(e.to_string()))?;
+    fn create_with(&self, record: Record, indexed: bool) -> Result<u64, UserDbError> {
+        // Generate new record ID
+        let record_id = self.generate_record_id()?;
 
-        // Encrypt the serialized data
-        let encrypted_data = self.ciphers.encrypt(&mut data);
+        // Serialize and encrypt the record
+        let encrypted_data = Self::seal_record(&self.ciphers, &record, indexed)?;
 
         // Create cipher record
         let cipher_record = CipherRecord {
@@ -64,12 +302,15 @@ impl<'a> UserDb<'a> {
             ver: 1, // Initial version
             cipher_options: self.get_cipher_options(),
             data: encrypted_data,
+            signature: vec![],
+            updated: current_timestamp(),
         };
 
         // Save to storage
         self.storage
             .set(record_id, &cipher_record)
             .map_err(UserDbError::StorageError)?;
+        self.storage.flush().map_err(UserDbError::StorageError)?;
 
         Ok(record_id)
     }
@@ -82,31 +323,172 @@ impl<'a> UserDb<'a> {
             .map_err(UserDbError::StorageError)?;
 
         // Verify user ID
-        if cipher_record.user_id != self.user_id {
+        if !self.owns(&cipher_record) {
             return Err(UserDbError::DecryptionError);
         }
 
-        // Decrypt data
-        let decrypted_data = self.ciphers.decrypt(&mut cipher_record.data);
+        let cached = match self.cache.lock().unwrap().as_mut() {
+            Some(cache) => cache.get(record_id, cipher_record.ver),
+            None => None,
+        };
+        let record = match cached {
+            Some(record) => record,
+            None => {
+                let record = self.decrypt_record(&mut cipher_record)?;
+                if let Some(cache) = self.cache.lock().unwrap().as_mut() {
+                    cache.insert(record_id, cipher_record.ver, record.clone());
+                }
+                record
+            }
+        };
+
+        self.with_last_accessed(record_id, &cipher_record, record)
+    }
+
+    /// `read`, recording the access in `last_accessed`. The time is kept in local vault
+    /// metadata, encrypted like the record so it doesn't reveal when which record was
+    /// viewed, and the stored record is left as it is, so viewing a record creates no
+    /// new version for sync to push.
+    pub fn read_and_touch(&self, record_id: u64) -> Result<Record, UserDbError> {
+        let mut record = self.read(record_id)?;
+        record.last_accessed = current_timestamp();
+        let current = self.read_raw(record_id)?;
+        let sealed = Self::seal_access_time(&self.record_ciphers(&current)?, record.last_accessed)?;
+        self.storage
+            .set_meta(&Self::last_accessed_key(record_id), &sealed)
+            .map_err(UserDbError::StorageError)?;
+        self.storage.flush().map_err(UserDbError::StorageError)?;
 
-        // Deserialize into Record
-        let record = deserialize(&decrypted_data)
-            .map_err(|e| UserDbError::SerializationError(e.to_string()))?;
+        Ok(record)
+    }
+
+    fn last_accessed_key(record_id: u64) -> String {
+        format!("{LAST_ACCESSED_META}{record_id}")
+    }
 
+    // The local access time, if any, over whatever the record was stored with
+    fn with_last_accessed(
+        &self,
+        record_id: u64,
+        cipher_record: &CipherRecord,
+        mut record: Record,
+    ) -> Result<Record, UserDbError> {
+        let stored = self
+            .storage
+            .get_meta(&Self::last_accessed_key(record_id))
+            .map_err(UserDbError::StorageError)?;
+        if let Some(sealed) = stored {
+            record.last_accessed =
+                Self::open_access_time(&self.record_ciphers(cipher_record)?, &sealed)?;
+        }
         Ok(record)
     }
 
-    pub fn update(&self, record_id: u64, record: Record) -> Result<(), UserDbError> {
+    fn seal_access_time(ciphers: &CipherChain, time: u64) -> Result<Vec<u8>, UserDbError> {
+        ciphers
+            .encrypt_authenticated(&mut time.to_be_bytes().to_vec())
+            .map_err(|_| UserDbError::EncryptionError)
+    }
+
+    fn open_access_time(ciphers: &CipherChain, sealed: &[u8]) -> Result<u64, UserDbError> {
+        let plaintext = ciphers
+            .decrypt_authenticated(&mut sealed.to_vec())
+            .map_err(|_| UserDbError::DecryptionError)?;
+        let bytes: [u8; 8] = plaintext
+            .as_slice()
+            .try_into()
+            .map_err(|_| UserDbError::SerializationError("malformed last_accessed".to_string()))?;
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    /// The access time of a record, if it has one, re-encrypted from `old` to `new`
+    /// ciphers as a meta entry to write along with the re-encrypted record
+    fn reseal_access_time(
+        &self,
+        record_id: u64,
+        old: &CipherChain,
+        new: &CipherChain,
+    ) -> Result<Option<(String, Vec<u8>)>, UserDbError> {
+        let key = Self::last_accessed_key(record_id);
+        match self
+            .storage
+            .get_meta(&key)
+            .map_err(UserDbError::StorageError)?
+        {
+            Some(sealed) => {
+                let time = Self::open_access_time(old, &sealed)?;
+                Ok(Some((key, Self::seal_access_time(new, time)?)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Decrypt a stored record with the chain it was written under, counted in `decrypts`
+    fn decrypt_record(&self, cipher_record: &mut CipherRecord) -> Result<Record, UserDbError> {
+        self.decrypts.fetch_add(1, Ordering::Relaxed);
+        Self::open_record(&self.record_ciphers(cipher_record)?, &cipher_record.data)
+    }
+
+    /// Decrypt and deserialize every record without keeping the plaintext, bypassing the
+    /// record cache so what is on disk gets checked
+    pub fn check_integrity(&self) -> Result<IntegrityReport, UserDbError> {
+        let mut report = IntegrityReport::default();
+        // Every stored id, `list_records` would skip the ones that don't decode
+        for id in self.storage.list_ids().map_err(UserDbError::StorageError)? {
+            let result = self
+                .read_raw(id)
+                .and_then(|mut cipher_record| self.decrypt_record(&mut cipher_record).map(|_| ()));
+            match result {
+                Ok(()) => report.ok += 1,
+                Err(e) => report.failed.push((id, e)),
+            }
+        }
+        Ok(report)
+    }
+
+    /// Stored record as-is, without decryption; meant for debugging
+    pub fn read_raw(&self, record_id: u64) -> Result<CipherRecord, UserDbError> {
+        let cipher_record = self
+            .storage
+            .get(record_id)
+            .map_err(UserDbError::StorageError)?;
+
+        if !self.owns(&cipher_record) {
+            return Err(UserDbError::DecryptionError);
+        }
+
+        Ok(cipher_record)
+    }
+
+    /// Replace a record's content and return its version. Content equal to the stored
+    /// record (see `Record::content_eq`) is left alone, so no new version gets synced.
+    pub fn update(&self, record_id: u64, record: Record) -> Result<u64, UserDbError> {
+        self.update_with(record_id, record, false)
+    }
+
+    /// `update`, with `force` writing a new version even when nothing changed (a touch)
+    pub fn update_with(
+        &self,
+        record_id: u64,
+        record: Record,
+        force: bool,
+    ) -> Result<u64, UserDbError> {
         // First read existing record to get current version
         let current = self
             .storage
             .get(record_id)
             .map_err(UserDbError::StorageError)?;
 
-        // Serialize and encrypt new data
-        let mut data =
-            serialize(&record).map_err(|e| UserDbError::SerializationError(e.to_string()))?;
-        let encrypted_data = self.ciphers.encrypt(&mut data);
+        if !force {
+            let stored = Self::open_record(&self.record_ciphers(&current)?, &current.data)?;
+            if stored.content_eq(&record) {
+                return Ok(current.ver);
+            }
+        }
+
+        // Serialize and encrypt new data, in the stored layout
+        let encrypted_data =
+            Self::seal_record(&self.ciphers, &record, Self::is_indexed(&current.data))?;
 
         // Create updated cipher record
         let cipher_record = CipherRecord {
@@ -115,18 +497,157 @@ impl<'a> UserDb<'a> {
             ver: current.ver + 1,
             cipher_options: self.get_cipher_options(),
             data: encrypted_data,
+            signature: vec![],
+            updated: current_timestamp(),
         };
 
         // Update storage
         self.storage
-            .up(record_id, &cipher_record /*&current */)
-            .map_err(UserDbError::StorageError)
+            .up(record_id, &cipher_record, &current)
+            .map_err(UserDbError::StorageError)?;
+        self.storage.flush().map_err(UserDbError::StorageError)?;
+        self.invalidate_cached(record_id);
+
+        Ok(cipher_record.ver)
+    }
+
+    /// Put back an earlier stored state of a record, e.g. to undo a change. The version
+    /// is bumped past both the saved and the current one so the restore syncs as a new change.
+    pub fn restore_raw(&self, prior: &CipherRecord) -> Result<u64, UserDbError> {
+        if !self.owns(prior) {
+            return Err(UserDbError::DecryptionError);
+        }
+
+        let record_id = prior.cipher_record_id;
+        let current_ver = match self.storage.get(record_id) {
+            Ok(current) => current.ver,
+            Err(StorageError::StorageDataNotFound(_)) => 0,
+            Err(e) => return Err(UserDbError::StorageError(e)),
+        };
+
+        let cipher_record = CipherRecord {
+            ver: current_ver.max(prior.ver) + 1,
+            signature: vec![],
+            updated: current_timestamp(),
+            ..prior.clone()
+        };
+        self.storage
+            .set(record_id, &cipher_record)
+            .map_err(UserDbError::StorageError)?;
+        self.invalidate_cached(record_id);
+        self.clear_tombstone(record_id)?;
+
+        Ok(cipher_record.ver)
     }
 
+    /// Delete a record and leave a tombstone, so the next sync deletes it on the server too
     pub fn delete(&self, record_id: u64) -> Result<(), UserDbError> {
+        let ver = match self.storage.get(record_id) {
+            Ok(current) => current.ver,
+            Err(StorageError::StorageDataNotFound(_)) => return Ok(()),
+            Err(e) => return Err(UserDbError::StorageError(e)),
+        };
+        self.apply_remote_delete(record_id)?;
+
+        let mut tombstones = self.tombstones()?;
+        tombstones.retain(|tombstone| tombstone.id != record_id);
+        tombstones.push(Tombstone {
+            id: record_id,
+            ver: ver + 1,
+            deleted: current_timestamp(),
+        });
+        self.save_tombstones(&tombstones)?;
+        self.storage.flush().map_err(UserDbError::StorageError)
+    }
+
+    /// Delete, with tombstones, every record `pred` holds for and return their ids, e.g.
+    /// all records of an import. A record failing to decrypt or delete doesn't stop the
+    /// rest; the failures come back as `PartialDelete` along with what was deleted.
+    pub fn delete_where(&self, pred: impl Fn(&Record) -> bool) -> Result<Vec<u64>, UserDbError> {
+        let mut deleted = Vec::new();
+        let mut failed = Vec::new();
+        for id in self.list_records()? {
+            match self.read(id).and_then(|record| {
+                if pred(&record) {
+                    self.delete(id).map(|()| true)
+                } else {
+                    Ok(false)
+                }
+            }) {
+                Ok(true) => deleted.push(id),
+                Ok(false) => {}
+                Err(e) => failed.push((id, e)),
+            }
+        }
+
+        if failed.is_empty() {
+            Ok(deleted)
+        } else {
+            Err(UserDbError::PartialDelete { deleted, failed })
+        }
+    }
+
+    /// Delete a record that is already gone on the server, without a tombstone
+    pub fn apply_remote_delete(&self, record_id: u64) -> Result<(), UserDbError> {
         self.storage
             .remove(record_id)
-            .map_err(UserDbError::StorageError)
+            .map_err(UserDbError::StorageError)?;
+        self.storage
+            .remove_meta(&Self::last_accessed_key(record_id))
+            .map_err(UserDbError::StorageError)?;
+        self.invalidate_cached(record_id);
+        Ok(())
+    }
+
+    /// Local deletes not yet propagated by sync
+    pub fn tombstones(&self) -> Result<Vec<Tombstone>, UserDbError> {
+        match self
+            .storage
+            .get_meta(TOMBSTONES_META)
+            .map_err(UserDbError::StorageError)?
+        {
+            Some(bytes) => {
+                deserialize(&bytes).map_err(|e| UserDbError::SerializationError(e.to_string()))
+            }
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// When this vault last completed a sync with the server, 0 if it never has
+    pub fn last_sync(&self) -> Result<u64, UserDbError> {
+        match self
+            .storage
+            .get_meta(LAST_SYNC_META)
+            .map_err(UserDbError::StorageError)?
+        {
+            Some(bytes) => {
+                let bytes: [u8; 8] = bytes.as_slice().try_into().map_err(|_| {
+                    UserDbError::SerializationError("malformed last_sync".to_string())
+                })?;
+                Ok(u64::from_be_bytes(bytes))
+            }
+            None => Ok(0),
+        }
+    }
+
+    /// Record a completed sync, kept with the vault so the next run, from any session,
+    /// only pushes what changed after it and can tell server deletes from new records
+    pub fn set_last_sync(&self, timestamp: u64) -> Result<(), UserDbError> {
+        self.storage
+            .set_meta(LAST_SYNC_META, &timestamp.to_be_bytes())
+            .map_err(UserDbError::StorageError)?;
+        self.storage.flush().map_err(UserDbError::StorageError)
+    }
+
+    /// Forget the tombstone of a record, once sync has reconciled it
+    pub fn clear_tombstone(&self, record_id: u64) -> Result<(), UserDbError> {
+        let mut tombstones = self.tombstones()?;
+        let before = tombstones.len();
+        tombstones.retain(|tombstone| tombstone.id != record_id);
+        if tombstones.len() == before {
+            return Ok(());
+        }
+        self.save_tombstones(&tombstones)
     }
 
     /// List all record IDs belonging to the current user
@@ -139,8 +660,7 @@ impl<'a> UserDb<'a> {
         for id_64 in ids {
             // Read the record to verify ownership
             if let Ok(record) = self.storage.get(id_64) {
-                if record.user_id == self.user_id {
-                    // Convert u128 to u64 for the record ID
+                if self.owns(&record) {
                     record_ids.push(record.cipher_record_id);
                 }
             }
@@ -149,91 +669,722 @@ impl<'a> UserDb<'a> {
         Ok(record_ids)
     }
 
-    /// List all records with their metadata
-    pub fn list_records_with_metadata(&self) -> Result<Vec<(u64, u64, [u8; 32])>, UserDbError> {
-        // Returns vector of (record_id, version, timestamp)
-        let ids = self.storage.list_ids().map_err(UserDbError::StorageError)?;
+    /// One page of record IDs in ID order, starting after `after` (`None` for the first
+    /// page). Only keys are read, so paging through a large vault decrypts nothing.
+    pub fn list_records_page(
+        &self,
+        after: Option<u64>,
+        limit: usize,
+    ) -> Result<Vec<u64>, UserDbError> {
+        self.storage
+            .list_ids_page(after, limit)
+            .map_err(UserDbError::StorageError)
+    }
 
-        let mut records = Vec::new();
-        for id_64 in ids {
-            if let Ok(record) = self.storage.get(id_64) {
-                if record.user_id == self.user_id {
-                    records.push((record.cipher_record_id, record.ver, record.user_id));
+    /// (record_id, version, updated) of the user's records, read from the record
+    /// headers without decrypting. Fails on a record that can't be decoded.
+    pub fn list_records_with_metadata(&self) -> Result<Vec<(u64, u64, u64)>, UserDbError> {
+        self.storage
+            .list_owned_with_metadata(&self.user_id)
+            .map_err(UserDbError::StorageError)
+    }
+
+    /// List (record_id, version, updated) of records modified after `ts` (unix seconds)
+    pub fn list_changed_since(&self, ts: u64) -> Result<Vec<(u64, u64, u64)>, UserDbError> {
+        self.storage
+            .list_owned_changed_since(&self.user_id, ts)
+            .map_err(UserDbError::StorageError)
+    }
+
+    /// Records whose field titles or visible values contain `query`, ignoring case.
+    /// Values of `Atributes::Hide` fields aren't searched, so a password can't be
+    /// found by its content. Decrypts every record; ones that fail to decrypt are skipped.
+    pub fn search(&self, query: &str) -> Result<Vec<(u64, Record)>, UserDbError> {
+        let query = query.to_lowercase();
+        let mut found = Vec::new();
+        for id in self.list_records()? {
+            let Ok(record) = self.read(id) else {
+                continue;
+            };
+            let matches = record.fields.iter().any(|item| {
+                item.title.to_lowercase().contains(&query)
+                    || (!item.types.contains(&Atributes::Hide)
+                        && item.value.to_lowercase().contains(&query))
+            });
+            if matches {
+                found.push((id, record));
+            }
+        }
+        Ok(found)
+    }
+
+    /// Ids of the `create_indexed` records having a field titled `title` whose value is
+    /// exactly `value`. Compares ciphertexts, so no record gets decrypted.
+    pub fn find_indexed(&self, title: &str, value: &str) -> Result<Vec<u64>, UserDbError> {
+        let wanted = SearchableField {
+            title: Self::seal_searchable(&self.ciphers, SEARCHABLE_TITLE, title)?,
+            value: Self::seal_searchable(&self.ciphers, SEARCHABLE_VALUE, value)?,
+        };
+        let mut found = Vec::new();
+        for id in self.list_records()? {
+            let current = self.read_raw(id)?;
+            if let Some(indexed) = Self::indexed_data(&current.data) {
+                if indexed.searchable.contains(&wanted) {
+                    found.push(id);
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    /// Import records, deduplicating against the vault and the batch itself
+    pub fn import_records(
+        &self,
+        records: Vec<Record>,
+        policy: &DedupPolicy,
+    ) -> Result<ImportReport, UserDbError> {
+        let mut report = ImportReport::default();
+
+        let mut existing = Vec::new();
+        if *policy != DedupPolicy::Off {
+            for id in self.list_records()? {
+                // Records that fail to decrypt can't be matched, leave them alone
+                if let Ok(record) = self.read(id) {
+                    existing.push((id, record));
+                }
+            }
+        }
+
+        for record in records {
+            let matched = match policy {
+                DedupPolicy::Off => None,
+                DedupPolicy::Content => existing
+                    .iter_mut()
+                    .find(|(_, current)| current.content_eq(&record)),
+                DedupPolicy::KeyFields(titles) => existing
+                    .iter_mut()
+                    .find(|(_, current)| Self::same_key(current, &record, titles)),
+            };
+
+            match matched {
+                Some((_, current)) if current.content_eq(&record) => report.skipped += 1,
+                Some((id, current)) => {
+                    Self::merge_into(current, record);
+                    self.update(*id, current.clone())?;
+                    report.merged += 1;
+                }
+                None => {
+                    let id = self.create(record.clone())?;
+                    existing.push((id, record));
+                    report.added += 1;
                 }
             }
         }
 
-        Ok(records)
+        Ok(report)
     }
 
-    // Helper methods
+    /// Write every record, still encrypted, to a JSON file at `path`. The file is written
+    /// next to `path` first and renamed over it, so a failed export leaves no partial file.
+    pub fn export_encrypted(&self, path: &Path) -> Result<(), UserDbError> {
+        let mut records = Vec::new();
+        for id in self.list_records()? {
+            records.push(self.read_raw(id)?);
+        }
+        let export = VaultExport {
+            format_version: EXPORT_FORMAT_VERSION,
+            user_id: self.user_id,
+            exported: current_timestamp(),
+            records,
+        };
 
-    fn generate_record_id(&self) -> u64 {
-        // Implementation needed: Generate unique record ID
-        // Could use timestamps, random numbers, or a combination
-        // For now, using a simple timestamp-based approach
-        use std::time::{SystemTime, UNIX_EPOCH};
-        SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
-        // .into()
+        let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(".tmp");
+        let tmp_path = path.with_file_name(tmp_name);
+        let result: Result<(), UserDbError> = (|| {
+            let mut writer = BufWriter::new(std::fs::File::create(&tmp_path)?);
+            serde_json::to_writer(&mut writer, &export)
+                .map_err(|e| UserDbError::SerializationError(e.to_string()))?;
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+            std::fs::rename(&tmp_path, path)?;
+            Ok(())
+        })();
+        if result.is_err() {
+            let _ = std::fs::remove_file(&tmp_path);
+        }
+        result
     }
 
-    fn get_cipher_options(&self) -> Vec<u8> {
-        // Return list of currently used cipher options
-        // This would depend on the specific cipher implementations used
-        vec![
-            CipherOption::AES256.code(),
-            CipherOption::XChaCha20.code(),
-            // Add other ciphers as needed
-        ]
+    /// Upsert the records of an `export_encrypted` file, skipping other users' records
+    pub fn import_encrypted(&self, path: &Path) -> Result<ImportReport, UserDbError> {
+        let reader = BufReader::new(std::fs::File::open(path)?);
+        let export: VaultExport = serde_json::from_reader(reader)
+            .map_err(|e| UserDbError::SerializationError(e.to_string()))?;
+        if export.format_version > EXPORT_FORMAT_VERSION {
+            return Err(UserDbError::UnsupportedExport(export.format_version));
+        }
+
+        let mut report = ImportReport::default();
+        for record in export.records {
+            if !self.owns(&record) {
+                report.skipped += 1;
+                continue;
+            }
+            self.storage
+                .set(record.cipher_record_id, &record)
+                .map_err(UserDbError::StorageError)?;
+            self.invalidate_cached(record.cipher_record_id);
+            report.added += 1;
+        }
+        Ok(report)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::structures::{Atributes, Item};
+    /// Compare with another vault (e.g. a backup), each side decrypted with its own keys.
+    /// Records with equal content are unchanged whatever their versions.
+    pub fn diff(&self, other: &UserDb) -> Result<VaultDiff, UserDbError> {
+        let mut diff = VaultDiff::default();
+        let mut other_ids = other.list_records()?;
+        other_ids.sort_unstable();
 
-    use super::*;
-    use rand::{rngs::OsRng, RngCore};
-    use tempdir::TempDir;
+        let mut ids = self.list_records()?;
+        ids.sort_unstable();
+        for &id in &ids {
+            if other_ids.binary_search(&id).is_err() {
+                diff.only_in_self.push(id);
+                continue;
+            }
+            if self.read(id)?.content_eq(&other.read(id)?) {
+                diff.unchanged += 1;
+            } else {
+                diff.differing.push(RecordDiff {
+                    id,
+                    self_ver: self.read_raw(id)?.ver,
+                    other_ver: other.read_raw(id)?.ver,
+                });
+            }
+        }
 
-    fn create_test_keys() -> MasterKeys {
-        let mut entropy = [0u8; 32];
-        OsRng.fill_bytes(&mut entropy);
-        MasterKeys::from_entropy(&entropy).unwrap()
+        diff.only_in_other = other_ids
+            .into_iter()
+            .filter(|id| ids.binary_search(id).is_err())
+            .collect();
+
+        Ok(diff)
     }
-    fn create_test_cipher_chain() -> Vec<CipherOption> {
-        vec![
-            CipherOption::AES256,
-            CipherOption::XChaCha20,
-            CipherOption::Kuznyechik,
-        ]
+
+    /// Re-encrypt every record under `new_keys` (e.g. after `MasterKeys::rotate_key`)
+    /// and persist their key generations. The vault must be reopened with `new_keys`.
+    pub fn rotate_key(&self, new_keys: &MasterKeys) -> Result<usize, UserDbError> {
+        let new_ciphers = CipherChain::new(new_keys, self.ciphers.cipher_chain.clone());
+
+        let mut rotated = Vec::new();
+        let mut access_times = Vec::new();
+        for id in self.list_records()? {
+            let current = self.storage.get(id).map_err(UserDbError::StorageError)?;
+            let old_ciphers = self.record_ciphers(&current)?;
+            let record = Self::open_record(&old_ciphers, &current.data)?;
+            access_times.extend(self.reseal_access_time(id, &old_ciphers, &new_ciphers)?);
+            let cipher_record = CipherRecord {
+                user_id: self.user_id,
+                cipher_record_id: id,
+                ver: current.ver + 1,
+                cipher_options: self.get_cipher_options(),
+                data: Self::seal_record(&new_ciphers, &record, Self::is_indexed(&current.data))?,
+                signature: vec![],
+                updated: current_timestamp(),
+            };
+            rotated.push((id, cipher_record));
+        }
+
+        let generations = serialize(&Self::key_generations_of(new_keys))
+            .map_err(|e| UserDbError::SerializationError(e.to_string()))?;
+        let mut meta_entries = vec![(KEY_GENERATIONS_META.to_string(), generations)];
+        if let Some(mut meta) = self.vault_meta()? {
+            meta.canary = Self::canary(&new_ciphers)?;
+            let bytes =
+                serialize(&meta).map_err(|e| UserDbError::SerializationError(e.to_string()))?;
+            meta_entries.push((VAULT_META.to_string(), bytes));
+        }
+        meta_entries.extend(access_times);
+        let meta_entries: Vec<(&str, &[u8])> = meta_entries
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_slice()))
+            .collect();
+        self.storage
+            .set_records_with_meta(&rotated, &meta_entries)
+            .map_err(UserDbError::StorageError)?;
+        if let Some(cache) = self.cache.lock().unwrap().as_mut() {
+            cache.clear();
+        }
+
+        Ok(rotated.len())
     }
-    fn create_record(password: &str) -> Record {
-        let item1 = Item {
-            title: String::from("Login"),
-            value: String::from("user"),
-            types: vec![],
-        };
 
-        let item2 = Item {
-            title: String::from("Password"),
-            value: password.to_string(),
-            types: vec![Atributes::Hide],
+    /// Re-encrypt every record under `new_chain`, e.g. to add a cipher. Each record is
+    /// saved together with the migration progress, so after a crash calling this again
+    /// with the same chain skips the records already done. The vault must be reopened
+    /// with `new_chain` afterwards, which `stored_cipher_chain` returns from then on.
+    pub fn migrate_chain(&self, new_chain: Vec<CipherOption>) -> Result<(), UserDbError> {
+        if let Some(cipher) = new_chain
+            .iter()
+            .find(|cipher| !self.ciphers.keys.has_key(cipher))
+        {
+            return Err(UserDbError::MissingKey(*cipher));
+        }
+        let new_ciphers = CipherChain::new(self.ciphers.keys, new_chain);
+        let codes: Vec<u8> = new_ciphers
+            .cipher_chain
+            .iter()
+            .map(|cipher| cipher.code())
+            .collect();
+
+        let mut progress = match self
+            .storage
+            .get_meta(CHAIN_MIGRATION_META)
+            .map_err(UserDbError::StorageError)?
+        {
+            Some(bytes) => {
+                let progress: ChainMigration = deserialize(&bytes)
+                    .map_err(|e| UserDbError::SerializationError(e.to_string()))?;
+                if progress.cipher_chain != codes {
+                    return Err(UserDbError::ChainMigrationPending);
+                }
+                progress
+            }
+            None => ChainMigration {
+                cipher_chain: codes.clone(),
+                last_done: None,
+            },
         };
-        Record {
-            icon: String::from("icon"),
-            created: 1,
-            updated: 2,
-            fields: vec![item1, item2],
+
+        for id in self.list_records()? {
+            if progress.last_done.is_some_and(|last| id <= last) {
+                continue;
+            }
+            let current = self.storage.get(id).map_err(UserDbError::StorageError)?;
+            let old_ciphers = self.record_ciphers(&current)?;
+            let record = Self::open_record(&old_ciphers, &current.data)?;
+            let access_time = self.reseal_access_time(id, &old_ciphers, &new_ciphers)?;
+            let cipher_record = CipherRecord {
+                user_id: self.user_id,
+                cipher_record_id: id,
+                ver: current.ver + 1,
+                cipher_options: codes.clone(),
+                data: Self::seal_record(&new_ciphers, &record, Self::is_indexed(&current.data))?,
+                signature: vec![],
+                updated: current_timestamp(),
+            };
+
+            progress.last_done = Some(id);
+            let bytes =
+                serialize(&progress).map_err(|e| UserDbError::SerializationError(e.to_string()))?;
+            let mut meta_entries = vec![(CHAIN_MIGRATION_META, bytes.as_slice())];
+            if let Some((key, sealed)) = &access_time {
+                meta_entries.push((key.as_str(), sealed.as_slice()));
+            }
+            self.storage
+                .set_records_with_meta(&[(id, cipher_record)], &meta_entries)
+                .map_err(UserDbError::StorageError)?;
+            self.invalidate_cached(id);
+        }
+
+        if let Some(mut meta) = self.vault_meta()? {
+            meta.cipher_chain = codes;
+            meta.canary = Self::canary(&new_ciphers)?;
+            let bytes =
+                serialize(&meta).map_err(|e| UserDbError::SerializationError(e.to_string()))?;
+            self.storage
+                .set_meta(VAULT_META, &bytes)
+                .map_err(UserDbError::StorageError)?;
         }
+        self.storage
+            .remove_meta(CHAIN_MIGRATION_META)
+            .map_err(UserDbError::StorageError)
     }
 
-    #[test]
-    fn test_list_records() {
-        // Create temporary directory for testing
+    /// Re-encrypt every record under the keys of a new seed and `new_chain`, e.g. when
+    /// the mnemonic may be compromised. The records go to the new user id's trees in
+    /// the same database and the current vault is left as is, so nothing is lost before
+    /// the new phrase is confirmed written down; `discard` the old vault after that.
+    pub fn rekey(
+        &self,
+        new_keys: &MasterKeys,
+        new_chain: Vec<CipherOption>,
+    ) -> Result<(), UserDbError> {
+        let new_user_id = new_keys.user_id;
+        let target = self
+            .storage
+            .open_sibling(new_user_id)
+            .map_err(UserDbError::StorageError)?;
+        let has_vault = target
+            .get_meta(VAULT_META)
+            .map_err(UserDbError::StorageError)?
+            .is_some();
+        if has_vault
+            || !target
+                .list_ids()
+                .map_err(UserDbError::StorageError)?
+                .is_empty()
+        {
+            return Err(UserDbError::RekeyTargetExists);
+        }
+
+        let new_ciphers = CipherChain::new(new_keys, new_chain);
+        let codes: Vec<u8> = new_ciphers
+            .cipher_chain
+            .iter()
+            .map(|cipher| cipher.code())
+            .collect();
+
+        let mut rekeyed = Vec::new();
+        for id in self.list_records()? {
+            let current = self.read_raw(id)?;
+            let record = Self::open_record(&self.record_ciphers(&current)?, &current.data)?;
+            let cipher_record = CipherRecord {
+                user_id: new_user_id,
+                cipher_record_id: id,
+                ver: current.ver + 1,
+                cipher_options: codes.clone(),
+                data: Self::seal_record(&new_ciphers, &record, Self::is_indexed(&current.data))?,
+                signature: vec![],
+                updated: current_timestamp(),
+            };
+            rekeyed.push((id, cipher_record));
+        }
+
+        let meta = VaultMeta {
+            schema_version: VAULT_SCHEMA_VERSION,
+            argon2_params: Argon2Params {
+                memory_kib: MasterKeys::MEMORY_SIZE,
+                time_cost: MasterKeys::TIME_COST,
+                parallelism: MasterKeys::PARALLELISM,
+            },
+            cipher_chain: codes,
+            canary: Self::canary(&new_ciphers)?,
+            created: current_timestamp(),
+        };
+        let meta = serialize(&meta).map_err(|e| UserDbError::SerializationError(e.to_string()))?;
+        let generations = serialize(&Self::key_generations_of(new_keys))
+            .map_err(|e| UserDbError::SerializationError(e.to_string()))?;
+        target
+            .set_records_with_meta(
+                &rekeyed,
+                &[(VAULT_META, &meta), (KEY_GENERATIONS_META, &generations)],
+            )
+            .map_err(UserDbError::StorageError)?;
+        target.flush().map_err(UserDbError::StorageError)
+    }
+
+    /// Delete this vault's records and metadata, e.g. the old vault after `rekey`
+    pub fn discard(self) -> Result<(), UserDbError> {
+        self.disable_record_cache();
+        self.storage.drop_trees().map_err(UserDbError::StorageError)
+    }
+
+    /// Size the record will occupy once encrypted, computed without encrypting
+    pub fn estimate_encrypted_size(&self, record: &Record) -> Result<usize, UserDbError> {
+        // One byte of schema version ahead of the record
+        let plain_len = bincode::serialized_size(record)
+            .map_err(|e| UserDbError::SerializationError(e.to_string()))?
+            + 1;
+        Ok(self.ciphers.encrypted_len(plain_len as usize))
+    }
+
+    /// Total of `estimate_encrypted_size` over a batch, e.g. before an import or sync
+    pub fn estimate_encrypted_size_batch(&self, records: &[Record]) -> Result<usize, UserDbError> {
+        records
+            .iter()
+            .map(|record| self.estimate_encrypted_size(record))
+            .sum()
+    }
+
+    // Helper methods
+
+    fn invalidate_cached(&self, record_id: u64) {
+        if let Some(cache) = self.cache.lock().unwrap().as_mut() {
+            cache.invalidate(record_id);
+        }
+    }
+
+    fn validate_vault_meta(&self, meta: &VaultMeta) -> Result<(), UserDbError> {
+        if meta.schema_version > VAULT_SCHEMA_VERSION {
+            return Err(UserDbError::UnsupportedSchema(meta.schema_version));
+        }
+        let expected = self.get_cipher_options();
+        if meta.cipher_chain != expected {
+            // A stored chain lacking any of our ciphers would weaken every later write
+            if expected
+                .iter()
+                .any(|code| !meta.cipher_chain.contains(code))
+            {
+                return Err(UserDbError::CipherChainDowngrade);
+            }
+            return Err(UserDbError::CipherChainMismatch);
+        }
+        // Decrypted rather than re-encrypted, so canaries made before a change to how
+        // synthetic IVs are derived still check out. Wrong keys fail to decrypt or give
+        // other plaintext. The canary was made under the original chain, so a chain
+        // rewritten to match a weaker caller fails here as well.
+        match self.ciphers.decrypt(&mut meta.canary.clone()) {
+            Ok(plaintext) if plaintext == VAULT_CANARY => Ok(()),
+            _ => Err(UserDbError::WrongKey),
+        }
+    }
+
+    fn canary(ciphers: &CipherChain) -> Result<Vec<u8>, UserDbError> {
+        ciphers
+            .encrypt_deterministic(&mut VAULT_CANARY.to_vec())
+            .map_err(|_| UserDbError::EncryptionError)
+    }
+
+    // Serialize and encrypt a record, as `IndexedRecordData` if `indexed`
+    fn seal_record(
+        ciphers: &CipherChain,
+        record: &Record,
+        indexed: bool,
+    ) -> Result<Vec<u8>, UserDbError> {
+        let mut blob_record = record.clone();
+        let mut searchable = Vec::new();
+        if indexed {
+            for field in blob_record.fields.iter_mut() {
+                if field.types.contains(&Atributes::Hide) {
+                    continue;
+                }
+                searchable.push(SearchableField {
+                    title: Self::seal_searchable(ciphers, SEARCHABLE_TITLE, &field.title)?,
+                    value: Self::seal_searchable(ciphers, SEARCHABLE_VALUE, &field.value)?,
+                });
+                field.title.clear();
+                field.value.clear();
+            }
+        }
+
+        let mut data = blob_record
+            .encode_versioned()
+            .map_err(|e| UserDbError::SerializationError(e.to_string()))?;
+        let blob = ciphers
+            .encrypt(&mut data)
+            .map_err(|_| UserDbError::EncryptionError)?;
+        if !indexed {
+            return Ok(blob);
+        }
+
+        let mut sealed = INDEXED_RECORD_MAGIC.to_vec();
+        bincode::serialize_into(&mut sealed, &IndexedRecordData { searchable, blob })
+            .map_err(|e| UserDbError::SerializationError(e.to_string()))?;
+        Ok(sealed)
+    }
+
+    // Decrypt and deserialize what `seal_record` produced, in either layout
+    fn open_record(ciphers: &CipherChain, data: &[u8]) -> Result<Record, UserDbError> {
+        let indexed = Self::indexed_data(data);
+        let blob = indexed
+            .as_ref()
+            .map_or(data, |indexed| indexed.blob.as_slice());
+        let decrypted = ciphers
+            .decrypt(&mut blob.to_vec())
+            .map_err(|_| UserDbError::DecryptionError)?;
+        let mut record = Record::decode_versioned(&decrypted)
+            .map_err(|e| UserDbError::SerializationError(e.to_string()))?;
+
+        if let Some(indexed) = indexed {
+            let mut searchable = indexed.searchable.into_iter();
+            for field in record.fields.iter_mut() {
+                if field.types.contains(&Atributes::Hide) {
+                    continue;
+                }
+                let sealed = searchable.next().ok_or(UserDbError::DecryptionError)?;
+                field.title = Self::open_searchable(ciphers, SEARCHABLE_TITLE, sealed.title)?;
+                field.value = Self::open_searchable(ciphers, SEARCHABLE_VALUE, sealed.value)?;
+            }
+            if searchable.next().is_some() {
+                return Err(UserDbError::DecryptionError);
+            }
+        }
+        Ok(record)
+    }
+
+    fn is_indexed(data: &[u8]) -> bool {
+        Self::indexed_data(data).is_some()
+    }
+
+    fn indexed_data(data: &[u8]) -> Option<IndexedRecordData> {
+        deserialize(data.strip_prefix(INDEXED_RECORD_MAGIC)?).ok()
+    }
+
+    fn seal_searchable(
+        ciphers: &CipherChain,
+        domain: u8,
+        text: &str,
+    ) -> Result<Vec<u8>, UserDbError> {
+        let mut data = vec![domain];
+        data.extend_from_slice(text.as_bytes());
+        ciphers
+            .encrypt_deterministic(&mut data)
+            .map_err(|_| UserDbError::EncryptionError)
+    }
+
+    fn open_searchable(
+        ciphers: &CipherChain,
+        domain: u8,
+        mut sealed: Vec<u8>,
+    ) -> Result<String, UserDbError> {
+        let data = ciphers
+            .decrypt(&mut sealed)
+            .map_err(|_| UserDbError::DecryptionError)?;
+        match data.split_first() {
+            Some((&found, text)) if found == domain => String::from_utf8(text.to_vec())
+                .map_err(|e| UserDbError::SerializationError(e.to_string())),
+            _ => Err(UserDbError::DecryptionError),
+        }
+    }
+
+    fn save_tombstones(&self, tombstones: &[Tombstone]) -> Result<(), UserDbError> {
+        let bytes =
+            serialize(tombstones).map_err(|e| UserDbError::SerializationError(e.to_string()))?;
+        self.storage
+            .set_meta(TOMBSTONES_META, &bytes)
+            .map_err(UserDbError::StorageError)
+    }
+
+    fn load_key_generations(storage: &Storage) -> Result<Vec<(u8, u32)>, UserDbError> {
+        match storage
+            .get_meta(KEY_GENERATIONS_META)
+            .map_err(UserDbError::StorageError)?
+        {
+            Some(bytes) => {
+                deserialize(&bytes).map_err(|e| UserDbError::SerializationError(e.to_string()))
+            }
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn key_generations_of(keys: &MasterKeys) -> Vec<(u8, u32)> {
+        let mut generations: Vec<(u8, u32)> = keys
+            .key_generations()
+            .iter()
+            .map(|(cipher, generation)| (cipher.code(), *generation))
+            .collect();
+        generations.sort();
+        generations
+    }
+
+    fn same_key(a: &Record, b: &Record, titles: &[String]) -> bool {
+        !titles.is_empty()
+            && titles.iter().all(|title| {
+                let value = a.field_value(title);
+                value.is_some() && value == b.field_value(title)
+            })
+    }
+
+    // Incoming values win; fields only present in the existing record are kept
+    fn merge_into(current: &mut Record, incoming: Record) {
+        for item in incoming.fields {
+            match current.fields.iter_mut().find(|f| f.title == item.title) {
+                Some(field) => *field = item,
+                None => current.fields.push(item),
+            }
+        }
+        if !incoming.icon.is_empty() {
+            current.icon = incoming.icon;
+        }
+        current.updated = current.updated.max(incoming.updated);
+    }
+
+    // Random, so records created in the same instant or on other devices don't collide
+    fn generate_record_id(&self) -> Result<u64, UserDbError> {
+        for _ in 0..RECORD_ID_ATTEMPTS {
+            let id = OsRng.next_u64();
+            match self.storage.get(id) {
+                Err(StorageError::StorageDataNotFound(_)) => return Ok(id),
+                Ok(_) => continue,
+                Err(e) => return Err(UserDbError::StorageError(e)),
+            }
+        }
+        Err(UserDbError::RecordIdExhausted)
+    }
+
+    /// Chain a stored record was encrypted with. Records stored without one (e.g. pulled
+    /// before the server kept chains) are taken to use the session chain.
+    fn record_ciphers(&self, cipher_record: &CipherRecord) -> Result<CipherChain<'a>, UserDbError> {
+        if cipher_record.cipher_options.is_empty() {
+            return Ok(CipherChain::new(
+                self.ciphers.keys,
+                self.ciphers.cipher_chain.clone(),
+            ));
+        }
+        let chain = chain_from_codes(&cipher_record.cipher_options)
+            .map_err(|e| UserDbError::UnknownCipher(e.0))?;
+        Ok(CipherChain::new(self.ciphers.keys, chain))
+    }
+
+    // Constant time, so timing doesn't reveal how much of an id matched
+    fn owns(&self, cipher_record: &CipherRecord) -> bool {
+        cipher_record.user_id.ct_eq(&self.user_id).into()
+    }
+
+    fn get_cipher_options(&self) -> Vec<u8> {
+        // Return list of currently used cipher options
+        self.ciphers
+            .cipher_chain
+            .iter()
+            .map(|cipher| cipher.code())
+            .collect()
+    }
+}
+
+fn current_timestamp() -> u64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::structures::{Item, RecordV1, RECORD_SCHEMA_VERSION};
+
+    use super::*;
+    use tempdir::TempDir;
+
+    fn create_test_keys() -> MasterKeys {
+        let mut entropy = [0u8; 32];
+        OsRng.fill_bytes(&mut entropy);
+        MasterKeys::from_entropy(&entropy).unwrap()
+    }
+    fn create_test_cipher_chain() -> Vec<CipherOption> {
+        vec![
+            CipherOption::AES256,
+            CipherOption::XChaCha20,
+            CipherOption::Kuznyechik,
+        ]
+    }
+    fn create_record(password: &str) -> Record {
+        let item1 = Item {
+            title: String::from("Login"),
+            value: String::from("user"),
+            types: vec![],
+        };
+
+        let item2 = Item {
+            title: String::from("Password"),
+            value: password.to_string(),
+            types: vec![Atributes::Hide],
+        };
+        Record {
+            icon: String::from("icon"),
+            created: 1,
+            updated: 2,
+            fields: vec![item1, item2],
+            last_accessed: 0,
+        }
+    }
+
+    #[test]
+    fn test_list_records() {
+        // Create temporary directory for testing
         let temp_dir = TempDir::new("user_db_test").unwrap();
         let master_keys = create_test_keys(); // Initialize test master keys
         let db = UserDb::new(
@@ -263,23 +1414,43 @@ mod tests {
         // Test listing with metadata
         let records_meta = db.list_records_with_metadata().unwrap();
         assert_eq!(records_meta.len(), 3);
-        for (id, ver, user_id) in records_meta {
+        for (id, ver, updated) in records_meta {
             assert!(vec![id1, id2, id3].contains(&id));
             assert_eq!(ver, 1); // All records should be version 1
-            assert_eq!(user_id, [1; 32]); // All records should belong to user 1
+            assert!(updated > 0);
         }
     }
 
     #[test]
-    fn test_crud_operations() {
-        // Create temporary directory for testing
+    fn test_corrupt_record_is_an_error() {
         let temp_dir = TempDir::new("user_db_test").unwrap();
+        let master_keys = create_test_keys();
+        let db = UserDb::new(
+            temp_dir.path(),
+            [1; 32],
+            &master_keys,
+            create_test_cipher_chain(),
+        )
+        .unwrap();
 
-        // Create test record
-        let record = create_record("Password1");
+        let record_id = db.create(create_record("Password1")).unwrap();
+        let mut raw = db.read_raw(record_id).unwrap();
+        raw.data = vec![1, 2, 3, 4, 5];
+        db.storage.set(record_id, &raw).unwrap();
 
-        // Initialize UserDb
-        let master_keys = create_test_keys(); // Initialize test master keys
+        assert!(matches!(
+            db.read(record_id),
+            Err(UserDbError::DecryptionError)
+        ));
+        // Other records stay readable
+        let other_id = db.create(create_record("Password2")).unwrap();
+        assert_eq!(db.read(other_id).unwrap(), create_record("Password2"));
+    }
+
+    #[test]
+    fn test_check_integrity() {
+        let temp_dir = TempDir::new("user_db_test").unwrap();
+        let master_keys = create_test_keys();
         let db = UserDb::new(
             temp_dir.path(),
             [1; 32],
@@ -287,28 +1458,1319 @@ mod tests {
             create_test_cipher_chain(),
         )
         .unwrap();
+        db.enable_record_cache(10);
 
-        // Test create
-        let record_id = db.create(record.clone()).unwrap();
+        let ids: Vec<u64> = ["Password1", "Password2", "Password3"]
+            .iter()
+            .map(|password| db.create(create_record(password)).unwrap())
+            .collect();
+        // Cached plaintext must not hide the corruption
+        for id in &ids {
+            db.read(*id).unwrap();
+        }
+        let report = db.check_integrity().unwrap();
+        assert_eq!((report.ok, report.failed.len()), (3, 0));
 
-        // Test read
-        let retrieved = db.read(record_id).unwrap();
-        assert_eq!(retrieved, record);
+        let mut raw = db.read_raw(ids[1]).unwrap();
+        raw.data.truncate(raw.data.len() - 1);
+        db.storage.set(ids[1], &raw).unwrap();
 
-        // Test update
-        let updated_record = create_record("password");
-        db.update(record_id, updated_record.clone()).unwrap();
+        let report = db.check_integrity().unwrap();
+        assert_eq!(report.ok, 2);
+        let failed: Vec<u64> = report.failed.iter().map(|(id, _)| *id).collect();
+        assert_eq!(failed, vec![ids[1]]);
+        drop(db);
 
-        let retrieved_updated = db.read(record_id).unwrap();
-        assert_eq!(retrieved_updated, updated_record);
+        // A stored CipherRecord that doesn't even decode is reported too
+        {
+            let raw_db = sled::open(temp_dir.path()).unwrap();
+            let tree = raw_db.open_tree([1; 32]).unwrap();
+            tree.insert(99u64.to_be_bytes(), &[1, 2, 3][..]).unwrap();
+            raw_db.flush().unwrap();
+        }
+        let db = UserDb::new(
+            temp_dir.path(),
+            [1; 32],
+            &master_keys,
+            create_test_cipher_chain(),
+        )
+        .unwrap();
+        let report = db.check_integrity().unwrap();
+        assert_eq!(report.ok, 2);
+        let mut failed: Vec<u64> = report.failed.iter().map(|(id, _)| *id).collect();
+        failed.sort();
+        let mut expected = vec![ids[1], 99];
+        expected.sort();
+        assert_eq!(failed, expected);
+    }
 
-        // Test delete
-        db.delete(record_id).unwrap();
-        assert!(matches!(
-            db.read(record_id),
-            Err(UserDbError::StorageError(
-                StorageError::StorageDataNotFound(_)
-            ))
-        ));
+    #[test]
+    fn test_export_import_encrypted() {
+        let master_keys = create_test_keys();
+        let source_dir = TempDir::new("user_db_test").unwrap();
+        let source = UserDb::new(
+            source_dir.path(),
+            [1; 32],
+            &master_keys,
+            create_test_cipher_chain(),
+        )
+        .unwrap();
+        let mut ids = Vec::new();
+        for password in ["Password1", "Password2", "Password3"] {
+            ids.push(source.create(create_record(password)).unwrap());
+        }
+
+        let export_path = source_dir.path().join("backup.json");
+        source.export_encrypted(&export_path).unwrap();
+        assert!(!source_dir.path().join("backup.json.tmp").exists());
+
+        let target_dir = TempDir::new("user_db_test").unwrap();
+        let target = UserDb::new(
+            target_dir.path(),
+            [1; 32],
+            &master_keys,
+            create_test_cipher_chain(),
+        )
+        .unwrap();
+        let report = target.import_encrypted(&export_path).unwrap();
+        assert_eq!(report.added, 3);
+        for (id, password) in ids.iter().zip(["Password1", "Password2", "Password3"]) {
+            assert_eq!(target.read(*id).unwrap(), create_record(password));
+        }
+
+        // Another user's vault takes none of them
+        let other_dir = TempDir::new("user_db_test").unwrap();
+        let other = UserDb::new(
+            other_dir.path(),
+            [2; 32],
+            &master_keys,
+            create_test_cipher_chain(),
+        )
+        .unwrap();
+        let report = other.import_encrypted(&export_path).unwrap();
+        assert_eq!((report.added, report.skipped), (0, 3));
+        assert!(other.list_records().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_search() {
+        let temp_dir = TempDir::new("user_db_test").unwrap();
+        let master_keys = create_test_keys();
+        let db = UserDb::new(
+            temp_dir.path(),
+            [1; 32],
+            &master_keys,
+            create_test_cipher_chain(),
+        )
+        .unwrap();
+
+        let mut mail = create_record("Secret1");
+        mail.fields.push(Item {
+            title: String::from("URL"),
+            value: String::from("https://Mail.example.com"),
+            types: vec![],
+        });
+        let mail_id = db.create(mail.clone()).unwrap();
+        let mut bank = create_record("Secret2");
+        bank.fields.push(Item {
+            title: String::from("Bank PIN"),
+            value: String::from("0000"),
+            types: vec![Atributes::Hide],
+        });
+        let bank_id = db.create(bank.clone()).unwrap();
+
+        // Title match, case-insensitive
+        assert_eq!(db.search("bank pin").unwrap(), vec![(bank_id, bank)]);
+        // URL value match
+        assert_eq!(db.search("mail.EXAMPLE").unwrap(), vec![(mail_id, mail)]);
+        // Shared field title matches both
+        assert_eq!(db.search("login").unwrap().len(), 2);
+        // Hidden values aren't searchable
+        assert!(db.search("secret1").unwrap().is_empty());
+        assert!(db.search("0000").unwrap().is_empty());
+        assert!(db.search("nothing like this").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_create_indexed() {
+        let temp_dir = TempDir::new("user_db_test").unwrap();
+        let master_keys = create_test_keys();
+        let db = UserDb::new(
+            temp_dir.path(),
+            [1; 32],
+            &master_keys,
+            create_test_cipher_chain(),
+        )
+        .unwrap();
+
+        let with_url = |password: &str| {
+            let mut record = create_record(password);
+            record.fields.push(Item {
+                title: String::from("URL"),
+                value: String::from("https://mail.example.com"),
+                types: vec![],
+            });
+            record
+        };
+        let first = with_url("Secret1");
+        let second = with_url("Secret2");
+        let first_id = db.create_indexed(first.clone()).unwrap();
+        let second_id = db.create_indexed(second.clone()).unwrap();
+        let plain_id = db.create(with_url("Secret3")).unwrap();
+
+        let first_data = UserDb::indexed_data(&db.read_raw(first_id).unwrap().data).unwrap();
+        let second_data = UserDb::indexed_data(&db.read_raw(second_id).unwrap().data).unwrap();
+        // Login and URL are searchable, the password stays in the blob
+        assert_eq!(first_data.searchable.len(), 2);
+        assert_eq!(first_data.searchable[1], second_data.searchable[1]);
+        assert_ne!(first_data.blob, second_data.blob);
+        assert!(UserDb::indexed_data(&db.read_raw(plain_id).unwrap().data).is_none());
+
+        assert_eq!(db.read(first_id).unwrap(), first);
+        assert_eq!(db.read(second_id).unwrap(), second);
+        assert_eq!(
+            db.find_indexed("URL", "https://mail.example.com")
+                .unwrap()
+                .len(),
+            2
+        );
+        assert!(db
+            .find_indexed("URL", "https://mail.example")
+            .unwrap()
+            .is_empty());
+
+        // Updates keep the record searchable
+        let mut changed = first.clone();
+        changed.fields[2].value = String::from("https://example.org");
+        db.update(first_id, changed.clone()).unwrap();
+        assert_eq!(db.read(first_id).unwrap(), changed);
+        assert_eq!(
+            db.find_indexed("URL", "https://example.org").unwrap(),
+            vec![first_id]
+        );
+    }
+
+    #[test]
+    fn test_delete_where() {
+        let temp_dir = TempDir::new("user_db_test").unwrap();
+        let master_keys = create_test_keys();
+        let db = UserDb::new(
+            temp_dir.path(),
+            [1; 32],
+            &master_keys,
+            create_test_cipher_chain(),
+        )
+        .unwrap();
+
+        let with_url = |url: &str| {
+            let mut record = create_record("Secret");
+            record.fields.push(Item {
+                title: String::from("URL"),
+                value: url.to_string(),
+                types: vec![],
+            });
+            record
+        };
+        let mail_id = db.create(with_url("https://mail.example.com")).unwrap();
+        let shop_id = db.create(with_url("https://shop.example.com")).unwrap();
+        let other_id = db.create(with_url("https://example.org")).unwrap();
+        let no_url_id = db.create(create_record("Secret")).unwrap();
+
+        let from_example_com = |record: &Record| {
+            record
+                .field_value("URL")
+                .is_some_and(|url| url.contains("example.com"))
+        };
+        let mut deleted = db.delete_where(from_example_com).unwrap();
+        deleted.sort();
+        let mut expected = vec![mail_id, shop_id];
+        expected.sort();
+        assert_eq!(deleted, expected);
+
+        let mut remaining = db.list_records().unwrap();
+        remaining.sort();
+        let mut expected = vec![other_id, no_url_id];
+        expected.sort();
+        assert_eq!(remaining, expected);
+        assert_eq!(db.tombstones().unwrap().len(), 2);
+
+        // A record that can't be decrypted is reported without stopping the rest
+        let corrupt_id = db.create(with_url("https://bank.example.com")).unwrap();
+        let mut raw = db.read_raw(corrupt_id).unwrap();
+        raw.data = vec![1, 2, 3, 4, 5];
+        db.storage.set(corrupt_id, &raw).unwrap();
+        let again_id = db.create(with_url("https://mail.example.com")).unwrap();
+        match db.delete_where(from_example_com) {
+            Err(UserDbError::PartialDelete { deleted, failed }) => {
+                assert_eq!(deleted, vec![again_id]);
+                assert_eq!(failed.len(), 1);
+                assert_eq!(failed[0].0, corrupt_id);
+            }
+            other => panic!("expected a partial delete, got {:?}", other),
+        }
+        assert!(db.read(again_id).is_err());
+        assert!(db.read(other_id).is_ok());
+    }
+
+    #[test]
+    fn test_unique_record_ids() {
+        let temp_dir = TempDir::new("user_db_test").unwrap();
+        let master_keys = create_test_keys();
+        let db = UserDb::new(
+            temp_dir.path(),
+            [1; 32],
+            &master_keys,
+            create_test_cipher_chain(),
+        )
+        .unwrap();
+
+        let record = create_record("Password1");
+        for _ in 0..1000 {
+            db.create(record.clone()).unwrap();
+        }
+        assert_eq!(db.list_records().unwrap().len(), 1000);
+    }
+
+    #[test]
+    fn test_list_changed_since() {
+        let temp_dir = TempDir::new("user_db_test").unwrap();
+        let master_keys = create_test_keys();
+        let db = UserDb::new(
+            temp_dir.path(),
+            [1; 32],
+            &master_keys,
+            create_test_cipher_chain(),
+        )
+        .unwrap();
+
+        // Write records directly so their timestamps are deterministic. Record 4
+        // belongs to another user and is never listed.
+        for (id, user_id, updated) in [
+            (1u64, [1; 32], 100u64),
+            (2, [1; 32], 200),
+            (3, [1; 32], 300),
+            (4, [2; 32], 400),
+        ] {
+            db.storage
+                .set(
+                    id,
+                    &CipherRecord {
+                        user_id,
+                        cipher_record_id: id,
+                        ver: 1,
+                        cipher_options: vec![],
+                        data: vec![0, 42],
+                        signature: vec![],
+                        updated,
+                    },
+                )
+                .unwrap();
+        }
+
+        let changed: Vec<u64> = db
+            .list_changed_since(200)
+            .unwrap()
+            .into_iter()
+            .map(|(id, _, _)| id)
+            .collect();
+        assert_eq!(changed, vec![3]);
+
+        assert_eq!(db.list_changed_since(0).unwrap().len(), 3);
+        assert!(db.list_changed_since(300).unwrap().is_empty());
+        assert_eq!(db.list_records_with_metadata().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_crud_operations() {
+        // Create temporary directory for testing
+        let temp_dir = TempDir::new("user_db_test").unwrap();
+
+        // Create test record
+        let record = create_record("Password1");
+
+        // Initialize UserDb
+        let master_keys = create_test_keys(); // Initialize test master keys
+        let db = UserDb::new(
+            temp_dir.path(),
+            [1; 32],
+            &master_keys,
+            create_test_cipher_chain(),
+        )
+        .unwrap();
+
+        // Test create
+        let record_id = db.create(record.clone()).unwrap();
+
+        // Test read
+        let retrieved = db.read(record_id).unwrap();
+        assert_eq!(retrieved, record);
+
+        // Test update
+        let updated_record = create_record("password");
+        db.update(record_id, updated_record.clone()).unwrap();
+
+        let retrieved_updated = db.read(record_id).unwrap();
+        assert_eq!(retrieved_updated, updated_record);
+
+        // Test delete
+        db.delete(record_id).unwrap();
+        assert!(matches!(
+            db.read(record_id),
+            Err(UserDbError::StorageError(
+                StorageError::StorageDataNotFound(_)
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_tombstones() {
+        let temp_dir = TempDir::new("user_db_test").unwrap();
+        let master_keys = create_test_keys();
+        let db = UserDb::new(
+            temp_dir.path(),
+            [1; 32],
+            &master_keys,
+            create_test_cipher_chain(),
+        )
+        .unwrap();
+
+        let deleted = db.create(create_record("Password1")).unwrap();
+        db.update(deleted, create_record("Password2")).unwrap();
+        let prior = db.read_raw(deleted).unwrap();
+        db.delete(deleted).unwrap();
+
+        let tombstones = db.tombstones().unwrap();
+        assert_eq!(tombstones.len(), 1);
+        assert_eq!(tombstones[0].id, deleted);
+        assert_eq!(tombstones[0].ver, 3);
+
+        // Undoing the delete takes the tombstone back
+        db.restore_raw(&prior).unwrap();
+        assert!(db.tombstones().unwrap().is_empty());
+
+        // Deletes coming from the server leave none
+        db.apply_remote_delete(deleted).unwrap();
+        assert!(db.read(deleted).is_err());
+        assert!(db.tombstones().unwrap().is_empty());
+
+        let other = db.create(create_record("Password3")).unwrap();
+        db.delete(other).unwrap();
+        db.clear_tombstone(other).unwrap();
+        assert!(db.tombstones().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_last_sync() {
+        let temp_dir = TempDir::new("user_db_test").unwrap();
+        let master_keys = create_test_keys();
+        {
+            let db = UserDb::new(
+                temp_dir.path(),
+                [1; 32],
+                &master_keys,
+                create_test_cipher_chain(),
+            )
+            .unwrap();
+            assert_eq!(db.last_sync().unwrap(), 0);
+            db.set_last_sync(1_700_000_000).unwrap();
+        }
+
+        // Kept with the vault across sessions
+        let db = UserDb::new(
+            temp_dir.path(),
+            [1; 32],
+            &master_keys,
+            create_test_cipher_chain(),
+        )
+        .unwrap();
+        assert_eq!(db.last_sync().unwrap(), 1_700_000_000);
+    }
+
+    #[test]
+    fn test_read_raw() {
+        let temp_dir = TempDir::new("user_db_test").unwrap();
+        let master_keys = create_test_keys();
+        let db = UserDb::new(
+            temp_dir.path(),
+            [1; 32],
+            &master_keys,
+            create_test_cipher_chain(),
+        )
+        .unwrap();
+
+        let record = create_record("Password1");
+        let plain_len = record.encode_versioned().unwrap().len();
+        let record_id = db.create(record).unwrap();
+
+        let raw = db.read_raw(record_id).unwrap();
+        assert_eq!(raw.cipher_record_id, record_id);
+        assert_eq!(raw.ver, 1);
+        let expected_options: Vec<u8> = create_test_cipher_chain()
+            .iter()
+            .map(|cipher| cipher.code())
+            .collect();
+        assert_eq!(raw.cipher_options, expected_options);
+
+        // AES256: 16 byte IV + padding to 16, XChaCha20: 24 byte nonce,
+        // Kuznyechik: 16 byte IV + padding to 16
+        let aes_len = 16 + (plain_len / 16 + 1) * 16;
+        let chacha_len = aes_len + 24;
+        let expected_len = 16 + (chacha_len / 16 + 1) * 16;
+        assert_eq!(raw.data.len(), expected_len);
+    }
+
+    fn login_record(url: &str, login: &str, password: &str) -> Record {
+        let field = |title: &str, value: &str| Item {
+            title: title.to_string(),
+            value: value.to_string(),
+            types: vec![],
+        };
+        Record {
+            icon: String::new(),
+            created: 1,
+            updated: 1,
+            fields: vec![
+                field("URL", url),
+                field("Login", login),
+                field("Password", password),
+            ],
+            last_accessed: 0,
+        }
+    }
+
+    #[test]
+    fn test_import_dedup() {
+        let temp_dir = TempDir::new("user_db_test").unwrap();
+        let master_keys = create_test_keys();
+        let db = UserDb::new(
+            temp_dir.path(),
+            [1; 32],
+            &master_keys,
+            create_test_cipher_chain(),
+        )
+        .unwrap();
+
+        let existing = login_record("example.com", "alice", "secret");
+        db.create(existing.clone()).unwrap();
+
+        // Same content with a different import timestamp is still a duplicate
+        let mut reimported = existing.clone();
+        reimported.created = 99;
+        let report = db
+            .import_records(
+                vec![
+                    reimported,
+                    login_record("example.org", "bob", "hunter2"),
+                    login_record("example.org", "bob", "hunter2"),
+                ],
+                &DedupPolicy::Content,
+            )
+            .unwrap();
+        assert_eq!(
+            report,
+            ImportReport {
+                added: 1,
+                skipped: 2,
+                merged: 0
+            }
+        );
+        assert_eq!(db.list_records().unwrap().len(), 2);
+
+        // Keyed on URL + Login a changed password is merged into the existing entry
+        let policy = DedupPolicy::KeyFields(vec!["URL".to_string(), "Login".to_string()]);
+        let report = db
+            .import_records(
+                vec![
+                    login_record("example.com", "alice", "new secret"),
+                    login_record("example.org", "bob", "hunter2"),
+                ],
+                &policy,
+            )
+            .unwrap();
+        assert_eq!(
+            report,
+            ImportReport {
+                added: 0,
+                skipped: 1,
+                merged: 1
+            }
+        );
+
+        let ids = db.list_records().unwrap();
+        assert_eq!(ids.len(), 2);
+        let passwords: Vec<String> = ids
+            .iter()
+            .map(|id| {
+                db.read(*id)
+                    .unwrap()
+                    .field_value("Password")
+                    .unwrap()
+                    .to_string()
+            })
+            .collect();
+        assert!(passwords.contains(&"new secret".to_string()));
+        assert!(!passwords.contains(&"secret".to_string()));
+    }
+
+    #[test]
+    fn test_rotate_key() {
+        let temp_dir = TempDir::new("user_db_test").unwrap();
+        let mut entropy = [0u8; 32];
+        OsRng.fill_bytes(&mut entropy);
+        let master_keys = MasterKeys::from_entropy(&entropy).unwrap();
+        let mut new_keys = MasterKeys::from_entropy(&entropy).unwrap();
+        new_keys.rotate_key(CipherOption::AES256).unwrap();
+
+        let mut record1 = create_record("Password1");
+        let record2 = create_record("Password2");
+        let (id1, id2) = {
+            let db = UserDb::new(
+                temp_dir.path(),
+                [1; 32],
+                &master_keys,
+                create_test_cipher_chain(),
+            )
+            .unwrap();
+            let id1 = db.create(record1.clone()).unwrap();
+            let id2 = db.create(record2.clone()).unwrap();
+            record1.last_accessed = db.read_and_touch(id1).unwrap().last_accessed;
+            assert_eq!(db.rotate_key(&new_keys).unwrap(), 2);
+            (id1, id2)
+        };
+
+        // Only the AES key changed
+        assert_ne!(new_keys.aes256_key, master_keys.aes256_key);
+        assert_eq!(new_keys.xchacha20_key, master_keys.xchacha20_key);
+        assert_eq!(new_keys.kuznyechik_key, master_keys.kuznyechik_key);
+
+        // The old keys no longer open the vault
+        assert!(matches!(
+            UserDb::new(
+                temp_dir.path(),
+                [1; 32],
+                &master_keys,
+                create_test_cipher_chain(),
+            ),
+            Err(UserDbError::KeyGenerationMismatch)
+        ));
+
+        // Keys rebuilt from the seed open the vault once the stored generations are applied
+        let generations = UserDb::stored_key_generations(temp_dir.path(), [1; 32]).unwrap();
+        assert_eq!(generations, vec![(CipherOption::AES256, 1)]);
+        let mut reopened_keys = MasterKeys::from_entropy(&entropy).unwrap();
+        for (cipher, generation) in generations {
+            reopened_keys.set_generation(cipher, generation).unwrap();
+        }
+        assert_eq!(reopened_keys.aes256_key, new_keys.aes256_key);
+
+        let db = UserDb::new(
+            temp_dir.path(),
+            [1; 32],
+            &reopened_keys,
+            create_test_cipher_chain(),
+        )
+        .unwrap();
+        assert_eq!(db.read(id1).unwrap(), record1);
+        assert_eq!(db.read(id2).unwrap(), record2);
+        assert_eq!(db.read_raw(id1).unwrap().ver, 2);
+    }
+
+    #[test]
+    fn test_migrate_chain() {
+        let temp_dir = TempDir::new("user_db_test").unwrap();
+        let master_keys = create_test_keys();
+        let old_chain = vec![CipherOption::AES256];
+        let new_chain = vec![CipherOption::AES256, CipherOption::XChaCha20];
+
+        let records: Vec<Record> = (0..4)
+            .map(|i| create_record(&format!("Password{}", i)))
+            .collect();
+        let (ids, skipped, touched) = {
+            let db =
+                UserDb::new(temp_dir.path(), [1; 32], &master_keys, old_chain.clone()).unwrap();
+            let ids: Vec<u64> = records
+                .iter()
+                .map(|record| db.create(record.clone()).unwrap())
+                .collect();
+            let touched = db.read_and_touch(ids[1]).unwrap().last_accessed;
+
+            // A migration to another chain left unfinished blocks a new one
+            let other = ChainMigration {
+                cipher_chain: vec![CipherOption::Kuznyechik.code()],
+                last_done: None,
+            };
+            db.storage
+                .set_meta(CHAIN_MIGRATION_META, &serialize(&other).unwrap())
+                .unwrap();
+            assert!(matches!(
+                db.migrate_chain(new_chain.clone()),
+                Err(UserDbError::ChainMigrationPending)
+            ));
+
+            // Resuming skips the records up to the last one done, in id order
+            let skipped = db.list_records().unwrap()[0];
+            let interrupted = ChainMigration {
+                cipher_chain: new_chain.iter().map(|cipher| cipher.code()).collect(),
+                last_done: Some(skipped),
+            };
+            db.storage
+                .set_meta(CHAIN_MIGRATION_META, &serialize(&interrupted).unwrap())
+                .unwrap();
+            db.migrate_chain(new_chain.clone()).unwrap();
+            assert_eq!(db.storage.get_meta(CHAIN_MIGRATION_META).unwrap(), None);
+            (ids, skipped, touched)
+        };
+
+        assert_eq!(
+            UserDb::stored_cipher_chain(temp_dir.path(), [1; 32]).unwrap(),
+            Some(new_chain.clone())
+        );
+
+        // The old chain no longer matches the vault
+        assert!(matches!(
+            UserDb::new(temp_dir.path(), [1; 32], &master_keys, old_chain),
+            Err(UserDbError::CipherChainMismatch)
+        ));
+
+        let db = UserDb::new(temp_dir.path(), [1; 32], &master_keys, new_chain).unwrap();
+        for (id, record) in ids.iter().zip(&records) {
+            // The access time was re-encrypted along with its record
+            let mut expected = record.clone();
+            if *id == ids[1] {
+                expected.last_accessed = touched;
+            }
+            assert_eq!(db.read(*id).unwrap(), expected);
+            let raw = db.read_raw(*id).unwrap();
+            if *id == skipped {
+                assert_eq!(raw.ver, 1);
+                assert_eq!(raw.cipher_options, vec![CipherOption::AES256.code()]);
+            } else {
+                assert_eq!(raw.ver, 2);
+                assert_eq!(
+                    raw.cipher_options,
+                    vec![CipherOption::AES256.code(), CipherOption::XChaCha20.code()]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_migrate_chain_missing_key() {
+        let temp_dir = TempDir::new("user_db_test").unwrap();
+        let master_keys = MasterKeys::from_entropy_for(&[4; 32], &[CipherOption::AES256]).unwrap();
+        let db = UserDb::new(
+            temp_dir.path(),
+            [1; 32],
+            &master_keys,
+            vec![CipherOption::AES256],
+        )
+        .unwrap();
+        let id = db.create(create_record("Password")).unwrap();
+
+        // Refused before any record is touched
+        assert!(matches!(
+            db.migrate_chain(vec![CipherOption::AES256, CipherOption::XChaCha20]),
+            Err(UserDbError::MissingKey(CipherOption::XChaCha20))
+        ));
+        assert_eq!(db.storage.get_meta(CHAIN_MIGRATION_META).unwrap(), None);
+        assert_eq!(db.read_raw(id).unwrap().ver, 1);
+    }
+
+    #[test]
+    fn test_rekey() {
+        let temp_dir = TempDir::new("user_db_test").unwrap();
+        let old_keys = create_test_keys();
+        let new_keys = create_test_keys();
+        let new_chain = vec![CipherOption::Serpent, CipherOption::XChaCha20];
+
+        let records: Vec<Record> = (0..3)
+            .map(|i| create_record(&format!("Password{}", i)))
+            .collect();
+        let ids: Vec<u64> = {
+            let db = UserDb::new(
+                temp_dir.path(),
+                old_keys.user_id,
+                &old_keys,
+                create_test_cipher_chain(),
+            )
+            .unwrap();
+            let ids = records
+                .iter()
+                .map(|record| db.create(record.clone()).unwrap())
+                .collect();
+            db.rekey(&new_keys, new_chain.clone()).unwrap();
+            // Only once
+            assert!(matches!(
+                db.rekey(&new_keys, new_chain.clone()),
+                Err(UserDbError::RekeyTargetExists)
+            ));
+            ids
+        };
+
+        // The old keys don't open the new vault
+        assert!(matches!(
+            UserDb::new(
+                temp_dir.path(),
+                new_keys.user_id,
+                &old_keys,
+                new_chain.clone()
+            ),
+            Err(UserDbError::WrongKey)
+        ));
+
+        {
+            let db = UserDb::new(
+                temp_dir.path(),
+                new_keys.user_id,
+                &new_keys,
+                new_chain.clone(),
+            )
+            .unwrap();
+            for (id, record) in ids.iter().zip(&records) {
+                assert_eq!(&db.read(*id).unwrap(), record);
+                assert_eq!(db.read_raw(*id).unwrap().user_id, new_keys.user_id);
+            }
+        }
+
+        // The old vault is intact until discarded
+        let old = UserDb::new(
+            temp_dir.path(),
+            old_keys.user_id,
+            &old_keys,
+            create_test_cipher_chain(),
+        )
+        .unwrap();
+        assert_eq!(old.list_records().unwrap().len(), 3);
+        old.discard().unwrap();
+
+        let old = UserDb::new(
+            temp_dir.path(),
+            old_keys.user_id,
+            &old_keys,
+            create_test_cipher_chain(),
+        )
+        .unwrap();
+        assert!(old.list_records().unwrap().is_empty());
+        drop(old);
+        let db = UserDb::new(temp_dir.path(), new_keys.user_id, &new_keys, new_chain).unwrap();
+        assert_eq!(db.read(ids[0]).unwrap(), records[0]);
+    }
+
+    #[test]
+    fn test_estimate_encrypted_size() {
+        let temp_dir = TempDir::new("user_db_test").unwrap();
+        let master_keys = create_test_keys();
+        let db = UserDb::new(
+            temp_dir.path(),
+            [1; 32],
+            &master_keys,
+            create_test_cipher_chain(),
+        )
+        .unwrap();
+
+        let long_password = "long password ".repeat(40);
+        let records: Vec<Record> = ["", "short", long_password.as_str()]
+            .iter()
+            .map(|password| create_record(password))
+            .collect();
+
+        let mut total = 0;
+        for record in &records {
+            let estimate = db.estimate_encrypted_size(record).unwrap();
+            let id = db.create(record.clone()).unwrap();
+            let actual = db.read_raw(id).unwrap().data.len();
+            assert_eq!(estimate, actual);
+            total += actual;
+        }
+        assert_eq!(db.estimate_encrypted_size_batch(&records).unwrap(), total);
+    }
+
+    #[test]
+    fn test_list_records_page() {
+        let temp_dir = TempDir::new("user_db_test").unwrap();
+        let master_keys = create_test_keys();
+        let db = UserDb::new(
+            temp_dir.path(),
+            [1; 32],
+            &master_keys,
+            create_test_cipher_chain(),
+        )
+        .unwrap();
+
+        let mut expected = Vec::new();
+        for i in 0..25 {
+            expected.push(db.create(create_record(&format!("password{i}"))).unwrap());
+        }
+        // A body that can't be decrypted; listing must not try
+        let broken = CipherRecord {
+            user_id: [1; 32],
+            cipher_record_id: u64::MAX,
+            ver: 1,
+            cipher_options: vec![],
+            data: vec![1, 2, 3],
+            signature: vec![],
+            updated: 0,
+        };
+        db.storage.set(u64::MAX, &broken).unwrap();
+        expected.push(u64::MAX);
+        expected.sort();
+
+        let mut visited = Vec::new();
+        let mut page_sizes = Vec::new();
+        let mut after = None;
+        loop {
+            let page = db.list_records_page(after, 10).unwrap();
+            if page.is_empty() {
+                break;
+            }
+            page_sizes.push(page.len());
+            after = page.last().copied();
+            visited.extend(page);
+        }
+
+        assert_eq!(visited, expected);
+        assert_eq!(page_sizes, vec![10, 10, 6]);
+    }
+
+    #[test]
+    fn test_vault_meta() {
+        let temp_dir = TempDir::new("user_db_test").unwrap();
+        let master_keys = create_test_keys();
+
+        let created = {
+            let db = UserDb::new(
+                temp_dir.path(),
+                [1; 32],
+                &master_keys,
+                create_test_cipher_chain(),
+            )
+            .unwrap();
+            let meta = db.vault_meta().unwrap().unwrap();
+            assert_eq!(meta.schema_version, VAULT_SCHEMA_VERSION);
+            assert_eq!(meta.argon2_params.memory_kib, MasterKeys::MEMORY_SIZE);
+            assert_eq!(meta.argon2_params.time_cost, MasterKeys::TIME_COST);
+            assert_eq!(meta.argon2_params.parallelism, MasterKeys::PARALLELISM);
+            assert_eq!(meta.cipher_chain, db.get_cipher_options());
+            assert!(!meta.canary.is_empty());
+            assert!(meta.created > 0);
+            meta
+        };
+
+        let db = UserDb::new(
+            temp_dir.path(),
+            [1; 32],
+            &master_keys,
+            create_test_cipher_chain(),
+        )
+        .unwrap();
+        assert_eq!(db.vault_meta().unwrap(), Some(created));
+        drop(db);
+
+        let other_keys = create_test_keys();
+        assert!(matches!(
+            UserDb::new(
+                temp_dir.path(),
+                [1; 32],
+                &other_keys,
+                create_test_cipher_chain(),
+            ),
+            Err(UserDbError::WrongKey)
+        ));
+        assert!(matches!(
+            UserDb::new(
+                temp_dir.path(),
+                [1; 32],
+                &master_keys,
+                vec![CipherOption::AES256],
+            ),
+            Err(UserDbError::CipherChainMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_read_uses_record_cipher_chain() {
+        let master_keys = create_test_keys();
+        let written_dir = TempDir::new("user_db_test").unwrap();
+        let written = UserDb::new(
+            written_dir.path(),
+            [1; 32],
+            &master_keys,
+            vec![CipherOption::Kuznyechik, CipherOption::AES256],
+        )
+        .unwrap();
+        let record_id = written.create(create_record("Password1")).unwrap();
+        let raw = written.read_raw(record_id).unwrap();
+        assert_eq!(
+            raw.cipher_options,
+            vec![CipherOption::Kuznyechik.code(), CipherOption::AES256.code()]
+        );
+
+        // Same keys, different default chain, e.g. a vault restored on another device
+        let other_dir = TempDir::new("user_db_test").unwrap();
+        let other = UserDb::new(
+            other_dir.path(),
+            [1; 32],
+            &master_keys,
+            create_test_cipher_chain(),
+        )
+        .unwrap();
+        other.storage.set(record_id, &raw).unwrap();
+        assert_eq!(other.read(record_id).unwrap(), create_record("Password1"));
+
+        // Writes use the session chain again
+        other.update(record_id, create_record("Password2")).unwrap();
+        assert_eq!(
+            other.read_raw(record_id).unwrap().cipher_options,
+            other.get_cipher_options()
+        );
+        assert_eq!(other.read(record_id).unwrap(), create_record("Password2"));
+
+        let mut unknown = raw.clone();
+        unknown.cipher_options = vec![200];
+        other.storage.set(record_id, &unknown).unwrap();
+        assert!(matches!(
+            other.read(record_id),
+            Err(UserDbError::UnknownCipher(200))
+        ));
+    }
+
+    #[test]
+    fn test_cipher_chain_downgrade() {
+        let temp_dir = TempDir::new("user_db_test").unwrap();
+        let master_keys = create_test_keys();
+
+        let db = UserDb::new(
+            temp_dir.path(),
+            [1; 32],
+            &master_keys,
+            create_test_cipher_chain(),
+        )
+        .unwrap();
+        let mut meta = db.vault_meta().unwrap().unwrap();
+        meta.cipher_chain = vec![CipherOption::AES256.code()];
+        db.storage
+            .set_meta(VAULT_META, &serialize(&meta).unwrap())
+            .unwrap();
+        drop(db);
+
+        assert!(matches!(
+            UserDb::new(
+                temp_dir.path(),
+                [1; 32],
+                &master_keys,
+                create_test_cipher_chain(),
+            ),
+            Err(UserDbError::CipherChainDowngrade)
+        ));
+        // Caught from the stored chain alone, before keys are derived for it
+        let stored = UserDb::stored_cipher_chain(temp_dir.path(), [1; 32])
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            UserDb::check_chain_policy(&stored, &create_test_cipher_chain()),
+            Err(UserDbError::CipherChainDowngrade)
+        ));
+        assert!(UserDb::check_chain_policy(&create_test_cipher_chain(), &stored).is_ok());
+        // Trusting the tampered chain doesn't help, the canary was made under the original
+        assert!(matches!(
+            UserDb::new(
+                temp_dir.path(),
+                [1; 32],
+                &master_keys,
+                vec![CipherOption::AES256],
+            ),
+            Err(UserDbError::WrongKey)
+        ));
+    }
+
+    #[test]
+    fn test_create_vault_at_occupied_path() {
+        let temp_dir = TempDir::new("user_db_test").unwrap();
+        let existing_keys = create_test_keys();
+        let new_keys = create_test_keys();
+
+        let db = UserDb::create_vault(
+            temp_dir.path(),
+            existing_keys.user_id,
+            &existing_keys,
+            create_test_cipher_chain(),
+            false,
+        )
+        .unwrap();
+        db.create(create_record("Password1")).unwrap();
+        drop(db);
+
+        assert!(matches!(
+            UserDb::create_vault(
+                temp_dir.path(),
+                new_keys.user_id,
+                &new_keys,
+                create_test_cipher_chain(),
+                false,
+            ),
+            Err(UserDbError::VaultExists(_))
+        ));
+
+        // Explicit override opens a separate vault next to the existing one
+        let db = UserDb::create_vault(
+            temp_dir.path(),
+            new_keys.user_id,
+            &new_keys,
+            create_test_cipher_chain(),
+            true,
+        )
+        .unwrap();
+        assert!(db.list_records().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_record_schema_versions() {
+        let record = create_record("Password1");
+        let encoded = record.encode_versioned().unwrap();
+        assert_eq!(encoded[0], RECORD_SCHEMA_VERSION);
+        assert_eq!(&encoded[1..], serialize(&record).unwrap().as_slice());
+        assert_eq!(Record::decode_versioned(&encoded).unwrap(), record);
+
+        // Version 1 lacks `last_accessed`
+        let v1 = RecordV1 {
+            icon: record.icon.clone(),
+            created: record.created,
+            updated: record.updated,
+            fields: record.fields.clone(),
+        };
+        let mut encoded_v1 = vec![1];
+        encoded_v1.extend(serialize(&v1).unwrap());
+        assert_eq!(Record::decode_versioned(&encoded_v1).unwrap(), record);
+
+        // Written before the prefix existed
+        assert_eq!(
+            Record::decode_versioned(&serialize(&record).unwrap()).unwrap(),
+            record
+        );
+        assert_eq!(
+            Record::decode_versioned(&serialize(&v1).unwrap()).unwrap(),
+            record
+        );
+
+        let mut future = encoded.clone();
+        future[0] = RECORD_SCHEMA_VERSION + 1;
+        assert!(Record::decode_versioned(&future).is_err());
+    }
+
+    #[test]
+    fn test_owns() {
+        let temp_dir = TempDir::new("user_db_test").unwrap();
+        let master_keys = create_test_keys();
+        let db = UserDb::new(
+            temp_dir.path(),
+            [1; 32],
+            &master_keys,
+            create_test_cipher_chain(),
+        )
+        .unwrap();
+        let id = db.create(create_record("Password1")).unwrap();
+        let mut record = db.read_raw(id).unwrap();
+        assert!(db.owns(&record));
+
+        record.user_id[31] = 2;
+        assert!(!db.owns(&record));
+        record.user_id = [0; 32];
+        assert!(!db.owns(&record));
+    }
+
+    #[test]
+    fn test_read_and_touch() {
+        let temp_dir = TempDir::new("user_db_test").unwrap();
+        let master_keys = create_test_keys();
+        let db = UserDb::new(
+            temp_dir.path(),
+            [1; 32],
+            &master_keys,
+            create_test_cipher_chain(),
+        )
+        .unwrap();
+
+        // A record serialized before `last_accessed` existed
+        let old = create_record("Password1");
+        let mut old_bytes = serialize(&(
+            old.icon.clone(),
+            old.created,
+            old.updated,
+            old.fields.clone(),
+        ))
+        .unwrap();
+        assert!(deserialize::<Record>(&old_bytes).is_err());
+        let id = 42;
+        db.storage
+            .set(
+                id,
+                &CipherRecord {
+                    user_id: [1; 32],
+                    cipher_record_id: id,
+                    ver: 1,
+                    cipher_options: db.get_cipher_options(),
+                    data: db.ciphers.encrypt(&mut old_bytes).unwrap(),
+                    signature: vec![],
+                    updated: 1,
+                },
+            )
+            .unwrap();
+        assert_eq!(db.read(id).unwrap(), old);
+        assert_eq!(db.read(id).unwrap().last_accessed, 0);
+
+        let stored = db.read_raw(id).unwrap();
+        let touched = db.read_and_touch(id).unwrap();
+        assert!(touched.last_accessed > 0);
+        assert_eq!(touched.updated, old.updated);
+        assert!(touched.content_eq(&old));
+        assert_eq!(db.read(id).unwrap(), touched);
+        // Local only: the stored record, and so what sync sees, is unchanged
+        assert_eq!(db.read_raw(id).unwrap(), stored);
+        // Nor is the time readable from the metadata
+        let sealed = db
+            .storage
+            .get_meta(&UserDb::last_accessed_key(id))
+            .unwrap()
+            .unwrap();
+        assert!(!sealed
+            .windows(8)
+            .any(|window| window == touched.last_accessed.to_be_bytes()));
+
+        db.delete(id).unwrap();
+        assert!(db
+            .storage
+            .get_meta(&UserDb::last_accessed_key(id))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_update_unchanged_content() {
+        let temp_dir = TempDir::new("user_db_test").unwrap();
+        let master_keys = create_test_keys();
+        let db = UserDb::new(
+            temp_dir.path(),
+            [1; 32],
+            &master_keys,
+            create_test_cipher_chain(),
+        )
+        .unwrap();
+
+        let record = create_record("Password1");
+        let id = db.create(record.clone()).unwrap();
+        let stored = db.read_raw(id).unwrap();
+
+        // Same content with newer timestamps is still unchanged
+        let mut same = record.clone();
+        same.updated += 10;
+        assert_eq!(db.update(id, same).unwrap(), 1);
+        assert_eq!(db.read_raw(id).unwrap(), stored);
+
+        assert_eq!(db.update(id, create_record("Password2")).unwrap(), 2);
+        assert_eq!(db.read(id).unwrap(), create_record("Password2"));
+
+        // A forced update is a touch
+        assert_eq!(
+            db.update_with(id, create_record("Password2"), true)
+                .unwrap(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_record_cache() {
+        let temp_dir = TempDir::new("user_db_test").unwrap();
+        let master_keys = create_test_keys();
+        let db = UserDb::new(
+            temp_dir.path(),
+            [1; 32],
+            &master_keys,
+            create_test_cipher_chain(),
+        )
+        .unwrap();
+        let decrypts = || db.decrypts.load(Ordering::Relaxed);
+
+        let id = db.create(create_record("Password1")).unwrap();
+
+        // Off by default
+        db.read(id).unwrap();
+        db.read(id).unwrap();
+        assert_eq!(decrypts(), 2);
+
+        db.enable_record_cache(4);
+        assert_eq!(db.read(id).unwrap(), create_record("Password1"));
+        assert_eq!(db.read(id).unwrap(), create_record("Password1"));
+        assert_eq!(decrypts(), 3);
+
+        // An update drops the cached version instead of serving it
+        db.update(id, create_record("Password2")).unwrap();
+        assert_eq!(db.read(id).unwrap(), create_record("Password2"));
+        assert_eq!(decrypts(), 4);
+        db.read(id).unwrap();
+        assert_eq!(decrypts(), 4);
+
+        db.delete(id).unwrap();
+        assert!(db.read(id).is_err());
+    }
+
+    #[test]
+    fn test_vault_diff() {
+        let live_dir = TempDir::new("user_db_test").unwrap();
+        let backup_dir = TempDir::new("user_db_test").unwrap();
+        let live_keys = create_test_keys();
+        let backup_keys = create_test_keys();
+        let live = UserDb::new(
+            live_dir.path(),
+            [1; 32],
+            &live_keys,
+            create_test_cipher_chain(),
+        )
+        .unwrap();
+        let backup = UserDb::new(
+            backup_dir.path(),
+            [1; 32],
+            &backup_keys,
+            create_test_cipher_chain(),
+        )
+        .unwrap();
+
+        let put = |db: &UserDb, id: u64, ver: u64, password: &str| {
+            let mut data = serialize(&create_record(password)).unwrap();
+            let cipher_record = CipherRecord {
+                user_id: [1; 32],
+                cipher_record_id: id,
+                ver,
+                cipher_options: db.get_cipher_options(),
+                data: db.ciphers.encrypt(&mut data).unwrap(),
+                signature: vec![],
+                updated: 0,
+            };
+            db.storage.set(id, &cipher_record).unwrap();
+        };
+
+        put(&live, 1, 1, "same");
+        put(&backup, 1, 1, "same");
+        // Same content, only the version moved on
+        put(&live, 2, 3, "same");
+        put(&backup, 2, 1, "same");
+        put(&live, 3, 2, "edited");
+        put(&backup, 3, 1, "original");
+        put(&live, 4, 1, "new");
+        put(&backup, 5, 1, "deleted");
+
+        let diff = live.diff(&backup).unwrap();
+        assert_eq!(
+            diff,
+            VaultDiff {
+                only_in_self: vec![4],
+                only_in_other: vec![5],
+                differing: vec![RecordDiff {
+                    id: 3,
+                    self_ver: 2,
+                    other_ver: 1,
+                }],
+                unchanged: 2,
+            }
+        );
     }
 }